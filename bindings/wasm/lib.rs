@@ -28,6 +28,10 @@ impl limbo_core::IO for IO {
         todo!();
     }
 
+    fn create_file(&self, _path: &str) -> Result<Box<dyn limbo_core::File>> {
+        todo!();
+    }
+
     fn run_once(&self) -> Result<()> {
         todo!();
     }
@@ -77,7 +77,7 @@ fn query(
     match conn.query(sql) {
         Ok(Some(ref mut rows)) => match output_mode {
             OutputMode::Raw => loop {
-                match rows.next()? {
+                match rows.try_next()? {
                     RowResult::Row(row) => {
                         print!("|");
                         for val in row.values.iter() {
@@ -100,7 +100,7 @@ fn query(
             OutputMode::Pretty => {
                 let mut table_rows: Vec<Vec<_>> = vec![];
                 loop {
-                    match rows.next()? {
+                    match rows.try_next()? {
                         RowResult::Row(row) => {
                             table_rows.push(
                                 row.values
@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
-use limbo_core::{Database, IO, PlatformIO};
+use limbo_core::{Database, PlatformIO, IO};
 use pprof::criterion::{Output, PProfProfiler};
 use std::rc::Rc;
 
@@ -16,7 +16,7 @@ fn bench(c: &mut Criterion) {
         let io = io.clone();
         b.iter(|| {
             let mut rows = stmt.query().unwrap();
-            match rows.next().unwrap() {
+            match rows.try_next().unwrap() {
                 limbo_core::RowResult::Row(row) => {
                     assert_eq!(row.get::<i64>(0).unwrap(), 1);
                 }
@@ -38,7 +38,7 @@ fn bench(c: &mut Criterion) {
             let io = io.clone();
             b.iter(|| {
                 let mut rows = stmt.query().unwrap();
-                match rows.next().unwrap() {
+                match rows.try_next().unwrap() {
                     limbo_core::RowResult::Row(row) => {
                         assert_eq!(row.get::<i64>(0).unwrap(), 1);
                     }
@@ -54,6 +54,32 @@ fn bench(c: &mut Criterion) {
         },
     );
 
+    let text_db = text_database();
+    let db = Database::open_file(io.clone(), text_db.to_str().unwrap()).unwrap();
+    let conn = db.connect();
+    let mut stmt = conn.prepare("SELECT a, b FROM t").unwrap();
+    group.bench_function("Execute prepared statement: 'SELECT a, b FROM t'", |b| {
+        let io = io.clone();
+        b.iter(|| {
+            let mut rows = stmt.query().unwrap();
+            let mut count = 0;
+            loop {
+                match rows.try_next().unwrap() {
+                    limbo_core::RowResult::Row(row) => {
+                        assert_eq!(row.get::<String>(1).unwrap().len(), 100);
+                        count += 1;
+                    }
+                    limbo_core::RowResult::IO => {
+                        io.run_once().unwrap();
+                    }
+                    limbo_core::RowResult::Done => break,
+                }
+            }
+            assert_eq!(count, TEXT_ROWS);
+            stmt.reset();
+        });
+    });
+
     drop(group);
 
     let mut group = c.benchmark_group("rusqlite");
@@ -83,6 +109,38 @@ fn bench(c: &mut Criterion) {
             });
         },
     );
+
+    let conn = rusqlite::Connection::open(&text_db).unwrap();
+    let mut stmt = conn.prepare("SELECT a, b FROM t").unwrap();
+    group.bench_function("Execute prepared statement: 'SELECT a, b FROM t'", |b| {
+        b.iter(|| {
+            let mut rows = stmt.query(()).unwrap();
+            let mut count = 0;
+            while let Some(row) = rows.next().unwrap() {
+                let b: String = row.get(1).unwrap();
+                assert_eq!(b.len(), 100);
+                count += 1;
+            }
+            assert_eq!(count, TEXT_ROWS);
+        });
+    });
+}
+
+const TEXT_ROWS: usize = 1000;
+
+/// Create a database with a table of text columns to measure reading text.
+fn text_database() -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("limbo-bench-text-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute_batch(&format!(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, a TEXT, b TEXT);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+         INSERT INTO t SELECT i, 'name' || i, printf('%0100d', i) FROM n;",
+        TEXT_ROWS
+    ))
+    .unwrap();
+    path
 }
 
 criterion_group! {
@@ -1,34 +1,103 @@
 use crate::pager::Pager;
-use crate::sqlite3_ondisk::{BTreeCell, TableInteriorCell, TableLeafCell};
-use crate::types::{Cursor, CursorResult, OwnedRecord};
+use crate::sqlite3_ondisk::{
+    cell_size, payload_local_size, table_max_local, BTreeCell, BTreePage, TableInteriorCell,
+    TableLeafCell, TextEncoding,
+};
+use crate::types::{compare_index_key, Cursor, CursorResult, OwnedRecord, OwnedValue};
 
 use anyhow::Result;
 
-use std::cell::{Ref, RefCell};
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell};
 use std::rc::Rc;
 
+/// The position of the cursor in a page, as an index of the page's entries
+/// in key order. The entries of a table leaf are its cells, and those of a
+/// table interior page are its children. In an index interior page, the
+/// children alternate with the keys of the cells: even indexes are the left
+/// child of cell `idx / 2` and odd indexes its key, and the right-most child
+/// comes last. The position means the same when moving in either direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    /// At the entry, which is the current one.
+    At(usize),
+    /// Between the entry and the one before it, neither of which is current.
+    Before(usize),
+    /// After the last entry of a page whose number of entries is not known
+    /// until it is read.
+    End,
+}
+
 pub struct MemPage {
     parent: Option<Rc<MemPage>>,
     page_idx: usize,
-    cell_idx: RefCell<usize>,
+    position: Cell<Position>,
 }
 
 impl MemPage {
-    pub fn new(parent: Option<Rc<MemPage>>, page_idx: usize, cell_idx: usize) -> Self {
+    fn new(parent: Option<Rc<MemPage>>, page_idx: usize, position: Position) -> Self {
         Self {
             parent,
             page_idx,
-            cell_idx: RefCell::new(cell_idx),
+            position: Cell::new(position),
         }
     }
 
-    pub fn cell_idx(&self) -> usize {
-        *self.cell_idx.borrow()
+    fn position(&self) -> Position {
+        self.position.get()
     }
 
-    pub fn advance(&self) {
-        let mut cell_idx = self.cell_idx.borrow_mut();
-        *cell_idx += 1;
+    fn set_position(&self, position: Position) {
+        self.position.set(position);
+    }
+}
+
+/// An entry of a B-Tree page.
+enum Entry<'a> {
+    Child(usize),
+    Record {
+        rowid: Option<u64>,
+        payload: &'a [u8],
+        payload_size: usize,
+        first_overflow_page: Option<u32>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SeekOp {
+    EQ,
+    GE,
+    GT,
+}
+
+impl SeekOp {
+    /// Returns true if an entry with the given rowid satisfies the seek
+    /// operation against the key. For `EQ`, this is true for every entry that
+    /// is not ordered before the key, which is what B-Tree descent needs.
+    fn matches(&self, rowid: u64, key: u64) -> bool {
+        match self {
+            SeekOp::EQ | SeekOp::GE => rowid >= key,
+            SeekOp::GT => rowid > key,
+        }
+    }
+
+    /// Like `matches`, for the leading fields of an index key.
+    fn matches_key(&self, index_key: &OwnedRecord, key: &OwnedRecord) -> bool {
+        let ord = compare_index_key(index_key, key);
+        match self {
+            SeekOp::EQ | SeekOp::GE => ord.is_ge(),
+            SeekOp::GT => ord.is_gt(),
+        }
+    }
+}
+
+/// Extract the rowid to seek to from a table B-Tree search key. Returns `None`
+/// if the key is negative, in which case every row is past the key.
+pub(crate) fn seek_key_rowid(key: &OwnedRecord) -> Result<Option<u64>> {
+    match key.values.first() {
+        Some(OwnedValue::Integer(rowid)) if *rowid >= 0 => Ok(Some(*rowid as u64)),
+        Some(OwnedValue::Integer(_)) => Ok(None),
+        _ => anyhow::bail!("table B-Tree search key must be an integer rowid"),
     }
 }
 
@@ -38,72 +107,605 @@ pub struct BTreeCursor {
     page: RefCell<Option<Rc<MemPage>>>,
     rowid: RefCell<Option<u64>>,
     record: RefCell<Option<OwnedRecord>>,
+    writable: bool,
+    /// Whether the cursor iterates an index B-Tree, whose cells hold key
+    /// records instead of rows.
+    index: bool,
+    encoding: TextEncoding,
+    overflow_read: RefCell<Option<OverflowRead>>,
+    index_seek: RefCell<Option<IndexSeek>>,
+}
+
+/// A payload whose overflow chain is being read. The chain is read one page
+/// at a time, so this keeps the progress when a page read needs I/O and the
+/// cursor operation is restarted.
+struct OverflowRead {
+    first_page: u32,
+    next_page: Option<u32>,
+    payload: Vec<u8>,
+}
+
+/// A seek of an index B-Tree in progress. Comparing against the keys of a
+/// page can need I/O for their overflow pages, so a seek restarted with the
+/// same key continues from the position of the cursor instead of the root.
+struct IndexSeek {
+    key: OwnedRecord,
+    op: SeekOp,
+    /// Whether the seek reached a leaf without a matching key, and moves on
+    /// to the next key, which is the first matching one.
+    stepping: bool,
 }
 
 impl BTreeCursor {
     pub fn new(pager: Rc<Pager>, root_page: usize) -> Self {
+        let encoding = pager.text_encoding();
         Self {
             pager,
             root_page,
             page: RefCell::new(None),
             rowid: RefCell::new(None),
             record: RefCell::new(None),
+            writable: false,
+            index: false,
+            encoding,
+            overflow_read: RefCell::new(None),
+            index_seek: RefCell::new(None),
+        }
+    }
+
+    /// Create a cursor that iterates the keys of an index B-Tree. The
+    /// cursor's record is the key, and it has no rowid.
+    pub fn new_index(pager: Rc<Pager>, root_page: usize) -> Self {
+        Self {
+            index: true,
+            ..Self::new(pager, root_page)
         }
     }
 
-    fn get_next_record(&mut self) -> Result<CursorResult<(Option<u64>, Option<OwnedRecord>)>> {
+    /// Create a cursor that can modify the B-Tree.
+    pub fn new_writable(pager: Rc<Pager>, root_page: usize) -> Self {
+        Self {
+            writable: true,
+            ..Self::new(pager, root_page)
+        }
+    }
+
+    /// Parse the record of a leaf cell, following its overflow chain if the
+    /// payload did not fit in the page.
+    fn cell_record(&self, cell: &TableLeafCell) -> Result<CursorResult<OwnedRecord>> {
+        self.payload_record(&cell._payload, cell.payload_size, cell.first_overflow_page)
+    }
+
+    fn payload_record(
+        &self,
+        local_payload: &[u8],
+        payload_size: usize,
+        first_overflow_page: Option<u32>,
+    ) -> Result<CursorResult<OwnedRecord>> {
+        let first_page = match first_overflow_page {
+            Some(first_page) => first_page,
+            None => {
+                return Ok(CursorResult::Ok(OwnedRecord::parse(
+                    local_payload,
+                    self.encoding,
+                )?))
+            }
+        };
+        let mut overflow_read = self.overflow_read.borrow_mut();
+        let mut read = match overflow_read.take() {
+            Some(read) if read.first_page == first_page => read,
+            _ => {
+                let mut payload = Vec::with_capacity(payload_size);
+                payload.extend_from_slice(local_payload);
+                OverflowRead {
+                    first_page,
+                    next_page: Some(first_page),
+                    payload,
+                }
+            }
+        };
+        while read.payload.len() < payload_size {
+            let page_idx = match read.next_page {
+                Some(page_idx) => page_idx as usize,
+                None => anyhow::bail!("overflow chain of page {} is too short", first_page),
+            };
+            let page = match self.pager.read_overflow_page(page_idx)? {
+                CursorResult::Ok(page) => page,
+                CursorResult::IO => {
+                    overflow_read.replace(read);
+                    return Ok(CursorResult::IO);
+                }
+            };
+            let page = page.contents.read().unwrap();
+            let page = page.as_ref().unwrap().as_overflow()?;
+            let len = page.payload.len().min(payload_size - read.payload.len());
+            read.payload.extend_from_slice(&page.payload[..len]);
+            read.next_page = page.next_page;
+        }
+        Ok(CursorResult::Ok(OwnedRecord::parse(
+            &read.payload,
+            self.encoding,
+        )?))
+    }
+
+    /// The number of entries of the page, as described in `Position`.
+    fn num_entries(&self, page: &BTreePage) -> usize {
+        match page.header.right_most_pointer {
+            Some(_) if self.index => 2 * page.cells.len() + 1,
+            Some(_) => page.cells.len() + 1,
+            None => page.cells.len(),
+        }
+    }
+
+    fn entry<'a>(&self, page: &'a BTreePage, idx: usize) -> Result<Entry<'a>> {
+        if let Some(right_most_pointer) = page.header.right_most_pointer {
+            if idx == self.num_entries(page) - 1 {
+                return Ok(Entry::Child(right_most_pointer as usize));
+            }
+        }
+        let cell_idx = if self.index && page.header.right_most_pointer.is_some() {
+            idx / 2
+        } else {
+            idx
+        };
+        match (&page.cells[cell_idx], self.index) {
+            (BTreeCell::TableInteriorCell(cell), false) => {
+                Ok(Entry::Child(cell._left_child_page as usize))
+            }
+            (BTreeCell::TableLeafCell(cell), false) => Ok(Entry::Record {
+                rowid: Some(cell._rowid),
+                payload: &cell._payload,
+                payload_size: cell.payload_size,
+                first_overflow_page: cell.first_overflow_page,
+            }),
+            (BTreeCell::IndexInteriorCell(cell), true) if idx.is_multiple_of(2) => {
+                Ok(Entry::Child(cell._left_child_page as usize))
+            }
+            (BTreeCell::IndexInteriorCell(cell), true) => Ok(Entry::Record {
+                rowid: None,
+                payload: &cell._payload,
+                payload_size: cell.payload_size,
+                first_overflow_page: cell.first_overflow_page,
+            }),
+            (BTreeCell::IndexLeafCell(cell), true) => Ok(Entry::Record {
+                rowid: None,
+                payload: &cell._payload,
+                payload_size: cell.payload_size,
+                first_overflow_page: cell.first_overflow_page,
+            }),
+            (_, false) => anyhow::bail!("expected a table B-Tree cell"),
+            (_, true) => anyhow::bail!("expected an index B-Tree cell"),
+        }
+    }
+
+    /// Move the cursor to the next record, or to the previous one if
+    /// `forward` is false. Child pages are entered before their first entry
+    /// when moving forward and after their last one when moving backward,
+    /// and left for their parent once they have no more entries in that
+    /// direction. The positions only change once a page is read, so the
+    /// operation can be restarted after I/O.
+    fn step(&mut self, forward: bool) -> Result<CursorResult<(Option<u64>, Option<OwnedRecord>)>> {
         loop {
             let mem_page = {
                 let mem_page = self.page.borrow();
-                let mem_page = mem_page.as_ref().unwrap();
-                mem_page.clone()
+                mem_page.as_ref().unwrap().clone()
+            };
+            let page = match self.pager.read_page(mem_page.page_idx)? {
+                CursorResult::Ok(page) => page,
+                CursorResult::IO => return Ok(CursorResult::IO),
             };
-            let page_idx = mem_page.page_idx;
-            let page = self.pager.read_page(page_idx)?;
-            if page.is_locked() {
-                return Ok(CursorResult::IO);
-            }
             let page = page.contents.read().unwrap();
-            let page = page.as_ref().unwrap();
-            if mem_page.cell_idx() >= page.cells.len() {
-                let parent = mem_page.parent.clone();
-                match page.header.right_most_pointer {
-                    Some(right_most_pointer) => {
-                        let mem_page = MemPage::new(parent.clone(), right_most_pointer as usize, 0);
-                        self.page.replace(Some(Rc::new(mem_page)));
-                        continue;
-                    }
-                    None => match parent {
+            let page = page.as_ref().unwrap().as_btree()?;
+            let num_entries = self.num_entries(page);
+            let idx = match (mem_page.position(), forward) {
+                (Position::At(idx), true) => Some(idx + 1),
+                (Position::Before(idx), true) => Some(idx),
+                (Position::End, true) => None,
+                (Position::At(idx) | Position::Before(idx), false) => idx.checked_sub(1),
+                (Position::End, false) => num_entries.checked_sub(1),
+            };
+            let idx = match idx.filter(|idx| *idx < num_entries) {
+                Some(idx) => idx,
+                None => {
+                    // Moving the other way from here visits the entries again.
+                    mem_page.set_position(Position::Before(if forward { num_entries } else { 0 }));
+                    match mem_page.parent {
                         Some(ref parent) => {
                             self.page.replace(Some(parent.clone()));
                             continue;
                         }
-                        None => {
-                            return Ok(CursorResult::Ok((None, None)));
+                        None => return Ok(CursorResult::Ok((None, None))),
+                    }
+                }
+            };
+            match self.entry(page, idx)? {
+                Entry::Child(child_page) => {
+                    mem_page.set_position(Position::At(idx));
+                    let position = if forward {
+                        Position::Before(0)
+                    } else {
+                        Position::End
+                    };
+                    let child = MemPage::new(Some(mem_page.clone()), child_page, position);
+                    self.page.replace(Some(Rc::new(child)));
+                }
+                Entry::Record {
+                    rowid,
+                    payload,
+                    payload_size,
+                    first_overflow_page,
+                } => {
+                    let record =
+                        match self.payload_record(payload, payload_size, first_overflow_page)? {
+                            CursorResult::Ok(record) => record,
+                            CursorResult::IO => return Ok(CursorResult::IO),
+                        };
+                    mem_page.set_position(Position::At(idx));
+                    return Ok(CursorResult::Ok((rowid, Some(record))));
+                }
+            }
+        }
+    }
+
+    /// Move the cursor in the direction and make the record it reaches the
+    /// current one.
+    fn move_to(&mut self, forward: bool) -> Result<CursorResult<()>> {
+        self.index_seek.replace(None);
+        match self.step(forward)? {
+            CursorResult::Ok((rowid, record)) => {
+                self.rowid.replace(rowid);
+                self.record.replace(record);
+                Ok(CursorResult::Ok(()))
+            }
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    /// Insert a cell with the given rowid and record payload into the table
+    /// B-Tree, replacing any cell with the same rowid, and position the cursor
+    /// at it. Records made by the program hold UTF-8 text, so the text is
+    /// stored in the encoding of the database.
+    fn insert_cell(&mut self, key: u64, payload: &[u8]) -> Result<CursorResult<()>> {
+        if self.index {
+            anyhow::bail!("inserting into an index B-Tree is not supported");
+        }
+        let payload = match self.encoding {
+            TextEncoding::Utf8 => Cow::Borrowed(payload),
+            encoding => {
+                Cow::Owned(OwnedRecord::parse(payload, TextEncoding::Utf8)?.serialize(encoding))
+            }
+        };
+        // FIXME: spill large payloads to overflow pages
+        let usable_size = self.pager.header()?.usable_size();
+        if payload_local_size(payload.len(), table_max_local(usable_size), usable_size)
+            < payload.len()
+        {
+            anyhow::bail!(
+                "record of {} bytes needs overflow pages, which are not supported",
+                payload.len()
+            );
+        }
+        let mut page_idx = self.root_page;
+        loop {
+            let page = match self.pager.read_page(page_idx)? {
+                CursorResult::Ok(page) => page,
+                CursorResult::IO => return Ok(CursorResult::IO),
+            };
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut()?;
+            if let Some(right_most_pointer) = contents.header.right_most_pointer {
+                page_idx = right_most_pointer as usize;
+                for cell in &contents.cells {
+                    match cell {
+                        BTreeCell::TableInteriorCell(TableInteriorCell {
+                            _left_child_page,
+                            _rowid,
+                        }) => {
+                            if *_rowid >= key {
+                                page_idx = *_left_child_page as usize;
+                                break;
+                            }
+                        }
+                        _ => anyhow::bail!("expected a table B-Tree cell"),
+                    }
+                }
+                continue;
+            }
+            let mut cell_idx = contents.cells.len();
+            for (idx, cell) in contents.cells.iter().enumerate() {
+                match cell {
+                    BTreeCell::TableLeafCell(TableLeafCell { _rowid, .. }) => {
+                        if *_rowid >= key {
+                            cell_idx = idx;
+                            break;
+                        }
+                    }
+                    _ => anyhow::bail!("expected a table B-Tree cell"),
+                }
+            }
+            let replaces = matches!(
+                contents.cells.get(cell_idx),
+                Some(BTreeCell::TableLeafCell(TableLeafCell { _rowid, .. })) if *_rowid == key
+            );
+            let cell = BTreeCell::TableLeafCell(TableLeafCell {
+                _rowid: key,
+                _payload: payload.to_vec(),
+                payload_size: payload.len(),
+                first_overflow_page: None,
+            });
+            let mut free_space = contents.free_space(page_idx, usable_size);
+            let mut needed = cell_size(&cell, usable_size);
+            if replaces {
+                free_space += cell_size(&contents.cells[cell_idx], usable_size);
+            } else {
+                needed += 2;
+            }
+            // FIXME: split the page when the cells no longer fit in it
+            if needed > free_space {
+                anyhow::bail!(
+                    "page {} is full, splitting B-Tree pages is not supported",
+                    page_idx
+                );
+            }
+            if replaces {
+                contents.cells[cell_idx] = cell;
+            } else {
+                contents.cells.insert(cell_idx, cell);
+            }
+            self.pager.add_dirty(page_idx, page.clone());
+            break;
+        }
+        // Inserting again with the same key is harmless, so if positioning
+        // the cursor needs I/O, the instruction can simply be restarted.
+        match self.seek(key, SeekOp::EQ)? {
+            CursorResult::Ok(_) => Ok(CursorResult::Ok(())),
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    /// Delete the cell of the current row from its leaf page. The cursor is
+    /// left between the cells around the deleted one, so moving it in either
+    /// direction visits the neighbouring row instead of skipping it.
+    fn delete_cell(&mut self) -> Result<CursorResult<()>> {
+        if self.index {
+            anyhow::bail!("deleting from an index B-Tree is not supported");
+        }
+        let rowid = match *self.rowid.borrow() {
+            Some(rowid) => rowid,
+            None => anyhow::bail!("cursor does not point at a row"),
+        };
+        let mem_page = {
+            let mem_page = self.page.borrow();
+            mem_page.as_ref().unwrap().clone()
+        };
+        let page = match self.pager.read_page(mem_page.page_idx)? {
+            CursorResult::Ok(page) => page,
+            CursorResult::IO => return Ok(CursorResult::IO),
+        };
+        {
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut()?;
+            let cell_idx = contents
+                .cells
+                .iter()
+                .position(|cell| match cell {
+                    BTreeCell::TableLeafCell(TableLeafCell { _rowid, .. }) => *_rowid == rowid,
+                    _ => false,
+                })
+                .ok_or_else(|| anyhow::anyhow!("row {} is not in the current page", rowid))?;
+            contents.cells.remove(cell_idx);
+            mem_page.set_position(match mem_page.position() {
+                Position::At(idx) if idx == cell_idx => Position::Before(idx),
+                Position::At(idx) if idx > cell_idx => Position::At(idx - 1),
+                Position::Before(idx) if idx > cell_idx => Position::Before(idx - 1),
+                position => position,
+            });
+        }
+        self.pager.add_dirty(mem_page.page_idx, page);
+        self.rowid.replace(None);
+        self.record.replace(None);
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Position the cursor at the first row whose rowid satisfies the seek
+    /// operation by descending the table B-Tree from the root page. Returns
+    /// `false` if there is no such row.
+    fn seek(&mut self, key: u64, op: SeekOp) -> Result<CursorResult<bool>> {
+        if self.index {
+            anyhow::bail!("seeking an index B-Tree by rowid is not supported");
+        }
+        let mut mem_page = Rc::new(MemPage::new(None, self.root_page, Position::Before(0)));
+        loop {
+            let page = match self.pager.read_page(mem_page.page_idx)? {
+                CursorResult::Ok(page) => page,
+                CursorResult::IO => return Ok(CursorResult::IO),
+            };
+            let page = page.contents.read().unwrap();
+            let page = page.as_ref().unwrap().as_btree()?;
+            if let Some(right_most_pointer) = page.header.right_most_pointer {
+                // Interior cells hold the largest rowid in their left subtree,
+                // so descend into the first subtree that can contain the rowid.
+                let mut cell_idx = None;
+                for (idx, cell) in page.cells.iter().enumerate() {
+                    match cell {
+                        BTreeCell::TableInteriorCell(TableInteriorCell { _rowid, .. }) => {
+                            if op.matches(*_rowid, key) {
+                                cell_idx = Some(idx);
+                                break;
+                            }
                         }
+                        _ => anyhow::bail!("expected a table B-Tree cell"),
+                    }
+                }
+                let (idx, child_page) = match cell_idx {
+                    Some(cell_idx) => match &page.cells[cell_idx] {
+                        BTreeCell::TableInteriorCell(TableInteriorCell {
+                            _left_child_page,
+                            ..
+                        }) => (cell_idx, *_left_child_page as usize),
+                        _ => anyhow::bail!("expected a table B-Tree cell"),
                     },
+                    None => (page.cells.len(), right_most_pointer as usize),
+                };
+                mem_page.set_position(Position::At(idx));
+                mem_page = Rc::new(MemPage::new(
+                    Some(mem_page),
+                    child_page,
+                    Position::Before(0),
+                ));
+                continue;
+            }
+            for (cell_idx, cell) in page.cells.iter().enumerate() {
+                match cell {
+                    BTreeCell::TableLeafCell(cell) => {
+                        if !op.matches(cell._rowid, key) {
+                            continue;
+                        }
+                        if op == SeekOp::EQ && cell._rowid != key {
+                            break;
+                        }
+                        let record = match self.cell_record(cell)? {
+                            CursorResult::Ok(record) => record,
+                            CursorResult::IO => return Ok(CursorResult::IO),
+                        };
+                        mem_page.set_position(Position::At(cell_idx));
+                        self.page.replace(Some(mem_page));
+                        self.rowid.replace(Some(cell._rowid));
+                        self.record.replace(Some(record));
+                        return Ok(CursorResult::Ok(true));
+                    }
+                    _ => anyhow::bail!("expected a table B-Tree cell"),
                 }
             }
-            let cell = &page.cells[mem_page.cell_idx()];
-            match &cell {
-                BTreeCell::TableInteriorCell(TableInteriorCell {
-                    _left_child_page,
-                    _rowid,
-                }) => {
-                    mem_page.advance();
-                    let mem_page =
-                        MemPage::new(Some(mem_page.clone()), *_left_child_page as usize, 0);
-                    self.page.replace(Some(Rc::new(mem_page)));
+            if op == SeekOp::EQ {
+                self.rowid.replace(None);
+                self.record.replace(None);
+                return Ok(CursorResult::Ok(false));
+            }
+            // Interior keys are only an upper bound of their left subtree, so the
+            // first matching row can be at the start of the next leaf.
+            mem_page.set_position(Position::Before(page.cells.len()));
+            self.page.replace(Some(mem_page));
+            return match self.step(true)? {
+                CursorResult::Ok((rowid, record)) => {
+                    let found = record.is_some();
+                    self.rowid.replace(rowid);
+                    self.record.replace(record);
+                    Ok(CursorResult::Ok(found))
+                }
+                CursorResult::IO => Ok(CursorResult::IO),
+            };
+        }
+    }
+}
+
+impl BTreeCursor {
+    /// Position the cursor at the first key of the index B-Tree whose leading
+    /// fields satisfy the seek operation. The keys of a left child come
+    /// before the key of its cell, so the descent enters the left child of
+    /// the first matching key of a page, or its right-most child if no key
+    /// matches. Returns `false` if there is no such key.
+    fn seek_index(&mut self, key: &OwnedRecord, op: SeekOp) -> Result<CursorResult<bool>> {
+        let resume = matches!(
+            &*self.index_seek.borrow(),
+            Some(seek) if seek.op == op && seek.key.values == key.values
+        );
+        if !resume {
+            let mem_page = MemPage::new(None, self.root_page, Position::Before(0));
+            self.page.replace(Some(Rc::new(mem_page)));
+            self.index_seek.replace(Some(IndexSeek {
+                key: key.clone(),
+                op,
+                stepping: false,
+            }));
+        }
+        loop {
+            if self.index_seek.borrow().as_ref().unwrap().stepping {
+                return match self.step(true)? {
+                    CursorResult::Ok((_, record)) => Ok(CursorResult::Ok(self.seek_done(record))),
+                    CursorResult::IO => Ok(CursorResult::IO),
+                };
+            }
+            let mem_page = {
+                let mem_page = self.page.borrow();
+                mem_page.as_ref().unwrap().clone()
+            };
+            let page = match self.pager.read_page(mem_page.page_idx)? {
+                CursorResult::Ok(page) => page,
+                CursorResult::IO => return Ok(CursorResult::IO),
+            };
+            let page = page.contents.read().unwrap();
+            let page = page.as_ref().unwrap().as_btree()?;
+            let num_entries = self.num_entries(page);
+            // Entries before the position were compared before the I/O.
+            let mut idx = match mem_page.position() {
+                Position::Before(idx) => idx,
+                _ => 0,
+            };
+            let child = loop {
+                if idx == num_entries {
+                    break None;
+                }
+                let (payload, payload_size, first_overflow_page) = match self.entry(page, idx)? {
+                    Entry::Child(child_page) if idx == num_entries - 1 => {
+                        break Some((idx, child_page));
+                    }
+                    Entry::Child(_) => {
+                        idx += 1;
+                        continue;
+                    }
+                    Entry::Record {
+                        payload,
+                        payload_size,
+                        first_overflow_page,
+                        ..
+                    } => (payload, payload_size, first_overflow_page),
+                };
+                let record =
+                    match self.payload_record(payload, payload_size, first_overflow_page)? {
+                        CursorResult::Ok(record) => record,
+                        CursorResult::IO => {
+                            mem_page.set_position(Position::Before(idx));
+                            return Ok(CursorResult::IO);
+                        }
+                    };
+                if !op.matches_key(&record, key) {
+                    idx += 1;
                     continue;
                 }
-                BTreeCell::TableLeafCell(TableLeafCell { _rowid, _payload }) => {
-                    mem_page.advance();
-                    let record = crate::sqlite3_ondisk::read_record(_payload)?;
-                    return Ok(CursorResult::Ok((Some(*_rowid), Some(record))));
+                if page.header.right_most_pointer.is_none() {
+                    mem_page.set_position(Position::At(idx));
+                    return Ok(CursorResult::Ok(self.seek_done(Some(record))));
+                }
+                match &page.cells[idx / 2] {
+                    BTreeCell::IndexInteriorCell(cell) => {
+                        break Some((idx - 1, cell._left_child_page as usize));
+                    }
+                    _ => anyhow::bail!("expected an index B-Tree cell"),
+                }
+            };
+            match child {
+                Some((idx, child_page)) => {
+                    mem_page.set_position(Position::At(idx));
+                    let child = MemPage::new(Some(mem_page), child_page, Position::Before(0));
+                    self.page.replace(Some(Rc::new(child)));
+                }
+                None => {
+                    mem_page.set_position(Position::Before(num_entries));
+                    self.index_seek.borrow_mut().as_mut().unwrap().stepping = true;
                 }
             }
         }
     }
+
+    /// Finish an index seek at `record`, which is `None` if no key matched.
+    fn seek_done(&self, record: Option<OwnedRecord>) -> bool {
+        self.index_seek.replace(None);
+        let found = record.is_some();
+        self.rowid.replace(None);
+        self.record.replace(record);
+        found
+    }
 }
 
 impl Cursor for BTreeCursor {
@@ -112,26 +714,46 @@ impl Cursor for BTreeCursor {
     }
 
     fn rewind(&mut self) -> Result<CursorResult<()>> {
-        let mem_page = MemPage::new(None, self.root_page, 0);
+        let mem_page = MemPage::new(None, self.root_page, Position::Before(0));
         self.page.replace(Some(Rc::new(mem_page)));
-        match self.get_next_record()? {
-            CursorResult::Ok((rowid, next)) => {
-                self.rowid.replace(rowid);
-                self.record.replace(next);
-                Ok(CursorResult::Ok(()))
-            }
-            CursorResult::IO => Ok(CursorResult::IO),
-        }
+        self.move_to(true)
     }
 
     fn next(&mut self) -> Result<CursorResult<()>> {
-        match self.get_next_record()? {
-            CursorResult::Ok((rowid, next)) => {
-                self.rowid.replace(rowid);
-                self.record.replace(next);
-                Ok(CursorResult::Ok(()))
-            }
-            CursorResult::IO => Ok(CursorResult::IO),
+        self.move_to(true)
+    }
+
+    fn last(&mut self) -> Result<CursorResult<()>> {
+        let mem_page = MemPage::new(None, self.root_page, Position::End);
+        self.page.replace(Some(Rc::new(mem_page)));
+        self.move_to(false)
+    }
+
+    fn prev(&mut self) -> Result<CursorResult<()>> {
+        self.move_to(false)
+    }
+
+    fn seek_rowid(&mut self, rowid: u64) -> Result<CursorResult<bool>> {
+        self.seek(rowid, SeekOp::EQ)
+    }
+
+    fn seek_ge(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        if self.index {
+            return self.seek_index(key, SeekOp::GE);
+        }
+        match seek_key_rowid(key)? {
+            Some(rowid) => self.seek(rowid, SeekOp::GE),
+            None => self.seek(0, SeekOp::GE),
+        }
+    }
+
+    fn seek_gt(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        if self.index {
+            return self.seek_index(key, SeekOp::GT);
+        }
+        match seek_key_rowid(key)? {
+            Some(rowid) => self.seek(rowid, SeekOp::GT),
+            None => self.seek(0, SeekOp::GE),
         }
     }
 
@@ -140,11 +762,370 @@ impl Cursor for BTreeCursor {
         Ok(())
     }
 
-    fn rowid(&self) -> Result<Ref<Option<u64>>> {
+    fn rowid(&self) -> Result<Ref<'_, Option<u64>>> {
         Ok(self.rowid.borrow())
     }
 
-    fn record(&self) -> Result<Ref<Option<OwnedRecord>>> {
+    fn record(&self) -> Result<Ref<'_, Option<OwnedRecord>>> {
         Ok(self.record.borrow())
     }
+
+    fn current_record(&mut self) -> Option<&OwnedRecord> {
+        self.record.get_mut().as_ref()
+    }
+
+    fn insert(&mut self, key: u64, payload: &[u8]) -> Result<CursorResult<()>> {
+        self.insert_cell(key, payload)
+    }
+
+    fn delete(&mut self) -> Result<CursorResult<()>> {
+        self.delete_cell()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::Page;
+    use crate::test_utils::open_fixture;
+    use crate::{Database, IO};
+    use rstest::rstest;
+
+    /// Run a cursor operation to completion, running the IO loop whenever
+    /// it needs to wait for I/O.
+    fn wait<T>(io: &Rc<dyn IO>, mut op: impl FnMut() -> Result<CursorResult<T>>) -> T {
+        loop {
+            match op().unwrap() {
+                CursorResult::Ok(value) => return value,
+                CursorResult::IO => io.run_once().unwrap(),
+            }
+        }
+    }
+
+    /// Scan the table from the first row to the last and return the rowids
+    /// and records.
+    fn scan(io: &Rc<dyn IO>, cursor: &mut BTreeCursor) -> Vec<(u64, Vec<OwnedValue>)> {
+        let mut rows = Vec::new();
+        wait(io, || cursor.rewind());
+        while !cursor.is_empty() {
+            let rowid = cursor.rowid().unwrap().unwrap();
+            let record = cursor.record().unwrap().as_ref().unwrap().values.clone();
+            rows.push((rowid, record));
+            wait(io, || cursor.next());
+        }
+        rows
+    }
+
+    /// Returns the number of levels of the B-Tree, following the leftmost
+    /// child of each interior page.
+    fn depth(io: &Rc<dyn IO>, pager: &Pager, root_page: usize) -> usize {
+        let mut page_idx = root_page;
+        let mut depth = 1;
+        loop {
+            let page = wait(io, || pager.read_page(page_idx));
+            let contents = page.contents.read().unwrap();
+            match &contents.as_ref().unwrap().as_btree().unwrap().cells[0] {
+                BTreeCell::TableInteriorCell(cell) => page_idx = cell._left_child_page as usize,
+                BTreeCell::IndexInteriorCell(cell) => page_idx = cell._left_child_page as usize,
+                BTreeCell::TableLeafCell(_) | BTreeCell::IndexLeafCell(_) => return depth,
+            }
+            depth += 1;
+        }
+    }
+
+    fn test_blob(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[rstest]
+    #[case::fits_in_page(1000)]
+    #[case::two_overflow_pages(10_000)]
+    #[case::longer_than_page_cache(200_000)]
+    fn test_read_overflow_payload(#[case] len: usize) {
+        let blob = test_blob(len);
+        let (io, db) = open_fixture(
+            "overflow",
+            &format!(
+                "CREATE TABLE t (x INTEGER PRIMARY KEY, y BLOB);
+                 INSERT INTO t VALUES (1, X'01');
+                 INSERT INTO t VALUES (2, X'{}');
+                 INSERT INTO t VALUES (3, X'03');",
+                hex(&blob)
+            ),
+        );
+        let expected = vec![
+            (1, vec![OwnedValue::Null, OwnedValue::Blob(vec![1].into())]),
+            (2, vec![OwnedValue::Null, OwnedValue::Blob(blob.into())]),
+            (3, vec![OwnedValue::Null, OwnedValue::Blob(vec![3].into())]),
+        ];
+        let mut cursor = BTreeCursor::new(db.pager.clone(), 2);
+        assert_eq!(scan(&io, &mut cursor), expected);
+
+        wait(&io, || cursor.seek_rowid(2));
+        assert_eq!(
+            cursor.record().unwrap().as_ref().unwrap().values,
+            expected[1].1
+        );
+
+        wait(&io, || cursor.last());
+        wait(&io, || cursor.prev());
+        assert_eq!(*cursor.rowid().unwrap(), Some(2));
+        assert_eq!(
+            cursor.record().unwrap().as_ref().unwrap().values,
+            expected[1].1
+        );
+    }
+
+    #[test]
+    fn test_overflow_payload_local_size() {
+        let (io, db) = open_fixture(
+            "overflow-cell",
+            &format!(
+                "CREATE TABLE t (x BLOB);
+                 INSERT INTO t VALUES (X'{}');",
+                hex(&test_blob(10_000))
+            ),
+        );
+        let page = wait(&io, || db.pager.read_page(2));
+        let contents = page.contents.read().unwrap();
+        match &contents.as_ref().unwrap().as_btree().unwrap().cells[0] {
+            BTreeCell::TableLeafCell(cell) => {
+                assert_eq!(cell.payload_size, 10_004);
+                // The part that does not fit in the minimum local size spills
+                // to overflow pages in chunks of the usable page size minus
+                // the next page pointer.
+                assert_eq!(cell._payload.len(), 489 + (10_004 - 489) % 4092);
+                assert!(cell.first_overflow_page.is_some());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[rstest]
+    #[case::needs_overflow_pages(
+        "CREATE TABLE t (x BLOB);",
+        5000,
+        "record of 5000 bytes needs overflow pages, which are not supported"
+    )]
+    #[case::page_full(
+        "CREATE TABLE t (x BLOB); INSERT INTO t VALUES (zeroblob(3900));",
+        1000,
+        "page 2 is full, splitting B-Tree pages is not supported"
+    )]
+    fn test_insert_too_large(#[case] sql: &str, #[case] len: usize, #[case] expected: &str) {
+        let (io, db) = open_fixture("insert-too-large", sql);
+        let num_cells = |page: &Page| {
+            let contents = page.contents.read().unwrap();
+            contents.as_ref().unwrap().as_btree().unwrap().cells.len()
+        };
+        let before = num_cells(&wait(&io, || db.pager.read_page(2)));
+        let mut cursor = BTreeCursor::new_writable(db.pager.clone(), 2);
+        let err = loop {
+            match cursor.insert(2, &test_blob(len)) {
+                Ok(CursorResult::IO) => io.run_once().unwrap(),
+                Ok(CursorResult::Ok(())) => panic!("inserting {} bytes succeeded", len),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.to_string(), expected);
+        assert_eq!(num_cells(&wait(&io, || db.pager.read_page(2))), before);
+    }
+
+    #[test]
+    fn test_scan_three_level_tree() {
+        let (io, db) = open_fixture(
+            "three-levels",
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, y TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000)
+             INSERT INTO t SELECT i, printf('%080d', i) FROM n;",
+        );
+        assert_eq!(depth(&io, &db.pager, 2), 3);
+        let expected: Vec<_> = (1..=5000)
+            .map(|i| {
+                (
+                    i as u64,
+                    vec![
+                        OwnedValue::Null,
+                        OwnedValue::Text(format!("{:080}", i).into()),
+                    ],
+                )
+            })
+            .collect();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), 2);
+        assert_eq!(scan(&io, &mut cursor), expected);
+
+        let mut rowids = Vec::new();
+        wait(&io, || cursor.last());
+        while !cursor.is_empty() {
+            rowids.push(cursor.rowid().unwrap().unwrap());
+            wait(&io, || cursor.prev());
+        }
+        assert_eq!(rowids, (1..=5000).rev().collect::<Vec<u64>>());
+
+        for rowid in [1, 2500, 4999, 5000] {
+            assert!(wait(&io, || cursor.seek_rowid(rowid)));
+            assert_eq!(
+                cursor.record().unwrap().as_ref().unwrap().values,
+                expected[rowid as usize - 1].1
+            );
+        }
+        assert!(!wait(&io, || cursor.seek_rowid(5001)));
+    }
+
+    /// An index of at least three levels on 2000 rows, and its keys in order.
+    /// Keys are longer than an index cell can hold in a 512-byte page, so
+    /// they spill to overflow pages too.
+    fn open_index(name: &str) -> (Rc<dyn IO>, Database, usize, Vec<Vec<OwnedValue>>) {
+        let (io, db) = open_fixture(
+            name,
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, y TEXT);
+             CREATE INDEX t_y ON t (y);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+             INSERT INTO t SELECT i, printf('%0120d', i * 7919 % 1000) FROM n;",
+        );
+        // The index is created right after the table, so it gets the next page.
+        let root_page = 3;
+        assert!(depth(&io, &db.pager, root_page) >= 3);
+        let mut expected: Vec<_> = (1..=2000)
+            .map(|i| (format!("{:0120}", i * 7919 % 1000), i))
+            .collect();
+        expected.sort();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(y, x)| vec![OwnedValue::Text(y.into()), OwnedValue::Integer(x)])
+            .collect();
+        (io, db, root_page, expected)
+    }
+
+    #[test]
+    fn test_scan_index() {
+        let (io, db, root_page, expected) = open_index("index");
+        let mut cursor = BTreeCursor::new_index(db.pager.clone(), root_page);
+        let mut keys = Vec::new();
+        wait(&io, || cursor.rewind());
+        while !cursor.is_empty() {
+            assert!(cursor.rowid().unwrap().is_none());
+            keys.push(cursor.record().unwrap().as_ref().unwrap().values.clone());
+            wait(&io, || cursor.next());
+        }
+        assert_eq!(keys, expected);
+
+        let mut keys = Vec::new();
+        wait(&io, || cursor.last());
+        while !cursor.is_empty() {
+            assert!(cursor.rowid().unwrap().is_none());
+            keys.push(cursor.record().unwrap().as_ref().unwrap().values.clone());
+            wait(&io, || cursor.prev());
+        }
+        keys.reverse();
+        assert_eq!(keys, expected);
+
+        // Stepping back and forth visits the neighbouring keys, also across
+        // pages.
+        let key = |cursor: &BTreeCursor| cursor.record().unwrap().as_ref().unwrap().values.clone();
+        wait(&io, || cursor.rewind());
+        for i in 1..expected.len() {
+            wait(&io, || cursor.next());
+            assert_eq!(key(&cursor), expected[i]);
+            wait(&io, || cursor.prev());
+            assert_eq!(key(&cursor), expected[i - 1]);
+            wait(&io, || cursor.next());
+        }
+    }
+
+    #[test]
+    fn test_seek_index() {
+        let (io, db, root_page, expected) = open_index("seek-index");
+        let mut cursor = BTreeCursor::new_index(db.pager.clone(), root_page);
+        let text = |i: i64| OwnedValue::Text(format!("{:0120}", i).into());
+        for (i, rowid) in [
+            (0, None),
+            (1, None),
+            (500, None),
+            (500, Some(1500)),
+            (999, None),
+        ] {
+            let mut values = vec![text(i)];
+            values.extend(rowid.map(OwnedValue::Integer));
+            let key = OwnedRecord::new(values);
+            for gt in [false, true] {
+                let first = expected.iter().position(|entry| {
+                    let ord = compare_index_key(&OwnedRecord::new(entry.clone()), &key);
+                    if gt {
+                        ord.is_gt()
+                    } else {
+                        ord.is_ge()
+                    }
+                });
+                let found = if gt {
+                    wait(&io, || cursor.seek_gt(&key))
+                } else {
+                    wait(&io, || cursor.seek_ge(&key))
+                };
+                assert_eq!(found, first.is_some());
+                let record = cursor.record().unwrap().as_ref().map(|r| r.values.clone());
+                assert_eq!(record.as_ref(), first.map(|first| &expected[first]));
+                if let Some(first) = first.filter(|first| first + 1 < expected.len()) {
+                    wait(&io, || cursor.next());
+                    let record = cursor.record().unwrap().as_ref().unwrap().values.clone();
+                    assert_eq!(record, expected[first + 1]);
+                }
+            }
+        }
+        let key = OwnedRecord::new(vec![text(1000)]);
+        assert!(!wait(&io, || cursor.seek_ge(&key)));
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn test_change_direction() {
+        let (io, db) = open_fixture(
+            "change-direction",
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (x INTEGER PRIMARY KEY, y TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+             INSERT INTO t SELECT i, printf('%040d', i) FROM n;",
+        );
+        assert!(depth(&io, &db.pager, 2) >= 2);
+        let mut cursor = BTreeCursor::new(db.pager.clone(), 2);
+        let rowid = |cursor: &BTreeCursor| *cursor.rowid().unwrap();
+
+        assert!(wait(&io, || cursor.seek_rowid(100)));
+        wait(&io, || cursor.prev());
+        assert_eq!(rowid(&cursor), Some(99));
+        wait(&io, || cursor.next());
+        assert_eq!(rowid(&cursor), Some(100));
+
+        wait(&io, || cursor.last());
+        assert_eq!(rowid(&cursor), Some(500));
+        wait(&io, || cursor.next());
+        assert_eq!(rowid(&cursor), None);
+        wait(&io, || cursor.prev());
+        assert_eq!(rowid(&cursor), Some(500));
+
+        wait(&io, || cursor.rewind());
+        wait(&io, || cursor.prev());
+        assert_eq!(rowid(&cursor), None);
+        wait(&io, || cursor.next());
+        assert_eq!(rowid(&cursor), Some(1));
+
+        for i in 1..=500 {
+            assert!(wait(&io, || cursor.seek_rowid(i)));
+            wait(&io, || cursor.next());
+            assert_eq!(rowid(&cursor), Some(i + 1).filter(|next| *next <= 500));
+            assert!(wait(&io, || cursor.seek_rowid(i)));
+            wait(&io, || cursor.prev());
+            assert_eq!(rowid(&cursor), Some(i - 1).filter(|prev| *prev >= 1));
+        }
+    }
 }
@@ -0,0 +1,548 @@
+//! Date and time functions.
+//!
+//! Like in SQLite, a time is a Julian day number: the days since noon in
+//! Greenwich on November 24, 4714 B.C. in the proleptic Gregorian calendar.
+//! It is kept in milliseconds, so that adding and subtracting time is exact.
+//! Times are always UTC, so the `localtime` and `utc` modifiers are not
+//! supported. An invalid time value or modifier makes the result NULL.
+use crate::types::OwnedValue;
+
+const DAY_MS: i64 = 86_400_000;
+
+/// The Julian day of the Unix epoch, in milliseconds.
+const UNIX_EPOCH_MS: i64 = 210_866_760_000_000;
+
+/// The last millisecond of 9999-12-31, the latest time that can be shown.
+const MAX_JD_MS: i64 = 464_269_060_799_999;
+
+/// A time as a Julian day number in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DateTime {
+    jd: i64,
+}
+
+/// The calendar date and time of a `DateTime`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Fields {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: f64,
+}
+
+impl DateTime {
+    fn now() -> Self {
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            jd: UNIX_EPOCH_MS + elapsed.as_millis() as i64,
+        }
+    }
+
+    /// The time at `jd` milliseconds, if it can be shown.
+    fn new(jd: i64) -> Option<Self> {
+        (0..=MAX_JD_MS).contains(&jd).then_some(Self { jd })
+    }
+
+    fn from_julian_day(jd: f64) -> Option<Self> {
+        if !jd.is_finite() {
+            return None;
+        }
+        Self::new((jd * DAY_MS as f64).round() as i64)
+    }
+
+    fn from_fields(fields: &Fields) -> Option<Self> {
+        // Keep `julian_day_ms` from overflowing.
+        if !(-4800..=10_000).contains(&fields.year) {
+            return None;
+        }
+        Self::new(fields.julian_day_ms())
+    }
+
+    fn fields(&self) -> Fields {
+        let z = (self.jd + DAY_MS / 2) / DAY_MS;
+        let a = ((z as f64 - 1867216.25) / 36524.25) as i64;
+        let a = z + 1 + a - a / 4;
+        let b = a + 1524;
+        let c = ((b as f64 - 122.1) / 365.25) as i64;
+        let d = (36525 * (c & 32767)) / 100;
+        let e = ((b - d) as f64 / 30.6001) as i64;
+        let x1 = (30.6001 * e as f64) as i64;
+        let month = if e < 14 { e - 1 } else { e - 13 };
+        let ms = (self.jd + DAY_MS / 2) % DAY_MS;
+        Fields {
+            year: if month > 2 { c - 4716 } else { c - 4715 },
+            month,
+            day: b - d - x1,
+            hour: ms / 3_600_000,
+            minute: ms / 60_000 % 60,
+            second: (ms % 60_000) as f64 / 1000.0,
+        }
+    }
+
+    /// The day of the week, from 0 for Sunday to 6 for Saturday.
+    fn weekday(&self) -> i64 {
+        ((self.jd + DAY_MS * 3 / 2) / DAY_MS) % 7
+    }
+}
+
+impl Fields {
+    /// The Julian day in milliseconds, which may be out of range.
+    fn julian_day_ms(&self) -> i64 {
+        let (mut year, mut month) = (self.year, self.month);
+        if month <= 2 {
+            year -= 1;
+            month += 12;
+        }
+        let a = year / 100;
+        let b = 2 - a + a / 4;
+        let x1 = 36525 * (year + 4716) / 100;
+        let x2 = 306001 * (month + 1) / 10000;
+        let days = (x1 + x2 + self.day + b) as f64 - 1524.5;
+        (days * DAY_MS as f64) as i64
+            + self.hour * 3_600_000
+            + self.minute * 60_000
+            + (self.second * 1000.0).round() as i64
+    }
+}
+
+/// Parse a time value: a date and time in ISO 8601 format, a Julian day
+/// number or `now`. Numbers are also returned as they are, for the
+/// `unixepoch` modifier, since they may be a valid Unix time but not a
+/// valid Julian day.
+fn parse_time_value(value: &OwnedValue) -> Option<(Option<DateTime>, Option<f64>)> {
+    let text = match value {
+        OwnedValue::Null => return None,
+        OwnedValue::Integer(i) => {
+            return Some((DateTime::from_julian_day(*i as f64), Some(*i as f64)))
+        }
+        OwnedValue::Float(f) => return Some((DateTime::from_julian_day(*f), Some(*f))),
+        value => value.to_text()?,
+    };
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("now") {
+        return Some((Some(DateTime::now()), None));
+    }
+    if let Ok(jd) = text.parse::<f64>() {
+        return Some((DateTime::from_julian_day(jd), Some(jd)));
+    }
+    Some((Some(parse_iso8601(text)?), None))
+}
+
+/// Parse `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS[.SSS]]` with an optional
+/// time zone, or a time alone, which is on 2000-01-01.
+fn parse_iso8601(text: &str) -> Option<DateTime> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+    };
+    let mut fields = Fields {
+        year: 2000,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0.0,
+    };
+    let has_date = text.len() >= 10 && text.as_bytes()[4] == b'-';
+    if has_date {
+        fields.year = parser.number(4)?;
+        parser.expect(b'-')?;
+        fields.month = parser.number(2)?;
+        parser.expect(b'-')?;
+        fields.day = parser.number(2)?;
+        if !(1..=12).contains(&fields.month) || !(1..=31).contains(&fields.day) {
+            return None;
+        }
+        if parser.at_end() {
+            return DateTime::from_fields(&fields);
+        }
+        if !parser.eat(b'T') {
+            parser.expect(b' ')?;
+            parser.skip_spaces();
+        }
+    }
+    fields.hour = parser.number(2)?;
+    parser.expect(b':')?;
+    fields.minute = parser.number(2)?;
+    if parser.eat(b':') {
+        fields.second = parser.number(2)? as f64;
+        if parser.eat(b'.') {
+            fields.second += parser.fraction()?;
+        }
+    }
+    if fields.hour > 23 || fields.minute > 59 || fields.second >= 60.0 {
+        return None;
+    }
+    let mut dt = DateTime::from_fields(&fields)?;
+    parser.skip_spaces();
+    if parser.eat(b'Z') || parser.eat(b'z') {
+        // UTC already.
+    } else if let Some(sign) = parser.sign() {
+        let hours = parser.number(2)?;
+        parser.expect(b':')?;
+        let minutes = parser.number(2)?;
+        dt = DateTime::new(dt.jd - sign * (hours * 3_600_000 + minutes * 60_000))?;
+    }
+    parser.skip_spaces();
+    parser.at_end().then_some(dt)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn at_end(&self) -> bool {
+        self.pos == self.text.len()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.text.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        self.eat(c).then_some(())
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.eat(b' ') {}
+    }
+
+    /// A `+` or `-`, as 1 or -1.
+    fn sign(&mut self) -> Option<i64> {
+        if self.eat(b'+') {
+            Some(1)
+        } else if self.eat(b'-') {
+            Some(-1)
+        } else {
+            None
+        }
+    }
+
+    /// A number of exactly `digits` digits.
+    fn number(&mut self, digits: usize) -> Option<i64> {
+        let end = self.pos + digits;
+        let bytes = self.text.get(self.pos..end)?;
+        if !bytes.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        self.pos = end;
+        std::str::from_utf8(bytes).ok()?.parse().ok()
+    }
+
+    /// The digits after a decimal point, as a fraction.
+    fn fraction(&mut self) -> Option<f64> {
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        let digits = std::str::from_utf8(&self.text[start..self.pos]).ok()?;
+        format!("0.{}", digits).parse().ok()
+    }
+}
+
+/// Apply a modifier to a time. `number` is the time value if it was a
+/// number and this is the first modifier, and `dt` is None if that number
+/// is not a valid Julian day.
+fn apply_modifier(dt: Option<DateTime>, modifier: &str, number: Option<f64>) -> Option<DateTime> {
+    let modifier = modifier.trim().to_ascii_lowercase();
+    if modifier == "unixepoch" {
+        let ms = (number? * 1000.0).round() as i64;
+        return DateTime::new(UNIX_EPOCH_MS.checked_add(ms)?);
+    }
+    let dt = dt?;
+    match modifier.as_str() {
+        "julianday" => return number.and(Some(dt)),
+        "start of day" | "start of month" | "start of year" => {
+            let mut fields = dt.fields();
+            fields.hour = 0;
+            fields.minute = 0;
+            fields.second = 0.0;
+            if modifier != "start of day" {
+                fields.day = 1;
+            }
+            if modifier == "start of year" {
+                fields.month = 1;
+            }
+            return DateTime::from_fields(&fields);
+        }
+        _ => {}
+    }
+    if let Some(weekday) = modifier.strip_prefix("weekday ") {
+        let weekday: i64 = weekday.trim().parse().ok()?;
+        if !(0..=6).contains(&weekday) {
+            return None;
+        }
+        let days = (weekday - dt.weekday()).rem_euclid(7);
+        return DateTime::new(dt.jd + days * DAY_MS);
+    }
+    let (amount, unit) = modifier.split_once(char::is_whitespace)?;
+    let amount: f64 = amount.parse().ok()?;
+    let unit = unit.trim();
+    let unit_ms = match unit.strip_suffix('s').unwrap_or(unit) {
+        "day" => DAY_MS,
+        "hour" => 3_600_000,
+        "minute" => 60_000,
+        "second" => 1000,
+        // Whole months and years are added to the calendar date, so that
+        // the day of the month stays the same unless the month is too
+        // short. The rest is added as 30 or 365 days.
+        "month" => {
+            let mut fields = dt.fields();
+            let month = (fields.month - 1).checked_add(amount.trunc() as i64)?;
+            fields.year = fields.year.checked_add(month.div_euclid(12))?;
+            fields.month = month.rem_euclid(12) + 1;
+            let dt = DateTime::from_fields(&fields)?;
+            let rest = (amount.fract() * 30.0 * DAY_MS as f64).round() as i64;
+            return DateTime::new(dt.jd + rest);
+        }
+        "year" => {
+            let mut fields = dt.fields();
+            fields.year = fields.year.checked_add(amount.trunc() as i64)?;
+            let dt = DateTime::from_fields(&fields)?;
+            let rest = (amount.fract() * 365.0 * DAY_MS as f64).round() as i64;
+            return DateTime::new(dt.jd + rest);
+        }
+        _ => return None,
+    };
+    DateTime::new(
+        dt.jd
+            .checked_add((amount * unit_ms as f64).round() as i64)?,
+    )
+}
+
+/// The time of a time value followed by modifiers, or the current time if
+/// there are no arguments.
+fn eval(args: &[OwnedValue]) -> Option<DateTime> {
+    let (mut dt, mut number) = match args.first() {
+        Some(value) => parse_time_value(value)?,
+        None => (Some(DateTime::now()), None),
+    };
+    for modifier in args.iter().skip(1) {
+        let modifier = match modifier {
+            OwnedValue::Null => return None,
+            modifier => modifier.to_text()?,
+        };
+        dt = Some(apply_modifier(dt, &modifier, number)?);
+        number = None;
+    }
+    dt
+}
+
+fn text_or_null(text: Option<String>) -> OwnedValue {
+    match text {
+        Some(text) => OwnedValue::Text(text.into()),
+        None => OwnedValue::Null,
+    }
+}
+
+/// `date(time-value, modifier, ...)`, as `YYYY-MM-DD`.
+pub fn exec_date(args: &[OwnedValue]) -> OwnedValue {
+    text_or_null(eval(args).map(|dt| {
+        let f = dt.fields();
+        format!("{:04}-{:02}-{:02}", f.year, f.month, f.day)
+    }))
+}
+
+/// `time(time-value, modifier, ...)`, as `HH:MM:SS`.
+pub fn exec_time(args: &[OwnedValue]) -> OwnedValue {
+    text_or_null(eval(args).map(|dt| {
+        let f = dt.fields();
+        format!("{:02}:{:02}:{:02}", f.hour, f.minute, f.second as i64)
+    }))
+}
+
+/// `datetime(time-value, modifier, ...)`, as `YYYY-MM-DD HH:MM:SS`.
+pub fn exec_datetime(args: &[OwnedValue]) -> OwnedValue {
+    text_or_null(eval(args).map(|dt| {
+        let f = dt.fields();
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            f.year, f.month, f.day, f.hour, f.minute, f.second as i64
+        )
+    }))
+}
+
+/// `strftime(format, time-value, modifier, ...)`. The result is NULL if
+/// the format has an unknown substitution.
+pub fn exec_strftime(format: &OwnedValue, args: &[OwnedValue]) -> OwnedValue {
+    let format = match format {
+        OwnedValue::Null => return OwnedValue::Null,
+        format => format.to_text(),
+    };
+    text_or_null(
+        format
+            .zip(eval(args))
+            .and_then(|(format, dt)| strftime(&format, dt)),
+    )
+}
+
+fn strftime(format: &str, dt: DateTime) -> Option<String> {
+    let f = dt.fields();
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'd' => out.push_str(&format!("{:02}", f.day)),
+            'f' => out.push_str(&format!("{:06.3}", f.second)),
+            'H' => out.push_str(&format!("{:02}", f.hour)),
+            'j' => out.push_str(&format!("{:03}", day_of_year(dt, &f) + 1)),
+            'J' => out.push_str(&format_julian_day(dt.jd as f64 / DAY_MS as f64)),
+            'm' => out.push_str(&format!("{:02}", f.month)),
+            'M' => out.push_str(&format!("{:02}", f.minute)),
+            's' => out.push_str(&((dt.jd - UNIX_EPOCH_MS) / 1000).to_string()),
+            'S' => out.push_str(&format!("{:02}", f.second as i64)),
+            'w' => out.push_str(&dt.weekday().to_string()),
+            'W' => {
+                // Weeks start on Monday, and the days before the first
+                // Monday are in week 0.
+                let monday_weekday = (dt.weekday() + 6) % 7;
+                let week = (day_of_year(dt, &f) + 7 - monday_weekday) / 7;
+                out.push_str(&format!("{:02}", week));
+            }
+            'Y' => out.push_str(&format!("{:04}", f.year)),
+            '%' => out.push('%'),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// The days since January 1 of the year of the time.
+fn day_of_year(dt: DateTime, fields: &Fields) -> i64 {
+    let start = Fields {
+        month: 1,
+        day: 1,
+        ..*fields
+    };
+    (dt.jd - start.julian_day_ms() + DAY_MS / 2) / DAY_MS
+}
+
+/// Format a Julian day with 16 significant digits, like `%.16g` in C.
+fn format_julian_day(jd: f64) -> String {
+    let int_digits = (jd.abs().log10().floor() as i64 + 1).max(1);
+    let decimals = (16 - int_digits).max(0) as usize;
+    let text = format!("{:.*}", decimals, jd);
+    match text.contains('.') {
+        true => text.trim_end_matches('0').trim_end_matches('.').to_string(),
+        false => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn text(s: &str) -> OwnedValue {
+        OwnedValue::Text(s.into())
+    }
+
+    fn args(args: &[&str]) -> Vec<OwnedValue> {
+        args.iter().map(|arg| text(arg)).collect()
+    }
+
+    #[rstest]
+    #[case::plain(&["2020-06-15"], "2020-06-15")]
+    #[case::add_month(&["2020-01-01", "+1 month"], "2020-02-01")]
+    #[case::month_overflow(&["2021-01-31", "+1 month"], "2021-03-03")]
+    #[case::fractional_month(&["2020-01-01", "+1.5 months"], "2020-02-16")]
+    #[case::leap_year(&["2020-02-29", "+1 year"], "2021-03-01")]
+    #[case::start_of_month(&["2020-06-15", "start of month"], "2020-06-01")]
+    #[case::start_of_year(&["2020-06-15", "start of year", "+10 days"], "2020-01-11")]
+    #[case::weekday(&["2024-03-14", "weekday 0"], "2024-03-17")]
+    #[case::same_weekday(&["2024-03-17", "weekday 0"], "2024-03-17")]
+    #[case::upper_case_unit(&["2020-01-01", "+1 DAYS"], "2020-01-02")]
+    #[case::julian_day_text(&["2459000.5"], "2020-05-31")]
+    fn test_date(#[case] input: &[&str], #[case] expected: &str) {
+        assert_eq!(exec_date(&args(input)), text(expected));
+    }
+
+    #[rstest]
+    #[case::add_hour(&["2020-02-28 23:30:00", "+1 hour"], "2020-02-29 00:30:00")]
+    #[case::subtract_days(&["2020-01-01 10:00", "-1.5 days"], "2019-12-30 22:00:00")]
+    #[case::time_zone(&["2020-01-01T10:00:00+02:00"], "2020-01-01 08:00:00")]
+    #[case::time_only(&["12:00"], "2000-01-01 12:00:00")]
+    fn test_datetime(#[case] input: &[&str], #[case] expected: &str) {
+        assert_eq!(exec_datetime(&args(input)), text(expected));
+    }
+
+    #[rstest]
+    #[case::fraction(&["12:34:56.789"], "12:34:56")]
+    #[case::minutes_and_seconds(&["10:00", "+90 minutes", "-30 seconds"], "11:29:30")]
+    fn test_time(#[case] input: &[&str], #[case] expected: &str) {
+        assert_eq!(exec_time(&args(input)), text(expected));
+    }
+
+    #[test]
+    fn test_numeric_time_values() {
+        let jd = [OwnedValue::Float(2459000.5)];
+        assert_eq!(exec_date(&jd), text("2020-05-31"));
+        let unix = [OwnedValue::Integer(1700000000), text("unixepoch")];
+        assert_eq!(exec_datetime(&unix), text("2023-11-14 22:13:20"));
+    }
+
+    #[rstest]
+    #[case::all(
+        "%Y-%m-%d %H:%M:%f %j %w %W %s %J %%",
+        "2020-03-01 12:05:07.250",
+        "2020-03-01 12:05:07.250 061 0 08 1583064307 2458910.003556134 %"
+    )]
+    #[case::week_zero("%W %j", "2021-01-03", "00 003")]
+    fn test_strftime(#[case] format: &str, #[case] input: &str, #[case] expected: &str) {
+        assert_eq!(
+            exec_strftime(&text(format), &args(&[input])),
+            text(expected)
+        );
+    }
+
+    #[rstest]
+    #[case::bad_month(&["2020-13-01"])]
+    #[case::short_month(&["2020-1-01"])]
+    #[case::bad_hour(&["2020-01-01 24:00:00"])]
+    #[case::unknown_modifier(&["2020-01-01", "+1 fortnight"])]
+    #[case::unixepoch_of_text(&["2020-01-01", "unixepoch"])]
+    #[case::out_of_range(&["9999-12-31", "+1 day"])]
+    #[case::before_range(&["-1"])]
+    #[case::unixepoch_overflow(&["9e18", "unixepoch"])]
+    #[case::julian_day_overflow(&["1e300", "+1 day"])]
+    #[case::start_of_day_overflow(&["1e300", "start of day"])]
+    #[case::month_overflow(&["2020-01-01", "+9e18 months"])]
+    #[case::year_overflow(&["2020-01-01", "-9e18 years"])]
+    #[case::day_overflow(&["2020-01-01", "+1e300 days"])]
+    #[case::not_a_number(&["nan"])]
+    fn test_invalid(#[case] input: &[&str]) {
+        assert_eq!(exec_date(&args(input)), OwnedValue::Null);
+    }
+
+    #[test]
+    fn test_null_and_now() {
+        assert_eq!(exec_date(&[OwnedValue::Null]), OwnedValue::Null);
+        assert_eq!(
+            exec_strftime(&text("%Q"), &args(&["2020-01-01"])),
+            OwnedValue::Null
+        );
+        let today = exec_date(&[]).to_text().unwrap();
+        assert_eq!(today.len(), 10);
+        assert!(today.starts_with("20"));
+        let now = exec_datetime(&args(&["now"])).to_text().unwrap();
+        assert_eq!(now.len(), 19);
+    }
+}
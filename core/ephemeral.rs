@@ -0,0 +1,426 @@
+use crate::btree::seek_key_rowid;
+use crate::sqlite3_ondisk::TextEncoding;
+use crate::types::{Cursor, CursorResult, OwnedRecord};
+
+use anyhow::Result;
+
+use std::cell::{Ref, RefCell};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// A cursor over a transient table or index which lives in memory for the
+/// duration of a program, such as the scratch tables of DISTINCT, GROUP BY
+/// or IN.
+///
+/// The rows of a table are ordered by rowid like in a table B-Tree, and the
+/// entries of an index are ordered by their key record. They are never
+/// written to the database file, so no operation needs I/O.
+pub struct EphemeralCursor {
+    num_columns: usize,
+    index: bool,
+    rows: BTreeMap<Key, OwnedRecord>,
+    /// The key of the current row, which is kept even if the row is deleted
+    /// so that the cursor can move on to its neighbours.
+    key: RefCell<Option<Key>>,
+    rowid: RefCell<Option<u64>>,
+    record: RefCell<Option<OwnedRecord>>,
+}
+
+/// The ordering key of a row: the rowid in a table and the whole record in
+/// an index.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Rowid(u64),
+    Record(IndexKey),
+}
+
+/// A record ordered field by field in the SQLite sort order, where a record
+/// sorts before the longer records it is a prefix of.
+#[derive(Clone)]
+struct IndexKey(OwnedRecord);
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .values
+            .iter()
+            .zip(&other.0.values)
+            .map(|(l, r)| l.compare(r))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| self.0.values.len().cmp(&other.0.values.len()))
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl EphemeralCursor {
+    /// Create a cursor on an empty table whose rows have up to
+    /// `num_columns` columns.
+    pub fn new(num_columns: usize) -> Self {
+        Self::with_kind(num_columns, false)
+    }
+
+    /// Create a cursor on an empty index whose keys have up to
+    /// `num_columns` fields.
+    pub fn new_index(num_columns: usize) -> Self {
+        Self::with_kind(num_columns, true)
+    }
+
+    fn with_kind(num_columns: usize, index: bool) -> Self {
+        Self {
+            num_columns,
+            index,
+            rows: BTreeMap::new(),
+            key: RefCell::new(None),
+            rowid: RefCell::new(None),
+            record: RefCell::new(None),
+        }
+    }
+
+    /// Move the cursor to a row, or past the end if there is none.
+    fn move_to(&self, row: Option<(&Key, &OwnedRecord)>) -> bool {
+        self.key.replace(row.map(|(key, _)| key.clone()));
+        self.rowid.replace(match row {
+            Some((Key::Rowid(rowid), _)) => Some(*rowid),
+            _ => None,
+        });
+        self.record.replace(row.map(|(_, record)| record.clone()));
+        row.is_some()
+    }
+
+    /// Move to the first row at or after the bound, skipping the index
+    /// entries whose leading fields equal the key if `skip_equal` is set.
+    fn seek(&self, from: Bound<Key>, skip_equal: Option<&OwnedRecord>) -> CursorResult<bool> {
+        let row = self
+            .rows
+            .range((from, Bound::Unbounded))
+            .find(|(_, record)| match skip_equal {
+                Some(key) => !has_prefix(record, key),
+                None => true,
+            });
+        CursorResult::Ok(self.move_to(row))
+    }
+}
+
+/// Whether the leading fields of the record are equal to the key.
+fn has_prefix(record: &OwnedRecord, key: &OwnedRecord) -> bool {
+    record
+        .values
+        .iter()
+        .zip(&key.values)
+        .all(|(l, r)| l.compare(r).is_eq())
+}
+
+impl Cursor for EphemeralCursor {
+    fn is_empty(&self) -> bool {
+        self.record.borrow().is_none()
+    }
+
+    fn rewind(&mut self) -> Result<CursorResult<()>> {
+        self.move_to(self.rows.iter().next());
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn next(&mut self) -> Result<CursorResult<()>> {
+        let key = self.key.borrow().clone();
+        if let Some(key) = key {
+            let row = self
+                .rows
+                .range((Bound::Excluded(key), Bound::Unbounded))
+                .next();
+            self.move_to(row);
+        }
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn last(&mut self) -> Result<CursorResult<()>> {
+        self.move_to(self.rows.iter().next_back());
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn prev(&mut self) -> Result<CursorResult<()>> {
+        let key = self.key.borrow().clone();
+        if let Some(key) = key {
+            self.move_to(self.rows.range(..key).next_back());
+        }
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn seek_rowid(&mut self, rowid: u64) -> Result<CursorResult<bool>> {
+        if self.index {
+            anyhow::bail!("seeking an index by rowid is not supported");
+        }
+        let row = self.rows.get_key_value(&Key::Rowid(rowid));
+        Ok(CursorResult::Ok(self.move_to(row)))
+    }
+
+    /// Move to the first row at or after the key: a rowid for a table, and
+    /// the leading fields of the entries for an index.
+    fn seek_ge(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        if self.index {
+            let from = Key::Record(IndexKey(key.clone()));
+            return Ok(self.seek(Bound::Included(from), None));
+        }
+        match seek_key_rowid(key)? {
+            Some(rowid) => Ok(self.seek(Bound::Included(Key::Rowid(rowid)), None)),
+            None => Ok(self.seek(Bound::Unbounded, None)),
+        }
+    }
+
+    fn seek_gt(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        if self.index {
+            let from = Key::Record(IndexKey(key.clone()));
+            return Ok(self.seek(Bound::Included(from), Some(key)));
+        }
+        match seek_key_rowid(key)? {
+            Some(rowid) => Ok(self.seek(Bound::Excluded(Key::Rowid(rowid)), None)),
+            None => Ok(self.seek(Bound::Unbounded, None)),
+        }
+    }
+
+    fn wait_for_completion(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn rowid(&self) -> Result<Ref<'_, Option<u64>>> {
+        Ok(self.rowid.borrow())
+    }
+
+    fn record(&self) -> Result<Ref<'_, Option<OwnedRecord>>> {
+        Ok(self.record.borrow())
+    }
+
+    fn current_record(&mut self) -> Option<&OwnedRecord> {
+        self.record.get_mut().as_ref()
+    }
+
+    /// Insert or replace a row and move the cursor to it. The key is the
+    /// rowid of the row in a table, and is ignored in an index. Records made
+    /// by the program always hold UTF-8 text, whatever the encoding of the
+    /// database.
+    fn insert(&mut self, key: u64, payload: &[u8]) -> Result<CursorResult<()>> {
+        let record = OwnedRecord::parse(payload, TextEncoding::Utf8)?;
+        if record.values.len() > self.num_columns {
+            anyhow::bail!(
+                "record has {} columns but the ephemeral table has {}",
+                record.values.len(),
+                self.num_columns
+            );
+        }
+        let key = if self.index {
+            Key::Record(IndexKey(record.clone()))
+        } else {
+            Key::Rowid(key)
+        };
+        self.rows.insert(key.clone(), record);
+        self.move_to(self.rows.get_key_value(&key));
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Delete the current row. Moving the cursor in either direction visits
+    /// the neighbouring row.
+    fn delete(&mut self) -> Result<CursorResult<()>> {
+        if self.record.borrow().is_none() {
+            anyhow::bail!("cursor does not point at a row");
+        }
+        if let Some(key) = &*self.key.borrow() {
+            self.rows.remove(key);
+        }
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OwnedValue;
+    use rstest::rstest;
+
+    fn record(value: i64) -> Vec<u8> {
+        OwnedRecord::new(vec![OwnedValue::Integer(value)]).serialize(TextEncoding::Utf8)
+    }
+
+    fn cursor_with_rows(rowids: &[u64]) -> EphemeralCursor {
+        let mut cursor = EphemeralCursor::new(1);
+        for rowid in rowids {
+            cursor.insert(*rowid, &record(*rowid as i64 * 10)).unwrap();
+        }
+        cursor
+    }
+
+    fn current(cursor: &EphemeralCursor) -> Option<(u64, i64)> {
+        let rowid = (*cursor.rowid().unwrap())?;
+        match cursor.record().unwrap().as_ref().unwrap().values[0] {
+            OwnedValue::Integer(value) => Some((rowid, value)),
+            _ => panic!("unexpected record"),
+        }
+    }
+
+    fn scan(cursor: &mut EphemeralCursor) -> Vec<(u64, i64)> {
+        let mut rows = Vec::new();
+        cursor.rewind().unwrap();
+        while let Some(row) = current(cursor) {
+            rows.push(row);
+            cursor.next().unwrap();
+        }
+        rows
+    }
+
+    #[test]
+    fn test_scan_in_rowid_order() {
+        let mut cursor = cursor_with_rows(&[3, 1, 2]);
+        assert_eq!(scan(&mut cursor), vec![(1, 10), (2, 20), (3, 30)]);
+        cursor.last().unwrap();
+        assert_eq!(current(&cursor), Some((3, 30)));
+        cursor.prev().unwrap();
+        assert_eq!(current(&cursor), Some((2, 20)));
+    }
+
+    #[test]
+    fn test_scan_empty() {
+        let mut cursor = EphemeralCursor::new(1);
+        cursor.rewind().unwrap();
+        assert!(cursor.is_empty());
+        cursor.next().unwrap();
+        assert!(cursor.is_empty());
+    }
+
+    #[rstest]
+    #[case::rowid_found(|c: &mut EphemeralCursor| c.seek_rowid(2), true, Some((2, 20)))]
+    #[case::rowid_missing(|c: &mut EphemeralCursor| c.seek_rowid(4), false, None)]
+    #[case::ge(|c: &mut EphemeralCursor| c.seek_ge(&key(4)), true, Some((5, 50)))]
+    #[case::ge_equal(|c: &mut EphemeralCursor| c.seek_ge(&key(5)), true, Some((5, 50)))]
+    #[case::gt(|c: &mut EphemeralCursor| c.seek_gt(&key(5)), false, None)]
+    #[case::ge_negative(|c: &mut EphemeralCursor| c.seek_ge(&key(-1)), true, Some((1, 10)))]
+    fn test_seek(
+        #[case] seek: fn(&mut EphemeralCursor) -> Result<CursorResult<bool>>,
+        #[case] found: bool,
+        #[case] expected: Option<(u64, i64)>,
+    ) {
+        let mut cursor = cursor_with_rows(&[1, 2, 5]);
+        match seek(&mut cursor).unwrap() {
+            CursorResult::Ok(f) => assert_eq!(f, found),
+            CursorResult::IO => panic!("ephemeral cursors do not need I/O"),
+        }
+        assert_eq!(current(&cursor), expected);
+    }
+
+    fn key(rowid: i64) -> OwnedRecord {
+        OwnedRecord::new(vec![OwnedValue::Integer(rowid)])
+    }
+
+    #[test]
+    fn test_insert_replaces_row() {
+        let mut cursor = cursor_with_rows(&[1, 2]);
+        cursor.insert(1, &record(7)).unwrap();
+        assert_eq!(current(&cursor), Some((1, 7)));
+        assert_eq!(scan(&mut cursor), vec![(1, 7), (2, 20)]);
+    }
+
+    #[test]
+    fn test_insert_rejects_extra_columns() {
+        let mut cursor = EphemeralCursor::new(1);
+        let payload = OwnedRecord::new(vec![OwnedValue::Integer(1), OwnedValue::Integer(2)])
+            .serialize(TextEncoding::Utf8);
+        assert!(cursor.insert(1, &payload).is_err());
+    }
+
+    #[test]
+    fn test_delete_while_scanning() {
+        let mut cursor = cursor_with_rows(&[1, 2, 3]);
+        cursor.rewind().unwrap();
+        cursor.next().unwrap();
+        cursor.delete().unwrap();
+        cursor.next().unwrap();
+        assert_eq!(current(&cursor), Some((3, 30)));
+        assert_eq!(scan(&mut cursor), vec![(1, 10), (3, 30)]);
+    }
+
+    fn index_entry(values: &[i64]) -> OwnedRecord {
+        OwnedRecord::new(values.iter().map(|v| OwnedValue::Integer(*v)).collect())
+    }
+
+    fn index_with_entries(entries: &[&[i64]]) -> EphemeralCursor {
+        let mut cursor = EphemeralCursor::new_index(2);
+        for entry in entries {
+            cursor
+                .insert(0, &index_entry(entry).serialize(TextEncoding::Utf8))
+                .unwrap();
+        }
+        cursor
+    }
+
+    fn current_entry(cursor: &EphemeralCursor) -> Option<Vec<i64>> {
+        let record = cursor.record().unwrap();
+        let values = &record.as_ref()?.values;
+        Some(
+            values
+                .iter()
+                .map(|v| match v {
+                    OwnedValue::Integer(i) => *i,
+                    _ => panic!("unexpected value"),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_scan_index_in_key_order() {
+        let mut cursor = index_with_entries(&[&[2, 1], &[1, 3], &[2, 0], &[1, 3]]);
+        let mut entries = Vec::new();
+        cursor.rewind().unwrap();
+        while let Some(entry) = current_entry(&cursor) {
+            assert!(cursor.rowid().unwrap().is_none());
+            entries.push(entry);
+            cursor.next().unwrap();
+        }
+        assert_eq!(entries, vec![vec![1, 3], vec![2, 0], vec![2, 1]]);
+    }
+
+    #[rstest]
+    #[case::ge_prefix(&[2], false, Some(vec![2, 0]))]
+    #[case::ge_between(&[1, 4], false, Some(vec![2, 0]))]
+    #[case::ge_exact(&[2, 1], false, Some(vec![2, 1]))]
+    #[case::ge_past_end(&[3], false, None)]
+    #[case::gt_prefix(&[1], true, Some(vec![2, 0]))]
+    #[case::gt_last_prefix(&[2], true, None)]
+    fn test_seek_index(#[case] key: &[i64], #[case] gt: bool, #[case] expected: Option<Vec<i64>>) {
+        let mut cursor = index_with_entries(&[&[1, 3], &[2, 0], &[2, 1]]);
+        let key = index_entry(key);
+        let found = match if gt {
+            cursor.seek_gt(&key)
+        } else {
+            cursor.seek_ge(&key)
+        }
+        .unwrap()
+        {
+            CursorResult::Ok(found) => found,
+            CursorResult::IO => panic!("ephemeral cursors do not need I/O"),
+        };
+        assert_eq!(found, expected.is_some());
+        assert_eq!(current_entry(&cursor), expected);
+    }
+
+    #[test]
+    fn test_index_rejects_rowid_seek() {
+        let mut cursor = EphemeralCursor::new_index(1);
+        assert!(cursor.seek_rowid(1).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+
+/// Errors returned when executing a program, classified so that drivers can
+/// map them to SQLite result codes.
+#[derive(Debug)]
+pub enum LimboError {
+    /// Reading or writing the database file failed.
+    IOError(std::io::Error),
+    /// The database file is malformed.
+    Corrupt(String),
+    /// The database is locked by another transaction.
+    Busy(String),
+    /// A constraint failed, with the extended SQLite error code.
+    Constraint { err_code: i32, message: String },
+    /// A value did not have the type an operation requires.
+    TypeMismatch(String),
+    /// An instruction accessed a register beyond the registers allocated for
+    /// the program.
+    RegisterOutOfRange { index: usize, max: usize },
+    /// Any other error, such as a malformed program.
+    Internal(String),
+}
+
+impl fmt::Display for LimboError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimboError::IOError(err) => write!(f, "I/O error: {}", err),
+            LimboError::Corrupt(message) => {
+                write!(f, "database disk image is malformed: {}", message)
+            }
+            LimboError::Busy(message) => write!(f, "{}", message),
+            LimboError::Constraint { err_code, message } => {
+                write!(f, "{} (error code {})", message, err_code)
+            }
+            LimboError::TypeMismatch(message) => write!(f, "datatype mismatch: {}", message),
+            LimboError::RegisterOutOfRange { index, max } => write!(
+                f,
+                "register {} is out of range (program has {} registers)",
+                index, max
+            ),
+            LimboError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LimboError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LimboError::IOError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LimboError {
+    fn from(err: std::io::Error) -> Self {
+        LimboError::IOError(err)
+    }
+}
+
+/// Recover the error kind of an `anyhow::Error` raised by the storage layer:
+/// a wrapped `LimboError` or `std::io::Error` keeps its kind, and anything
+/// else is an internal error.
+impl From<anyhow::Error> for LimboError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<LimboError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        match err.downcast::<std::io::Error>() {
+            Ok(err) => LimboError::IOError(err),
+            Err(err) => LimboError::Internal(format!("{:#}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_from_io_error() {
+        let err = LimboError::from(std::io::Error::new(ErrorKind::NotFound, "no such file"));
+        assert!(matches!(err, LimboError::IOError(err) if err.kind() == ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_from_anyhow_io_error() {
+        let err = anyhow::Error::new(std::io::Error::new(ErrorKind::UnexpectedEof, "short read"));
+        let err = LimboError::from(err);
+        assert!(matches!(err, LimboError::IOError(err) if err.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_from_anyhow_limbo_error() {
+        let err = anyhow::Error::new(LimboError::Corrupt("bad page".to_string()));
+        assert!(
+            matches!(LimboError::from(err), LimboError::Corrupt(message) if message == "bad page")
+        );
+    }
+
+    #[test]
+    fn test_from_anyhow_keeps_context() {
+        let err = anyhow::anyhow!("invalid page type").context("reading page 2");
+        assert!(matches!(
+            LimboError::from(err),
+            LimboError::Internal(message) if message == "reading page 2: invalid page type"
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let err = LimboError::Constraint {
+            err_code: 1299,
+            message: "NOT NULL constraint failed: t.x".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "NOT NULL constraint failed: t.x (error code 1299)"
+        );
+        let err = LimboError::TypeMismatch("Insert key must be an integer rowid".to_string());
+        assert_eq!(
+            err.to_string(),
+            "datatype mismatch: Insert key must be an integer rowid"
+        );
+        let err = LimboError::RegisterOutOfRange { index: 3, max: 2 };
+        assert_eq!(
+            err.to_string(),
+            "register 3 is out of range (program has 2 registers)"
+        );
+    }
+}
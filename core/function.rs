@@ -0,0 +1,885 @@
+use crate::datetime;
+use crate::types::{is_number, text_to_number, Affinity, OwnedValue};
+
+use anyhow::Result;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A built-in scalar SQL function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalarFunc {
+    Abs,
+    Coalesce,
+    Date,
+    DateTime,
+    Glob,
+    Hex,
+    IfNull,
+    Instr,
+    Length,
+    Like,
+    Lower,
+    Quote,
+    Replace,
+    StrFTime,
+    Substr,
+    Time,
+    Upper,
+    Typeof,
+}
+
+impl ScalarFunc {
+    /// The name of the function in SQL.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScalarFunc::Abs => "abs",
+            ScalarFunc::Coalesce => "coalesce",
+            ScalarFunc::Date => "date",
+            ScalarFunc::DateTime => "datetime",
+            ScalarFunc::Glob => "glob",
+            ScalarFunc::Hex => "hex",
+            ScalarFunc::IfNull => "ifnull",
+            ScalarFunc::Instr => "instr",
+            ScalarFunc::Length => "length",
+            ScalarFunc::Like => "like",
+            ScalarFunc::Lower => "lower",
+            ScalarFunc::Quote => "quote",
+            ScalarFunc::Replace => "replace",
+            ScalarFunc::StrFTime => "strftime",
+            ScalarFunc::Substr => "substr",
+            ScalarFunc::Time => "time",
+            ScalarFunc::Upper => "upper",
+            ScalarFunc::Typeof => "typeof",
+        }
+    }
+
+    /// Call the function with the given arguments.
+    pub fn call(&self, args: &[OwnedValue]) -> Result<OwnedValue> {
+        // Functions with several arguments. The date and time functions take
+        // a time value and any number of modifiers.
+        match (self, args) {
+            (ScalarFunc::Coalesce, [_, _, ..]) | (ScalarFunc::IfNull, [_, _]) => {
+                return Ok(exec_coalesce(args))
+            }
+            (ScalarFunc::Glob, [pattern, value]) => return Ok(exec_glob(pattern, value)),
+            (ScalarFunc::Instr, [haystack, needle]) => return Ok(exec_instr(haystack, needle)),
+            (ScalarFunc::Like, [_, _] | [_, _, _]) => return exec_like(args),
+            (ScalarFunc::Replace, [x, from, to]) => return Ok(exec_replace(x, from, to)),
+            (ScalarFunc::Substr, [_, _] | [_, _, _]) => return Ok(exec_substr(args)),
+            (ScalarFunc::Date, args) => return Ok(datetime::exec_date(args)),
+            (ScalarFunc::DateTime, args) => return Ok(datetime::exec_datetime(args)),
+            (ScalarFunc::Time, args) => return Ok(datetime::exec_time(args)),
+            (ScalarFunc::StrFTime, [format, args @ ..]) => {
+                return Ok(datetime::exec_strftime(format, args))
+            }
+            _ => {}
+        }
+        let arg = match args {
+            [arg] => arg,
+            _ => anyhow::bail!("wrong number of arguments to function {}()", self.name()),
+        };
+        match self {
+            ScalarFunc::Abs => exec_abs(arg),
+            ScalarFunc::Hex => Ok(exec_hex(arg)),
+            ScalarFunc::Length => Ok(exec_length(arg)),
+            ScalarFunc::Lower => Ok(map_text(arg, |s| s.to_ascii_lowercase())),
+            ScalarFunc::Quote => Ok(exec_quote(arg)),
+            ScalarFunc::Upper => Ok(map_text(arg, |s| s.to_ascii_uppercase())),
+            ScalarFunc::Typeof => Ok(exec_typeof(arg)),
+            ScalarFunc::Coalesce
+            | ScalarFunc::Glob
+            | ScalarFunc::IfNull
+            | ScalarFunc::Instr
+            | ScalarFunc::Like
+            | ScalarFunc::Replace
+            | ScalarFunc::Substr => {
+                anyhow::bail!("wrong number of arguments to function {}()", self.name())
+            }
+            ScalarFunc::Date | ScalarFunc::DateTime | ScalarFunc::StrFTime | ScalarFunc::Time => {
+                unreachable!("date and time functions are called above")
+            }
+        }
+    }
+}
+
+/// The function called by a `Function` instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Func {
+    Scalar(ScalarFunc),
+    /// A function registered by the application, which is looked up by
+    /// name when the instruction runs.
+    External(String),
+}
+
+impl Func {
+    pub fn name(&self) -> &str {
+        match self {
+            Func::Scalar(func) => func.name(),
+            Func::External(name) => name,
+        }
+    }
+}
+
+/// A scalar function defined by the application.
+pub type ExternalFunc = Box<dyn Fn(&[OwnedValue]) -> Result<OwnedValue>>;
+
+/// The scalar functions registered by the application, by name and number
+/// of arguments. Names are case-insensitive, like in SQL.
+#[derive(Default)]
+pub struct FunctionRegistry {
+    funcs: HashMap<(String, usize), ExternalFunc>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `func` as the function `name` taking `arg_count`
+    /// arguments, replacing any function registered before.
+    pub fn register(&mut self, name: &str, arg_count: usize, func: ExternalFunc) {
+        self.funcs
+            .insert((name.to_ascii_lowercase(), arg_count), func);
+    }
+
+    /// Call the function `name` that takes as many arguments as `args`.
+    pub fn call(&self, name: &str, args: &[OwnedValue]) -> Result<OwnedValue> {
+        let name = name.to_ascii_lowercase();
+        match self.funcs.get(&(name.clone(), args.len())) {
+            Some(func) => func(args),
+            None if self.funcs.keys().any(|(n, _)| *n == name) => {
+                anyhow::bail!("wrong number of arguments to function {}()", name)
+            }
+            None => anyhow::bail!("no such function: {}", name),
+        }
+    }
+}
+
+/// The first argument that is not NULL, or NULL if they all are.
+fn exec_coalesce(args: &[OwnedValue]) -> OwnedValue {
+    args.iter()
+        .find(|arg| **arg != OwnedValue::Null)
+        .cloned()
+        .unwrap_or(OwnedValue::Null)
+}
+
+/// The absolute value of a number. Text and blobs are converted to a float,
+/// which is zero if they do not look like a number.
+fn exec_abs(arg: &OwnedValue) -> Result<OwnedValue> {
+    match arg {
+        OwnedValue::Null => Ok(OwnedValue::Null),
+        OwnedValue::Integer(i) => match i.checked_abs() {
+            Some(i) => Ok(OwnedValue::Integer(i)),
+            None => anyhow::bail!("integer overflow"),
+        },
+        OwnedValue::Float(f) => Ok(OwnedValue::Float(f.abs())),
+        OwnedValue::Text(_) | OwnedValue::Blob(_) => {
+            Ok(OwnedValue::Float(to_float(&to_number(arg)).abs()))
+        }
+    }
+}
+
+/// The number of characters of text, which ends at the first NUL character,
+/// or the number of bytes of a blob. Numbers are measured as text.
+fn exec_length(arg: &OwnedValue) -> OwnedValue {
+    match arg {
+        OwnedValue::Null => OwnedValue::Null,
+        OwnedValue::Blob(b) => OwnedValue::Integer(b.len() as i64),
+        _ => {
+            let text = arg.to_text().unwrap();
+            OwnedValue::Integer(text.chars().take_while(|c| *c != '\0').count() as i64)
+        }
+    }
+}
+
+/// Apply a function to the text form of the value. Like SQLite without ICU,
+/// case conversions only apply to ASCII characters.
+fn map_text(arg: &OwnedValue, f: fn(&str) -> String) -> OwnedValue {
+    match arg.to_text() {
+        Some(text) => OwnedValue::Text(f(&text).into()),
+        None => OwnedValue::Null,
+    }
+}
+
+/// The upper-case hexadecimal form of a blob, or of the UTF-8 bytes of the
+/// text form of any other value. NULL is the empty string.
+fn exec_hex(arg: &OwnedValue) -> OwnedValue {
+    let hex = match arg {
+        OwnedValue::Null => String::new(),
+        OwnedValue::Blob(b) => to_hex(b),
+        arg => to_hex(arg.to_text().unwrap().as_bytes()),
+    };
+    OwnedValue::Text(hex.into())
+}
+
+/// The value as an SQL literal: text in single quotes, blobs as `X'...'`
+/// and NULL as `NULL`.
+fn exec_quote(arg: &OwnedValue) -> OwnedValue {
+    let literal = match arg {
+        OwnedValue::Null => "NULL".to_string(),
+        OwnedValue::Integer(_) | OwnedValue::Float(_) => arg.to_text().unwrap(),
+        OwnedValue::Text(s) => format!("'{}'", s.as_str().replace('\'', "''")),
+        OwnedValue::Blob(b) => format!("X'{}'", to_hex(b)),
+    };
+    OwnedValue::Text(literal.into())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn exec_typeof(arg: &OwnedValue) -> OwnedValue {
+    let name = match arg {
+        OwnedValue::Null => "null",
+        OwnedValue::Integer(_) => "integer",
+        OwnedValue::Float(_) => "real",
+        OwnedValue::Text(_) => "text",
+        OwnedValue::Blob(_) => "blob",
+    };
+    OwnedValue::Text(name.into())
+}
+
+/// `substr(x, start, len)`: `len` characters of text, or bytes of a blob,
+/// from the 1-based position `start`. A negative `start` counts from the
+/// end and a negative `len` takes the characters before `start`. Without
+/// `len`, the rest of the value is taken.
+fn exec_substr(args: &[OwnedValue]) -> OwnedValue {
+    if args.contains(&OwnedValue::Null) {
+        return OwnedValue::Null;
+    }
+    let start = to_integer(&args[1]);
+    let (mut start, mut len, negative_len) = match args.get(2).map(to_integer) {
+        Some(len) if len < 0 => (start, len.saturating_neg(), true),
+        Some(len) => (start, len, false),
+        None => (start, i64::MAX, false),
+    };
+    let total = match &args[0] {
+        OwnedValue::Blob(b) => b.len(),
+        arg => arg.to_text().unwrap().chars().count(),
+    } as i64;
+    if start < 0 {
+        start += total;
+        if start < 0 {
+            len = len.saturating_add(start).max(0);
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if len > 0 {
+        // Position 0 is just before the first character.
+        len -= 1;
+    }
+    if negative_len {
+        start -= len;
+        if start < 0 {
+            len += start;
+            start = 0;
+        }
+    }
+    let start = start.min(total) as usize;
+    let len = len.clamp(0, total - start as i64) as usize;
+    match &args[0] {
+        OwnedValue::Blob(b) => OwnedValue::Blob(Rc::new(b[start..start + len].to_vec())),
+        arg => {
+            let text = arg.to_text().unwrap();
+            let substr: String = text.chars().skip(start).take(len).collect();
+            OwnedValue::Text(substr.into())
+        }
+    }
+}
+
+/// `instr(haystack, needle)`: the 1-based position of the first occurrence
+/// of `needle`, or 0. Positions count bytes if both values are blobs and
+/// characters otherwise.
+fn exec_instr(haystack: &OwnedValue, needle: &OwnedValue) -> OwnedValue {
+    let pos = match (haystack, needle) {
+        (OwnedValue::Null, _) | (_, OwnedValue::Null) => return OwnedValue::Null,
+        (OwnedValue::Blob(_), OwnedValue::Blob(needle)) if needle.is_empty() => 1,
+        (OwnedValue::Blob(haystack), OwnedValue::Blob(needle)) => haystack
+            .windows(needle.len())
+            .position(|window| window == needle.as_slice())
+            .map_or(0, |pos| pos + 1),
+        (haystack, needle) => {
+            let haystack = haystack.to_text().unwrap();
+            match haystack.find(&needle.to_text().unwrap()) {
+                Some(pos) => haystack[..pos].chars().count() + 1,
+                None => 0,
+            }
+        }
+    };
+    OwnedValue::Integer(pos as i64)
+}
+
+/// `replace(x, from, to)`: the text of `x` with every occurrence of `from`
+/// replaced by `to`.
+fn exec_replace(x: &OwnedValue, from: &OwnedValue, to: &OwnedValue) -> OwnedValue {
+    match (x.to_text(), from.to_text(), to.to_text()) {
+        (Some(x), Some(from), Some(_)) if from.is_empty() => OwnedValue::Text(x.into()),
+        (Some(x), Some(from), Some(to)) => OwnedValue::Text(x.replace(&from, &to).into()),
+        _ => OwnedValue::Null,
+    }
+}
+
+/// `like(pattern, value, escape)`, which is `value LIKE pattern ESCAPE
+/// escape`: `%` matches any text and `_` any character. Like SQLite without
+/// ICU, only ASCII letters match regardless of case.
+fn exec_like(args: &[OwnedValue]) -> Result<OwnedValue> {
+    if args.contains(&OwnedValue::Null) {
+        return Ok(OwnedValue::Null);
+    }
+    let escape = match args.get(2) {
+        Some(escape) => {
+            let escape = escape.to_text().unwrap();
+            let mut chars = escape.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => anyhow::bail!("ESCAPE expression must be a single character"),
+            }
+        }
+        None => None,
+    };
+    let wildcards = Wildcards {
+        any: '%',
+        one: '_',
+        classes: false,
+        escape,
+        no_case: true,
+    };
+    Ok(pattern_result(&args[0], &args[1], &wildcards))
+}
+
+/// `glob(pattern, value)`, which is `value GLOB pattern`: `*` matches any
+/// text, `?` any character and `[...]` any character of a class. Case
+/// matters.
+fn exec_glob(pattern: &OwnedValue, value: &OwnedValue) -> OwnedValue {
+    if *pattern == OwnedValue::Null || *value == OwnedValue::Null {
+        return OwnedValue::Null;
+    }
+    let wildcards = Wildcards {
+        any: '*',
+        one: '?',
+        classes: true,
+        escape: None,
+        no_case: false,
+    };
+    pattern_result(pattern, value, &wildcards)
+}
+
+/// The wildcards of a LIKE or GLOB pattern.
+struct Wildcards {
+    any: char,
+    one: char,
+    /// Whether `[...]` matches a character class.
+    classes: bool,
+    /// The character that makes the next character of the pattern match
+    /// only itself.
+    escape: Option<char>,
+    no_case: bool,
+}
+
+fn pattern_result(pattern: &OwnedValue, value: &OwnedValue, wildcards: &Wildcards) -> OwnedValue {
+    let pattern: Vec<char> = pattern.to_text().unwrap().chars().collect();
+    let value: Vec<char> = value.to_text().unwrap().chars().collect();
+    OwnedValue::Integer(pattern_match(&pattern, &value, wildcards) as i64)
+}
+
+fn pattern_match(pattern: &[char], text: &[char], wildcards: &Wildcards) -> bool {
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        let mut c = pattern[p];
+        p += 1;
+        if Some(c) == wildcards.escape {
+            c = match pattern.get(p) {
+                Some(c) => *c,
+                None => return false,
+            };
+            p += 1;
+        } else if c == wildcards.any {
+            while pattern.get(p) == Some(&wildcards.any) {
+                p += 1;
+            }
+            return (t..=text.len()).any(|t| pattern_match(&pattern[p..], &text[t..], wildcards));
+        } else if c == wildcards.one {
+            if t == text.len() {
+                return false;
+            }
+            t += 1;
+            continue;
+        } else if c == '[' && wildcards.classes {
+            let len = match text.get(t).and_then(|c| match_class(&pattern[p..], *c)) {
+                Some(len) => len,
+                None => return false,
+            };
+            p += len;
+            t += 1;
+            continue;
+        }
+        match text.get(t) {
+            Some(t) if *t == c => {}
+            Some(t) if wildcards.no_case && t.eq_ignore_ascii_case(&c) => {}
+            _ => return false,
+        }
+        t += 1;
+    }
+    t == text.len()
+}
+
+/// Match `c` against the character class that `pattern` starts with, after
+/// the opening `[`. Returns the length of the rest of the class if `c` is
+/// in it.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 0;
+    let negated = pattern.first() == Some(&'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        if start == ']' && !first {
+            i += 1;
+            break;
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(end)) if *end != ']' => {
+                matched |= (start..=*end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= start == c;
+                i += 1;
+            }
+        }
+    }
+    (matched != negated).then_some(i)
+}
+
+/// Convert a value to an integer, like `CAST(x AS INTEGER)`.
+fn to_integer(arg: &OwnedValue) -> i64 {
+    match arg.cast(Affinity::Integer) {
+        OwnedValue::Integer(i) => i,
+        _ => 0,
+    }
+}
+
+/// A built-in aggregate SQL function.
+///
+/// The accumulator of an aggregate lives in registers which start out NULL.
+/// AVG keeps the running sum in its first register and the number of values
+/// in the second; the other functions need a single register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggFunc {
+    Avg,
+    Count,
+    Max,
+    Min,
+    Sum,
+}
+
+impl AggFunc {
+    /// The name of the function in SQL.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggFunc::Avg => "avg",
+            AggFunc::Count => "count",
+            AggFunc::Max => "max",
+            AggFunc::Min => "min",
+            AggFunc::Sum => "sum",
+        }
+    }
+
+    /// The number of registers that hold the accumulator.
+    pub fn accumulator_size(&self) -> usize {
+        match self {
+            AggFunc::Avg => 2,
+            _ => 1,
+        }
+    }
+
+    /// Add a value to the accumulator. Like in SQLite, NULL values are
+    /// skipped.
+    pub fn step(&self, acc: &mut [OwnedValue], arg: &OwnedValue) -> Result<()> {
+        if *arg == OwnedValue::Null {
+            return Ok(());
+        }
+        match self {
+            AggFunc::Avg => {
+                let sum = match &acc[0] {
+                    OwnedValue::Float(f) => *f,
+                    _ => 0.0,
+                };
+                let count = match &acc[1] {
+                    OwnedValue::Integer(i) => *i,
+                    _ => 0,
+                };
+                acc[0] = OwnedValue::Float(sum + to_float(&to_number(arg)));
+                acc[1] = OwnedValue::Integer(count + 1);
+            }
+            AggFunc::Count => {
+                acc[0] = match &acc[0] {
+                    OwnedValue::Integer(i) => OwnedValue::Integer(i + 1),
+                    _ => OwnedValue::Integer(1),
+                };
+            }
+            AggFunc::Max => {
+                if acc[0] == OwnedValue::Null || arg.compare(&acc[0]) == Ordering::Greater {
+                    acc[0] = arg.clone();
+                }
+            }
+            AggFunc::Min => {
+                if acc[0] == OwnedValue::Null || arg.compare(&acc[0]) == Ordering::Less {
+                    acc[0] = arg.clone();
+                }
+            }
+            AggFunc::Sum => {
+                // The sum stays an integer until a non-integer value is added.
+                // Text only counts as an integer if all of it is one.
+                let value = match arg {
+                    OwnedValue::Text(s) if is_number(s) => to_number(arg),
+                    OwnedValue::Text(_) | OwnedValue::Blob(_) => {
+                        OwnedValue::Float(to_float(&to_number(arg)))
+                    }
+                    _ => arg.clone(),
+                };
+                acc[0] = match (&acc[0], value) {
+                    (OwnedValue::Null, value) => value,
+                    (OwnedValue::Integer(l), OwnedValue::Integer(r)) => match l.checked_add(r) {
+                        Some(sum) => OwnedValue::Integer(sum),
+                        None => anyhow::bail!("integer overflow"),
+                    },
+                    (sum, value) => OwnedValue::Float(to_float(sum) + to_float(&value)),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute the result of the aggregate from the accumulator.
+    pub fn finalize(&self, acc: &[OwnedValue]) -> OwnedValue {
+        match self {
+            AggFunc::Avg => match (&acc[0], &acc[1]) {
+                (OwnedValue::Float(sum), OwnedValue::Integer(count)) => {
+                    OwnedValue::Float(sum / *count as f64)
+                }
+                _ => OwnedValue::Null,
+            },
+            AggFunc::Count => match &acc[0] {
+                OwnedValue::Null => OwnedValue::Integer(0),
+                count => count.clone(),
+            },
+            AggFunc::Max | AggFunc::Min | AggFunc::Sum => acc[0].clone(),
+        }
+    }
+}
+
+/// Convert a non-NULL value to an integer or a float, like the arithmetic
+/// operators do.
+fn to_number(arg: &OwnedValue) -> OwnedValue {
+    match arg {
+        OwnedValue::Text(_) | OwnedValue::Blob(_) => text_to_number(&arg.to_text().unwrap()),
+        _ => arg.clone(),
+    }
+}
+
+fn to_float(value: &OwnedValue) -> f64 {
+    match value {
+        OwnedValue::Integer(i) => *i as f64,
+        OwnedValue::Float(f) => *f,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use std::rc::Rc;
+
+    fn text(s: &str) -> OwnedValue {
+        OwnedValue::Text(s.into())
+    }
+
+    fn blob(b: &[u8]) -> OwnedValue {
+        OwnedValue::Blob(Rc::new(b.to_vec()))
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    #[case(OwnedValue::Integer(-5), OwnedValue::Integer(5))]
+    #[case(OwnedValue::Integer(i64::MAX), OwnedValue::Integer(i64::MAX))]
+    #[case(OwnedValue::Float(-5.5), OwnedValue::Float(5.5))]
+    #[case(text("-5"), OwnedValue::Float(5.0))]
+    #[case(text("x"), OwnedValue::Float(0.0))]
+    #[case(blob(b"-5"), OwnedValue::Float(5.0))]
+    #[case(text("-5.5x"), OwnedValue::Float(5.5))]
+    #[case(text("-12abc"), OwnedValue::Float(12.0))]
+    #[case(text("inf"), OwnedValue::Float(0.0))]
+    fn test_abs(#[case] arg: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::Abs.call(&[arg]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_abs_overflow() {
+        let err = ScalarFunc::Abs
+            .call(&[OwnedValue::Integer(i64::MIN)])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "integer overflow");
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    #[case(text("hello"), OwnedValue::Integer(5))]
+    #[case(text("h\u{e9}llo \u{1f600}"), OwnedValue::Integer(7))]
+    #[case(text("a\0b"), OwnedValue::Integer(1))]
+    #[case(blob(&[0, 1, 2]), OwnedValue::Integer(3))]
+    #[case(OwnedValue::Integer(-12), OwnedValue::Integer(3))]
+    #[case(OwnedValue::Float(1.0), OwnedValue::Integer(3))]
+    fn test_length(#[case] arg: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::Length.call(&[arg]).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    #[case(text("HeLLo"), text("hello"))]
+    #[case(text("\u{c9}T\u{c9}"), text("\u{c9}t\u{c9}"))]
+    #[case(OwnedValue::Float(1.5), text("1.5"))]
+    #[case(blob(b"AB"), text("ab"))]
+    fn test_lower(#[case] arg: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::Lower.call(&[arg]).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    #[case(text("HeLLo"), text("HELLO"))]
+    #[case(text("h\u{e9}llo"), text("H\u{e9}LLO"))]
+    #[case(OwnedValue::Integer(1), text("1"))]
+    #[case(blob(b"ab"), text("AB"))]
+    fn test_upper(#[case] arg: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::Upper.call(&[arg]).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, "null")]
+    #[case(OwnedValue::Integer(1), "integer")]
+    #[case(OwnedValue::Float(1.0), "real")]
+    #[case(text("1"), "text")]
+    #[case(blob(b"1"), "blob")]
+    fn test_typeof(#[case] arg: OwnedValue, #[case] expected: &str) {
+        assert_eq!(ScalarFunc::Typeof.call(&[arg]).unwrap(), text(expected));
+    }
+
+    #[test]
+    fn test_wrong_number_of_arguments() {
+        let err = ScalarFunc::Upper.call(&[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "wrong number of arguments to function upper()"
+        );
+        let err = ScalarFunc::Coalesce.call(&[text("a")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "wrong number of arguments to function coalesce()"
+        );
+        let args = [OwnedValue::Null, OwnedValue::Null, text("a")];
+        assert!(ScalarFunc::IfNull.call(&args).is_err());
+    }
+
+    fn int(i: i64) -> OwnedValue {
+        OwnedValue::Integer(i)
+    }
+
+    #[rstest]
+    #[case::one_based(vec![text("hello"), int(2), int(3)], text("ell"))]
+    #[case::zero_start(vec![text("hello"), int(0), int(2)], text("h"))]
+    #[case::no_len(vec![text("hello"), int(0)], text("hello"))]
+    #[case::negative_start(vec![text("hello"), int(-3)], text("llo"))]
+    #[case::negative_start_with_len(vec![text("hello"), int(-3), int(2)], text("ll"))]
+    #[case::before_start(vec![text("hello"), int(-10), int(7)], text("he"))]
+    #[case::negative_len(vec![text("hello"), int(3), int(-2)], text("he"))]
+    #[case::negative_len_at_start(vec![text("hello"), int(1), int(-1)], text(""))]
+    #[case::len_out_of_range(vec![text("hello"), int(2), int(100)], text("ello"))]
+    #[case::start_out_of_range(vec![text("hello"), int(10)], text(""))]
+    #[case::multibyte(vec![text("h\u{e9}llo w\u{f6}rld"), int(2), int(4)], text("\u{e9}llo"))]
+    #[case::multibyte_negative(vec![text("h\u{e9}llo"), int(-4), int(-1)], text("h"))]
+    #[case::blob(vec![blob(&[1, 2, 3, 4, 5]), int(2), int(2)], blob(&[2, 3]))]
+    #[case::number(vec![int(12345), int(2), int(2)], text("23"))]
+    #[case::null_len(vec![text("hello"), int(2), OwnedValue::Null], OwnedValue::Null)]
+    fn test_substr(#[case] args: Vec<OwnedValue>, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::Substr.call(&args).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::multibyte(text("h\u{e9}llo w\u{f6}rld"), text("w\u{f6}"), int(7))]
+    #[case::not_found(text("hello"), text("z"), int(0))]
+    #[case::empty_needle(text("hello"), text(""), int(1))]
+    #[case::blobs(blob(&[1, 2, 3]), blob(&[2, 3]), int(2))]
+    #[case::numbers(int(12345), int(34), int(3))]
+    #[case::null(OwnedValue::Null, text("a"), OwnedValue::Null)]
+    fn test_instr(
+        #[case] haystack: OwnedValue,
+        #[case] needle: OwnedValue,
+        #[case] expected: OwnedValue,
+    ) {
+        assert_eq!(
+            ScalarFunc::Instr.call(&[haystack, needle]).unwrap(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::every_occurrence(text("aXbXc"), text("X"), text("--"), text("a--b--c"))]
+    #[case::empty_from(text("abc"), text(""), text("z"), text("abc"))]
+    #[case::multibyte(text("h\u{e9}llo"), text("\u{e9}"), text("e"), text("hello"))]
+    #[case::null(text("abc"), OwnedValue::Null, text("z"), OwnedValue::Null)]
+    fn test_replace(
+        #[case] x: OwnedValue,
+        #[case] from: OwnedValue,
+        #[case] to: OwnedValue,
+        #[case] expected: OwnedValue,
+    ) {
+        assert_eq!(ScalarFunc::Replace.call(&[x, from, to]).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::prefix("ab%", "abcdef", 1)]
+    #[case::not_prefix("ab%", "xabc", 0)]
+    #[case::suffix("%ef", "abcdef", 1)]
+    #[case::not_suffix("%ef", "abcdefg", 0)]
+    #[case::contains("%cd%", "abcdef", 1)]
+    #[case::not_contains("%cd%", "abdcef", 0)]
+    #[case::single_char("a_c", "abc", 1)]
+    #[case::single_char_missing("a_c", "ac", 0)]
+    #[case::single_char_twice("a_c", "abbc", 0)]
+    #[case::ascii_case("ABC", "abc", 1)]
+    #[case::non_ascii_case("\u{e9}", "\u{c9}", 0)]
+    #[case::empty("%", "", 1)]
+    fn test_like(#[case] pattern: &str, #[case] value: &str, #[case] expected: i64) {
+        let args = [text(pattern), text(value)];
+        assert_eq!(ScalarFunc::Like.call(&args).unwrap(), int(expected));
+    }
+
+    #[rstest]
+    #[case::escaped_any("10!%", "10%", 1)]
+    #[case::escaped_any_is_literal("10!%", "100", 0)]
+    #[case::escaped_one("a!_c", "a_c", 1)]
+    #[case::escaped_one_is_literal("a!_c", "abc", 0)]
+    fn test_like_escape(#[case] pattern: &str, #[case] value: &str, #[case] expected: i64) {
+        let args = [text(pattern), text(value), text("!")];
+        assert_eq!(ScalarFunc::Like.call(&args).unwrap(), int(expected));
+    }
+
+    #[test]
+    fn test_like_null_and_bad_escape() {
+        let args = [text("a"), OwnedValue::Null];
+        assert_eq!(ScalarFunc::Like.call(&args).unwrap(), OwnedValue::Null);
+        let err = ScalarFunc::Like
+            .call(&[text("a"), text("a"), text("xy")])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ESCAPE expression must be a single character"
+        );
+    }
+
+    #[rstest]
+    #[case::prefix("a*", "abc", 1)]
+    #[case::case_sensitive("a*", "Abc", 0)]
+    #[case::suffix("*.rs", "main.rs", 1)]
+    #[case::single_char("a?c", "abc", 1)]
+    #[case::class_range("[a-c]x", "bx", 1)]
+    #[case::negated_class("[^a-c]x", "bx", 0)]
+    #[case::bracket_in_class("[]]", "]", 1)]
+    #[case::unclosed_class("[ab", "a", 0)]
+    fn test_glob(#[case] pattern: &str, #[case] value: &str, #[case] expected: i64) {
+        let args = [text(pattern), text(value)];
+        assert_eq!(ScalarFunc::Glob.call(&args).unwrap(), int(expected));
+    }
+
+    #[rstest]
+    #[case::null(OwnedValue::Null, "NULL")]
+    #[case::integer(int(-3), "-3")]
+    #[case::real(OwnedValue::Float(1.5), "1.5")]
+    #[case::whole_real(OwnedValue::Float(1.0), "1.0")]
+    #[case::large_real(OwnedValue::Float(1e100), "1.0e+100")]
+    #[case::text(text("it's"), "'it''s'")]
+    #[case::empty_text(text(""), "''")]
+    #[case::blob(blob(&[0x01, 0xab]), "X'01AB'")]
+    #[case::empty_blob(blob(&[]), "X''")]
+    fn test_quote(#[case] arg: OwnedValue, #[case] expected: &str) {
+        assert_eq!(ScalarFunc::Quote.call(&[arg]).unwrap(), text(expected));
+    }
+
+    #[rstest]
+    #[case::null(OwnedValue::Null, "")]
+    #[case::integer(int(12), "3132")]
+    #[case::real(OwnedValue::Float(1.5), "312E35")]
+    #[case::text(text("h\u{e9}"), "68C3A9")]
+    #[case::blob(blob(&[0x01, 0xab]), "01AB")]
+    fn test_hex(#[case] arg: OwnedValue, #[case] expected: &str) {
+        assert_eq!(ScalarFunc::Hex.call(&[arg]).unwrap(), text(expected));
+    }
+
+    #[rstest]
+    #[case::all_null(vec![OwnedValue::Null, OwnedValue::Null, OwnedValue::Null], OwnedValue::Null)]
+    #[case::first_not_null(vec![OwnedValue::Integer(1), OwnedValue::Null, text("a")], OwnedValue::Integer(1))]
+    #[case::mixed_types(vec![OwnedValue::Null, blob(b"b"), OwnedValue::Float(1.5)], blob(b"b"))]
+    #[case::last(vec![OwnedValue::Null, OwnedValue::Null, text("")], text(""))]
+    fn test_coalesce(#[case] args: Vec<OwnedValue>, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::Coalesce.call(&args).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, text("b"), text("b"))]
+    #[case(OwnedValue::Float(0.0), text("b"), OwnedValue::Float(0.0))]
+    #[case(OwnedValue::Null, OwnedValue::Null, OwnedValue::Null)]
+    fn test_ifnull(#[case] a: OwnedValue, #[case] b: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(ScalarFunc::IfNull.call(&[a, b]).unwrap(), expected);
+    }
+
+    fn aggregate(func: AggFunc, args: &[OwnedValue]) -> Result<OwnedValue> {
+        let mut acc = vec![OwnedValue::Null; func.accumulator_size()];
+        for arg in args {
+            func.step(&mut acc, arg)?;
+        }
+        Ok(func.finalize(&acc))
+    }
+
+    #[rstest]
+    #[case::count(AggFunc::Count, vec![OwnedValue::Integer(1), OwnedValue::Null, text("a")], OwnedValue::Integer(2))]
+    #[case::count_empty(AggFunc::Count, vec![], OwnedValue::Integer(0))]
+    #[case::sum(AggFunc::Sum, vec![OwnedValue::Integer(1), OwnedValue::Null, text("2")], OwnedValue::Integer(3))]
+    #[case::sum_float(AggFunc::Sum, vec![OwnedValue::Integer(1), OwnedValue::Float(0.5)], OwnedValue::Float(1.5))]
+    #[case::sum_text(AggFunc::Sum, vec![OwnedValue::Integer(1), text("x")], OwnedValue::Float(1.0))]
+    #[case::sum_integer_text(AggFunc::Sum, vec![text(" 12 ")], OwnedValue::Integer(12))]
+    #[case::sum_real_text(AggFunc::Sum, vec![text("1e2")], OwnedValue::Float(100.0))]
+    #[case::sum_text_prefix(AggFunc::Sum, vec![text("12abc")], OwnedValue::Float(12.0))]
+    #[case::sum_blob(AggFunc::Sum, vec![blob(b"12")], OwnedValue::Float(12.0))]
+    #[case::sum_empty(AggFunc::Sum, vec![OwnedValue::Null], OwnedValue::Null)]
+    #[case::avg(AggFunc::Avg, vec![OwnedValue::Integer(1), OwnedValue::Null, OwnedValue::Integer(2)], OwnedValue::Float(1.5))]
+    #[case::avg_text_prefix(AggFunc::Avg, vec![text("4x"), text("nan")], OwnedValue::Float(2.0))]
+    #[case::avg_empty(AggFunc::Avg, vec![], OwnedValue::Null)]
+    #[case::min(AggFunc::Min, vec![OwnedValue::Integer(3), OwnedValue::Null, OwnedValue::Float(1.5), text("0")], OwnedValue::Float(1.5))]
+    #[case::min_empty(AggFunc::Min, vec![OwnedValue::Null], OwnedValue::Null)]
+    #[case::max(AggFunc::Max, vec![OwnedValue::Integer(3), text("a"), blob(b"a"), OwnedValue::Null], blob(b"a"))]
+    #[case::max_empty(AggFunc::Max, vec![], OwnedValue::Null)]
+    fn test_aggregate(
+        #[case] func: AggFunc,
+        #[case] args: Vec<OwnedValue>,
+        #[case] expected: OwnedValue,
+    ) {
+        assert_eq!(aggregate(func, &args).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let args = [OwnedValue::Integer(i64::MAX), OwnedValue::Integer(1)];
+        let err = aggregate(AggFunc::Sum, &args).unwrap_err();
+        assert_eq!(err.to_string(), "integer overflow");
+        let args = [OwnedValue::Float(1.0), OwnedValue::Integer(i64::MAX)];
+        assert!(aggregate(AggFunc::Sum, &args).is_ok());
+    }
+}
@@ -0,0 +1,332 @@
+//! Integrity check, like `PRAGMA integrity_check`.
+//!
+//! Every table and index B-Tree in the schema is walked from its root page.
+//! The check looks for pages that cannot be parsed or are referenced more
+//! than once, pages of the wrong kind for their tree, leaves at different
+//! depths, table rowids out of order and overflow chains that do not hold
+//! the rest of their payload. Keys of indexes are not compared, because
+//! their order depends on the collations of the index.
+use crate::io::IO;
+use crate::pager::{Page, PageIdx, Pager};
+use crate::schema::{ObjectType, Schema};
+use crate::sqlite3_ondisk::{BTreeCell, PageType};
+use crate::types::CursorResult;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Check the B-Trees of the schema and return the problems found. An empty
+/// list means the database is fine.
+pub fn integrity_check(pager: &Pager, schema: &Schema, io: &dyn IO) -> Result<Vec<String>> {
+    let mut checker = Checker {
+        pager,
+        io,
+        usable_size: pager.header()?.usable_size(),
+        visited: HashSet::new(),
+        errors: Vec::new(),
+    };
+    let mut roots = vec![(1, true)];
+    for object in schema.objects.values() {
+        match object.ty {
+            ObjectType::Table => roots.push((object.root_page, true)),
+            ObjectType::Index => roots.push((object.root_page, false)),
+            ObjectType::View | ObjectType::Trigger => {}
+        }
+    }
+    roots.sort();
+    for (root_page, table) in roots {
+        checker.check_tree(root_page, table, None, None)?;
+    }
+    Ok(checker.errors)
+}
+
+struct Checker<'a> {
+    pager: &'a Pager,
+    io: &'a dyn IO,
+    usable_size: usize,
+    visited: HashSet<PageIdx>,
+    errors: Vec<String>,
+}
+
+impl Checker<'_> {
+    /// Check the subtree at `page_idx`, whose rowids must be greater than
+    /// `min_rowid` and at most `max_rowid` in a table. Returns the depth of
+    /// the subtree, or `None` if it could not be checked.
+    fn check_tree(
+        &mut self,
+        page_idx: PageIdx,
+        table: bool,
+        min_rowid: Option<u64>,
+        max_rowid: Option<u64>,
+    ) -> Result<Option<usize>> {
+        if !self.visit(page_idx) {
+            return Ok(None);
+        }
+        let page = match self.read_page(page_idx, Pager::read_page)? {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let contents = page.contents.read().unwrap();
+        let btree = match contents.as_ref().unwrap().as_btree() {
+            Ok(btree) => btree,
+            Err(err) => {
+                self.errors.push(format!("Page {}: {}", page_idx, err));
+                return Ok(None);
+            }
+        };
+        let page_type = btree.header.page_type();
+        let is_table = matches!(page_type, PageType::TableInterior | PageType::TableLeaf);
+        if is_table != table {
+            let kind = if table { "table" } else { "index" };
+            self.errors
+                .push(format!("Page {}: expected a {} page", page_idx, kind));
+            return Ok(None);
+        }
+        let mut children = Vec::new();
+        let mut prev_rowid = min_rowid;
+        for (cell_idx, cell) in btree.cells.iter().enumerate() {
+            let (rowid, child, payload) = match cell {
+                BTreeCell::TableInteriorCell(cell) => {
+                    (Some(cell._rowid), Some(cell._left_child_page), None)
+                }
+                BTreeCell::TableLeafCell(cell) => (
+                    Some(cell._rowid),
+                    None,
+                    Some((&cell._payload, cell.payload_size, cell.first_overflow_page)),
+                ),
+                BTreeCell::IndexInteriorCell(cell) => (
+                    None,
+                    Some(cell._left_child_page),
+                    Some((&cell._payload, cell.payload_size, cell.first_overflow_page)),
+                ),
+                BTreeCell::IndexLeafCell(cell) => (
+                    None,
+                    None,
+                    Some((&cell._payload, cell.payload_size, cell.first_overflow_page)),
+                ),
+            };
+            if let Some(rowid) = rowid {
+                let in_order = prev_rowid.is_none_or(|prev| rowid > prev)
+                    && max_rowid.is_none_or(|max| rowid <= max);
+                if !in_order {
+                    self.errors.push(format!(
+                        "Page {} cell {}: rowid {} out of order",
+                        page_idx, cell_idx, rowid
+                    ));
+                }
+            }
+            if let Some(child) = child {
+                children.push((child as PageIdx, prev_rowid, rowid.or(max_rowid)));
+            }
+            if let Some((payload, payload_size, Some(first_overflow_page))) = payload {
+                self.check_overflow(
+                    page_idx,
+                    cell_idx,
+                    first_overflow_page as PageIdx,
+                    payload_size - payload.len(),
+                )?;
+            }
+            if rowid.is_some() {
+                prev_rowid = rowid;
+            }
+        }
+        if let Some(right_most_pointer) = btree.header.right_most_pointer {
+            children.push((right_most_pointer as PageIdx, prev_rowid, max_rowid));
+        }
+        drop(contents);
+        let mut depth = None;
+        for (child, min_rowid, max_rowid) in children {
+            let child_depth = match self.check_tree(child, table, min_rowid, max_rowid)? {
+                Some(child_depth) => child_depth,
+                None => continue,
+            };
+            match depth {
+                None => depth = Some(child_depth),
+                Some(depth) if depth != child_depth => {
+                    self.errors
+                        .push(format!("Page {}: child page depth differs", page_idx));
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(Some(depth.map_or(0, |depth| depth + 1)))
+    }
+
+    /// Check that the overflow chain starting at `page_idx` holds the
+    /// `remaining` bytes of the payload of a cell.
+    fn check_overflow(
+        &mut self,
+        cell_page: PageIdx,
+        cell_idx: usize,
+        mut page_idx: PageIdx,
+        remaining: usize,
+    ) -> Result<()> {
+        let mut expected = remaining;
+        let mut found = 0;
+        loop {
+            if !self.visit(page_idx) {
+                return Ok(());
+            }
+            let page = match self.read_page(page_idx, Pager::read_overflow_page)? {
+                Some(page) => page,
+                None => return Ok(()),
+            };
+            let contents = page.contents.read().unwrap();
+            let overflow = match contents.as_ref().unwrap().as_overflow() {
+                Ok(overflow) => overflow,
+                Err(err) => {
+                    self.errors.push(format!("Page {}: {}", page_idx, err));
+                    return Ok(());
+                }
+            };
+            let len = expected.min(self.usable_size - 4);
+            found += len;
+            expected -= len;
+            match overflow.next_page {
+                Some(next_page) if expected > 0 => page_idx = next_page as PageIdx,
+                Some(_) => {
+                    self.errors.push(format!(
+                        "Page {} cell {}: overflow chain is longer than its payload",
+                        cell_page, cell_idx
+                    ));
+                    return Ok(());
+                }
+                None => break,
+            }
+        }
+        if found != remaining {
+            self.errors.push(format!(
+                "Page {} cell {}: overflow chain holds {} bytes, expected {}",
+                cell_page, cell_idx, found, remaining
+            ));
+        }
+        Ok(())
+    }
+
+    /// Mark the page as visited. Returns `false`, and reports it, if the page
+    /// was already visited or is not a valid page number.
+    fn visit(&mut self, page_idx: PageIdx) -> bool {
+        if page_idx == 0 {
+            self.errors.push("Invalid page number 0".to_string());
+            return false;
+        }
+        if !self.visited.insert(page_idx) {
+            self.errors
+                .push(format!("Page {} is referenced more than once", page_idx));
+            return false;
+        }
+        true
+    }
+
+    /// Read a page, running the IO loop until it arrives. A page that cannot
+    /// be read is reported and `None` is returned.
+    fn read_page(
+        &mut self,
+        page_idx: PageIdx,
+        read: fn(&Pager, PageIdx) -> Result<CursorResult<Rc<Page>>>,
+    ) -> Result<Option<Rc<Page>>> {
+        loop {
+            match read(self.pager, page_idx) {
+                Ok(CursorResult::Ok(page)) => return Ok(Some(page)),
+                Ok(CursorResult::IO) => self.io.run_once()?,
+                Err(err) => {
+                    self.errors.push(format!("Page {}: {}", page_idx, err));
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::create_fixture;
+    use crate::{Database, MemoryIO, PageSource, IO};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PAGE_SIZE: usize = 4096;
+
+    /// A database whose table `t` spans several levels of pages and has rows
+    /// with overflow pages, and the root page of `t`.
+    fn database_bytes() -> (Vec<u8>, usize) {
+        let path = create_fixture(
+            "integrity",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, x TEXT);
+             CREATE INDEX t_x ON t (x);
+             CREATE TABLE u (a TEXT UNIQUE);
+             CREATE VIEW v AS SELECT x FROM t;
+             WITH RECURSIVE c(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM c WHERE i < 2000)
+             INSERT INTO t SELECT i, printf('%0100d', i) FROM c;
+             INSERT INTO t VALUES (5000, zeroblob(10000));
+             INSERT INTO u VALUES ('a'), ('b');",
+        );
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let root_page: i64 = conn
+            .query_row(
+                "SELECT rootpage FROM sqlite_schema WHERE name = 't'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        drop(conn);
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        (bytes, root_page as usize)
+    }
+
+    fn integrity_check(bytes: Vec<u8>) -> Vec<String> {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        let file = io.open_file("test.db").unwrap();
+        file.pwrite(
+            0,
+            Rc::new(RefCell::new(bytes)),
+            crate::io::empty_completion(),
+        )
+        .unwrap();
+        let db = Database::open(io.clone(), PageSource::from_file(file)).unwrap();
+        db.integrity_check(&*io).unwrap()
+    }
+
+    fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    }
+
+    /// The offset of the page in the file, and the page of its first child.
+    fn first_child(bytes: &[u8], page_idx: usize) -> (usize, usize) {
+        let page = (page_idx - 1) * PAGE_SIZE;
+        // Only interior pages have children.
+        assert_eq!(bytes[page], 5);
+        let cell = u16::from_be_bytes([bytes[page + 12], bytes[page + 13]]) as usize;
+        (page, read_u32(bytes, page + cell) as usize)
+    }
+
+    #[test]
+    fn test_good_database() {
+        let (bytes, _) = database_bytes();
+        assert!(integrity_check(bytes).is_empty());
+    }
+
+    #[test]
+    fn test_page_referenced_twice() {
+        let (mut bytes, root_page) = database_bytes();
+        let (page, child) = first_child(&bytes, root_page);
+        // Point the right-most pointer at the first child too.
+        bytes[page + 8..page + 12].copy_from_slice(&(child as u32).to_be_bytes());
+        let errors = integrity_check(bytes);
+        assert!(errors.contains(&format!("Page {} is referenced more than once", child)));
+    }
+
+    #[test]
+    fn test_index_page_in_table() {
+        let (mut bytes, root_page) = database_bytes();
+        let (_, child) = first_child(&bytes, root_page);
+        // Turn a leaf of `t` into an index leaf.
+        bytes[(child - 1) * PAGE_SIZE] = 10;
+        let errors = integrity_check(bytes);
+        assert_eq!(
+            errors,
+            vec![format!("Page {}: expected a table page", child)]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use super::{Completion, File, IO};
+use anyhow::Result;
+use log::trace;
+use std::cell::RefCell;
+use std::io::{Read, Seek, Write};
+use std::rc::Rc;
+
+pub struct GenericIO {}
+
+impl GenericIO {
+    pub fn new() -> Result<Self> {
+        Ok(Self {})
+    }
+}
+
+impl IO for GenericIO {
+    fn open_file(&self, path: &str) -> Result<Box<dyn File>> {
+        trace!("open_file(path = {})", path);
+        let (file, read_only) = super::open_read_write(path)?;
+        Ok(Box::new(GenericFile {
+            file: RefCell::new(file),
+            read_only,
+        }))
+    }
+
+    fn create_file(&self, path: &str) -> Result<Box<dyn File>> {
+        trace!("create_file(path = {})", path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(GenericFile {
+            file: RefCell::new(file),
+            read_only: false,
+        }))
+    }
+
+    fn run_once(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct GenericFile {
+    file: RefCell<std::fs::File>,
+    read_only: bool,
+}
+
+impl File for GenericFile {
+    fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(std::io::SeekFrom::Start(pos as u64))?;
+        let len = {
+            let mut buf = c.buf_mut();
+            let buf = buf.as_mut_slice();
+            if let Err(e) = file.read_exact(buf) {
+                let err = std::io::Error::new(e.kind(), e.to_string());
+                c.set_result(Err(e));
+                return Err(err.into());
+            }
+            buf.len()
+        };
+        c.set_result(Ok(len));
+        c.complete();
+        Ok(())
+    }
+
+    fn pwrite(&self, pos: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("attempt to write a readonly database");
+        }
+        let mut file = self.file.borrow_mut();
+        file.seek(std::io::SeekFrom::Start(pos as u64))?;
+        let len = {
+            let buf = buf.borrow();
+            file.write_all(&buf)?;
+            buf.len()
+        };
+        c.set_result(Ok(len));
+        c.complete();
+        Ok(())
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("attempt to write a readonly database");
+        }
+        let file = self.file.borrow();
+        file.sync_all()?;
+        c.set_result(Ok(0));
+        c.complete();
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.file.borrow().metadata()?.len() as usize)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
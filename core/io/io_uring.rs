@@ -0,0 +1,370 @@
+use super::{Completion, File, IO};
+use anyhow::Result;
+use log::trace;
+use std::cell::RefCell;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+
+pub struct UringIO {
+    ring: Rc<RefCell<io_uring::IoUring>>,
+}
+
+impl UringIO {
+    pub fn new() -> Result<Self> {
+        let ring = io_uring::IoUring::new(128)?;
+        Ok(Self {
+            ring: Rc::new(RefCell::new(ring)),
+        })
+    }
+}
+
+impl IO for UringIO {
+    fn open_file(&self, path: &str) -> Result<Box<dyn File>> {
+        trace!("open_file(path = {})", path);
+        let (file, read_only) = super::open_read_write(path)?;
+        Ok(Box::new(UringFile {
+            ring: self.ring.clone(),
+            file,
+            read_only,
+        }))
+    }
+
+    fn create_file(&self, path: &str) -> Result<Box<dyn File>> {
+        trace!("create_file(path = {})", path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(UringFile {
+            ring: self.ring.clone(),
+            file,
+            read_only: false,
+        }))
+    }
+
+    fn run_once(&self) -> Result<()> {
+        trace!("run_once()");
+        let mut ring = self.ring.borrow_mut();
+        ring.submit_and_wait(0)?;
+        while let Some(cqe) = ring.completion().next() {
+            let op = unsafe { Box::from_raw(cqe.user_data() as *mut InFlight) };
+            let result = cqe.result();
+            // Failed operations complete too, so that whoever waits on them
+            // sees the error.
+            let result = if result < 0 {
+                Err(std::io::Error::from_raw_os_error(-result))
+            } else if (result as usize) < op.len {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("transferred {} of {} bytes", result, op.len),
+                ))
+            } else {
+                Ok(result as usize)
+            };
+            op.c.set_result(result);
+            op.c.complete();
+        }
+        Ok(())
+    }
+}
+
+/// An operation submitted to the ring. It keeps the completion and any
+/// buffer the kernel reads from alive until the operation completes.
+struct InFlight {
+    c: Rc<Completion>,
+    _buf: Option<Rc<RefCell<Vec<u8>>>>,
+    /// The number of bytes to transfer. Transferring fewer is an error.
+    len: usize,
+}
+
+pub struct UringFile {
+    ring: Rc<RefCell<io_uring::IoUring>>,
+    file: std::fs::File,
+    read_only: bool,
+}
+
+impl UringFile {
+    /// Queue an operation. A full submission queue is first handed to the
+    /// kernel, so any number of operations can be in flight.
+    fn submit(&self, entry: io_uring::squeue::Entry) -> Result<()> {
+        let mut ring = self.ring.borrow_mut();
+        let full = ring.submission().is_full();
+        let submitted = if full {
+            ring.submit().map(|_| ())
+        } else {
+            Ok(())
+        };
+        if let Err(err) = submitted {
+            // The operation never reaches the kernel, so free it here.
+            drop(unsafe { Box::from_raw(entry.get_user_data() as *mut InFlight) });
+            return Err(err.into());
+        }
+        if unsafe { ring.submission().push(&entry) }.is_err() {
+            drop(unsafe { Box::from_raw(entry.get_user_data() as *mut InFlight) });
+            anyhow::bail!("submission queue is full");
+        }
+        Ok(())
+    }
+}
+
+impl File for UringFile {
+    fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()> {
+        trace!("pread(pos = {}, length = {})", pos, c.buf().len());
+        let fd = io_uring::types::Fd(self.file.as_raw_fd());
+        let read_e = {
+            let mut buf = c.buf_mut();
+            let len = buf.len();
+            let buf = buf.as_mut_ptr();
+            let op = Box::new(InFlight {
+                c: c.clone(),
+                _buf: None,
+                len,
+            });
+            io_uring::opcode::Read::new(fd, buf, len as u32)
+                .offset(pos as u64)
+                .build()
+                .user_data(Box::into_raw(op) as u64)
+        };
+        self.submit(read_e)
+    }
+
+    fn pwrite(&self, pos: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()> {
+        trace!("pwrite(pos = {}, length = {})", pos, buf.borrow().len());
+        if self.read_only {
+            anyhow::bail!("attempt to write a readonly database");
+        }
+        let fd = io_uring::types::Fd(self.file.as_raw_fd());
+        let write_e = {
+            let (ptr, len) = {
+                let buf = buf.borrow();
+                (buf.as_ptr(), buf.len())
+            };
+            let op = Box::new(InFlight {
+                c,
+                _buf: Some(buf),
+                len,
+            });
+            io_uring::opcode::Write::new(fd, ptr, len as u32)
+                .offset(pos as u64)
+                .build()
+                .user_data(Box::into_raw(op) as u64)
+        };
+        self.submit(write_e)
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        trace!("sync()");
+        if self.read_only {
+            anyhow::bail!("attempt to write a readonly database");
+        }
+        let fd = io_uring::types::Fd(self.file.as_raw_fd());
+        let op = Box::new(InFlight {
+            c,
+            _buf: None,
+            len: 0,
+        });
+        let sync_e = io_uring::opcode::Fsync::new(fd)
+            .build()
+            .user_data(Box::into_raw(op) as u64);
+        self.submit(sync_e)
+    }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.file.metadata()?.len() as usize)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{Buffer, BufferData};
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("limbo-uring-{}-{}.db", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_pwrite_sync_and_read_back() {
+        let path = temp_path("pwrite");
+        std::fs::File::create(&path).unwrap();
+        let io = UringIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let written = Rc::new(RefCell::new(false));
+        let complete = {
+            let written = written.clone();
+            Box::new(move |_buf: &Buffer| *written.borrow_mut() = true)
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(Buffer::allocate(0, drop_fn), complete));
+        let buf = Rc::new(RefCell::new(vec![42; 16]));
+        file.pwrite(100, buf, c).unwrap();
+        while !*written.borrow() {
+            io.run_once().unwrap();
+        }
+
+        let synced = Rc::new(RefCell::new(false));
+        let complete = {
+            let synced = synced.clone();
+            Box::new(move |_buf: &Buffer| *synced.borrow_mut() = true)
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(Buffer::allocate(0, drop_fn), complete));
+        file.sync(c).unwrap();
+        while !*synced.borrow() {
+            io.run_once().unwrap();
+        }
+
+        let read = Rc::new(RefCell::new(None));
+        let complete = {
+            let read = read.clone();
+            Box::new(move |buf: &Buffer| *read.borrow_mut() = Some(buf.as_slice().to_vec()))
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(Buffer::allocate(16, drop_fn), complete));
+        file.pread(100, c).unwrap();
+        while read.borrow().is_none() {
+            io.run_once().unwrap();
+        }
+        assert_eq!(read.borrow().as_deref(), Some(&[42; 16][..]));
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        const PAGE_SIZE: usize = 512;
+        const NR_PAGES: usize = 8;
+        let path = temp_path("reads");
+        {
+            let mut file = std::fs::File::create(&path).unwrap();
+            for page in 0..NR_PAGES {
+                file.write_all(&[page as u8; PAGE_SIZE]).unwrap();
+            }
+        }
+        let io = UringIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let pages = Rc::new(RefCell::new(vec![None; NR_PAGES]));
+        // Submit every read before reaping any completion.
+        for page in (0..NR_PAGES).rev() {
+            let drop_fn = Rc::new(|_buf: BufferData| {});
+            let buf = Buffer::allocate(PAGE_SIZE, drop_fn);
+            let complete = {
+                let pages = pages.clone();
+                Box::new(move |buf: &Buffer| {
+                    pages.borrow_mut()[page] = Some(buf.as_slice().to_vec());
+                })
+            };
+            let c = Rc::new(Completion::new(buf, complete));
+            file.pread(page * PAGE_SIZE, c).unwrap();
+        }
+        assert!(pages.borrow().iter().all(|page| page.is_none()));
+        while pages.borrow().iter().any(|page| page.is_none()) {
+            io.run_once().unwrap();
+        }
+        for (i, page) in pages.borrow().iter().enumerate() {
+            assert_eq!(page.as_deref(), Some(&[i as u8; PAGE_SIZE][..]));
+        }
+    }
+
+    #[test]
+    fn test_more_operations_than_queue_entries() {
+        let path = temp_path("many-writes");
+        std::fs::File::create(&path).unwrap();
+        let io = UringIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let written = Rc::new(RefCell::new(0));
+        for i in 0..300 {
+            let complete = {
+                let written = written.clone();
+                Box::new(move |_buf: &Buffer| *written.borrow_mut() += 1)
+            };
+            let drop_fn = Rc::new(|_buf: BufferData| {});
+            let c = Rc::new(Completion::new(Buffer::allocate(0, drop_fn), complete));
+            let buf = Rc::new(RefCell::new(vec![i as u8; 16]));
+            file.pwrite(i * 16, buf, c).unwrap();
+        }
+        while *written.borrow() < 300 {
+            io.run_once().unwrap();
+        }
+        assert_eq!(file.size().unwrap(), 300 * 16);
+    }
+
+    /// Read `len` bytes at `pos` and return the result of the completion.
+    fn read_result(
+        io: &UringIO,
+        file: &dyn File,
+        pos: usize,
+        len: usize,
+    ) -> std::io::Result<usize> {
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(
+            Buffer::allocate(len, drop_fn),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        file.pread(pos, c.clone()).unwrap();
+        while c.result().is_none() {
+            io.run_once().unwrap();
+        }
+        let result = c.result();
+        match result.as_ref().unwrap() {
+            Ok(n) => Ok(*n),
+            Err(err) => Err(std::io::Error::new(err.kind(), err.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_short_read_is_an_error() {
+        let path = temp_path("short-read");
+        std::fs::write(&path, [1; 16]).unwrap();
+        let io = UringIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_result(&io, file.as_ref(), 0, 16).unwrap(), 16);
+        let err = read_result(&io, file.as_ref(), 0, 512).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), "transferred 16 of 512 bytes");
+    }
+
+    #[test]
+    fn test_failed_operation_completes() {
+        let path = temp_path("failed-write");
+        std::fs::File::create(&path).unwrap();
+        let io = UringIO::new().unwrap();
+        // The kernel rejects writes to a file descriptor opened for reading.
+        let file = UringFile {
+            ring: io.ring.clone(),
+            file: std::fs::File::open(&path).unwrap(),
+            read_only: false,
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        let failed = Rc::new(RefCell::new(false));
+        let on_error = {
+            let failed = failed.clone();
+            Box::new(move |_err: &std::io::Error| *failed.borrow_mut() = true)
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(
+            Completion::new(Buffer::allocate(0, drop_fn), Box::new(|_buf: &Buffer| {}))
+                .on_error(on_error),
+        );
+        file.pwrite(0, Rc::new(RefCell::new(vec![1; 16])), c.clone())
+            .unwrap();
+        while c.result().is_none() {
+            io.run_once().unwrap();
+        }
+        assert!(*failed.borrow());
+        assert!(c.result().as_ref().unwrap().is_err());
+    }
+}
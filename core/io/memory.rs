@@ -0,0 +1,207 @@
+use super::{Completion, File, IO};
+use anyhow::Result;
+use log::trace;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// An IO backend that keeps files in memory. Files opened with the same path
+/// share their contents for as long as the `MemoryIO` lives.
+pub struct MemoryIO {
+    files: RefCell<HashMap<String, Rc<RefCell<Vec<u8>>>>>,
+}
+
+impl MemoryIO {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            files: RefCell::new(HashMap::new()),
+        })
+    }
+}
+
+impl IO for MemoryIO {
+    fn open_file(&self, path: &str) -> Result<Box<dyn File>> {
+        trace!("open_file(path = {})", path);
+        let data = self
+            .files
+            .borrow_mut()
+            .entry(path.to_string())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemoryFile { data }))
+    }
+
+    fn create_file(&self, path: &str) -> Result<Box<dyn File>> {
+        // Opening a file creates it anyway.
+        self.open_file(path)
+    }
+
+    fn run_once(&self) -> Result<()> {
+        // Every operation completes immediately.
+        Ok(())
+    }
+}
+
+pub struct MemoryFile {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryFile {
+    pub fn new() -> Self {
+        Self {
+            data: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.borrow().is_empty()
+    }
+}
+
+impl Default for MemoryFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl File for MemoryFile {
+    fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()> {
+        trace!("pread(pos = {}, length = {})", pos, c.buf().len());
+        {
+            let data = self.data.borrow();
+            let mut buf = c.buf_mut();
+            let buf = buf.as_mut_slice();
+            // Reads past the end of the file are zero-filled.
+            let start = pos.min(data.len());
+            let end = (pos + buf.len()).min(data.len());
+            let n = end - start;
+            buf[..n].copy_from_slice(&data[start..end]);
+            buf[n..].fill(0);
+            c.set_result(Ok(n));
+        }
+        c.complete();
+        Ok(())
+    }
+
+    fn pwrite(&self, pos: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()> {
+        trace!("pwrite(pos = {}, length = {})", pos, buf.borrow().len());
+        {
+            // Writes past the end of the file grow it.
+            let buf = buf.borrow();
+            let mut data = self.data.borrow_mut();
+            let end = pos + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[pos..end].copy_from_slice(&buf);
+            c.set_result(Ok(buf.len()));
+        }
+        c.complete();
+        Ok(())
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        // There is nothing to persist.
+        c.set_result(Ok(0));
+        c.complete();
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.len())
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{Buffer, BufferData};
+    use std::cell::Cell;
+
+    fn read_page(file: &dyn File, pos: usize, size: usize) -> Vec<u8> {
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let buf = Buffer::allocate(size, drop_fn);
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let done = Rc::new(Cell::new(false));
+        let complete = {
+            let out = out.clone();
+            let done = done.clone();
+            Box::new(move |buf: &Buffer| {
+                *out.borrow_mut() = buf.as_slice().to_vec();
+                done.set(true);
+            })
+        };
+        let c = Rc::new(Completion::new(buf, complete));
+        file.pread(pos, c).unwrap();
+        assert!(done.get());
+        let out = out.borrow().clone();
+        out
+    }
+
+    fn write_page(file: &dyn File, pos: usize, data: &[u8]) {
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let done = Rc::new(Cell::new(false));
+        let complete = {
+            let done = done.clone();
+            Box::new(move |_buf: &Buffer| done.set(true))
+        };
+        let c = Rc::new(Completion::new(Buffer::allocate(0, drop_fn), complete));
+        file.pwrite(pos, Rc::new(RefCell::new(data.to_vec())), c)
+            .unwrap();
+        assert!(done.get());
+    }
+
+    #[test]
+    fn test_write_and_read_pages() {
+        let file = MemoryFile::new();
+        write_page(&file, 0, &[1; 512]);
+        write_page(&file, 1024, &[3; 512]);
+        assert_eq!(file.len(), 1536);
+        assert_eq!(read_page(&file, 0, 512), vec![1; 512]);
+        assert_eq!(read_page(&file, 512, 512), vec![0; 512]);
+        assert_eq!(read_page(&file, 1024, 512), vec![3; 512]);
+    }
+
+    #[test]
+    fn test_read_past_end_is_zero_filled() {
+        let file = MemoryFile::new();
+        write_page(&file, 0, &[7; 4]);
+        assert_eq!(read_page(&file, 2, 4), vec![7, 7, 0, 0]);
+        assert_eq!(read_page(&file, 100, 4), vec![0; 4]);
+    }
+
+    #[test]
+    fn test_short_read_reports_bytes_read() {
+        let file = MemoryFile::new();
+        write_page(&file, 0, &[7; 4]);
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(
+            Buffer::allocate(8, drop_fn),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        assert!(c.result().is_none());
+        file.pread(2, c.clone()).unwrap();
+        assert_eq!(*c.result().as_ref().unwrap().as_ref().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_open_file_shares_contents() {
+        let io = MemoryIO::new().unwrap();
+        io.open_file("test.db").unwrap();
+        let data = io.files.borrow().get("test.db").unwrap().clone();
+        let file = MemoryFile { data };
+        write_page(&file, 0, &[9; 8]);
+        let second = io.open_file("test.db").unwrap();
+        assert_eq!(read_page(second.as_ref(), 0, 8), vec![9; 8]);
+        let other = io.open_file("other.db").unwrap();
+        assert_eq!(read_page(other.as_ref(), 0, 8), vec![0; 8]);
+    }
+}
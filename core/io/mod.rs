@@ -9,25 +9,73 @@ use std::{
 
 pub trait File {
     fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()>;
+    fn pwrite(&self, pos: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()>;
+    fn sync(&self, c: Rc<Completion>) -> Result<()>;
+    /// The size of the file in bytes.
+    fn size(&self) -> Result<usize>;
+    /// Whether the file could only be opened for reading.
+    fn is_read_only(&self) -> bool;
 }
 
 pub trait IO {
     fn open_file(&self, path: &str) -> Result<Box<dyn File>>;
 
+    /// Like `open_file`, but creates the file if it does not exist.
+    fn create_file(&self, path: &str) -> Result<Box<dyn File>>;
+
     fn run_once(&self) -> Result<()>;
 }
 
 pub type Complete = dyn Fn(&Buffer);
 
+/// Called instead of `Complete` when the operation fails.
+pub type OnError = dyn Fn(&std::io::Error);
+
+pub type Callback = dyn FnOnce();
+
 pub struct Completion {
     pub buf: RefCell<Buffer>,
     pub complete: Box<Complete>,
+    on_error: Option<Box<OnError>>,
+    result: RefCell<Option<std::io::Result<usize>>>,
+    callback: RefCell<Option<Box<Callback>>>,
 }
 
 impl Completion {
     pub fn new(buf: Buffer, complete: Box<Complete>) -> Self {
         let buf = RefCell::new(buf);
-        Self { buf, complete }
+        Self {
+            buf,
+            complete,
+            on_error: None,
+            result: RefCell::new(None),
+            callback: RefCell::new(None),
+        }
+    }
+
+    /// Creates a completion that runs `callback` the first time the IO
+    /// operation completes.
+    pub fn with_callback(buf: Buffer, callback: Box<Callback>) -> Self {
+        let c = Self::new(buf, Box::new(|_buf: &Buffer| {}));
+        *c.callback.borrow_mut() = Some(callback);
+        c
+    }
+
+    /// Handle a failed IO operation with `on_error`. Without it, failures
+    /// are only seen in the result.
+    pub fn on_error(mut self, on_error: Box<OnError>) -> Self {
+        self.on_error = Some(on_error);
+        self
+    }
+
+    /// Returns the outcome of the IO operation: the number of bytes
+    /// transferred or the error. `None` means the operation has not finished.
+    pub fn result(&self) -> Ref<'_, Option<std::io::Result<usize>>> {
+        self.result.borrow()
+    }
+
+    pub fn set_result(&self, result: std::io::Result<usize>) {
+        *self.result.borrow_mut() = Some(result);
     }
 
     pub fn buf(&self) -> Ref<'_, Buffer> {
@@ -38,9 +86,24 @@ impl Completion {
         self.buf.borrow_mut()
     }
 
+    /// Finish the IO operation, whose result must be set. The buffer is only
+    /// passed on if the operation succeeded.
     pub fn complete(&self) {
-        let buf = self.buf.borrow_mut();
-        (self.complete)(&buf);
+        match &*self.result.borrow() {
+            Some(Err(err)) => {
+                if let Some(on_error) = &self.on_error {
+                    on_error(err);
+                }
+            }
+            _ => {
+                let buf = self.buf.borrow_mut();
+                (self.complete)(&buf);
+            }
+        }
+        let callback = self.callback.borrow_mut().take();
+        if let Some(callback) = callback {
+            callback();
+        }
     }
 }
 
@@ -75,6 +138,10 @@ impl Buffer {
         self.data.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         &self.data
     }
@@ -92,10 +159,79 @@ impl Buffer {
     }
 }
 
+/// Read `len` bytes at `pos`, running the IO loop until they arrive.
+pub(crate) fn read_at(file: &dyn File, io: &dyn IO, pos: usize, len: usize) -> Result<Vec<u8>> {
+    let data = Rc::new(RefCell::new(Vec::new()));
+    let complete = {
+        let data = data.clone();
+        Box::new(move |buf: &Buffer| {
+            data.replace(buf.as_slice().to_vec());
+        })
+    };
+    let buf = Buffer::allocate(len, Rc::new(|_buf| {}));
+    let c = Rc::new(Completion::new(buf, complete));
+    file.pread(pos, c.clone())?;
+    wait_for_completion(io, &c)?;
+    Ok(data.take())
+}
+
+/// Run the IO loop until the operation of the completion finishes.
+pub(crate) fn wait_for_completion(io: &dyn IO, c: &Completion) -> Result<()> {
+    while c.result().is_none() {
+        io.run_once()?;
+    }
+    match &*c.result() {
+        Some(Err(err)) => Err(anyhow::anyhow!("I/O failed: {}", err)),
+        _ => Ok(()),
+    }
+}
+
+/// A completion for an operation whose progress is polled with
+/// `wait_for_completion`.
+pub(crate) fn empty_completion() -> Rc<Completion> {
+    let buf = Buffer::allocate(0, Rc::new(|_buf| {}));
+    Rc::new(Completion::new(buf, Box::new(|_buf: &Buffer| {})))
+}
+
+/// Open an existing file for reading and writing, or only for reading if
+/// it cannot be written. Returns whether the file is read-only.
+pub(crate) fn open_read_write(path: &str) -> Result<(std::fs::File, bool)> {
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        Ok(file) => Ok((file, false)),
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem
+            ) =>
+        {
+            Ok((std::fs::File::open(path)?, true))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Whether opening a file failed because it does not exist.
+pub(crate) fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+}
+
+mod memory;
+pub use memory::{MemoryFile, MemoryIO};
+
 cfg_block! {
-    #[cfg(target_os = "linux")] {
-        mod linux;
-        pub use linux::LinuxIO as PlatformIO;
+    #[cfg(all(target_os = "linux", feature = "io_uring"))] {
+        mod io_uring;
+        pub use self::io_uring::UringIO as PlatformIO;
+    }
+
+    #[cfg(all(target_os = "linux", not(feature = "io_uring")))] {
+        mod generic;
+        pub use generic::GenericIO as PlatformIO;
     }
 
     #[cfg(target_os = "macos")] {
@@ -108,3 +244,67 @@ cfg_block! {
         pub use windows::WindowsIO as PlatformIO;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_callback_runs_once() {
+        let calls = Rc::new(Cell::new(0));
+        let callback = {
+            let calls = calls.clone();
+            Box::new(move || calls.set(calls.get() + 1))
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Completion::with_callback(Buffer::allocate(0, drop_fn), callback);
+        assert_eq!(calls.get(), 0);
+        c.set_result(Ok(0));
+        c.complete();
+        c.complete();
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_failed_completion() {
+        let completed = Rc::new(Cell::new(false));
+        let complete = {
+            let completed = completed.clone();
+            Box::new(move |_buf: &Buffer| completed.set(true))
+        };
+        let error = Rc::new(RefCell::new(None));
+        let on_error = {
+            let error = error.clone();
+            Box::new(move |err: &std::io::Error| *error.borrow_mut() = Some(err.kind()))
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Completion::new(Buffer::allocate(0, drop_fn), complete).on_error(on_error);
+        c.set_result(Err(std::io::ErrorKind::UnexpectedEof.into()));
+        c.complete();
+        assert!(!completed.get());
+        assert_eq!(*error.borrow(), Some(std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_open_read_only_file() {
+        let path = std::env::temp_dir().join(format!("limbo-read-only-{}.db", std::process::id()));
+        std::fs::write(&path, [0; 16]).unwrap();
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        let io = PlatformIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap());
+        // Permissions do not stop the superuser from writing.
+        let writable = std::fs::OpenOptions::new().write(true).open(&path).is_ok();
+        std::fs::remove_file(&path).unwrap();
+        let file = file.unwrap();
+        let data = read_at(file.as_ref(), &io, 0, 16).unwrap();
+        assert_eq!(data, vec![0; 16]);
+        if !writable {
+            let buf = Rc::new(RefCell::new(vec![1; 16]));
+            let err = file.pwrite(0, buf, empty_completion()).unwrap_err();
+            assert_eq!(err.to_string(), "attempt to write a readonly database");
+        }
+    }
+}
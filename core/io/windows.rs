@@ -1,9 +1,9 @@
 use super::{Completion, File, IO};
-use anyhow::{Ok, Result};
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::io::{Read, Seek};
+use anyhow::Result;
 use log::trace;
+use std::cell::RefCell;
+use std::io::{Read, Seek, Write};
+use std::rc::Rc;
 
 pub struct WindowsIO {}
 
@@ -16,7 +16,25 @@ impl WindowsIO {
 impl IO for WindowsIO {
     fn open_file(&self, path: &str) -> Result<Box<dyn File>> {
         trace!("open_file(path = {})", path);
-        let file = std::fs::File::open(path)?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(WindowsFile {
+            file: RefCell::new(file),
+        }))
+    }
+
+    fn create_file(&self, path: &str) -> Result<Box<dyn File>> {
+        trace!("create_file(path = {})", path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
         Ok(Box::new(WindowsFile {
             file: RefCell::new(file),
         }))
@@ -35,12 +53,120 @@ impl File for WindowsFile {
     fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()> {
         let mut file = self.file.borrow_mut();
         file.seek(std::io::SeekFrom::Start(pos as u64))?;
-        {
+        let len = {
             let mut buf = c.buf_mut();
             let buf = buf.as_mut_slice();
-            file.read_exact(buf)?;
-        }
+            if let Err(e) = file.read_exact(buf) {
+                let err = std::io::Error::new(e.kind(), e.to_string());
+                c.set_result(Err(e));
+                return Err(err.into());
+            }
+            buf.len()
+        };
+        c.set_result(Ok(len));
+        c.complete();
+        Ok(())
+    }
+
+    fn pwrite(&self, pos: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(std::io::SeekFrom::Start(pos as u64))?;
+        let len = {
+            let buf = buf.borrow();
+            file.write_all(&buf)?;
+            buf.len()
+        };
+        c.set_result(Ok(len));
+        c.complete();
+        Ok(())
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        let file = self.file.borrow();
+        file.sync_all()?;
+        c.set_result(Ok(0));
         c.complete();
         Ok(())
     }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.file.borrow().metadata()?.len() as usize)
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{Buffer, BufferData};
+
+    #[test]
+    fn test_open_new_file_read_write() {
+        let path = std::env::temp_dir().join(format!("limbo-windows-{}.db", std::process::id()));
+        assert!(!path.exists());
+        let io = WindowsIO::new().unwrap();
+        // Opening a path that does not exist yet creates the file.
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(
+            Buffer::allocate(0, drop_fn),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        file.pwrite(100, Rc::new(RefCell::new(vec![42; 16])), c)
+            .unwrap();
+
+        let read = Rc::new(RefCell::new(Vec::new()));
+        let complete = {
+            let read = read.clone();
+            Box::new(move |buf: &Buffer| *read.borrow_mut() = buf.as_slice().to_vec())
+        };
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(Buffer::allocate(16, drop_fn), complete));
+        file.pread(100, c).unwrap();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*read.borrow(), vec![42; 16]);
+    }
+
+    #[test]
+    fn test_pwrite_and_sync() {
+        let path =
+            std::env::temp_dir().join(format!("limbo-windows-sync-{}.db", std::process::id()));
+        let io = WindowsIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(
+            Buffer::allocate(0, drop_fn),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        file.pwrite(0, Rc::new(RefCell::new(vec![1; 512])), c.clone())
+            .unwrap();
+        let result = file.sync(c);
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_short_read_reports_error() {
+        let path =
+            std::env::temp_dir().join(format!("limbo-windows-short-{}.db", std::process::id()));
+        std::fs::write(&path, [7; 8]).unwrap();
+        let io = WindowsIO::new().unwrap();
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        let drop_fn = Rc::new(|_buf: BufferData| {});
+        let c = Rc::new(Completion::new(
+            Buffer::allocate(16, drop_fn),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        assert!(file.pread(0, c.clone()).is_err());
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        let result = c.result();
+        let err = result.as_ref().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
 }
@@ -0,0 +1,272 @@
+//! Rollback journal.
+//!
+//! Before a commit overwrites pages of the database file, it writes their
+//! original contents to the journal and syncs it. If the commit does not
+//! finish, the journal is "hot" and the next open copies the original pages
+//! back. The commit is done once the journal header is zeroed again, as in
+//! the persistent journal mode of SQLite.
+//!
+//! The journal has the layout of an SQLite rollback journal:
+//!
+//! ```text
+//! +----------------+----------------------------------+-----+
+//! |     Header     |  Page number | Page | Checksum   | ... |
+//! | (sector sized) |   (4 bytes)  |      | (4 bytes)  |     |
+//! +----------------+----------------------------------+-----+
+//! ```
+use crate::io::{
+    empty_completion, is_not_found, read_at, wait_for_completion, Completion, File, IO,
+};
+use crate::PageSource;
+use anyhow::{anyhow, Result};
+use log::trace;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The magic string every valid journal header starts with.
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// The size of the journal header, which fills a sector.
+const JOURNAL_HEADER_SIZE: usize = 512;
+
+pub struct Journal {
+    io: Rc<dyn IO>,
+    path: String,
+    /// The journal file, which is created by the first commit unless it is
+    /// already there.
+    file: RefCell<Option<Rc<dyn File>>>,
+}
+
+impl Journal {
+    pub fn new(io: Rc<dyn IO>, path: impl Into<String>) -> Self {
+        Self {
+            io,
+            path: path.into(),
+            file: RefCell::new(None),
+        }
+    }
+
+    fn file(&self) -> Result<Rc<dyn File>> {
+        let mut file = self.file.borrow_mut();
+        if file.is_none() {
+            *file = Some(self.io.create_file(&self.path)?.into());
+        }
+        Ok(file.as_ref().unwrap().clone())
+    }
+
+    /// Write a journal holding the original contents of `pages`, for a
+    /// database of `database_size` pages.
+    pub fn write(
+        &self,
+        pages: &[(usize, Vec<u8>)],
+        database_size: u32,
+        c: Rc<Completion>,
+    ) -> Result<()> {
+        trace!("write journal of {} pages", pages.len());
+        let page_size = pages.first().map_or(0, |(_, page)| page.len());
+        let nonce = nonce();
+        let mut buf = vec![0; JOURNAL_HEADER_SIZE];
+        buf[0..8].copy_from_slice(&JOURNAL_MAGIC);
+        buf[8..12].copy_from_slice(&(pages.len() as u32).to_be_bytes());
+        buf[12..16].copy_from_slice(&nonce.to_be_bytes());
+        buf[16..20].copy_from_slice(&database_size.to_be_bytes());
+        buf[20..24].copy_from_slice(&(JOURNAL_HEADER_SIZE as u32).to_be_bytes());
+        buf[24..28].copy_from_slice(&(page_size as u32).to_be_bytes());
+        for (page_idx, page) in pages {
+            buf.extend_from_slice(&(*page_idx as u32).to_be_bytes());
+            buf.extend_from_slice(page);
+            buf.extend_from_slice(&checksum(nonce, page).to_be_bytes());
+        }
+        self.file()?.pwrite(0, Rc::new(RefCell::new(buf)), c)
+    }
+
+    pub fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        self.file()?.sync(c)
+    }
+
+    /// Zero the header, so that the journal is no longer hot.
+    pub fn clear(&self, c: Rc<Completion>) -> Result<()> {
+        trace!("clear journal");
+        let buf = vec![0; JOURNAL_HEADER_SIZE];
+        self.file()?.pwrite(0, Rc::new(RefCell::new(buf)), c)
+    }
+
+    /// The header of the journal if it is hot, which is when a commit
+    /// wrote it but did not finish.
+    fn hot_header(&self) -> Result<Option<Vec<u8>>> {
+        let file: Rc<dyn File> = match self.io.open_file(&self.path) {
+            Ok(file) => file.into(),
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        self.file.replace(Some(file.clone()));
+        if file.size()? < JOURNAL_HEADER_SIZE {
+            return Ok(None);
+        }
+        let header = read_at(&*file, &*self.io, 0, JOURNAL_HEADER_SIZE)?;
+        Ok((header[0..8] == JOURNAL_MAGIC).then_some(header))
+    }
+
+    /// Whether there is a journal left behind by a commit that did not
+    /// finish, whose pages must be restored before the database is read.
+    pub fn is_hot(&self) -> Result<bool> {
+        Ok(self.hot_header()?.is_some())
+    }
+
+    /// Copy the pages of a hot journal back to the database and clear the
+    /// journal. Pages are restored up to the first one whose checksum does
+    /// not match, which was not completely written. Returns the number of
+    /// pages restored.
+    ///
+    /// Other processes are not locked out, so this must not run while one
+    /// of them is committing to the database.
+    pub fn recover(&self, page_source: &PageSource) -> Result<usize> {
+        let Some(header) = self.hot_header()? else {
+            return Ok(0);
+        };
+        let file = self.file()?;
+        let io = &*self.io;
+        let size = file.size()?;
+        let num_pages = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let nonce = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+        let sector_size = u32::from_be_bytes([header[20], header[21], header[22], header[23]]);
+        let page_size = u32::from_be_bytes([header[24], header[25], header[26], header[27]]);
+        let (sector_size, page_size) = (sector_size as usize, page_size as usize);
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            return Err(anyhow!("invalid journal page size: {}", page_size));
+        }
+        if sector_size < JOURNAL_HEADER_SIZE {
+            return Err(anyhow!("invalid journal sector size: {}", sector_size));
+        }
+        let record_size = page_size + 8;
+        let mut pos = sector_size;
+        let mut restored = 0;
+        while restored < num_pages as usize && pos + record_size <= size {
+            let record = read_at(&*file, io, pos, record_size)?;
+            pos += record_size;
+            let page_idx = u32::from_be_bytes([record[0], record[1], record[2], record[3]]);
+            let page = &record[4..4 + page_size];
+            let expected = u32::from_be_bytes([
+                record[4 + page_size],
+                record[5 + page_size],
+                record[6 + page_size],
+                record[7 + page_size],
+            ]);
+            if page_idx == 0 || checksum(nonce, page) != expected {
+                break;
+            }
+            trace!("restore page {} from the journal", page_idx);
+            let c = empty_completion();
+            page_source.put(
+                page_idx as usize,
+                Rc::new(RefCell::new(page.to_vec())),
+                c.clone(),
+            )?;
+            wait_for_completion(io, &c)?;
+            restored += 1;
+        }
+        let c = empty_completion();
+        page_source.sync(c.clone())?;
+        wait_for_completion(io, &c)?;
+        let c = empty_completion();
+        self.clear(c.clone())?;
+        wait_for_completion(io, &c)?;
+        let c = empty_completion();
+        self.sync(c.clone())?;
+        wait_for_completion(io, &c)?;
+        Ok(restored)
+    }
+}
+
+/// The checksum of a journal record, which SQLite computes by adding every
+/// 200th byte of the page, counting back from the end, to the nonce.
+fn checksum(nonce: u32, page: &[u8]) -> u32 {
+    let mut checksum = nonce;
+    let mut i = page.len() as isize - 200;
+    while i > 0 {
+        checksum = checksum.wrapping_add(page[i as usize] as u32);
+        i -= 200;
+    }
+    checksum
+}
+
+/// A value that differs between journals, so that records left over from
+/// an older journal do not pass the checksum of a newer one.
+fn nonce() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryIO;
+
+    const PAGE_SIZE: usize = 512;
+
+    fn write_file(io: &Rc<dyn IO>, path: &str, pos: usize, data: Vec<u8>) {
+        let file = io.open_file(path).unwrap();
+        file.pwrite(pos, Rc::new(RefCell::new(data)), empty_completion())
+            .unwrap();
+    }
+
+    fn read_file(io: &Rc<dyn IO>, path: &str) -> Vec<u8> {
+        let file = io.open_file(path).unwrap();
+        read_at(&*file, &**io, 0, file.size().unwrap()).unwrap()
+    }
+
+    /// Journal pages 1 and 2 of a database holding `1`s and `2`s, then
+    /// overwrite the database with zeros.
+    fn journal_database(io: &Rc<dyn IO>) -> (PageSource, Journal) {
+        let mut original = vec![1; PAGE_SIZE];
+        original.extend_from_slice(&[2; PAGE_SIZE]);
+        write_file(io, "test.db", 0, original);
+        let journal = Journal::new(io.clone(), "test.db-journal");
+        let pages = vec![(1, vec![1; PAGE_SIZE]), (2, vec![2; PAGE_SIZE])];
+        let c = empty_completion();
+        journal.write(&pages, 2, c.clone()).unwrap();
+        wait_for_completion(&**io, &c).unwrap();
+        write_file(io, "test.db", 0, vec![0; 2 * PAGE_SIZE]);
+        let page_source = PageSource::from_file(io.open_file("test.db").unwrap());
+        (page_source, journal)
+    }
+
+    #[test]
+    fn test_recover() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        let (page_source, journal) = journal_database(&io);
+        assert!(journal.is_hot().unwrap());
+        assert_eq!(journal.recover(&page_source).unwrap(), 2);
+        assert!(!journal.is_hot().unwrap());
+        let mut expected = vec![1; PAGE_SIZE];
+        expected.extend_from_slice(&[2; PAGE_SIZE]);
+        assert_eq!(read_file(&io, "test.db"), expected);
+        // The journal is cleared, so it is not replayed again.
+        assert_eq!(read_file(&io, "test.db-journal")[..8], [0; 8]);
+        assert_eq!(journal.recover(&page_source).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_recover_stops_at_torn_page() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        let (page_source, journal) = journal_database(&io);
+        // Corrupt the second page record, after its page number.
+        let pos = JOURNAL_HEADER_SIZE + (PAGE_SIZE + 8) + 4 + PAGE_SIZE - 200;
+        write_file(&io, "test.db-journal", pos, vec![7]);
+        assert_eq!(journal.recover(&page_source).unwrap(), 1);
+        let mut expected = vec![1; PAGE_SIZE];
+        expected.extend_from_slice(&[0; PAGE_SIZE]);
+        assert_eq!(read_file(&io, "test.db"), expected);
+    }
+
+    #[test]
+    fn test_recover_without_journal() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        write_file(&io, "test.db", 0, vec![1; PAGE_SIZE]);
+        let page_source = PageSource::from_file(io.open_file("test.db").unwrap());
+        let journal = Journal::new(io.clone(), "test.db-journal");
+        assert_eq!(journal.recover(&page_source).unwrap(), 0);
+        assert_eq!(read_file(&io, "test.db"), vec![1; PAGE_SIZE]);
+    }
+}
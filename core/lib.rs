@@ -1,13 +1,24 @@
 mod btree;
 mod buffer_pool;
+mod datetime;
+mod ephemeral;
+mod error;
+mod function;
+mod integrity;
 mod io;
+mod journal;
 mod pager;
 mod schema;
+mod serialization;
+mod sorter;
 mod sqlite3_ondisk;
 mod storage;
+#[cfg(test)]
+mod test_utils;
 mod translate;
 mod types;
 mod vdbe;
+mod wal;
 
 #[cfg(not(target_family = "wasm"))]
 #[global_allocator]
@@ -15,19 +26,26 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use anyhow::Result;
 use fallible_iterator::FallibleIterator;
+use function::FunctionRegistry;
+use journal::Journal;
 use log::trace;
 use pager::Pager;
 use schema::Schema;
 use sqlite3_parser::{ast::Cmd, lexer::sql::Parser};
+use std::cell::RefCell;
 use std::rc::Rc;
+use wal::Wal;
 
+pub use error::LimboError;
 #[cfg(feature = "fs")]
 pub use io::PlatformIO;
 pub use io::{Buffer, Completion, File, IO};
+pub use io::{MemoryFile, MemoryIO};
 pub use storage::{PageIO, PageSource};
-pub use types::Value;
+pub use types::{OwnedValue, Value};
 
 pub struct Database {
+    io: Rc<dyn crate::io::IO>,
     pager: Rc<Pager>,
     schema: Rc<Schema>,
 }
@@ -37,60 +55,120 @@ impl Database {
     pub fn open_file(io: Rc<dyn crate::io::IO>, path: &str) -> Result<Database> {
         let file = io.open_file(path)?;
         let storage = storage::PageSource::from_file(file);
-        Self::open(io, storage)
+        let journal = Journal::new(io.clone(), format!("{}-journal", path));
+        let wal = Wal::open(&*io, &format!("{}-wal", path))?;
+        Self::open_with(io, storage, Some(journal), wal)
     }
 
     pub fn open(io: Rc<dyn crate::io::IO>, page_source: PageSource) -> Result<Database> {
-        let db_header = Pager::begin_open(&page_source)?;
+        Self::open_with(io, page_source, None, None)
+    }
+
+    /// Open a database whose commits go through `journal` and whose pages
+    /// are read from `wal` if it has them. A journal left behind by an
+    /// interrupted commit is rolled back first, which needs write access.
+    ///
+    /// The journal is not protected by file locks, so the database must not
+    /// be written by another process while it is open.
+    fn open_with(
+        io: Rc<dyn crate::io::IO>,
+        page_source: PageSource,
+        journal: Option<Journal>,
+        wal: Option<Wal>,
+    ) -> Result<Database> {
+        if let Some(journal) = &journal {
+            if page_source.is_read_only() {
+                if journal.is_hot()? {
+                    anyhow::bail!("database has a hot journal but is opened read-only");
+                }
+            } else {
+                journal.recover(&page_source)?;
+            }
+        }
+        // The database file of a new database in WAL mode may not even have
+        // a header yet.
+        let header_source = match &wal {
+            Some(wal) if wal.contains(1) => wal.page_source(),
+            _ => &page_source,
+        };
+        let db_header = Pager::begin_open(header_source)?;
         io.run_once()?;
-        let pager = Rc::new(Pager::finish_open(db_header, page_source)?);
+        let mut pager = Pager::finish_open(db_header, page_source)?;
+        if let Some(journal) = journal {
+            pager.set_journal(journal);
+        }
+        if let Some(wal) = wal {
+            pager.set_wal(wal);
+        }
+        let pager = Rc::new(pager);
         let bootstrap_schema = Rc::new(Schema::new());
         let conn = Connection {
+            io: io.clone(),
             pager: pager.clone(),
             schema: bootstrap_schema.clone(),
+            state_pool: Rc::new(RefCell::new(vdbe::ProgramStatePool::new())),
+            functions: Rc::new(RefCell::new(FunctionRegistry::new())),
         };
-        let mut schema = Schema::new();
-        let rows = conn.query("SELECT * FROM sqlite_schema")?;
-        if let Some(mut rows) = rows {
-            loop {
-                match rows.next()? {
-                    RowResult::Row(row) => {
-                        let ty = row.get::<String>(0)?;
-                        if ty != "table" {
-                            continue;
-                        }
-                        let root_page: i64 = row.get::<i64>(3)?;
-                        let sql: String = row.get::<String>(4)?;
-                        let table = schema::Table::from_sql(&sql, root_page as usize)?;
-                        schema.add_table(&table.name.to_owned(), table);
-                    }
-                    RowResult::IO => {
-                        // TODO: How do we ensure that the I/O we submitted to
-                        // read the schema is actually complete?
-                        io.run_once()?;
-                    }
-                    RowResult::Done => break,
-                }
-            }
-        }
+        let schema = read_schema(&conn)?;
         let schema = Rc::new(schema);
-        Ok(Database { pager, schema })
+        Ok(Database { io, pager, schema })
+    }
+
+    /// Check the B-Trees of the database like `PRAGMA integrity_check`, and
+    /// return the problems found. An empty list means the database is fine.
+    pub fn integrity_check(&self, io: &dyn crate::io::IO) -> Result<Vec<String>> {
+        integrity::integrity_check(&self.pager, &self.schema, io)
     }
 
     pub fn connect(&self) -> Connection {
         Connection {
+            io: self.io.clone(),
             pager: self.pager.clone(),
             schema: self.schema.clone(),
+            state_pool: Rc::new(RefCell::new(vdbe::ProgramStatePool::new())),
+            functions: Rc::new(RefCell::new(FunctionRegistry::new())),
         }
     }
 }
 
+/// Build the schema from the rows of the sqlite_schema table, which map the
+/// name of every table and index to its root page and SQL.
+fn read_schema(conn: &Connection) -> Result<Schema> {
+    let mut schema = Schema::new();
+    let mut stmt = conn.prepare("SELECT type, name, tbl_name, rootpage, sql FROM sqlite_schema")?;
+    while let Some(row) = stmt.step()? {
+        let ty = row.get::<String>(0)?;
+        schema.add_object(schema::SchemaObject {
+            ty: schema::ObjectType::try_from(ty.as_str())?,
+            name: row.get::<String>(1)?,
+            tbl_name: row.get::<String>(2)?,
+            root_page: row.get::<i64>(3)? as usize,
+            sql: row.get::<Option<String>>(4)?,
+        })?;
+    }
+    Ok(schema)
+}
+
 pub struct Connection {
+    io: Rc<dyn crate::io::IO>,
     pager: Rc<Pager>,
     schema: Rc<Schema>,
+    /// The states of finished statements, whose registers are reused by
+    /// the next statements.
+    state_pool: Rc<RefCell<vdbe::ProgramStatePool>>,
+    functions: Rc<RefCell<FunctionRegistry>>,
 }
 
 impl Connection {
+    /// Open the database file at `path` with the I/O backend of the platform
+    /// and connect to it.
+    #[cfg(feature = "fs")]
+    pub fn open(path: &str) -> Result<Connection> {
+        let io: Rc<dyn crate::io::IO> = Rc::new(PlatformIO::new()?);
+        let db = Database::open_file(io, path)?;
+        Ok(db.connect())
+    }
+
     pub fn prepare(&self, sql: impl Into<String>) -> Result<Statement> {
         let sql = sql.into();
         trace!("Preparing: {}", sql);
@@ -100,16 +178,36 @@ impl Connection {
             match cmd {
                 Cmd::Stmt(stmt) => {
                     let program = Rc::new(translate::translate(&self.schema, stmt)?);
-                    Ok(Statement::new(program, self.pager.clone()))
+                    Ok(Statement::new(
+                        program,
+                        self.io.clone(),
+                        self.pager.clone(),
+                        self.state_pool.clone(),
+                        self.functions.clone(),
+                    ))
+                }
+                Cmd::Explain(_) | Cmd::ExplainQueryPlan(_) => {
+                    anyhow::bail!("EXPLAIN cannot be prepared, use Connection::query")
                 }
-                Cmd::Explain(_stmt) => todo!(),
-                Cmd::ExplainQueryPlan(_stmt) => todo!(),
             }
         } else {
-            todo!()
+            anyhow::bail!("no statement to prepare")
         }
     }
 
+    /// Register a scalar function taking `arg_count` arguments, which
+    /// statements call if there is no built-in function with its name.
+    pub fn create_scalar_function(
+        &self,
+        name: &str,
+        arg_count: usize,
+        func: impl Fn(&[OwnedValue]) -> Result<OwnedValue> + 'static,
+    ) {
+        self.functions
+            .borrow_mut()
+            .register(name, arg_count, Box::new(func));
+    }
+
     pub fn query(&self, sql: impl Into<String>) -> Result<Option<Rows>> {
         let sql = sql.into();
         trace!("Querying: {}", sql);
@@ -119,7 +217,13 @@ impl Connection {
             match cmd {
                 Cmd::Stmt(stmt) => {
                     let program = Rc::new(translate::translate(&self.schema, stmt)?);
-                    let stmt = Statement::new(program, self.pager.clone());
+                    let stmt = Statement::new(
+                        program,
+                        self.io.clone(),
+                        self.pager.clone(),
+                        self.state_pool.clone(),
+                        self.functions.clone(),
+                    );
                     Ok(Some(Rows { stmt }))
                 }
                 Cmd::Explain(stmt) => {
@@ -127,7 +231,13 @@ impl Connection {
                     program.explain();
                     Ok(None)
                 }
-                Cmd::ExplainQueryPlan(_stmt) => Ok(None),
+                Cmd::ExplainQueryPlan(stmt) => {
+                    let program = translate::translate(&self.schema, stmt)?;
+                    for step in program.query_plan() {
+                        println!("{}", step);
+                    }
+                    Ok(None)
+                }
             }
         } else {
             Ok(None)
@@ -147,8 +257,11 @@ impl Connection {
                 Cmd::ExplainQueryPlan(_stmt) => todo!(),
                 Cmd::Stmt(stmt) => {
                     let program = translate::translate(&self.schema, stmt)?;
-                    let mut state = vdbe::ProgramState::new(program.max_registers);
-                    program.step(&mut state, self.pager.clone())?;
+                    let mut state = self.state_pool.borrow_mut().acquire(&program);
+                    state.set_functions(self.functions.clone());
+                    let result = program.step(&mut state, self.pager.clone()).map(|_| ());
+                    self.state_pool.borrow_mut().release(state);
+                    result?;
                 }
             }
         }
@@ -158,35 +271,89 @@ impl Connection {
 
 pub struct Statement {
     program: Rc<vdbe::Program>,
-    state: vdbe::ProgramState,
+    /// The state is only taken when the statement is dropped, to return it
+    /// to the connection's pool.
+    state: Option<vdbe::ProgramState>,
+    io: Rc<dyn crate::io::IO>,
     pager: Rc<Pager>,
+    state_pool: Rc<RefCell<vdbe::ProgramStatePool>>,
+    functions: Rc<RefCell<FunctionRegistry>>,
 }
 
 impl Statement {
-    pub fn new(program: Rc<vdbe::Program>, pager: Rc<Pager>) -> Self {
-        let state = vdbe::ProgramState::new(program.max_registers);
+    pub fn new(
+        program: Rc<vdbe::Program>,
+        io: Rc<dyn crate::io::IO>,
+        pager: Rc<Pager>,
+        state_pool: Rc<RefCell<vdbe::ProgramStatePool>>,
+        functions: Rc<RefCell<FunctionRegistry>>,
+    ) -> Self {
+        let mut state = state_pool.borrow_mut().acquire(&program);
+        state.set_functions(functions.clone());
         Self {
             program,
-            state,
+            state: Some(state),
+            io,
             pager,
+            state_pool,
+            functions,
+        }
+    }
+
+    /// Step the statement to its next row, running I/O until it arrives.
+    /// Returns `None` once the statement is done.
+    pub fn step(&mut self) -> Result<Option<Row<'_>>> {
+        loop {
+            let state = self.state.as_mut().unwrap();
+            match self.program.step_to_row(state, self.pager.clone())? {
+                vdbe::RowStep::Row => break,
+                vdbe::RowStep::IO => self.io.run_once()?,
+                vdbe::RowStep::Done => return Ok(None),
+                vdbe::RowStep::Interrupted => anyhow::bail!("Interrupted"),
+            }
+        }
+        match self.try_step()? {
+            RowResult::Row(row) => Ok(Some(row)),
+            RowResult::IO | RowResult::Done => unreachable!("result rows do not need I/O"),
         }
     }
 
-    pub fn step(&mut self) -> Result<RowResult<'_>> {
-        let result = self.program.step(&mut self.state, self.pager.clone())?;
+    /// Step the statement without blocking: returns `RowResult::IO` when
+    /// the statement has to wait for I/O, after which it can be stepped
+    /// again.
+    pub fn try_step(&mut self) -> Result<RowResult<'_>> {
+        let state = self.state.as_mut().unwrap();
+        let result = self.program.step(state, self.pager.clone())?;
         match result {
             vdbe::StepResult::Row(row) => Ok(RowResult::Row(Row { values: row.values })),
             vdbe::StepResult::IO => Ok(RowResult::IO),
             vdbe::StepResult::Done => Ok(RowResult::Done),
+            vdbe::StepResult::Interrupted => anyhow::bail!("Interrupted"),
         }
     }
 
     pub fn query(&mut self) -> Result<Rows> {
-        let stmt = Statement::new(self.program.clone(), self.pager.clone());
+        let stmt = Statement::new(
+            self.program.clone(),
+            self.io.clone(),
+            self.pager.clone(),
+            self.state_pool.clone(),
+            self.functions.clone(),
+        );
         Ok(Rows::new(stmt))
     }
 
-    pub fn reset(&self) {}
+    pub fn reset(&mut self) {
+        self.state.as_mut().unwrap().reset();
+    }
+}
+
+impl Drop for Statement {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            self.state_pool.borrow_mut().release(state);
+        }
+    }
 }
 
 pub enum RowResult<'a> {
@@ -204,6 +371,85 @@ impl<'a> Row<'a> {
         let value = &self.values[idx];
         T::from_value(value)
     }
+
+    /// The integer in column `idx`, or `None` if it is NULL.
+    pub fn get_int(&self, idx: usize) -> Result<Option<i64>, LimboError> {
+        match self.column(idx)? {
+            Value::Null => Ok(None),
+            Value::Integer(i) => Ok(Some(*i)),
+            value => Err(type_mismatch(idx, value, "an integer")),
+        }
+    }
+
+    /// The real in column `idx`, or `None` if it is NULL. Integers are
+    /// converted to reals.
+    pub fn get_real(&self, idx: usize) -> Result<Option<f64>, LimboError> {
+        match self.column(idx)? {
+            Value::Null => Ok(None),
+            Value::Integer(i) => Ok(Some(*i as f64)),
+            Value::Float(f) => Ok(Some(*f)),
+            value => Err(type_mismatch(idx, value, "a real")),
+        }
+    }
+
+    /// The text in column `idx`, or `None` if it is NULL.
+    pub fn get_text(&self, idx: usize) -> Result<Option<&'a str>, LimboError> {
+        match self.column(idx)? {
+            Value::Null => Ok(None),
+            Value::Text(s) => Ok(Some(s)),
+            value => Err(type_mismatch(idx, value, "text")),
+        }
+    }
+
+    /// The blob in column `idx`, or `None` if it is NULL.
+    pub fn get_blob(&self, idx: usize) -> Result<Option<&'a [u8]>, LimboError> {
+        match self.column(idx)? {
+            Value::Null => Ok(None),
+            Value::Blob(b) => Ok(Some(b.as_slice())),
+            value => Err(type_mismatch(idx, value, "a blob")),
+        }
+    }
+
+    fn column(&self, idx: usize) -> Result<&Value<'a>, LimboError> {
+        self.values.get(idx).ok_or_else(|| {
+            LimboError::Internal(format!(
+                "column index {} out of range (row has {} columns)",
+                idx,
+                self.values.len()
+            ))
+        })
+    }
+
+    /// Copy the values out of the statement, so that the row outlives the
+    /// next step.
+    pub fn to_owned(&self) -> OwnedRow {
+        OwnedRow {
+            values: self.values.iter().map(types::to_owned_value).collect(),
+        }
+    }
+}
+
+fn type_mismatch(idx: usize, value: &Value, expected: &str) -> LimboError {
+    let found = match value {
+        Value::Null => "NULL",
+        Value::Integer(_) => "an integer",
+        Value::Float(_) => "a real",
+        Value::Text(_) => "text",
+        Value::Blob(_) => "a blob",
+    };
+    LimboError::TypeMismatch(format!("column {} is {}, not {}", idx, found, expected))
+}
+
+/// A row that owns its values, as yielded by iterating over `Rows`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedRow {
+    pub values: Vec<OwnedValue>,
+}
+
+impl OwnedRow {
+    pub fn get<T: crate::types::FromValue>(&self, idx: usize) -> Result<T> {
+        T::from_value(&types::to_value(&self.values[idx]))
+    }
 }
 
 pub struct Rows {
@@ -215,7 +461,164 @@ impl Rows {
         Self { stmt }
     }
 
-    pub fn next(&mut self) -> Result<RowResult<'_>> {
-        self.stmt.step()
+    /// Step to the next row without blocking, like `Statement::try_step`.
+    pub fn try_next(&mut self) -> Result<RowResult<'_>> {
+        self.stmt.try_step()
+    }
+}
+
+/// Iterating runs I/O until each row arrives, and copies its values.
+impl Iterator for Rows {
+    type Item = Result<OwnedRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.stmt.step() {
+            Ok(Some(row)) => Some(Ok(row.to_owned())),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_fixture, open_fixture};
+    use rstest::rstest;
+
+    fn row(blob: &Vec<u8>) -> Row<'_> {
+        Row {
+            values: vec![
+                Value::Integer(2),
+                Value::Float(1.5),
+                Value::Text("a"),
+                Value::Blob(blob),
+                Value::Null,
+            ],
+        }
+    }
+
+    #[test]
+    fn test_typed_getters() {
+        let blob = vec![1, 2];
+        let row = row(&blob);
+        assert_eq!(row.get_int(0).unwrap(), Some(2));
+        assert_eq!(row.get_real(0).unwrap(), Some(2.0));
+        assert_eq!(row.get_real(1).unwrap(), Some(1.5));
+        assert_eq!(row.get_text(2).unwrap(), Some("a"));
+        assert_eq!(row.get_blob(3).unwrap(), Some([1, 2].as_slice()));
+        assert_eq!(row.get_int(4).unwrap(), None);
+        assert_eq!(row.get_text(4).unwrap(), None);
+    }
+
+    #[rstest]
+    #[case::real_as_int(|row: &Row| row.get_int(1).map(|_| ()), "column 1 is a real, not an integer")]
+    #[case::text_as_real(|row: &Row| row.get_real(2).map(|_| ()), "column 2 is text, not a real")]
+    #[case::int_as_text(|row: &Row| row.get_text(0).map(|_| ()), "column 0 is an integer, not text")]
+    #[case::text_as_blob(|row: &Row| row.get_blob(2).map(|_| ()), "column 2 is text, not a blob")]
+    fn test_type_mismatch(#[case] get: fn(&Row) -> Result<(), LimboError>, #[case] message: &str) {
+        let blob = vec![1, 2];
+        match get(&row(&blob)) {
+            Err(LimboError::TypeMismatch(m)) => assert_eq!(m, message),
+            result => panic!("expected a type mismatch, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_column_out_of_range() {
+        let blob = vec![1, 2];
+        assert!(matches!(
+            row(&blob).get_int(5),
+            Err(LimboError::Internal(_))
+        ));
+    }
+
+    #[test]
+    fn test_statement_step() {
+        let (_io, db) = open_fixture(
+            "statement_step",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, x TEXT);
+             INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'c');",
+        );
+        let conn = db.connect();
+        let mut stmt = conn.prepare("SELECT id, x FROM t").unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = stmt.step().unwrap() {
+            rows.push((row.get::<i64>(0).unwrap(), row.get::<String>(1).unwrap()));
+        }
+        assert_eq!(
+            rows,
+            vec![
+                (1, "a".to_string()),
+                (2, "b".to_string()),
+                (3, "c".to_string())
+            ]
+        );
+        // A reset statement runs again from the start.
+        stmt.reset();
+        assert_eq!(stmt.step().unwrap().unwrap().get::<i64>(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_connection_open() {
+        let path = create_fixture(
+            "connection_open",
+            "CREATE TABLE t (x TEXT);
+             INSERT INTO t VALUES ('a'), ('b');",
+        );
+        let conn = crate::Connection::open(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut stmt = conn.prepare("SELECT x FROM t").unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = stmt.step().unwrap() {
+            rows.push(row.get::<String>(0).unwrap());
+        }
+        assert_eq!(rows, vec!["a", "b"]);
+    }
+
+    struct ReadOnlyPages;
+
+    impl PageIO for ReadOnlyPages {
+        fn get(&self, _page_idx: usize, _c: Rc<crate::Completion>) -> Result<()> {
+            unreachable!("the database is not read")
+        }
+    }
+
+    #[test]
+    fn test_hot_journal_on_read_only_database() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        let journal = Journal::new(io.clone(), "test.db-journal");
+        let c = io::empty_completion();
+        journal.write(&[(1, vec![0; 512])], 1, c.clone()).unwrap();
+        io::wait_for_completion(&*io, &c).unwrap();
+        let page_source = PageSource::from_io(Rc::new(ReadOnlyPages));
+        let err = match Database::open_with(io, page_source, Some(journal), None) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert_eq!(
+            err.to_string(),
+            "database has a hot journal but is opened read-only"
+        );
+    }
+
+    #[test]
+    fn test_iterate_rows() {
+        let (_io, db) = open_fixture(
+            "iterate_rows",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, x TEXT);
+             INSERT INTO t VALUES (1, 'a'), (2, NULL);",
+        );
+        let conn = db.connect();
+        let rows = conn.query("SELECT id, x FROM t").unwrap().unwrap();
+        let rows = rows.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get::<i64>(0).unwrap(), 1);
+        assert_eq!(rows[0].get::<String>(1).unwrap(), "a");
+        assert_eq!(rows[1].get::<Option<String>>(1).unwrap(), None);
+        assert_eq!(
+            rows[1].values,
+            vec![OwnedValue::Integer(2), OwnedValue::Null]
+        );
     }
 }
@@ -1,17 +1,23 @@
 use crate::buffer_pool::BufferPool;
-use crate::sqlite3_ondisk::BTreePage;
-use crate::sqlite3_ondisk::{self, DatabaseHeader};
+use crate::io::{Buffer, Completion};
+use crate::journal::Journal;
+use crate::sqlite3_ondisk::PageContent;
+use crate::sqlite3_ondisk::{self, DatabaseHeader, TextEncoding};
+use crate::types::CursorResult;
+use crate::wal::Wal;
+use crate::LimboError;
 use crate::PageSource;
 use log::trace;
 use sieve_cache::SieveCache;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::RwLock;
 
 pub struct Page {
     flags: AtomicUsize,
-    pub contents: RwLock<Option<BTreePage>>,
+    pub contents: RwLock<Option<PageContent>>,
 }
 
 /// Page is up-to-date.
@@ -72,6 +78,17 @@ impl Page {
     }
 }
 
+/// Index of a page in the database file. The first page is 1.
+pub type PageIdx = usize;
+
+/// Starts reading a page from the page source and parsing it into the page
+/// contents.
+type BeginReadPage =
+    fn(&PageSource, Rc<BufferPool>, Rc<Page>, PageIdx, usize) -> anyhow::Result<()>;
+
+/// Number of pages the page cache holds unless configured otherwise.
+pub const DEFAULT_PAGE_CACHE_CAPACITY: usize = 10;
+
 /// The pager interface implements the persistence layer by providing access
 /// to pages of the database file, including caching, concurrency control, and
 /// transaction management.
@@ -79,6 +96,135 @@ pub struct Pager {
     page_source: PageSource,
     page_cache: RefCell<SieveCache<usize, Rc<Page>>>,
     buffer_pool: Rc<BufferPool>,
+    cache_hits: Cell<usize>,
+    cache_misses: Cell<usize>,
+    /// Pages read from the page source whose read has not been returned yet.
+    /// Finding them in the page cache when the read is restarted is not a
+    /// cache hit.
+    loading: RefCell<HashSet<PageIdx>>,
+    db_header: DatabaseHeader,
+    /// Pages modified in memory. They are kept out of the page cache so that
+    /// eviction cannot drop the changes.
+    dirty_pages: RefCell<HashMap<PageIdx, Rc<Page>>>,
+    /// The number of open read transactions.
+    readers: Cell<usize>,
+    /// Whether a write transaction is open. There can only be one at a time.
+    writer: Cell<bool>,
+    commit_state: RefCell<Option<CommitState>>,
+    /// The rollback journal that makes commits crash-safe, if any.
+    journal: Option<Journal>,
+    /// The savepoints of the write transaction, from the oldest to the most
+    /// recent.
+    savepoints: RefCell<Vec<Savepoint>>,
+    /// The WAL of a database in WAL mode, whose pages replace the pages of
+    /// the page source.
+    wal: Option<Wal>,
+}
+
+/// The kind of lock a transaction holds on the database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionMode {
+    Read,
+    Write,
+}
+
+/// The steps of a commit, in order. Each step starts some I/O, and the
+/// commit moves on to the next step once all of it has completed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CommitPhase {
+    /// The original contents of the dirty pages are read for the journal.
+    ReadOriginals,
+    WriteJournal,
+    SyncJournal,
+    WritePages,
+    SyncPages,
+    /// The journal header is zeroed, which is the point the commit is done.
+    ClearJournal,
+    SyncClearedJournal,
+}
+
+impl CommitPhase {
+    /// The step after this one, skipping the journal steps if there is no
+    /// journal. `None` means the commit is finished.
+    fn next(self, journal: bool) -> Option<CommitPhase> {
+        match self {
+            CommitPhase::ReadOriginals => Some(CommitPhase::WriteJournal),
+            CommitPhase::WriteJournal => Some(CommitPhase::SyncJournal),
+            CommitPhase::SyncJournal => Some(CommitPhase::WritePages),
+            CommitPhase::WritePages => Some(CommitPhase::SyncPages),
+            CommitPhase::SyncPages if journal => Some(CommitPhase::ClearJournal),
+            CommitPhase::SyncPages => None,
+            CommitPhase::ClearJournal => Some(CommitPhase::SyncClearedJournal),
+            CommitPhase::SyncClearedJournal => None,
+        }
+    }
+}
+
+/// A point in a write transaction that the dirty pages can be rolled back
+/// to.
+struct Savepoint {
+    name: String,
+    /// Copies of the pages that were dirty when the savepoint was created.
+    /// Pages that became dirty later still hold their contents in the page
+    /// source.
+    dirty_pages: HashMap<PageIdx, PageContent>,
+}
+
+/// The contents of a page as stored in the database file.
+type PageImage = (PageIdx, Vec<u8>);
+
+/// How far a commit has got in writing the dirty pages to the page source.
+struct CommitState {
+    phase: CommitPhase,
+    /// The number of I/O operations of the phase that have not completed.
+    pending: Rc<Cell<usize>>,
+    /// The first I/O operation of the phase that failed.
+    error: Rc<RefCell<Option<std::io::Error>>>,
+    /// The reads of the original contents of the dirty pages, for the
+    /// journal.
+    originals: RefCell<Vec<(PageIdx, Rc<Completion>)>>,
+}
+
+/// An open transaction, which holds its lock on the database until it is
+/// committed. Dropping a transaction rolls it back.
+pub struct Transaction {
+    pager: Rc<Pager>,
+    mode: TransactionMode,
+    finished: bool,
+}
+
+impl Transaction {
+    pub fn mode(&self) -> TransactionMode {
+        self.mode
+    }
+
+    /// Write the changes of a write transaction to the database and release
+    /// the lock. Returns `CursorResult::IO` while the writes are in flight;
+    /// call again after running the IO loop.
+    pub fn commit(&mut self) -> anyhow::Result<CursorResult<()>> {
+        match self.mode {
+            TransactionMode::Read => self.pager.end_read_transaction(),
+            TransactionMode::Write => {
+                if let CursorResult::IO = self.pager.commit()? {
+                    return Ok(CursorResult::IO);
+                }
+            }
+        }
+        self.finished = true;
+        Ok(CursorResult::Ok(()))
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        match self.mode {
+            TransactionMode::Read => self.pager.end_read_transaction(),
+            TransactionMode::Write => self.pager.rollback(),
+        }
+    }
 }
 
 impl Pager {
@@ -90,33 +236,876 @@ impl Pager {
         db_header: Rc<RefCell<DatabaseHeader>>,
         page_source: PageSource,
     ) -> anyhow::Result<Self> {
-        let db_header = db_header.borrow();
-        let page_size = db_header.page_size as usize;
+        Self::finish_open_with_cache_capacity(db_header, page_source, DEFAULT_PAGE_CACHE_CAPACITY)
+    }
+
+    /// Like `finish_open`, but with a page cache that holds up to
+    /// `cache_capacity` pages.
+    pub fn finish_open_with_cache_capacity(
+        db_header: Rc<RefCell<DatabaseHeader>>,
+        page_source: PageSource,
+        cache_capacity: usize,
+    ) -> anyhow::Result<Self> {
+        let db_header = db_header.borrow().clone();
+        db_header.validate()?;
+        let page_size = db_header.page_size();
         let buffer_pool = Rc::new(BufferPool::new(page_size));
-        let page_cache = RefCell::new(SieveCache::new(10).unwrap());
+        let page_cache = match SieveCache::new(cache_capacity) {
+            Ok(cache) => RefCell::new(cache),
+            Err(e) => anyhow::bail!("invalid page cache capacity {}: {}", cache_capacity, e),
+        };
         Ok(Self {
             page_source,
             buffer_pool,
             page_cache,
+            cache_hits: Cell::new(0),
+            cache_misses: Cell::new(0),
+            loading: RefCell::new(HashSet::new()),
+            db_header,
+            dirty_pages: RefCell::new(HashMap::new()),
+            readers: Cell::new(0),
+            writer: Cell::new(false),
+            commit_state: RefCell::new(None),
+            journal: None,
+            savepoints: RefCell::new(Vec::new()),
+            wal: None,
         })
     }
 
-    pub fn read_page(&self, page_idx: usize) -> anyhow::Result<Rc<Page>> {
+    /// Write the original pages to `journal` before each commit, so that an
+    /// interrupted commit can be rolled back.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    /// Read the pages that `wal` contains from it instead of the page
+    /// source. Databases with a WAL are read-only.
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    /// Returns the database header read from page 1.
+    pub fn header(&self) -> anyhow::Result<DatabaseHeader> {
+        self.db_header.validate()?;
+        Ok(self.db_header.clone())
+    }
+
+    /// Returns the encoding of text values in the database.
+    pub fn text_encoding(&self) -> TextEncoding {
+        self.db_header.text_encoding()
+    }
+
+    /// Marks a page as modified in memory.
+    pub fn add_dirty(&self, page_idx: PageIdx, page: Rc<Page>) {
+        self.dirty_pages.borrow_mut().insert(page_idx, page);
+    }
+
+    /// Start a transaction. Any number of read transactions can be open, but
+    /// a write transaction fails if another one is open.
+    pub fn begin_transaction(
+        self: &Rc<Self>,
+        mode: TransactionMode,
+    ) -> anyhow::Result<Transaction> {
+        match mode {
+            TransactionMode::Read => self.readers.set(self.readers.get() + 1),
+            TransactionMode::Write => {
+                if self.wal.is_some() {
+                    anyhow::bail!("attempt to write a readonly database");
+                }
+                if self.writer.get() {
+                    return Err(LimboError::Busy("database is locked".to_string()).into());
+                }
+                self.writer.set(true);
+            }
+        }
+        Ok(Transaction {
+            pager: self.clone(),
+            mode,
+            finished: false,
+        })
+    }
+
+    /// Returns whether a write transaction is open.
+    pub fn in_write_transaction(&self) -> bool {
+        self.writer.get()
+    }
+
+    fn end_read_transaction(&self) {
+        self.readers.set(self.readers.get() - 1);
+    }
+
+    /// Create a savepoint in the write transaction.
+    pub fn savepoint(&self, name: &str) {
+        trace!("savepoint {}", name);
+        let dirty_pages = self
+            .dirty_pages
+            .borrow()
+            .iter()
+            .map(|(page_idx, page)| {
+                let contents = page.contents.read().unwrap();
+                (*page_idx, contents.as_ref().unwrap().clone())
+            })
+            .collect();
+        self.savepoints.borrow_mut().push(Savepoint {
+            name: name.to_string(),
+            dirty_pages,
+        });
+    }
+
+    /// Remove the most recent savepoint called `name`, and every savepoint
+    /// created after it. Their changes stay in the transaction.
+    pub fn release_savepoint(&self, name: &str) -> anyhow::Result<()> {
+        let idx = self.find_savepoint(name)?;
+        self.savepoints.borrow_mut().truncate(idx);
+        Ok(())
+    }
+
+    /// Discard the changes made since the most recent savepoint called `name`
+    /// was created, and the savepoints created after it.
+    pub fn rollback_to_savepoint(&self, name: &str) -> anyhow::Result<()> {
+        let idx = self.find_savepoint(name)?;
+        let mut savepoints = self.savepoints.borrow_mut();
+        savepoints.truncate(idx + 1);
+        let savepoint = &savepoints[idx];
+        let mut page_cache = self.page_cache.borrow_mut();
+        self.dirty_pages.borrow_mut().retain(|page_idx, page| {
+            match savepoint.dirty_pages.get(page_idx) {
+                Some(contents) => {
+                    *page.contents.write().unwrap() = Some(contents.clone());
+                    true
+                }
+                None => {
+                    page_cache.remove(page_idx);
+                    false
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn find_savepoint(&self, name: &str) -> anyhow::Result<usize> {
+        self.savepoints
+            .borrow()
+            .iter()
+            .rposition(|savepoint| savepoint.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow::anyhow!("no such savepoint: {}", name))
+    }
+
+    /// Write the dirty pages to the page source and sync it, then end the
+    /// write transaction. With a journal, the original pages are written to
+    /// the journal and synced first.
+    fn commit(&self) -> anyhow::Result<CursorResult<()>> {
+        let mut commit_state = self.commit_state.borrow_mut();
+        if commit_state.is_none() {
+            if self.dirty_pages.borrow().is_empty() {
+                self.savepoints.borrow_mut().clear();
+                self.writer.set(false);
+                return Ok(CursorResult::Ok(()));
+            }
+            let phase = match self.journal {
+                Some(_) => CommitPhase::ReadOriginals,
+                None => CommitPhase::WritePages,
+            };
+            let state = CommitState {
+                phase,
+                pending: Rc::new(Cell::new(0)),
+                error: Rc::new(RefCell::new(None)),
+                originals: RefCell::new(Vec::new()),
+            };
+            self.start_commit_phase(&state)?;
+            *commit_state = Some(state);
+        }
+        loop {
+            let state = commit_state.as_mut().unwrap();
+            if state.pending.get() > 0 {
+                return Ok(CursorResult::IO);
+            }
+            if let Some(err) = state.error.borrow_mut().take() {
+                anyhow::bail!("I/O error during commit: {}", err);
+            }
+            match state.phase.next(self.journal.is_some()) {
+                Some(phase) => {
+                    state.phase = phase;
+                    self.start_commit_phase(state)?;
+                }
+                None => {
+                    *commit_state = None;
+                    self.dirty_pages.borrow_mut().clear();
+                    self.savepoints.borrow_mut().clear();
+                    self.writer.set(false);
+                    return Ok(CursorResult::Ok(()));
+                }
+            }
+        }
+    }
+
+    fn start_commit_phase(&self, state: &CommitState) -> anyhow::Result<()> {
+        trace!("commit: {:?}", state.phase);
+        let journal = || self.journal.as_ref().unwrap();
+        match state.phase {
+            CommitPhase::ReadOriginals => {
+                let page_size = self.db_header.page_size();
+                for page_idx in self.dirty_pages.borrow().keys() {
+                    let buf = Buffer::allocate(page_size, Rc::new(|_buf| {}));
+                    let c = pending_completion(state, buf);
+                    state.originals.borrow_mut().push((*page_idx, c.clone()));
+                    self.page_source.get(*page_idx, c)?;
+                }
+            }
+            CommitPhase::WriteJournal => {
+                let mut originals: Vec<PageImage> = state
+                    .originals
+                    .borrow()
+                    .iter()
+                    .map(|(page_idx, c)| (*page_idx, c.buf().as_slice().to_vec()))
+                    .collect();
+                originals.sort_by_key(|(page_idx, _)| *page_idx);
+                journal().write(
+                    &originals,
+                    self.db_header.database_size(),
+                    pending_completion(state, completion_buffer()),
+                )?;
+            }
+            CommitPhase::SyncJournal | CommitPhase::SyncClearedJournal => {
+                journal().sync(pending_completion(state, completion_buffer()))?;
+            }
+            CommitPhase::WritePages => {
+                for (page_idx, page) in self.dirty_pages.borrow().iter() {
+                    let contents = page.contents.read().unwrap();
+                    let buf = sqlite3_ondisk::write_page(
+                        *page_idx,
+                        contents.as_ref().unwrap(),
+                        &self.db_header,
+                    )?;
+                    self.page_source.put(
+                        *page_idx,
+                        Rc::new(RefCell::new(buf)),
+                        pending_completion(state, completion_buffer()),
+                    )?;
+                }
+            }
+            CommitPhase::SyncPages => {
+                self.page_source
+                    .sync(pending_completion(state, completion_buffer()))?;
+            }
+            CommitPhase::ClearJournal => {
+                journal().clear(pending_completion(state, completion_buffer()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard the dirty pages, so that they are read again from the page
+    /// source, and end the write transaction.
+    fn rollback(&self) {
+        let mut page_cache = self.page_cache.borrow_mut();
+        for (page_idx, _) in self.dirty_pages.borrow_mut().drain() {
+            page_cache.remove(&page_idx);
+        }
+        *self.commit_state.borrow_mut() = None;
+        self.savepoints.borrow_mut().clear();
+        self.writer.set(false);
+    }
+
+    /// Number of page reads served from the page cache.
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits.get()
+    }
+
+    /// Number of page reads that had to go to the page source.
+    pub fn cache_misses(&self) -> usize {
+        self.cache_misses.get()
+    }
+
+    /// Returns a shared handle to the page, reading it through the page cache.
+    /// Returns `CursorResult::IO` while the read is in flight; call again
+    /// after running the IO loop.
+    pub fn read_page(&self, page_idx: PageIdx) -> anyhow::Result<CursorResult<Rc<Page>>> {
         trace!("read_page(page_idx = {})", page_idx);
+        self.read_page_with(page_idx, sqlite3_ondisk::begin_read_btree_page)
+    }
+
+    /// Like `read_page`, but for a page of an overflow chain.
+    pub fn read_overflow_page(&self, page_idx: PageIdx) -> anyhow::Result<CursorResult<Rc<Page>>> {
+        trace!("read_overflow_page(page_idx = {})", page_idx);
+        self.read_page_with(page_idx, sqlite3_ondisk::begin_read_overflow_page)
+    }
+
+    fn read_page_with(
+        &self,
+        page_idx: PageIdx,
+        begin_read: BeginReadPage,
+    ) -> anyhow::Result<CursorResult<Rc<Page>>> {
+        let (page, cached) = self.get_or_load_page(page_idx, begin_read)?;
+        if page.is_error() {
+            self.page_cache.borrow_mut().remove(&page_idx);
+            self.loading.borrow_mut().remove(&page_idx);
+            anyhow::bail!("I/O error reading page {}", page_idx);
+        }
+        if page.is_locked() {
+            return Ok(CursorResult::IO);
+        }
+        let loaded = self.loading.borrow_mut().remove(&page_idx);
+        if cached && !loaded {
+            self.cache_hits.set(self.cache_hits.get() + 1);
+        }
+        Ok(CursorResult::Ok(page))
+    }
+
+    /// Returns the page and whether it was already in the page cache. Pages
+    /// that are not cached are read from the page source.
+    fn get_or_load_page(
+        &self,
+        page_idx: PageIdx,
+        begin_read: BeginReadPage,
+    ) -> anyhow::Result<(Rc<Page>, bool)> {
+        if let Some(page) = self.dirty_pages.borrow().get(&page_idx) {
+            return Ok((page.clone(), true));
+        }
         let mut page_cache = self.page_cache.borrow_mut();
         if let Some(page) = page_cache.get(&page_idx) {
-            return Ok(page.clone());
+            return Ok((page.clone(), true));
         }
+        self.cache_misses.set(self.cache_misses.get() + 1);
+        self.loading.borrow_mut().insert(page_idx);
         let page = Rc::new(Page::new());
         page.set_locked();
-        sqlite3_ondisk::begin_read_btree_page(
-            &self.page_source,
+        let page_source = match &self.wal {
+            Some(wal) if wal.contains(page_idx) => wal.page_source(),
+            _ => &self.page_source,
+        };
+        if let Err(err) = begin_read(
+            page_source,
             self.buffer_pool.clone(),
             page.clone(),
             page_idx,
+            self.db_header.usable_size(),
+        ) {
+            self.loading.borrow_mut().remove(&page_idx);
+            return Err(err);
+        }
+        page_cache.insert(page_idx, page.clone());
+        Ok((page, false))
+    }
+}
+
+/// A buffer for a completion whose data is passed separately, such as a
+/// write or a sync.
+fn completion_buffer() -> Buffer {
+    Buffer::allocate(0, Rc::new(|_buf| {}))
+}
+
+/// A completion for an I/O operation of the commit, which is counted as
+/// pending until it completes. If it fails, the commit fails.
+fn pending_completion(state: &CommitState, buf: Buffer) -> Rc<Completion> {
+    state.pending.set(state.pending.get() + 1);
+    let pending = state.pending.clone();
+    let error = state.error.clone();
+    let on_error = Box::new(move |err: &std::io::Error| {
+        error
+            .borrow_mut()
+            .get_or_insert_with(|| std::io::Error::new(err.kind(), err.to_string()));
+    });
+    Rc::new(
+        Completion::with_callback(buf, Box::new(move || pending.set(pending.get() - 1)))
+            .on_error(on_error),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_fixture, open_fixture};
+    use crate::{PlatformIO, IO};
+
+    fn create_database(name: &str) -> std::path::PathBuf {
+        create_fixture(
+            name,
+            "CREATE TABLE t (x TEXT);
+             WITH RECURSIVE c(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM c WHERE i < 200)
+             INSERT INTO t SELECT printf('%0100d', i) FROM c;",
+        )
+    }
+
+    fn open_pager(name: &str, cache_capacity: usize) -> (Rc<dyn IO>, Pager) {
+        let path = create_database(name);
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let page_source = PageSource::from_file(file);
+        let db_header = Pager::begin_open(&page_source).unwrap();
+        io.run_once().unwrap();
+        let pager =
+            Pager::finish_open_with_cache_capacity(db_header, page_source, cache_capacity).unwrap();
+        (io, pager)
+    }
+
+    fn read_page(io: &Rc<dyn IO>, pager: &Pager, page_idx: PageIdx) -> Rc<Page> {
+        loop {
+            match pager.read_page(page_idx).unwrap() {
+                CursorResult::Ok(page) => return page,
+                CursorResult::IO => io.run_once().unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_page_returns_shared_handle() {
+        let (io, pager) = open_pager("shared", 10);
+        assert!(matches!(pager.read_page(1).unwrap(), CursorResult::IO));
+        let page = read_page(&io, &pager, 1);
+        assert!(page.is_uptodate());
+        // Page 1 is the root of the sqlite_schema table.
+        let contents = page.contents.read().unwrap();
+        let contents = contents.as_ref().unwrap();
+        let btree = contents.as_btree().unwrap();
+        assert!(btree.header.right_most_pointer.is_none());
+        assert_eq!(btree.cells.len(), 1);
+        // Pages are kept parsed, so check the magic string at the start of
+        // page 1 as it is written back.
+        let header = pager.header().unwrap();
+        let buf = sqlite3_ondisk::write_page(1, contents, &header).unwrap();
+        assert_eq!(&buf[..16], b"SQLite format 3\0");
+    }
+
+    #[test]
+    fn test_second_read_served_from_cache() {
+        let (io, pager) = open_pager("hit", 10);
+        let first = read_page(&io, &pager, 1);
+        assert_eq!(pager.cache_misses(), 1);
+        // Restarting the read until it completes is not a cache hit.
+        assert_eq!(pager.cache_hits(), 0);
+        // The page is up-to-date, so the read completes without IO.
+        let second = match pager.read_page(1).unwrap() {
+            CursorResult::Ok(page) => page,
+            CursorResult::IO => panic!("cached page needs IO"),
+        };
+        assert_eq!(pager.cache_hits(), 1);
+        assert_eq!(pager.cache_misses(), 1);
+        assert!(Rc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn test_cache_capacity_evicts_pages() {
+        let (io, pager) = open_pager("evict", 1);
+        read_page(&io, &pager, 1);
+        read_page(&io, &pager, 2);
+        read_page(&io, &pager, 1);
+        assert_eq!(pager.cache_misses(), 3);
+    }
+
+    #[test]
+    fn test_header() {
+        let (_io, pager) = open_pager("header", 10);
+        let header = pager.header().unwrap();
+        assert_eq!(header.page_size(), 4096);
+        assert_eq!(header.text_encoding(), TextEncoding::Utf8);
+        assert_eq!(header.write_version(), 1);
+        assert_eq!(header.read_version(), 1);
+        assert!(header.database_size() > 1);
+    }
+
+    #[test]
+    fn test_header_page_size_65536() {
+        let mut header = DatabaseHeader::default();
+        header.page_size = 1;
+        assert_eq!(header.page_size(), 65536);
+        assert!(header.validate().is_ok());
+    }
+
+    #[test]
+    fn test_read_row_with_64k_pages() {
+        let (io, db) = open_fixture(
+            "64k",
+            "PRAGMA page_size = 65536;
+             CREATE TABLE t (x INTEGER, y TEXT);
+             INSERT INTO t VALUES (42, 'hello');",
+        );
+        assert_eq!(db.pager.header().unwrap().page_size(), 65536);
+        let conn = db.connect();
+        let mut rows = conn.query("SELECT * FROM t").unwrap().unwrap();
+        loop {
+            match rows.try_next().unwrap() {
+                crate::RowResult::Row(row) => {
+                    assert_eq!(row.get::<i64>(0).unwrap(), 42);
+                    assert_eq!(row.get::<String>(1).unwrap(), "hello");
+                    break;
+                }
+                crate::RowResult::IO => io.run_once().unwrap(),
+                crate::RowResult::Done => panic!("no rows"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_row_with_utf16le_encoding() {
+        let (io, db) = open_fixture(
+            "utf16",
+            "PRAGMA encoding = 'UTF-16le';
+             CREATE TABLE t (x TEXT);
+             INSERT INTO t VALUES ('h\u{e9}llo \u{1f600}');",
+        );
+        assert_eq!(db.pager.text_encoding(), TextEncoding::Utf16Le);
+        let conn = db.connect();
+        let mut rows = conn.query("SELECT * FROM t").unwrap().unwrap();
+        loop {
+            match rows.try_next().unwrap() {
+                crate::RowResult::Row(row) => {
+                    assert_eq!(row.get::<String>(0).unwrap(), "h\u{e9}llo \u{1f600}");
+                    break;
+                }
+                crate::RowResult::IO => io.run_once().unwrap(),
+                crate::RowResult::Done => panic!("no rows"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_rejects_bad_magic() {
+        let path =
+            std::env::temp_dir().join(format!("limbo-pager-bad-magic-{}.db", std::process::id()));
+        std::fs::write(&path, vec![0xab; 4096]).unwrap();
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let file = io.open_file(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let page_source = PageSource::from_file(file);
+        let db_header = Pager::begin_open(&page_source).unwrap();
+        io.run_once().unwrap();
+        let err = Pager::finish_open(db_header, page_source).err().unwrap();
+        assert_eq!(err.to_string(), "file is not a database");
+    }
+
+    #[test]
+    fn test_one_write_transaction_at_a_time() {
+        let (_io, pager) = open_pager("write_lock", DEFAULT_PAGE_CACHE_CAPACITY);
+        let pager = Rc::new(pager);
+        let read = pager.begin_transaction(TransactionMode::Read).unwrap();
+        let write = pager.begin_transaction(TransactionMode::Write).unwrap();
+        assert!(pager.in_write_transaction());
+        let err = match pager.begin_transaction(TransactionMode::Write) {
+            Err(err) => LimboError::from(err),
+            Ok(_) => panic!("expected the database to be locked"),
+        };
+        assert!(matches!(err, LimboError::Busy(message) if message == "database is locked"));
+        drop(write);
+        assert!(!pager.in_write_transaction());
+        assert!(pager.begin_transaction(TransactionMode::Write).is_ok());
+        drop(read);
+    }
+
+    #[test]
+    fn test_rollback_discards_dirty_pages() {
+        let (io, pager) = open_pager("rollback", DEFAULT_PAGE_CACHE_CAPACITY);
+        let pager = Rc::new(pager);
+        let transaction = pager.begin_transaction(TransactionMode::Write).unwrap();
+        let page = read_page(&io, &pager, 2);
+        page.contents
+            .write()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .as_btree_mut()
+            .unwrap()
+            .cells
+            .clear();
+        pager.add_dirty(2, page.clone());
+        drop(transaction);
+        let reread = read_page(&io, &pager, 2);
+        assert!(!Rc::ptr_eq(&page, &reread));
+        let contents = reread.contents.read().unwrap();
+        assert!(!contents
+            .as_ref()
+            .unwrap()
+            .as_btree()
+            .unwrap()
+            .cells
+            .is_empty());
+    }
+
+    /// A file whose writes fail after the first `writes` ones, as if the
+    /// process crashed while writing.
+    struct CrashingFile {
+        file: Box<dyn crate::File>,
+        writes: Cell<usize>,
+    }
+
+    impl crate::File for CrashingFile {
+        fn pread(&self, pos: usize, c: Rc<Completion>) -> anyhow::Result<()> {
+            self.file.pread(pos, c)
+        }
+
+        fn pwrite(
+            &self,
+            pos: usize,
+            buf: Rc<RefCell<Vec<u8>>>,
+            c: Rc<Completion>,
+        ) -> anyhow::Result<()> {
+            if self.writes.get() == 0 {
+                anyhow::bail!("crashed");
+            }
+            self.writes.set(self.writes.get() - 1);
+            self.file.pwrite(pos, buf, c)
+        }
+
+        fn sync(&self, c: Rc<Completion>) -> anyhow::Result<()> {
+            self.file.sync(c)
+        }
+
+        fn size(&self) -> anyhow::Result<usize> {
+            self.file.size()
+        }
+
+        fn is_read_only(&self) -> bool {
+            self.file.is_read_only()
+        }
+    }
+
+    fn file_contents(io: &Rc<dyn IO>, path: &str) -> Vec<u8> {
+        let file = io.open_file(path).unwrap();
+        let data = Rc::new(RefCell::new(Vec::new()));
+        let complete = Box::new({
+            let data = data.clone();
+            move |buf: &Buffer| {
+                data.replace(buf.as_slice().to_vec());
+            }
+        });
+        let buf = Buffer::allocate(file.size().unwrap(), Rc::new(|_buf| {}));
+        file.pread(0, Rc::new(Completion::new(buf, complete)))
+            .unwrap();
+        data.take()
+    }
+
+    #[test]
+    fn test_interrupted_commit_is_rolled_back() {
+        let path = create_database("journal");
+        let original = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let io: Rc<dyn IO> = Rc::new(crate::MemoryIO::new().unwrap());
+        let c = Rc::new(Completion::new(
+            completion_buffer(),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        io.open_file("test.db")
+            .unwrap()
+            .pwrite(0, Rc::new(RefCell::new(original.clone())), c)
+            .unwrap();
+        // Only the first of the two page writes reaches the database file.
+        let file = CrashingFile {
+            file: io.open_file("test.db").unwrap(),
+            writes: Cell::new(1),
+        };
+        let page_source = PageSource::from_file(Box::new(file));
+        let db_header = Pager::begin_open(&page_source).unwrap();
+        let mut pager = Pager::finish_open(db_header, page_source).unwrap();
+        pager.set_journal(Journal::new(io.clone(), "test.db-journal"));
+        let pager = Rc::new(pager);
+        let mut transaction = pager.begin_transaction(TransactionMode::Write).unwrap();
+        for page_idx in [2, 3] {
+            let page = read_page(&io, &pager, page_idx);
+            page.contents
+                .write()
+                .unwrap()
+                .as_mut()
+                .unwrap()
+                .as_btree_mut()
+                .unwrap()
+                .cells
+                .clear();
+            pager.add_dirty(page_idx, page);
+        }
+        let result = loop {
+            match transaction.commit() {
+                Ok(CursorResult::IO) => io.run_once().unwrap(),
+                result => break result,
+            }
+        };
+        assert!(result.is_err());
+        drop(transaction);
+        drop(pager);
+        assert_ne!(file_contents(&io, "test.db"), original);
+
+        // Opening the database again restores the original pages.
+        let page_source = PageSource::from_file(io.open_file("test.db").unwrap());
+        let journal = Journal::new(io.clone(), "test.db-journal");
+        assert_eq!(journal.recover(&page_source).unwrap(), 2);
+        assert_eq!(file_contents(&io, "test.db"), original);
+        assert_eq!(journal.recover(&page_source).unwrap(), 0);
+    }
+
+    /// A file whose writes fail when they complete, like on a failing disk.
+    struct FailingWritesFile {
+        file: Box<dyn crate::File>,
+    }
+
+    impl crate::File for FailingWritesFile {
+        fn pread(&self, pos: usize, c: Rc<Completion>) -> anyhow::Result<()> {
+            self.file.pread(pos, c)
+        }
+
+        fn pwrite(
+            &self,
+            _pos: usize,
+            _buf: Rc<RefCell<Vec<u8>>>,
+            c: Rc<Completion>,
+        ) -> anyhow::Result<()> {
+            c.set_result(Err(std::io::Error::other("disk failure")));
+            c.complete();
+            Ok(())
+        }
+
+        fn sync(&self, c: Rc<Completion>) -> anyhow::Result<()> {
+            self.file.sync(c)
+        }
+
+        fn size(&self) -> anyhow::Result<usize> {
+            self.file.size()
+        }
+
+        fn is_read_only(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_failed_write_fails_commit() {
+        let path = create_database("failed-write");
+        let original = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let io: Rc<dyn IO> = Rc::new(crate::MemoryIO::new().unwrap());
+        let c = Rc::new(Completion::new(
+            completion_buffer(),
+            Box::new(|_buf: &Buffer| {}),
+        ));
+        io.open_file("test.db")
+            .unwrap()
+            .pwrite(0, Rc::new(RefCell::new(original.clone())), c)
+            .unwrap();
+        let file = FailingWritesFile {
+            file: io.open_file("test.db").unwrap(),
+        };
+        let page_source = PageSource::from_file(Box::new(file));
+        let db_header = Pager::begin_open(&page_source).unwrap();
+        let mut pager = Pager::finish_open(db_header, page_source).unwrap();
+        pager.set_journal(Journal::new(io.clone(), "test.db-journal"));
+        let pager = Rc::new(pager);
+        let mut transaction = pager.begin_transaction(TransactionMode::Write).unwrap();
+        let page = read_page(&io, &pager, 2);
+        pager.add_dirty(2, page);
+        let err = loop {
+            match transaction.commit() {
+                Ok(CursorResult::IO) => io.run_once().unwrap(),
+                Ok(CursorResult::Ok(())) => panic!("expected an error"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.to_string(), "I/O error during commit: disk failure");
+        drop(transaction);
+        assert_eq!(file_contents(&io, "test.db"), original);
+    }
+
+    /// Pages whose reads fail when they complete.
+    struct FailingPageIO;
+
+    impl crate::PageIO for FailingPageIO {
+        fn get(&self, _page_idx: usize, c: Rc<crate::Completion>) -> anyhow::Result<()> {
+            c.set_result(Err(std::io::Error::other("disk failure")));
+            c.complete();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_read_is_an_error() {
+        let mut header = DatabaseHeader::default();
+        header.page_size = 4096;
+        let page_source = PageSource::from_io(Rc::new(FailingPageIO));
+        let pager = Pager::finish_open(Rc::new(RefCell::new(header)), page_source).unwrap();
+        // The page is not left locked, so reading it again fails again.
+        for _ in 0..2 {
+            let err = match pager.read_page(2) {
+                Err(err) => err,
+                Ok(_) => panic!("expected an error"),
+            };
+            assert_eq!(err.to_string(), "I/O error reading page 2");
+        }
+        assert_eq!(pager.cache_misses(), 2);
+    }
+
+    /// A database in WAL mode whose table `t` has the rows 'a' and 'b' in the
+    /// database file and the row 'c' only in the WAL.
+    fn create_wal_database() -> std::path::PathBuf {
+        let path = create_database("wal-source");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA wal_autocheckpoint = 0;
+             DROP TABLE t;
+             CREATE TABLE t (x TEXT);
+             INSERT INTO t VALUES ('a'), ('b');
+             PRAGMA wal_checkpoint(TRUNCATE);
+             INSERT INTO t VALUES ('c');",
         )
         .unwrap();
-        page_cache.insert(page_idx, page.clone());
-        Ok(page)
+        // SQLite checkpoints the WAL when the last connection closes, so copy
+        // the files while the connection is open.
+        let copy = create_fixture("wal", "");
+        std::fs::copy(&path, &copy).unwrap();
+        std::fs::copy(wal_path(&path), wal_path(&copy)).unwrap();
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+        copy
+    }
+
+    fn wal_path(path: &std::path::Path) -> String {
+        format!("{}-wal", path.display())
+    }
+
+    fn cell_count(io: &Rc<dyn IO>, pager: &Pager, page_idx: PageIdx) -> usize {
+        let page = read_page(io, pager, page_idx);
+        let contents = page.contents.read().unwrap();
+        contents.as_ref().unwrap().as_btree().unwrap().cells.len()
+    }
+
+    #[test]
+    fn test_read_page_from_wal() {
+        let path = create_wal_database();
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let open = |wal: Option<Wal>| {
+            let file = io.open_file(path.to_str().unwrap()).unwrap();
+            let page_source = PageSource::from_file(file);
+            let db_header = Pager::begin_open(&page_source).unwrap();
+            io.run_once().unwrap();
+            let mut pager = Pager::finish_open(db_header, page_source).unwrap();
+            if let Some(wal) = wal {
+                pager.set_wal(wal);
+            }
+            Rc::new(pager)
+        };
+        // The root page of `t` in the database file has only 'a' and 'b'.
+        assert_eq!(cell_count(&io, &open(None), 2), 2);
+        let wal = Wal::open(&*io, &wal_path(&path)).unwrap().unwrap();
+        let pager = open(Some(wal));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal_path(&path));
+        assert_eq!(cell_count(&io, &pager, 2), 3);
+        assert!(pager.begin_transaction(TransactionMode::Write).is_err());
+    }
+
+    struct NoPageIO;
+
+    impl crate::PageIO for NoPageIO {
+        fn get(&self, _page_idx: usize, _c: Rc<crate::Completion>) -> anyhow::Result<()> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_zero_cache_capacity_is_an_error() {
+        let mut header = DatabaseHeader::default();
+        header.page_size = 4096;
+        let page_source = PageSource::from_io(Rc::new(NoPageIO));
+        let pager =
+            Pager::finish_open_with_cache_capacity(Rc::new(RefCell::new(header)), page_source, 0);
+        assert!(pager.is_err());
     }
 }
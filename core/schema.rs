@@ -1,5 +1,5 @@
+use crate::types::Affinity;
 use anyhow::Result;
-use core::fmt;
 use fallible_iterator::FallibleIterator;
 use log::trace;
 use sqlite3_parser::{
@@ -10,13 +10,18 @@ use std::collections::HashMap;
 
 pub struct Schema {
     pub tables: HashMap<String, Table>,
+    /// The rows of the sqlite_schema table, by object name.
+    pub objects: HashMap<String, SchemaObject>,
 }
 
 impl Schema {
     pub fn new() -> Self {
         let mut tables: HashMap<String, Table> = HashMap::new();
         tables.insert("sqlite_schema".to_string(), sqlite_schema_table());
-        Self { tables }
+        Self {
+            tables,
+            objects: HashMap::new(),
+        }
     }
 
     pub fn add_table(&mut self, name: &str, table: Table) {
@@ -28,6 +33,59 @@ impl Schema {
         let name = normalize_ident(name);
         self.tables.get(&name)
     }
+
+    /// Add a row of sqlite_schema, and the table it defines if it is a table.
+    pub fn add_object(&mut self, object: SchemaObject) -> Result<()> {
+        if let (ObjectType::Table, Some(sql)) = (object.ty, &object.sql) {
+            let table = Table::from_sql(sql, object.root_page)?;
+            self.add_table(&table.name.to_owned(), table);
+        }
+        self.objects.insert(normalize_ident(&object.name), object);
+        Ok(())
+    }
+
+    #[allow(dead_code)] // used in tests
+    pub fn get_object(&self, name: &str) -> Option<&SchemaObject> {
+        self.objects.get(&normalize_ident(name))
+    }
+}
+
+/// The kind of object a row of sqlite_schema describes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectType {
+    Table,
+    Index,
+    View,
+    Trigger,
+}
+
+impl TryFrom<&str> for ObjectType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "table" => Ok(Self::Table),
+            "index" => Ok(Self::Index),
+            "view" => Ok(Self::View),
+            "trigger" => Ok(Self::Trigger),
+            _ => Err(anyhow::anyhow!("Invalid schema object type: {}", value)),
+        }
+    }
+}
+
+/// A row of the sqlite_schema table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaObject {
+    pub ty: ObjectType,
+    pub name: String,
+    /// The table the object belongs to. A table belongs to itself.
+    pub tbl_name: String,
+    /// The root page of the B-Tree of a table or an index, or 0 for views
+    /// and triggers.
+    pub root_page: usize,
+    /// The statement that created the object, which is NULL for the indexes
+    /// SQLite creates for UNIQUE and PRIMARY KEY constraints.
+    pub sql: Option<String>,
 }
 
 pub struct Table {
@@ -40,7 +98,7 @@ impl Table {
     pub fn get_column(&self, name: &str) -> Option<(usize, &Column)> {
         let name = normalize_ident(name);
         for (i, column) in self.columns.iter().enumerate() {
-            if column.name == name {
+            if normalize_ident(&column.name) == name {
                 return Some((i, column));
             }
         }
@@ -51,20 +109,10 @@ impl Table {
         let mut parser = Parser::new(sql.as_bytes());
         let cmd = parser.next()?;
         match cmd {
-            Some(cmd) => match cmd {
-                Cmd::Stmt(stmt) => match stmt {
-                    Stmt::CreateTable { tbl_name, body, .. } => {
-                        create_table(tbl_name, body, root_page)
-                    }
-                    _ => {
-                        anyhow::bail!("Expected CREATE TABLE statement");
-                    }
-                },
-                _ => {
-                    anyhow::bail!("Expected CREATE TABLE statement");
-                }
-            },
-            None => {
+            Some(Cmd::Stmt(Stmt::CreateTable { tbl_name, body, .. })) => {
+                create_table(tbl_name, body, root_page)
+            }
+            _ => {
                 anyhow::bail!("Expected CREATE TABLE statement");
             }
         }
@@ -79,8 +127,10 @@ impl Table {
             }
             sql.push_str("  ");
             sql.push_str(&column.name);
-            sql.push(' ');
-            sql.push_str(&column.ty.to_string());
+            if !column.decl_type.is_empty() {
+                sql.push(' ');
+                sql.push_str(&column.decl_type);
+            }
         }
         sql.push_str(");\n");
         sql
@@ -94,30 +144,12 @@ fn create_table(tbl_name: QualifiedName, body: CreateTableBody, root_page: usize
     match body {
         CreateTableBody::ColumnsAndConstraints { columns, .. } => {
             for column in columns {
-                let name = column.col_name.0.to_string();
-                let ty = match column.col_type {
-                    Some(data_type) => {
-                        let type_name = data_type.name.as_str();
-                        if type_name.contains("INT") {
-                            Type::Integer
-                        } else if type_name.contains("CHAR")
-                            || type_name.contains("CLOB")
-                            || type_name.contains("TEXT")
-                        {
-                            Type::Text
-                        } else if type_name.contains("BLOB") || type_name.is_empty() {
-                            Type::Blob
-                        } else if type_name.contains("REAL")
-                            || type_name.contains("FLOA")
-                            || type_name.contains("DOUB")
-                        {
-                            Type::Real
-                        } else {
-                            Type::Numeric
-                        }
-                    }
-                    None => Type::Null,
-                };
+                let name = unquote_ident(&column.col_name.0);
+                let decl_type = column
+                    .col_type
+                    .map(|data_type| data_type.name)
+                    .unwrap_or_default();
+                let affinity = Affinity::from_declared_type(&decl_type);
                 let primary_key = column.constraints.iter().any(|c| {
                     matches!(
                         c.constraint,
@@ -126,7 +158,8 @@ fn create_table(tbl_name: QualifiedName, body: CreateTableBody, root_page: usize
                 });
                 cols.push(Column {
                     name,
-                    ty,
+                    decl_type,
+                    affinity,
                     primary_key,
                 });
             }
@@ -141,42 +174,34 @@ fn create_table(tbl_name: QualifiedName, body: CreateTableBody, root_page: usize
 }
 
 fn normalize_ident(ident: &str) -> String {
-    if ident.starts_with('"') && ident.ends_with('"') {
-        ident[1..ident.len() - 1].to_string().to_lowercase()
-    } else {
-        ident.to_lowercase()
+    unquote_ident(ident).to_lowercase()
+}
+
+/// Remove the quotes SQL allows around an identifier: "x", [x], `x` and 'x'.
+fn unquote_ident(ident: &str) -> String {
+    let mut chars = ident.chars();
+    let (first, last) = (chars.next(), chars.next_back());
+    match (first, last) {
+        (Some('['), Some(']')) => chars.as_str().to_string(),
+        (Some(quote @ ('"' | '`' | '\'')), Some(last)) if last == quote => {
+            // A quote inside the identifier is written twice.
+            let quote = quote.to_string();
+            chars.as_str().replace(&quote.repeat(2), &quote)
+        }
+        _ => ident.to_string(),
     }
 }
 
 pub struct Column {
     pub name: String,
-    pub ty: Type,
+    /// The type name the column was declared with, without its size, or an
+    /// empty string if it has no type.
+    pub decl_type: String,
+    #[allow(dead_code)] // used in tests
+    pub affinity: Affinity,
     pub primary_key: bool,
 }
 
-pub enum Type {
-    Null,
-    Text,
-    Numeric,
-    Integer,
-    Real,
-    Blob,
-}
-
-impl fmt::Display for Type {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match self {
-            Type::Null => "NULL",
-            Type::Text => "TEXT",
-            Type::Numeric => "NUMERIC",
-            Type::Integer => "INTEGER",
-            Type::Real => "REAL",
-            Type::Blob => "BLOB",
-        };
-        write!(f, "{}", s)
-    }
-}
-
 pub fn sqlite_schema_table() -> Table {
     Table {
         root_page: 1,
@@ -184,27 +209,32 @@ pub fn sqlite_schema_table() -> Table {
         columns: vec![
             Column {
                 name: "type".to_string(),
-                ty: Type::Text,
+                decl_type: "TEXT".to_string(),
+                affinity: Affinity::Text,
                 primary_key: false,
             },
             Column {
                 name: "name".to_string(),
-                ty: Type::Text,
+                decl_type: "TEXT".to_string(),
+                affinity: Affinity::Text,
                 primary_key: false,
             },
             Column {
                 name: "tbl_name".to_string(),
-                ty: Type::Text,
+                decl_type: "TEXT".to_string(),
+                affinity: Affinity::Text,
                 primary_key: false,
             },
             Column {
                 name: "rootpage".to_string(),
-                ty: Type::Integer,
+                decl_type: "INTEGER".to_string(),
+                affinity: Affinity::Integer,
                 primary_key: false,
             },
             Column {
                 name: "sql".to_string(),
-                ty: Type::Text,
+                decl_type: "TEXT".to_string(),
+                affinity: Affinity::Text,
                 primary_key: false,
             },
         ],
@@ -214,6 +244,89 @@ pub fn sqlite_schema_table() -> Table {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::create_fixture;
+    use crate::{Database, PlatformIO, IO};
+    use rstest::rstest;
+    use std::rc::Rc;
+
+    fn columns(sql: &str) -> Vec<(String, String, Affinity, bool)> {
+        let table = Table::from_sql(sql, 2).unwrap();
+        table
+            .columns
+            .into_iter()
+            .map(|c| (c.name, c.decl_type, c.affinity, c.primary_key))
+            .collect()
+    }
+
+    fn column(name: &str, decl_type: &str, affinity: Affinity) -> (String, String, Affinity, bool) {
+        (name.to_string(), decl_type.to_string(), affinity, false)
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        let sql = "CREATE TABLE users (
+            id INTEGER PRIMARY KEY,
+            name VARCHAR(100) NOT NULL,
+            bio text,
+            avatar BLOB,
+            score DOUBLE PRECISION,
+            balance DECIMAL(10, 2),
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            misc
+        )";
+        let mut expected = vec![
+            column("id", "INTEGER", Affinity::Integer),
+            column("name", "VARCHAR", Affinity::Text),
+            column("bio", "text", Affinity::Text),
+            column("avatar", "BLOB", Affinity::Blob),
+            column("score", "DOUBLE PRECISION", Affinity::Real),
+            column("balance", "DECIMAL", Affinity::Numeric),
+            column("created_at", "DATETIME", Affinity::Numeric),
+            column("misc", "", Affinity::Blob),
+        ];
+        expected[0].3 = true;
+        assert_eq!(columns(sql), expected);
+    }
+
+    #[test]
+    fn test_parse_quoted_identifiers() {
+        let sql = r#"CREATE TABLE "Order Items" (
+            "Item ID" INT,
+            [unit price] REAL,
+            `note` TEXT,
+            "say ""hi""" CHARACTER(20)
+        )"#;
+        let table = Table::from_sql(sql, 2).unwrap();
+        assert_eq!(table.name, "order items");
+        assert_eq!(
+            columns(sql),
+            vec![
+                column("Item ID", "INT", Affinity::Integer),
+                column("unit price", "REAL", Affinity::Real),
+                column("note", "TEXT", Affinity::Text),
+                column("say \"hi\"", "CHARACTER", Affinity::Text),
+            ]
+        );
+        // Columns are looked up by name without regard to case or quotes.
+        assert_eq!(table.get_column("item id").unwrap().0, 0);
+        assert_eq!(table.get_column("[Unit Price]").unwrap().0, 1);
+    }
+
+    #[rstest]
+    #[case("INTEGER", Affinity::Integer)]
+    #[case("TINYINT", Affinity::Integer)]
+    #[case("UNSIGNED BIG INT", Affinity::Integer)]
+    #[case("NVARCHAR", Affinity::Text)]
+    #[case("CLOB", Affinity::Text)]
+    #[case("", Affinity::Blob)]
+    #[case("FLOAT", Affinity::Real)]
+    #[case("NUMERIC", Affinity::Numeric)]
+    #[case("BOOLEAN", Affinity::Numeric)]
+    // "POINT" contains "INT".
+    #[case("POINT", Affinity::Integer)]
+    fn test_affinity_from_declared_type(#[case] decl_type: &str, #[case] expected: Affinity) {
+        assert_eq!(Affinity::from_declared_type(decl_type), expected);
+    }
 
     #[test]
     pub fn test_sqlite_schema() {
@@ -227,4 +340,53 @@ mod tests {
         let actual = sqlite_schema_table().to_sql();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_schema_root_pages() {
+        let path = create_fixture(
+            "schema",
+            "CREATE TABLE a (id INTEGER PRIMARY KEY, x TEXT);
+             CREATE TABLE b (x TEXT UNIQUE, y INTEGER);
+             CREATE INDEX b_y ON b (y);
+             CREATE VIEW v AS SELECT x FROM a;",
+        );
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT type, name, tbl_name, rootpage, sql FROM sqlite_schema")
+            .unwrap();
+        let expected: Vec<(String, String, String, i64, Option<String>)> = stmt
+            .query_map((), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        drop(stmt);
+        drop(conn);
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        // The UNIQUE constraint adds an index without SQL.
+        assert_eq!(expected.len(), 5);
+        for (ty, name, tbl_name, root_page, sql) in expected {
+            let object = db.schema.get_object(&name).unwrap();
+            assert_eq!(object.ty, ObjectType::try_from(ty.as_str()).unwrap());
+            assert_eq!(object.tbl_name, tbl_name);
+            assert_eq!(object.root_page, root_page as usize);
+            assert_eq!(object.sql, sql);
+        }
+        assert_eq!(db.schema.get_table("a").unwrap().root_page, 2);
+        assert!(db
+            .schema
+            .get_object("sqlite_autoindex_b_1")
+            .unwrap()
+            .sql
+            .is_none());
+    }
 }
@@ -0,0 +1,171 @@
+//! Encoding of the primitive values used in the database file format.
+
+use anyhow::{anyhow, Result};
+
+/// Decode a big-endian base-128 varint, returning the value and the number
+/// of bytes it took. The high bit of each of the first eight bytes says
+/// whether another byte follows; a ninth byte contributes all of its 8 bits.
+pub fn read_varint(buf: &[u8]) -> Result<(u64, usize)> {
+    let mut v: u64 = 0;
+    for i in 0..8 {
+        match buf.get(i) {
+            Some(c) => {
+                v = (v << 7) + (c & 0x7f) as u64;
+                if (c & 0x80) == 0 {
+                    return Ok((v, i + 1));
+                }
+            }
+            None => {
+                return Err(anyhow!("Invalid varint"));
+            }
+        }
+    }
+    match buf.get(8) {
+        Some(c) => Ok(((v << 8) + *c as u64, 9)),
+        None => Err(anyhow!("Invalid varint")),
+    }
+}
+
+/// Encode a value as a varint, appending it to `out` and returning the
+/// number of bytes written. Values that need more than 56 bits use the
+/// 9-byte form.
+pub fn write_varint(value: u64, out: &mut Vec<u8>) -> usize {
+    if value > 0x00ffffffffffffff {
+        // The 9-byte form stores the low 8 bits in full in the last byte.
+        let mut bytes = [0u8; 9];
+        bytes[8] = value as u8;
+        let mut v = value >> 8;
+        for byte in bytes[..8].iter_mut().rev() {
+            *byte = (v & 0x7f) as u8 | 0x80;
+            v >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return 9;
+    }
+    let mut bytes = [0u8; 9];
+    let mut n = 0;
+    let mut v = value;
+    loop {
+        bytes[n] = (v & 0x7f) as u8 | 0x80;
+        n += 1;
+        v >>= 7;
+        if v == 0 {
+            break;
+        }
+    }
+    bytes[0] &= 0x7f;
+    bytes[..n].reverse();
+    out.extend_from_slice(&bytes[..n]);
+    n
+}
+
+/// Returns the number of bytes the varint encoding of the value takes.
+pub fn varint_len(value: u64) -> usize {
+    write_varint(value, &mut Vec::with_capacity(9))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(&[0x00], (0, 1))]
+    #[case(&[0x01], (1, 1))]
+    #[case(&[0x7f], (127, 1))]
+    #[case(&[0x81, 0x00], (128, 2))]
+    #[case(&[0x81, 0x01], (129, 2))]
+    #[case(&[0xff, 0x7f], (16383, 2))]
+    #[case(&[0x81, 0x81, 0x01], (16513, 3))]
+    #[case(&[0x81, 0x81, 0x81, 0x01], (2113665, 4))]
+    #[case(&[0x81, 0x81, 0x81, 0x81, 0x01], (270549121, 5))]
+    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (34630287489, 6))]
+    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (4432676798593, 7))]
+    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (567382630219905, 8))]
+    #[case(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f], (0x00ffffffffffffff, 8))]
+    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (145249953336295681, 9))]
+    #[case(&[0x80, 0xc0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00], (0x0100000000000000, 9))]
+    #[case(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], (u64::MAX, 9))]
+    #[case(&[0x01, 0xff], (1, 1))]
+    fn test_read_varint(#[case] input: &[u8], #[case] expected: (u64, usize)) {
+        let result = read_varint(input).unwrap();
+        assert_eq!(result, expected);
+    }
+
+    #[rstest]
+    #[case(0, &[0x00])]
+    #[case(127, &[0x7f])]
+    #[case(128, &[0x81, 0x00])]
+    #[case(16383, &[0xff, 0x7f])]
+    #[case(16384, &[0x81, 0x80, 0x00])]
+    #[case(0x00ffffffffffffff, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f])]
+    #[case(0x0100000000000000, &[0x80, 0xc0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00])]
+    #[case(u64::MAX, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])]
+    fn test_write_varint(#[case] value: u64, #[case] expected: &[u8]) {
+        let mut out = vec![0xaa];
+        assert_eq!(write_varint(value, &mut out), expected.len());
+        assert_eq!(&out[1..], expected);
+        assert_eq!(varint_len(value), expected.len());
+    }
+
+    /// Values at both ends of each encoded length, and their neighbours.
+    fn boundary_values() -> Vec<u64> {
+        let mut values = vec![0, u64::MAX, u64::MAX - 1];
+        for bits in (7..=56).step_by(7) {
+            let limit = 1u64 << bits;
+            values.extend([limit - 2, limit - 1, limit, limit + 1]);
+        }
+        values
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        let mut values = boundary_values();
+        // A spread of values across the whole range.
+        let mut x: u64 = 0x9e3779b97f4a7c15;
+        for _ in 0..10000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            values.push(x >> (x % 64));
+        }
+        for value in values {
+            let mut out = Vec::new();
+            let written = write_varint(value, &mut out);
+            assert_eq!(written, out.len());
+            assert_eq!(
+                read_varint(&out).unwrap(),
+                (value, written),
+                "value {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_varint_lengths() {
+        for value in boundary_values() {
+            let expected = match 64 - value.leading_zeros() {
+                0..=7 => 1,
+                8..=14 => 2,
+                15..=21 => 3,
+                22..=28 => 4,
+                29..=35 => 5,
+                36..=42 => 6,
+                43..=49 => 7,
+                50..=56 => 8,
+                _ => 9,
+            };
+            assert_eq!(varint_len(value), expected, "value {}", value);
+        }
+    }
+
+    #[rstest]
+    #[case(&[])]
+    #[case(&[0b11111110])]
+    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81])]
+    fn test_read_invalid_varint(#[case] input: &[u8]) {
+        let result = read_varint(input);
+        assert!(result.is_err());
+    }
+}
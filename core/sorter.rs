@@ -0,0 +1,226 @@
+use crate::sqlite3_ondisk::TextEncoding;
+use crate::types::{Collation, Cursor, CursorResult, OwnedRecord, OwnedValue};
+
+use anyhow::Result;
+
+use std::cell::{Ref, RefCell};
+use std::cmp::Ordering;
+
+/// The direction in which a sort key is ordered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// A column of the records in a sorter to order them by.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortKey {
+    pub column: usize,
+    pub order: SortOrder,
+    pub collation: Collation,
+}
+
+/// An in-memory sorter for the rows of an ORDER BY without an index.
+///
+/// Records are inserted in any order and `rewind` sorts them by the sort
+/// keys, comparing the first key and then the following ones for rows that
+/// are equal. Rows with equal keys keep the order they were inserted in.
+pub struct Sorter {
+    keys: Vec<SortKey>,
+    /// The records inserted since the last sort.
+    records: Vec<OwnedRecord>,
+    /// The sorted records after the current one.
+    sorted: std::vec::IntoIter<OwnedRecord>,
+    record: RefCell<Option<OwnedRecord>>,
+    /// Sorted rows have no rowid, so this is always `None`.
+    rowid: RefCell<Option<u64>>,
+}
+
+impl Sorter {
+    pub fn new(keys: Vec<SortKey>) -> Self {
+        Self {
+            keys,
+            records: Vec::new(),
+            sorted: Vec::new().into_iter(),
+            record: RefCell::new(None),
+            rowid: RefCell::new(None),
+        }
+    }
+
+    fn compare(&self, lhs: &OwnedRecord, rhs: &OwnedRecord) -> Ordering {
+        for key in &self.keys {
+            let l = lhs.values.get(key.column).unwrap_or(&OwnedValue::Null);
+            let r = rhs.values.get(key.column).unwrap_or(&OwnedValue::Null);
+            let ordering = match key.order {
+                SortOrder::Asc => l.compare_with(r, key.collation),
+                SortOrder::Desc => r.compare_with(l, key.collation),
+            };
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl Cursor for Sorter {
+    fn is_empty(&self) -> bool {
+        self.record.borrow().is_none()
+    }
+
+    /// Sort the inserted records and move to the first one.
+    fn rewind(&mut self) -> Result<CursorResult<()>> {
+        let mut records = std::mem::take(&mut self.records);
+        records.sort_by(|l, r| self.compare(l, r));
+        self.sorted = records.into_iter();
+        self.record.replace(self.sorted.next());
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn next(&mut self) -> Result<CursorResult<()>> {
+        self.record.replace(self.sorted.next());
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn last(&mut self) -> Result<CursorResult<()>> {
+        anyhow::bail!("sorter cursors can only be read forwards")
+    }
+
+    fn prev(&mut self) -> Result<CursorResult<()>> {
+        anyhow::bail!("sorter cursors can only be read forwards")
+    }
+
+    fn seek_rowid(&mut self, _rowid: u64) -> Result<CursorResult<bool>> {
+        anyhow::bail!("sorter cursors do not support seeking")
+    }
+
+    fn seek_ge(&mut self, _key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        anyhow::bail!("sorter cursors do not support seeking")
+    }
+
+    fn seek_gt(&mut self, _key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        anyhow::bail!("sorter cursors do not support seeking")
+    }
+
+    fn wait_for_completion(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn rowid(&self) -> Result<Ref<'_, Option<u64>>> {
+        Ok(self.rowid.borrow())
+    }
+
+    fn record(&self) -> Result<Ref<'_, Option<OwnedRecord>>> {
+        Ok(self.record.borrow())
+    }
+
+    fn current_record(&mut self) -> Option<&OwnedRecord> {
+        self.record.get_mut().as_ref()
+    }
+
+    /// Add a record to the sorter. The key is ignored because sorted rows
+    /// have no rowid. Records made by the program always hold UTF-8 text,
+    /// whatever the encoding of the database.
+    fn insert(&mut self, _key: u64, payload: &[u8]) -> Result<CursorResult<()>> {
+        self.records
+            .push(OwnedRecord::parse(payload, TextEncoding::Utf8)?);
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn delete(&mut self) -> Result<CursorResult<()>> {
+        anyhow::bail!("sorter cursors do not support deleting rows")
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn key(column: usize, order: SortOrder) -> SortKey {
+        SortKey {
+            column,
+            order,
+            collation: Collation::Binary,
+        }
+    }
+
+    fn row(num: i64, text: &str) -> OwnedRecord {
+        OwnedRecord::new(vec![
+            OwnedValue::Integer(num),
+            OwnedValue::Text(text.into()),
+        ])
+    }
+
+    fn sort(keys: Vec<SortKey>, rows: Vec<OwnedRecord>) -> Vec<(i64, String)> {
+        let mut sorter = Sorter::new(keys);
+        for row in rows {
+            assert!(matches!(
+                sorter
+                    .insert(0, &row.serialize(TextEncoding::Utf8))
+                    .unwrap(),
+                CursorResult::Ok(())
+            ));
+        }
+        let mut sorted = Vec::new();
+        sorter.rewind().unwrap();
+        while !sorter.is_empty() {
+            let record = sorter.record().unwrap();
+            let values = &record.as_ref().unwrap().values;
+            match (&values[0], &values[1]) {
+                (OwnedValue::Integer(num), OwnedValue::Text(text)) => {
+                    sorted.push((*num, text.to_string()))
+                }
+                _ => panic!("unexpected record {:?}", values),
+            }
+            drop(record);
+            sorter.next().unwrap();
+        }
+        sorted
+    }
+
+    fn pairs(rows: &[(i64, &str)]) -> Vec<(i64, String)> {
+        rows.iter().map(|(n, t)| (*n, t.to_string())).collect()
+    }
+
+    #[rstest]
+    #[case::asc(vec![key(0, SortOrder::Asc)], &[(1, "c"), (2, "a"), (2, "b"), (3, "a")])]
+    #[case::desc(vec![key(0, SortOrder::Desc)], &[(3, "a"), (2, "a"), (2, "b"), (1, "c")])]
+    #[case::second_column(vec![key(1, SortOrder::Asc)], &[(2, "a"), (3, "a"), (2, "b"), (1, "c")])]
+    #[case::two_columns(
+        vec![key(1, SortOrder::Desc), key(0, SortOrder::Asc)],
+        &[(1, "c"), (2, "b"), (2, "a"), (3, "a")]
+    )]
+    #[case::missing_column(vec![key(5, SortOrder::Asc)], &[(2, "a"), (3, "a"), (1, "c"), (2, "b")])]
+    fn test_sort(#[case] keys: Vec<SortKey>, #[case] expected: &[(i64, &str)]) {
+        let rows = vec![row(2, "a"), row(3, "a"), row(1, "c"), row(2, "b")];
+        assert_eq!(sort(keys, rows), pairs(expected));
+    }
+
+    #[test]
+    fn test_sort_with_collation() {
+        let keys = vec![SortKey {
+            column: 1,
+            order: SortOrder::Asc,
+            collation: Collation::NoCase,
+        }];
+        let rows = vec![row(1, "b"), row(2, "B"), row(3, "A"), row(4, "a")];
+        assert_eq!(
+            sort(keys, rows),
+            pairs(&[(3, "A"), (4, "a"), (1, "b"), (2, "B")])
+        );
+    }
+
+    #[test]
+    fn test_sort_empty() {
+        let mut sorter = Sorter::new(vec![key(0, SortOrder::Asc)]);
+        sorter.rewind().unwrap();
+        assert!(sorter.is_empty());
+        assert!(sorter.rowid().unwrap().is_none());
+    }
+}
@@ -26,6 +26,7 @@
 use crate::buffer_pool::BufferPool;
 use crate::io::{Buffer, Completion};
 use crate::pager::Page;
+use crate::serialization::{read_varint, varint_len, write_varint};
 use crate::types::{OwnedRecord, OwnedValue};
 use crate::PageSource;
 use anyhow::{anyhow, Result};
@@ -36,7 +37,10 @@ use std::rc::Rc;
 /// The size of the database header in bytes.
 pub const DATABASE_HEADER_SIZE: usize = 100;
 
-#[derive(Debug, Default)]
+/// The magic string every database file starts with.
+pub const DATABASE_HEADER_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+#[derive(Debug, Clone)]
 pub struct DatabaseHeader {
     magic: [u8; 16],
     pub page_size: u16,
@@ -63,6 +67,87 @@ pub struct DatabaseHeader {
     version_number: u32,
 }
 
+impl Default for DatabaseHeader {
+    /// The header of a new, empty database.
+    fn default() -> Self {
+        Self {
+            magic: *DATABASE_HEADER_MAGIC,
+            page_size: 4096,
+            write_version: 1,
+            read_version: 1,
+            unused_space: 0,
+            max_embed_frac: 64,
+            min_embed_frac: 32,
+            min_leaf_frac: 32,
+            change_counter: 0,
+            database_size: 1,
+            freelist_trunk_page: 0,
+            freelist_pages: 0,
+            schema_cookie: 0,
+            schema_format: 4,
+            default_cache_size: 0,
+            vacuum: 0,
+            text_encoding: 1,
+            user_version: 0,
+            incremental_vacuum: 0,
+            application_id: 0,
+            reserved: [0; 20],
+            version_valid_for: 0,
+            version_number: 0,
+        }
+    }
+}
+
+impl DatabaseHeader {
+    /// Checks that the header belongs to an SQLite database file.
+    pub fn validate(&self) -> Result<()> {
+        if &self.magic != DATABASE_HEADER_MAGIC {
+            return Err(anyhow!("file is not a database"));
+        }
+        let page_size = self.page_size();
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            return Err(anyhow!("invalid page size: {}", page_size));
+        }
+        TextEncoding::try_from(self.text_encoding)?;
+        Ok(())
+    }
+
+    /// The size of a database page in bytes. The header stores a page size
+    /// of 65536 as 1 because it does not fit in two bytes.
+    pub fn page_size(&self) -> usize {
+        match self.page_size {
+            1 => 65536,
+            page_size => page_size as usize,
+        }
+    }
+
+    /// The encoding of text values, which is checked by `validate()`.
+    pub fn text_encoding(&self) -> TextEncoding {
+        TextEncoding::try_from(self.text_encoding).expect("invalid text encoding")
+    }
+
+    /// The file format version number for writing.
+    pub fn write_version(&self) -> u8 {
+        self.write_version
+    }
+
+    /// The file format version number for reading.
+    pub fn read_version(&self) -> u8 {
+        self.read_version
+    }
+
+    /// The number of usable bytes in each page, which excludes the space
+    /// reserved at the end of every page for extensions.
+    pub fn usable_size(&self) -> usize {
+        self.page_size() - self.unused_space as usize
+    }
+
+    /// The size of the database file in pages.
+    pub fn database_size(&self) -> u32 {
+        self.database_size
+    }
+}
+
 pub fn begin_read_database_header(page_source: &PageSource) -> Result<Rc<RefCell<DatabaseHeader>>> {
     let drop_fn = Rc::new(|_buf| {});
     let buf = Buffer::allocate(512, drop_fn);
@@ -106,7 +191,35 @@ fn finish_read_database_header(buf: &Buffer, header: Rc<RefCell<DatabaseHeader>>
     Ok(())
 }
 
-#[derive(Debug)]
+/// Encode the database header into the first `DATABASE_HEADER_SIZE` bytes of
+/// `buf`.
+fn write_database_header(header: &DatabaseHeader, buf: &mut [u8]) {
+    buf[0..16].copy_from_slice(&header.magic);
+    buf[16..18].copy_from_slice(&header.page_size.to_be_bytes());
+    buf[18] = header.write_version;
+    buf[19] = header.read_version;
+    buf[20] = header.unused_space;
+    buf[21] = header.max_embed_frac;
+    buf[22] = header.min_embed_frac;
+    buf[23] = header.min_leaf_frac;
+    buf[24..28].copy_from_slice(&header.change_counter.to_be_bytes());
+    buf[28..32].copy_from_slice(&header.database_size.to_be_bytes());
+    buf[32..36].copy_from_slice(&header.freelist_trunk_page.to_be_bytes());
+    buf[36..40].copy_from_slice(&header.freelist_pages.to_be_bytes());
+    buf[40..44].copy_from_slice(&header.schema_cookie.to_be_bytes());
+    buf[44..48].copy_from_slice(&header.schema_format.to_be_bytes());
+    buf[48..52].copy_from_slice(&header.default_cache_size.to_be_bytes());
+    buf[52..56].copy_from_slice(&header.vacuum.to_be_bytes());
+    buf[56..60].copy_from_slice(&header.text_encoding.to_be_bytes());
+    buf[60..64].copy_from_slice(&header.user_version.to_be_bytes());
+    buf[64..68].copy_from_slice(&header.incremental_vacuum.to_be_bytes());
+    buf[68..72].copy_from_slice(&header.application_id.to_be_bytes());
+    buf[72..92].copy_from_slice(&header.reserved);
+    buf[92..96].copy_from_slice(&header.version_valid_for.to_be_bytes());
+    buf[96..100].copy_from_slice(&header.version_number.to_be_bytes());
+}
+
+#[derive(Clone, Debug)]
 pub struct BTreePageHeader {
     page_type: PageType,
     _first_freeblock_offset: u16,
@@ -116,8 +229,14 @@ pub struct BTreePageHeader {
     pub(crate) right_most_pointer: Option<u32>,
 }
 
+impl BTreePageHeader {
+    pub fn page_type(&self) -> PageType {
+        self.page_type
+    }
+}
+
 #[repr(u8)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PageType {
     IndexInterior = 2,
     TableInterior = 5,
@@ -139,17 +258,79 @@ impl TryFrom<u8> for PageType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BTreePage {
     pub header: BTreePageHeader,
     pub cells: Vec<BTreeCell>,
 }
 
+impl BTreePage {
+    /// The bytes left for more cells and their cell pointers when the page
+    /// is written to `usable_size` bytes by `write_page`.
+    pub fn free_space(&self, page_idx: usize, usable_size: usize) -> usize {
+        let header_pos = if page_idx == 1 {
+            DATABASE_HEADER_SIZE
+        } else {
+            0
+        };
+        let header_size = match self.header.right_most_pointer {
+            Some(_) => 12,
+            None => 8,
+        };
+        let used: usize = self
+            .cells
+            .iter()
+            .map(|cell| 2 + cell_size(cell, usable_size))
+            .sum();
+        usable_size.saturating_sub(header_pos + header_size + used)
+    }
+}
+
+/// The contents of a page, parsed according to how it is used.
+#[derive(Clone, Debug)]
+pub enum PageContent {
+    BTree(BTreePage),
+    Overflow(OverflowPage),
+}
+
+impl PageContent {
+    pub fn as_btree(&self) -> Result<&BTreePage> {
+        match self {
+            PageContent::BTree(page) => Ok(page),
+            PageContent::Overflow(_) => Err(anyhow!("expected a B-Tree page")),
+        }
+    }
+
+    pub fn as_btree_mut(&mut self) -> Result<&mut BTreePage> {
+        match self {
+            PageContent::BTree(page) => Ok(page),
+            PageContent::Overflow(_) => Err(anyhow!("expected a B-Tree page")),
+        }
+    }
+
+    pub fn as_overflow(&self) -> Result<&OverflowPage> {
+        match self {
+            PageContent::Overflow(page) => Ok(page),
+            PageContent::BTree(_) => Err(anyhow!("expected an overflow page")),
+        }
+    }
+}
+
+/// A page in the chain that holds the part of a payload that does not fit in
+/// its cell.
+#[derive(Clone, Debug)]
+pub struct OverflowPage {
+    /// The next page of the chain, or `None` if this is the last one.
+    pub next_page: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
 pub fn begin_read_btree_page(
     page_source: &PageSource,
     buffer_pool: Rc<BufferPool>,
     page: Rc<Page>,
     page_idx: usize,
+    usable_size: usize,
 ) -> Result<()> {
     trace!("begin_read_btree_page(page_idx = {})", page_idx);
     let buf = buffer_pool.get();
@@ -158,18 +339,27 @@ pub fn begin_read_btree_page(
         buffer_pool.put(buf);
     });
     let buf = Buffer::new(buf, drop_fn);
-    let complete = Box::new(move |buf: &Buffer| {
+    let complete = Box::new({
         let page = page.clone();
-        if let Err(_) = finish_read_btree_page(page_idx, buf, page.clone()) {
-            page.set_error();
+        move |buf: &Buffer| {
+            let page = page.clone();
+            if finish_read_btree_page(page_idx, buf, page.clone(), usable_size).is_err() {
+                page.set_error();
+            }
         }
     });
-    let c = Rc::new(Completion::new(buf, complete));
+    let on_error = Box::new(move |_err: &std::io::Error| page.set_error());
+    let c = Rc::new(Completion::new(buf, complete).on_error(on_error));
     page_source.get(page_idx, c.clone())?;
     Ok(())
 }
 
-fn finish_read_btree_page(page_idx: usize, buf: &Buffer, page: Rc<Page>) -> Result<()> {
+fn finish_read_btree_page(
+    page_idx: usize,
+    buf: &Buffer,
+    page: Rc<Page>,
+    usable_size: usize,
+) -> Result<()> {
     trace!("finish_read_btree_page(page_idx = {})", page_idx);
     let mut pos = if page_idx == 1 {
         DATABASE_HEADER_SIZE
@@ -199,37 +389,209 @@ fn finish_read_btree_page(page_idx: usize, buf: &Buffer, page: Rc<Page>) -> Resu
     for _ in 0..header.num_cells {
         let cell_pointer = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
         pos += 2;
-        let cell = read_btree_cell(buf, &header.page_type, cell_pointer as usize)?;
+        let cell = read_btree_cell(
+            &buf[..usable_size],
+            &header.page_type,
+            cell_pointer as usize,
+        )?;
         cells.push(cell);
     }
     let inner = BTreePage { header, cells };
-    page.contents.write().unwrap().replace(inner);
+    page.contents
+        .write()
+        .unwrap()
+        .replace(PageContent::BTree(inner));
     page.set_uptodate();
     page.clear_locked();
     Ok(())
 }
 
-#[derive(Debug)]
+pub fn begin_read_overflow_page(
+    page_source: &PageSource,
+    buffer_pool: Rc<BufferPool>,
+    page: Rc<Page>,
+    page_idx: usize,
+    usable_size: usize,
+) -> Result<()> {
+    trace!("begin_read_overflow_page(page_idx = {})", page_idx);
+    let buf = buffer_pool.get();
+    let drop_fn = Rc::new(move |buf| {
+        let buffer_pool = buffer_pool.clone();
+        buffer_pool.put(buf);
+    });
+    let buf = Buffer::new(buf, drop_fn);
+    let complete = Box::new({
+        let page = page.clone();
+        move |buf: &Buffer| {
+            let buf = &buf.as_slice()[..usable_size];
+            let next_page = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let inner = OverflowPage {
+                next_page: (next_page != 0).then_some(next_page),
+                payload: buf[4..].to_vec(),
+            };
+            page.contents
+                .write()
+                .unwrap()
+                .replace(PageContent::Overflow(inner));
+            page.set_uptodate();
+            page.clear_locked();
+        }
+    });
+    let on_error = Box::new(move |_err: &std::io::Error| page.set_error());
+    let c = Rc::new(Completion::new(buf, complete).on_error(on_error));
+    page_source.get(page_idx, c)?;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum BTreeCell {
     TableInteriorCell(TableInteriorCell),
     TableLeafCell(TableLeafCell),
+    IndexInteriorCell(IndexInteriorCell),
+    IndexLeafCell(IndexLeafCell),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TableInteriorCell {
     pub _left_child_page: u32,
     pub _rowid: u64,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TableLeafCell {
     pub _rowid: u64,
+    /// The part of the payload that is stored in the cell.
     pub _payload: Vec<u8>,
+    /// The size of the whole payload, including the part that spilled to
+    /// overflow pages.
+    pub payload_size: usize,
+    pub first_overflow_page: Option<u32>,
+}
+
+/// An index interior cell holds a key, which is ordered after every key in
+/// its left subtree.
+#[derive(Clone, Debug)]
+pub struct IndexInteriorCell {
+    pub _left_child_page: u32,
+    /// The part of the key record that is stored in the cell.
+    pub _payload: Vec<u8>,
+    pub payload_size: usize,
+    pub first_overflow_page: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexLeafCell {
+    /// The part of the key record that is stored in the cell.
+    pub _payload: Vec<u8>,
+    pub payload_size: usize,
+    pub first_overflow_page: Option<u32>,
+}
+
+/// Returns the number of payload bytes that are stored in the cell itself
+/// when the payload does not fit in `max_local` bytes. The rest is stored in
+/// overflow pages.
+pub(crate) fn payload_local_size(
+    payload_size: usize,
+    max_local: usize,
+    usable_size: usize,
+) -> usize {
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    let local = min_local + (payload_size - min_local) % (usable_size - 4);
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
+/// Reads the cell at `pos`. The page must not include the space reserved at
+/// the end of it, because its size determines how much of a payload is
+/// stored in the cell.
+/// Reads a payload of `payload_size` bytes that starts at `pos` and returns
+/// its local part and the first page of its overflow chain.
+fn read_payload(
+    page: &[u8],
+    pos: usize,
+    payload_size: usize,
+    max_local: usize,
+) -> Result<(Vec<u8>, Option<u32>)> {
+    let usable_size = page.len();
+    let local_size = payload_local_size(payload_size, max_local, usable_size);
+    if pos + local_size > page.len() {
+        return Err(anyhow!("Invalid cell payload size: {}", payload_size));
+    }
+    let payload = page[pos..pos + local_size].to_vec();
+    let pos = pos + local_size;
+    if local_size == payload_size {
+        return Ok((payload, None));
+    }
+    if pos + 4 > page.len() {
+        return Err(anyhow!("Invalid cell overflow page"));
+    }
+    let first_overflow_page =
+        u32::from_be_bytes([page[pos], page[pos + 1], page[pos + 2], page[pos + 3]]);
+    Ok((payload, Some(first_overflow_page)))
+}
+
+/// The most payload bytes a table leaf cell stores in the page.
+pub(crate) fn table_max_local(usable_size: usize) -> usize {
+    usable_size - 35
+}
+
+/// The most payload bytes an index cell stores in the page.
+fn index_max_local(usable_size: usize) -> usize {
+    (usable_size - 12) * 64 / 255 - 23
+}
+
+/// The number of bytes a cell takes in a page of `usable_size` bytes, not
+/// counting its cell pointer.
+pub(crate) fn cell_size(cell: &BTreeCell, usable_size: usize) -> usize {
+    let (header_size, payload_size, max_local) = match cell {
+        BTreeCell::TableInteriorCell(cell) => return 4 + varint_len(cell._rowid),
+        BTreeCell::TableLeafCell(cell) => (
+            varint_len(cell.payload_size as u64) + varint_len(cell._rowid),
+            cell.payload_size,
+            table_max_local(usable_size),
+        ),
+        BTreeCell::IndexInteriorCell(cell) => (
+            4 + varint_len(cell.payload_size as u64),
+            cell.payload_size,
+            index_max_local(usable_size),
+        ),
+        BTreeCell::IndexLeafCell(cell) => (
+            varint_len(cell.payload_size as u64),
+            cell.payload_size,
+            index_max_local(usable_size),
+        ),
+    };
+    let local_size = payload_local_size(payload_size, max_local, usable_size);
+    let overflow_pointer_size = if local_size < payload_size { 4 } else { 0 };
+    header_size + local_size + overflow_pointer_size
 }
 
 pub fn read_btree_cell(page: &[u8], page_type: &PageType, pos: usize) -> Result<BTreeCell> {
     match page_type {
-        PageType::IndexInterior => todo!(),
+        PageType::IndexInterior => {
+            let mut pos = pos;
+            let left_child_page =
+                u32::from_be_bytes([page[pos], page[pos + 1], page[pos + 2], page[pos + 3]]);
+            pos += 4;
+            let (payload_size, nr) = read_varint(&page[pos..])?;
+            pos += nr;
+            let payload_size = payload_size as usize;
+            let (payload, first_overflow_page) =
+                read_payload(page, pos, payload_size, index_max_local(page.len()))?;
+            Ok(BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                _left_child_page: left_child_page,
+                _payload: payload,
+                payload_size,
+                first_overflow_page,
+            }))
+        }
         PageType::TableInterior => {
             let mut pos = pos;
             let left_child_page =
@@ -241,23 +603,175 @@ pub fn read_btree_cell(page: &[u8], page_type: &PageType, pos: usize) -> Result<
                 _rowid: rowid,
             }))
         }
-        PageType::IndexLeaf => todo!(),
+        PageType::IndexLeaf => {
+            let (payload_size, nr) = read_varint(&page[pos..])?;
+            let payload_size = payload_size as usize;
+            let (payload, first_overflow_page) =
+                read_payload(page, pos + nr, payload_size, index_max_local(page.len()))?;
+            Ok(BTreeCell::IndexLeafCell(IndexLeafCell {
+                _payload: payload,
+                payload_size,
+                first_overflow_page,
+            }))
+        }
         PageType::TableLeaf => {
             let mut pos = pos;
             let (payload_size, nr) = read_varint(&page[pos..])?;
             pos += nr;
             let (rowid, nr) = read_varint(&page[pos..])?;
             pos += nr;
-            let payload = &page[pos..pos + payload_size as usize];
-            // FIXME: page overflows if the payload is too large
+            let payload_size = payload_size as usize;
+            let (payload, first_overflow_page) =
+                read_payload(page, pos, payload_size, table_max_local(page.len()))?;
             Ok(BTreeCell::TableLeafCell(TableLeafCell {
                 _rowid: rowid,
-                _payload: payload.to_vec(),
+                _payload: payload,
+                payload_size,
+                first_overflow_page,
             }))
         }
     }
 }
 
+/// Encode the contents of a page. Page 1 starts with the database header.
+/// B-Tree pages are written defragmented, with the cells packed at the end
+/// of the usable space.
+pub fn write_page(
+    page_idx: usize,
+    contents: &PageContent,
+    db_header: &DatabaseHeader,
+) -> Result<Vec<u8>> {
+    let usable_size = db_header.usable_size();
+    let mut buf = vec![0; db_header.page_size()];
+    match contents {
+        PageContent::BTree(page) => {
+            if page_idx == 1 {
+                write_database_header(db_header, &mut buf);
+            }
+            write_btree_page(page_idx, page, &mut buf[..usable_size])?;
+        }
+        PageContent::Overflow(page) => {
+            buf[0..4].copy_from_slice(&page.next_page.unwrap_or(0).to_be_bytes());
+            let len = page.payload.len().min(usable_size - 4);
+            buf[4..4 + len].copy_from_slice(&page.payload[..len]);
+        }
+    }
+    Ok(buf)
+}
+
+fn write_btree_page(page_idx: usize, page: &BTreePage, buf: &mut [u8]) -> Result<()> {
+    let usable_size = buf.len();
+    let header_pos = if page_idx == 1 {
+        DATABASE_HEADER_SIZE
+    } else {
+        0
+    };
+    let page_type = page.header.page_type;
+    let header_size = match page.header.right_most_pointer {
+        Some(_) => 12,
+        None => 8,
+    };
+    let mut pointer_pos = header_pos + header_size;
+    let mut cell_content_area = usable_size;
+    let mut cell = Vec::new();
+    for btree_cell in &page.cells {
+        cell.clear();
+        write_btree_cell(btree_cell, usable_size, &mut cell)?;
+        if pointer_pos + 2 + cell.len() > cell_content_area {
+            return Err(anyhow!("page {} is full", page_idx));
+        }
+        cell_content_area -= cell.len();
+        buf[cell_content_area..cell_content_area + cell.len()].copy_from_slice(&cell);
+        buf[pointer_pos..pointer_pos + 2]
+            .copy_from_slice(&(cell_content_area as u16).to_be_bytes());
+        pointer_pos += 2;
+    }
+    let header = &mut buf[header_pos..header_pos + header_size];
+    header[0] = page_type as u8;
+    header[3..5].copy_from_slice(&(page.cells.len() as u16).to_be_bytes());
+    // A cell content area that starts at 65536 is stored as zero.
+    header[5..7].copy_from_slice(&(cell_content_area as u16).to_be_bytes());
+    if let Some(right_most_pointer) = page.header.right_most_pointer {
+        header[8..12].copy_from_slice(&right_most_pointer.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Encode a cell. The local part of its payload must be as long as
+/// `read_btree_cell` expects it to be in a page of `usable_size` bytes.
+fn write_btree_cell(cell: &BTreeCell, usable_size: usize, buf: &mut Vec<u8>) -> Result<()> {
+    let (payload, payload_size, first_overflow_page, max_local) = match cell {
+        BTreeCell::TableInteriorCell(cell) => {
+            buf.extend_from_slice(&cell._left_child_page.to_be_bytes());
+            write_varint(cell._rowid, buf);
+            return Ok(());
+        }
+        BTreeCell::TableLeafCell(cell) => {
+            write_varint(cell.payload_size as u64, buf);
+            write_varint(cell._rowid, buf);
+            (
+                &cell._payload,
+                cell.payload_size,
+                cell.first_overflow_page,
+                table_max_local(usable_size),
+            )
+        }
+        BTreeCell::IndexInteriorCell(cell) => {
+            buf.extend_from_slice(&cell._left_child_page.to_be_bytes());
+            write_varint(cell.payload_size as u64, buf);
+            (
+                &cell._payload,
+                cell.payload_size,
+                cell.first_overflow_page,
+                index_max_local(usable_size),
+            )
+        }
+        BTreeCell::IndexLeafCell(cell) => {
+            write_varint(cell.payload_size as u64, buf);
+            (
+                &cell._payload,
+                cell.payload_size,
+                cell.first_overflow_page,
+                index_max_local(usable_size),
+            )
+        }
+    };
+    if payload.len() != payload_local_size(payload_size, max_local, usable_size)
+        || first_overflow_page.is_some() != (payload.len() < payload_size)
+    {
+        return Err(anyhow!(
+            "payload of {} bytes does not fit in the cell",
+            payload_size
+        ));
+    }
+    buf.extend_from_slice(payload);
+    if let Some(first_overflow_page) = first_overflow_page {
+        buf.extend_from_slice(&first_overflow_page.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// The encoding of text values in the database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl TryFrom<u32> for TextEncoding {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Utf8),
+            2 => Ok(Self::Utf16Le),
+            3 => Ok(Self::Utf16Be),
+            _ => Err(anyhow!("Invalid text encoding: {}", value)),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SerialType {
     Null,
@@ -289,14 +803,16 @@ impl TryFrom<u64> for SerialType {
             7 => Ok(Self::BEFloat64),
             8 => Ok(Self::ConstInt0),
             9 => Ok(Self::ConstInt1),
-            n if value > 12 && value % 2 == 0 => Ok(Self::Blob(((n - 12) / 2) as usize)),
-            n if value > 13 && value % 2 == 1 => Ok(Self::String(((n - 13) / 2) as usize)),
+            n if value >= 12 && value.is_multiple_of(2) => Ok(Self::Blob(((n - 12) / 2) as usize)),
+            n if value >= 13 && !value.is_multiple_of(2) => {
+                Ok(Self::String(((n - 13) / 2) as usize))
+            }
             _ => Err(anyhow!("Invalid serial type: {}", value)),
         }
     }
 }
 
-pub fn read_record(payload: &[u8]) -> Result<OwnedRecord> {
+pub fn read_record(payload: &[u8], encoding: TextEncoding) -> Result<OwnedRecord> {
     let mut pos = 0;
     let (header_size, nr) = read_varint(payload)?;
     assert!((header_size as usize) >= nr);
@@ -307,28 +823,119 @@ pub fn read_record(payload: &[u8]) -> Result<OwnedRecord> {
         let (serial_type, nr) = read_varint(&payload[pos..])?;
         let serial_type = SerialType::try_from(serial_type)?;
         serial_types.push(serial_type);
-        assert!(pos + nr < payload.len());
+        assert!(pos + nr <= payload.len());
         pos += nr;
         assert!(header_size >= nr);
         header_size -= nr;
     }
     let mut values = Vec::with_capacity(serial_types.len());
     for serial_type in &serial_types {
-        let (value, usize) = read_value(&payload[pos..], serial_type)?;
+        let (value, usize) = read_value(&payload[pos..], serial_type, encoding)?;
         pos += usize;
         values.push(value);
     }
     Ok(OwnedRecord::new(values))
 }
 
-pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, usize)> {
+/// Encode a record in the record format: a header with the header size and
+/// the serial type of each value, followed by the values, with text in the
+/// given encoding.
+pub fn write_record(record: &OwnedRecord, encoding: TextEncoding, buf: &mut Vec<u8>) {
+    let mut serial_types = Vec::new();
+    for value in &record.values {
+        write_varint(serial_type(value, encoding), &mut serial_types);
+    }
+    // The header size includes the varint that encodes it.
+    let mut header_size = serial_types.len() + 1;
+    while header_size < serial_types.len() + varint_len(header_size as u64) {
+        header_size += 1;
+    }
+    write_varint(header_size as u64, buf);
+    buf.extend_from_slice(&serial_types);
+    for value in &record.values {
+        write_value(value, encoding, buf);
+    }
+}
+
+/// Returns the serial type that stores the value in the fewest bytes.
+fn serial_type(value: &OwnedValue, encoding: TextEncoding) -> u64 {
+    match value {
+        OwnedValue::Null => 0,
+        OwnedValue::Integer(0) => 8,
+        OwnedValue::Integer(1) => 9,
+        OwnedValue::Integer(i) => match i {
+            -128..=127 => 1,
+            -32768..=32767 => 2,
+            -8388608..=8388607 => 3,
+            -2147483648..=2147483647 => 4,
+            -140737488355328..=140737488355327 => 5,
+            _ => 6,
+        },
+        OwnedValue::Float(_) => 7,
+        OwnedValue::Text(t) => {
+            let len = match encoding {
+                TextEncoding::Utf8 => t.len(),
+                TextEncoding::Utf16Le | TextEncoding::Utf16Be => t.encode_utf16().count() * 2,
+            };
+            (len * 2 + 13) as u64
+        }
+        OwnedValue::Blob(b) => (b.len() * 2 + 12) as u64,
+    }
+}
+
+fn write_value(value: &OwnedValue, encoding: TextEncoding, buf: &mut Vec<u8>) {
+    match value {
+        OwnedValue::Null => {}
+        OwnedValue::Integer(i) => {
+            let len = match serial_type(value, encoding) {
+                8 | 9 => 0,
+                5 => 6,
+                6 => 8,
+                n => n as usize,
+            };
+            buf.extend_from_slice(&i.to_be_bytes()[8 - len..]);
+        }
+        OwnedValue::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+        OwnedValue::Text(t) => match encoding {
+            TextEncoding::Utf8 => buf.extend_from_slice(t.as_bytes()),
+            TextEncoding::Utf16Le => buf.extend(t.encode_utf16().flat_map(u16::to_le_bytes)),
+            TextEncoding::Utf16Be => buf.extend(t.encode_utf16().flat_map(u16::to_be_bytes)),
+        },
+        OwnedValue::Blob(b) => buf.extend_from_slice(b),
+    }
+}
+
+fn decode_text(bytes: &[u8], encoding: TextEncoding) -> Result<String> {
+    match encoding {
+        TextEncoding::Utf8 => Ok(unsafe { String::from_utf8_unchecked(bytes.to_vec()) }),
+        TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+            if !bytes.len().is_multiple_of(2) {
+                return Err(anyhow!("Invalid UTF-16 value"));
+            }
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|unit| match encoding {
+                    TextEncoding::Utf16Le => u16::from_le_bytes([unit[0], unit[1]]),
+                    _ => u16::from_be_bytes([unit[0], unit[1]]),
+                })
+                .collect();
+            String::from_utf16(&units).map_err(|_| anyhow!("Invalid UTF-16 value"))
+        }
+    }
+}
+
+pub fn read_value(
+    buf: &[u8],
+    serial_type: &SerialType,
+    encoding: TextEncoding,
+) -> Result<(OwnedValue, usize)> {
     match *serial_type {
         SerialType::Null => Ok((OwnedValue::Null, 0)),
         SerialType::UInt8 => {
             if buf.is_empty() {
                 return Err(anyhow!("Invalid UInt8 value"));
             }
-            Ok((OwnedValue::Integer(buf[0] as i64), 1))
+            Ok((OwnedValue::Integer(buf[0] as i8 as i64), 1))
         }
         SerialType::BEInt16 => {
             if buf.len() < 2 {
@@ -343,8 +950,9 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
             if buf.len() < 3 {
                 return Err(anyhow!("Invalid BEInt24 value"));
             }
+            // Shift the value into the top bytes to sign-extend it.
             Ok((
-                OwnedValue::Integer(i32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as i64),
+                OwnedValue::Integer((i32::from_be_bytes([buf[0], buf[1], buf[2], 0]) >> 8) as i64),
                 3,
             ))
         }
@@ -362,9 +970,10 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
                 return Err(anyhow!("Invalid BEInt48 value"));
             }
             Ok((
-                OwnedValue::Integer(i64::from_be_bytes([
-                    0, 0, buf[0], buf[1], buf[2], buf[3], buf[4], buf[5],
-                ])),
+                OwnedValue::Integer(
+                    i64::from_be_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], 0, 0])
+                        >> 16,
+                ),
                 6,
             ))
         }
@@ -402,36 +1011,17 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
             if buf.len() < n {
                 return Err(anyhow!("Invalid String value"));
             }
-            let bytes = buf[0..n].to_vec();
-            let value = unsafe { String::from_utf8_unchecked(bytes) };
+            let value = decode_text(&buf[0..n], encoding)?;
             Ok((OwnedValue::Text(value.into()), n))
         }
     }
 }
 
-fn read_varint(buf: &[u8]) -> Result<(u64, usize)> {
-    let mut v: u64 = 0;
-    for i in 0..8 {
-        match buf.get(i) {
-            Some(c) => {
-                v = (v << 7) + (c & 0x7f) as u64;
-                if (c & 0x80) == 0 {
-                    return Ok((v, i + 1));
-                }
-            }
-            None => {
-                return Err(anyhow!("Invalid varint"));
-            }
-        }
-    }
-    v = (v << 8) + buf[8] as u64;
-    Ok((v, 9))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use rstest::rstest;
+    use std::pin::Pin;
 
     #[rstest]
     #[case(0, SerialType::Null)]
@@ -444,6 +1034,8 @@ mod tests {
     #[case(7, SerialType::BEFloat64)]
     #[case(8, SerialType::ConstInt0)]
     #[case(9, SerialType::ConstInt1)]
+    #[case(12, SerialType::Blob(0))]
+    #[case(13, SerialType::String(0))]
     #[case(14, SerialType::Blob(1))]
     #[case(15, SerialType::String(1))]
     fn test_read_serial_type(#[case] input: u64, #[case] expected: SerialType) {
@@ -459,13 +1051,16 @@ mod tests {
 
     #[rstest]
     #[case(&[], SerialType::Null, OwnedValue::Null)]
-    #[case(&[255], SerialType::UInt8, OwnedValue::Integer(255))]
+    #[case(&[127], SerialType::UInt8, OwnedValue::Integer(127))]
+    #[case(&[255], SerialType::UInt8, OwnedValue::Integer(-1))]
+    #[case(&[0xff, 0xff, 0xfe], SerialType::BEInt24, OwnedValue::Integer(-2))]
+    #[case(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xfe], SerialType::BEInt48, OwnedValue::Integer(-2))]
     #[case(&[0x12, 0x34], SerialType::BEInt16, OwnedValue::Integer(0x1234))]
     #[case(&[0x12, 0x34, 0x56], SerialType::BEInt24, OwnedValue::Integer(0x123456))]
     #[case(&[0x12, 0x34, 0x56, 0x78], SerialType::BEInt32, OwnedValue::Integer(0x12345678))]
     #[case(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC], SerialType::BEInt48, OwnedValue::Integer(0x123456789ABC))]
     #[case(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xFF], SerialType::BEInt64, OwnedValue::Integer(0x123456789ABCDEFF))]
-    #[case(&[64, 9, 33, 251, 84, 68, 45, 24], SerialType::BEFloat64, OwnedValue::Float(3.141592653589793))]
+    #[case(&[64, 9, 33, 251, 84, 68, 45, 24], SerialType::BEFloat64, OwnedValue::Float(std::f64::consts::PI))]
     #[case(&[], SerialType::ConstInt0, OwnedValue::Integer(0))]
     #[case(&[], SerialType::ConstInt1, OwnedValue::Integer(1))]
     #[case(&[1, 2, 3], SerialType::Blob(3), OwnedValue::Blob(vec![1, 2, 3].into()))]
@@ -475,7 +1070,7 @@ mod tests {
         #[case] serial_type: SerialType,
         #[case] expected: OwnedValue,
     ) {
-        let result = read_value(buf, &serial_type).unwrap();
+        let result = read_value(buf, &serial_type, TextEncoding::Utf8).unwrap();
         assert_eq!(result, (expected, buf.len()));
     }
 
@@ -491,29 +1086,211 @@ mod tests {
     #[case(&[65, 66], SerialType::String(3))]
     // TODO: UTF-8 validation is disabled #[case(&[192], SerialType::String(1))] // invalid UTF-8 sequence
     fn test_read_invalid_value(#[case] buf: &[u8], #[case] serial_type: SerialType) {
-        let result = read_value(buf, &serial_type);
+        let result = read_value(buf, &serial_type, TextEncoding::Utf8);
         assert!(result.is_err());
     }
 
     #[rstest]
-    #[case(&[0x01], (1, 1))]
-    #[case(&[0x81, 0x01], (129, 2))]
-    #[case(&[0x81, 0x81, 0x01], (16513, 3))]
-    #[case(&[0x81, 0x81, 0x81, 0x01], (2113665, 4))]
-    #[case(&[0x81, 0x81, 0x81, 0x81, 0x01], (270549121, 5))]
-    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (34630287489, 6))]
-    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (4432676798593, 7))]
-    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (567382630219905, 8))]
-    #[case(&[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01], (145249953336295681, 9))]
-    fn read_varint_test(#[case] input: &[u8], #[case] expected: (u64, usize)) {
-        let result = read_varint(input).unwrap();
-        assert_eq!(result, expected);
+    #[case(vec![OwnedValue::Integer(42), OwnedValue::Text("hello".to_string().into())], &[3, 1, 23, 42, b'h', b'e', b'l', b'l', b'o'])]
+    #[case(vec![OwnedValue::Null, OwnedValue::Integer(0), OwnedValue::Integer(1)], &[4, 0, 8, 9])]
+    #[case(vec![OwnedValue::Integer(-2), OwnedValue::Integer(1000)], &[3, 1, 2, 0xfe, 0x03, 0xe8])]
+    #[case(vec![OwnedValue::Integer(1 << 40)], &[2, 5, 0x01, 0, 0, 0, 0, 0])]
+    #[case(vec![OwnedValue::Float(0.5), OwnedValue::Blob(vec![0xab].into())], &[3, 7, 14, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0, 0xab])]
+    fn test_write_record(#[case] values: Vec<OwnedValue>, #[case] expected: &[u8]) {
+        let mut buf = Vec::new();
+        write_record(
+            &OwnedRecord::new(values.clone()),
+            TextEncoding::Utf8,
+            &mut buf,
+        );
+        assert_eq!(buf, expected);
+        assert_eq!(
+            OwnedRecord::parse(&buf, TextEncoding::Utf8).unwrap().values,
+            values
+        );
+    }
+
+    #[rstest]
+    #[case::null(&[2, 0], vec![OwnedValue::Null])]
+    #[case::int8(&[2, 1, 0x80], vec![OwnedValue::Integer(-128)])]
+    #[case::int16(&[2, 2, 0x01, 0x00], vec![OwnedValue::Integer(256)])]
+    #[case::int24(&[2, 3, 0x01, 0x00, 0x00], vec![OwnedValue::Integer(65536)])]
+    #[case::int32(&[2, 4, 0x01, 0x00, 0x00, 0x00], vec![OwnedValue::Integer(1 << 24)])]
+    #[case::int48(&[2, 5, 0x01, 0, 0, 0, 0, 0], vec![OwnedValue::Integer(1 << 40)])]
+    #[case::int64(&[2, 6, 0x01, 0, 0, 0, 0, 0, 0, 0], vec![OwnedValue::Integer(1 << 56)])]
+    #[case::float(&[2, 7, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0], vec![OwnedValue::Float(1.0)])]
+    #[case::const_int0(&[2, 8], vec![OwnedValue::Integer(0)])]
+    #[case::const_int1(&[2, 9], vec![OwnedValue::Integer(1)])]
+    #[case::blob(&[2, 16, 1, 2], vec![OwnedValue::Blob(vec![1, 2].into())])]
+    #[case::empty_blob(&[2, 12], vec![OwnedValue::Blob(vec![].into())])]
+    #[case::text(&[2, 17, b'h', b'i'], vec![OwnedValue::Text("hi".to_string().into())])]
+    #[case::empty_text(&[2, 13], vec![OwnedValue::Text("".to_string().into())])]
+    #[case::mixed(&[4, 13, 8, 12], vec![OwnedValue::Text("".to_string().into()), OwnedValue::Integer(0), OwnedValue::Blob(vec![].into())])]
+    fn test_parse_record(#[case] payload: &[u8], #[case] expected: Vec<OwnedValue>) {
+        let record = OwnedRecord::parse(payload, TextEncoding::Utf8).unwrap();
+        assert_eq!(record.values, expected);
+    }
+
+    #[rstest]
+    #[case::int24_minus_one(&[2, 3, 0xff, 0xff, 0xff], -1)]
+    #[case::int24_min(&[2, 3, 0x80, 0x00, 0x00], -(1 << 23))]
+    #[case::int24_max(&[2, 3, 0x7f, 0xff, 0xff], (1 << 23) - 1)]
+    #[case::int48_minus_one(&[2, 5, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], -1)]
+    #[case::int48_min(&[2, 5, 0x80, 0, 0, 0, 0, 0], -(1 << 47))]
+    #[case::int48_max(&[2, 5, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff], (1 << 47) - 1)]
+    #[case::int64_minus_one(&[2, 6, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], -1)]
+    #[case::int64_min(&[2, 6, 0x80, 0, 0, 0, 0, 0, 0, 0], i64::MIN)]
+    #[case::int64_max(&[2, 6, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], i64::MAX)]
+    fn test_parse_record_sign_extends(#[case] payload: &[u8], #[case] expected: i64) {
+        let record = OwnedRecord::parse(payload, TextEncoding::Utf8).unwrap();
+        assert_eq!(record.values, vec![OwnedValue::Integer(expected)]);
+    }
+
+    #[rstest]
+    #[case::utf8(TextEncoding::Utf8, &[2, 21, b'h', 0xc3, 0xa9, b'y'], "h\u{e9}y")]
+    #[case::utf16le(TextEncoding::Utf16Le, &[2, 25, b'h', 0, 0xe9, 0, b'y', 0], "h\u{e9}y")]
+    #[case::utf16be(TextEncoding::Utf16Be, &[2, 25, 0, b'h', 0, 0xe9, 0, b'y'], "h\u{e9}y")]
+    #[case::utf16le_surrogate_pair(TextEncoding::Utf16Le, &[2, 21, 0x3d, 0xd8, 0x00, 0xde], "\u{1f600}")]
+    fn test_record_text_encoding(
+        #[case] encoding: TextEncoding,
+        #[case] payload: &[u8],
+        #[case] expected: &str,
+    ) {
+        let record = OwnedRecord::parse(payload, encoding).unwrap();
+        assert_eq!(
+            record.values,
+            vec![OwnedValue::Text(expected.to_string().into())]
+        );
+        assert_eq!(record.serialize(encoding), payload);
+    }
+
+    #[rstest]
+    #[case::odd_length(&[2, 19, b'h', 0, b'i'])]
+    #[case::unpaired_surrogate(&[2, 17, 0x3d, 0xd8])]
+    fn test_parse_record_invalid_utf16(#[case] payload: &[u8]) {
+        assert!(OwnedRecord::parse(payload, TextEncoding::Utf16Le).is_err());
+    }
+
+    #[rstest]
+    #[case(1, TextEncoding::Utf8)]
+    #[case(2, TextEncoding::Utf16Le)]
+    #[case(3, TextEncoding::Utf16Be)]
+    fn test_text_encoding_from_header(#[case] value: u32, #[case] expected: TextEncoding) {
+        let header = DatabaseHeader {
+            text_encoding: value,
+            ..Default::default()
+        };
+        assert!(header.validate().is_ok());
+        assert_eq!(header.text_encoding(), expected);
     }
 
     #[test]
-    fn test_read_invalid_varint() {
-        let buf = [0b11111110];
-        let result = read_varint(&buf);
-        assert!(result.is_err());
+    fn test_validate_rejects_invalid_text_encoding() {
+        let header = DatabaseHeader {
+            text_encoding: 4,
+            ..Default::default()
+        };
+        assert!(header.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_record_with_truncated_value() {
+        assert!(OwnedRecord::parse(&[2, 4, 0x01, 0x00], TextEncoding::Utf8).is_err());
+    }
+
+    #[test]
+    fn test_write_record_with_long_header() {
+        // 200 one-byte serial types need a two-byte header size varint.
+        let values = vec![OwnedValue::Integer(2); 200];
+        let mut buf = Vec::new();
+        write_record(
+            &OwnedRecord::new(values.clone()),
+            TextEncoding::Utf8,
+            &mut buf,
+        );
+        assert_eq!(&buf[..2], &[0x81, 0x4a]);
+        assert_eq!(
+            OwnedRecord::parse(&buf, TextEncoding::Utf8).unwrap().values,
+            values
+        );
+    }
+
+    fn leaf_cell(rowid: u64, payload: &[u8]) -> BTreeCell {
+        BTreeCell::TableLeafCell(TableLeafCell {
+            _rowid: rowid,
+            _payload: payload.to_vec(),
+            payload_size: payload.len(),
+            first_overflow_page: None,
+        })
+    }
+
+    /// Parse an encoded B-Tree page and return the rowids and payloads of
+    /// its cells.
+    fn read_leaf_page(page_idx: usize, buf: Vec<u8>) -> Vec<(u64, Vec<u8>)> {
+        let usable_size = buf.len();
+        let buf = Buffer::new(Pin::new(buf), Rc::new(|_buf| {}));
+        let page = Rc::new(Page::new());
+        finish_read_btree_page(page_idx, &buf, page.clone(), usable_size).unwrap();
+        let contents = page.contents.read().unwrap();
+        let cells = &contents.as_ref().unwrap().as_btree().unwrap().cells;
+        cells
+            .iter()
+            .map(|cell| match cell {
+                BTreeCell::TableLeafCell(cell) => (cell._rowid, cell._payload.clone()),
+                _ => panic!("unexpected cell {:?}", cell),
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case::first_page(1)]
+    #[case::other_page(2)]
+    fn test_write_page(#[case] page_idx: usize) {
+        let cells = vec![(1, b"one".to_vec()), (7, vec![42; 300]), (300, vec![])];
+        let page = PageContent::BTree(BTreePage {
+            header: BTreePageHeader {
+                page_type: PageType::TableLeaf,
+                _first_freeblock_offset: 0,
+                num_cells: 0,
+                _cell_content_area: 0,
+                _num_frag_free_bytes: 0,
+                right_most_pointer: None,
+            },
+            cells: cells
+                .iter()
+                .map(|(rowid, payload)| leaf_cell(*rowid, payload))
+                .collect(),
+        });
+        let header = DatabaseHeader {
+            database_size: 2,
+            ..Default::default()
+        };
+        let buf = write_page(page_idx, &page, &header).unwrap();
+        assert_eq!(buf.len(), 4096);
+        if page_idx == 1 {
+            let read_header = Rc::new(RefCell::new(DatabaseHeader::default()));
+            let header_buf = Buffer::new(Pin::new(buf.clone()), Rc::new(|_buf| {}));
+            finish_read_database_header(&header_buf, read_header.clone()).unwrap();
+            assert_eq!(read_header.borrow().database_size(), 2);
+            assert!(read_header.borrow().validate().is_ok());
+        }
+        assert_eq!(read_leaf_page(page_idx, buf), cells);
+    }
+
+    #[test]
+    fn test_write_page_that_is_full() {
+        let page = PageContent::BTree(BTreePage {
+            header: BTreePageHeader {
+                page_type: PageType::TableLeaf,
+                _first_freeblock_offset: 0,
+                num_cells: 0,
+                _cell_content_area: 0,
+                _num_frag_free_bytes: 0,
+                right_most_pointer: None,
+            },
+            cells: (0..20).map(|rowid| leaf_cell(rowid, &[0; 300])).collect(),
+        });
+        let err = write_page(2, &page, &DatabaseHeader::default()).unwrap_err();
+        assert_eq!(err.to_string(), "page 2 is full");
     }
 }
@@ -2,6 +2,7 @@ use crate::io::Completion;
 #[cfg(feature = "fs")]
 use crate::io::File;
 use anyhow::Result;
+use std::cell::RefCell;
 use std::rc::Rc;
 
 pub struct PageSource {
@@ -23,10 +24,40 @@ impl PageSource {
     pub fn get(&self, page_idx: usize, c: Rc<Completion>) -> Result<()> {
         self.io.get(page_idx, c)
     }
+
+    pub fn put(&self, page_idx: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()> {
+        self.io.put(page_idx, buf, c)
+    }
+
+    pub fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        self.io.sync(c)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.io.is_read_only()
+    }
 }
 
+/// Reads and writes whole pages. Page sources are read-only unless they
+/// implement `put` and `sync`.
 pub trait PageIO {
     fn get(&self, page_idx: usize, c: Rc<Completion>) -> Result<()>;
+
+    /// Write the page in `buf`, which is exactly one page long.
+    fn put(&self, _page_idx: usize, _buf: Rc<RefCell<Vec<u8>>>, _c: Rc<Completion>) -> Result<()> {
+        anyhow::bail!("attempt to write a readonly database")
+    }
+
+    /// Make the pages written so far durable.
+    fn sync(&self, _c: Rc<Completion>) -> Result<()> {
+        anyhow::bail!("attempt to write a readonly database")
+    }
+
+    /// Whether pages cannot be written, which is the case unless `put` and
+    /// `sync` are implemented.
+    fn is_read_only(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(feature = "fs")]
@@ -46,6 +77,20 @@ impl PageIO for FileStorage {
         self.file.pread(pos, c)?;
         Ok(())
     }
+
+    fn put(&self, page_idx: usize, buf: Rc<RefCell<Vec<u8>>>, c: Rc<Completion>) -> Result<()> {
+        assert!(page_idx > 0);
+        let pos = (page_idx - 1) * buf.borrow().len();
+        self.file.pwrite(pos, buf, c)
+    }
+
+    fn sync(&self, c: Rc<Completion>) -> Result<()> {
+        self.file.sync(c)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.file.is_read_only()
+    }
 }
 
 #[cfg(feature = "fs")]
@@ -0,0 +1,32 @@
+//! Fixtures shared by the unit tests.
+use crate::{Database, PlatformIO, IO};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Create a database file with the given schema and contents using SQLite.
+pub(crate) fn create_fixture(name: &str, sql: &str) -> PathBuf {
+    // Test cases run in parallel, so give each fixture a unique file.
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "limbo-{}-{}-{}.db",
+        name,
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    conn.execute_batch(sql).unwrap();
+    drop(conn);
+    path
+}
+
+/// Create a database with the given schema and contents using SQLite and
+/// open it with Limbo.
+pub(crate) fn open_fixture(name: &str, sql: &str) -> (Rc<dyn IO>, Database) {
+    let path = create_fixture(name, sql);
+    let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+    let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+    // The open file handle keeps the contents alive.
+    let _ = std::fs::remove_file(&path);
+    (io, db)
+}
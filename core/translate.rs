@@ -1,5 +1,5 @@
 use crate::schema::Schema;
-use crate::vdbe::{Insn, Program, ProgramBuilder};
+use crate::vdbe::{Insn, PlanStep, Program, ProgramBuilder, ResultSource};
 use anyhow::Result;
 use sqlite3_parser::ast::{Expr, Literal, OneSelect, Select, Stmt};
 
@@ -42,57 +42,47 @@ fn translate_select(schema: &Schema, select: Select) -> Result<Program> {
                     Some(cursor_id),
                     Some(table),
                     &limit.expr,
-                ))
+                )?)
             } else {
                 None
             };
+            program.add_plan_step(PlanStep::ScanTable {
+                table: table.name.clone(),
+                cursor_id,
+            });
             program.emit_insn(Insn::OpenReadAsync {
                 cursor_id,
                 root_page,
             });
             program.emit_insn(Insn::OpenReadAwait);
             program.emit_insn(Insn::RewindAsync { cursor_id });
-            let rewind_await_offset = program.emit_placeholder();
-            let limit_decr_insn = limit_reg.map(|_| program.emit_placeholder());
-            let (register_start, register_end) =
-                translate_columns(&mut program, Some(cursor_id), Some(table), columns);
-            program.emit_insn(Insn::ResultRow {
-                register_start,
-                register_end,
+            let rewind_await = program.emit_jump_placeholder(Insn::RewindAwait {
+                cursor_id,
+                pc_if_empty: 0,
             });
+            let rewind_await_offset = rewind_await.offset();
+            let loop_end = program.alloc_label();
+            if let Some(reg) = limit_reg {
+                program.emit_jump(Insn::DecrJumpZero { reg, target_pc: 0 }, loop_end);
+            }
+            let sources = translate_columns(&mut program, Some(cursor_id), Some(table), columns)?;
+            emit_result_row(&mut program, sources);
             program.emit_insn(Insn::NextAsync { cursor_id });
             program.emit_insn(Insn::NextAwait {
                 cursor_id,
                 pc_if_next: rewind_await_offset,
             });
-            program.fixup_insn(
-                rewind_await_offset,
-                Insn::RewindAwait {
-                    cursor_id,
-                    pc_if_empty: program.offset(),
-                },
-            );
-            if let Some(limit_decr_insn) = limit_decr_insn {
-                program.fixup_insn(
-                    limit_decr_insn,
-                    Insn::DecrJumpZero {
-                        reg: limit_reg.unwrap(),
-                        target_pc: program.offset(),
-                    },
-                );
-            }
+            let end_offset = program.offset();
+            program.resolve_jump(rewind_await, end_offset);
+            program.resolve_label(loop_end);
         }
         OneSelect::Select {
             columns,
             from: None,
             ..
         } => {
-            let (register_start, register_end) =
-                translate_columns(&mut program, None, None, columns);
-            program.emit_insn(Insn::ResultRow {
-                register_start,
-                register_end,
-            });
+            let sources = translate_columns(&mut program, None, None, columns)?;
+            emit_result_row(&mut program, sources);
         }
         _ => todo!(),
     }
@@ -103,38 +93,54 @@ fn translate_select(schema: &Schema, select: Select) -> Result<Program> {
             target_pc: program.offset(),
         },
     );
-    program.emit_insn(Insn::Transaction);
+    program.emit_insn(Insn::Transaction { write: false });
     program.emit_insn(Insn::Goto {
         target_pc: start_offset,
     });
-    Ok(program.build())
+    Ok(program.build()?)
 }
 
+/// Translate the result columns of a query. Columns of the table are read
+/// straight from the cursor when the row is emitted, and other expressions
+/// are evaluated into registers.
 fn translate_columns(
     program: &mut ProgramBuilder,
     cursor_id: Option<usize>,
     table: Option<&crate::schema::Table>,
     columns: Vec<sqlite3_parser::ast::ResultColumn>,
-) -> (usize, usize) {
-    let register_start = program.next_free_register();
+) -> Result<Vec<ResultSource>> {
+    let mut sources = Vec::new();
     for col in columns {
         match col {
+            sqlite3_parser::ast::ResultColumn::Expr(Expr::Id(ident), _) if cursor_id.is_some() => {
+                let (idx, col) = table.unwrap().get_column(&ident.0).unwrap();
+                if col.primary_key {
+                    let reg = translate_expr(program, cursor_id, table, &Expr::Id(ident))?;
+                    sources.push(ResultSource::Register(reg));
+                } else {
+                    sources.push(ResultSource::Column {
+                        cursor_id: cursor_id.unwrap(),
+                        column: idx,
+                    });
+                }
+            }
             sqlite3_parser::ast::ResultColumn::Expr(expr, _) => {
-                let _ = translate_expr(program, cursor_id, table, &expr);
+                let reg = translate_expr(program, cursor_id, table, &expr)?;
+                sources.push(ResultSource::Register(reg));
             }
             sqlite3_parser::ast::ResultColumn::Star => {
                 for (i, col) in table.unwrap().columns.iter().enumerate() {
-                    let dest = program.alloc_register();
                     if col.primary_key {
+                        let dest = program.alloc_register();
                         program.emit_insn(Insn::RowId {
                             cursor_id: cursor_id.unwrap(),
                             dest,
                         });
+                        sources.push(ResultSource::Register(dest));
                     } else {
-                        program.emit_insn(Insn::Column {
-                            column: i,
-                            dest,
+                        sources.push(ResultSource::Column {
                             cursor_id: cursor_id.unwrap(),
+                            column: i,
                         });
                     }
                 }
@@ -142,8 +148,28 @@ fn translate_columns(
             sqlite3_parser::ast::ResultColumn::TableStar(_) => todo!(),
         }
     }
-    let register_end = program.next_free_register();
-    (register_start, register_end)
+    Ok(sources)
+}
+
+/// Emit a row of results. A row of consecutive registers is emitted with
+/// `ResultRow`, and any other row with `ResultRowRef`.
+fn emit_result_row(program: &mut ProgramBuilder, sources: Vec<ResultSource>) {
+    let register_start = match sources.first() {
+        Some(ResultSource::Register(reg)) => *reg,
+        _ => 0,
+    };
+    let consecutive = sources
+        .iter()
+        .enumerate()
+        .all(|(i, source)| *source == ResultSource::Register(register_start + i));
+    if consecutive {
+        program.emit_insn(Insn::ResultRow {
+            register_start,
+            register_end: register_start + sources.len(),
+        });
+    } else {
+        program.emit_insn(Insn::ResultRowRef { sources });
+    }
 }
 
 fn translate_expr(
@@ -151,8 +177,8 @@ fn translate_expr(
     cursor_id: Option<usize>,
     table: Option<&crate::schema::Table>,
     expr: &Expr,
-) -> usize {
-    match expr {
+) -> Result<usize> {
+    let reg = match expr {
         Expr::Between { .. } => todo!(),
         Expr::Binary(_, _, _) => todo!(),
         Expr::Case { .. } => todo!(),
@@ -171,11 +197,14 @@ fn translate_expr(
                     dest,
                 });
             } else {
-                program.emit_insn(Insn::Column {
-                    column: idx,
-                    dest,
-                    cursor_id: cursor_id.unwrap(),
-                });
+                program.emit_insn_with_comment(
+                    Insn::Column {
+                        column: idx,
+                        dest,
+                        cursor_id: cursor_id.unwrap(),
+                    },
+                    format!("r[{}]={}.{}", dest, table.unwrap().name, col.name),
+                );
             }
             dest
         }
@@ -187,10 +216,14 @@ fn translate_expr(
         Expr::Literal(lit) => match lit {
             Literal::Numeric(val) => {
                 let dest = program.alloc_register();
-                program.emit_insn(Insn::Integer {
-                    value: val.parse().unwrap(),
-                    dest,
-                });
+                if let Ok(value) = val.parse() {
+                    program.emit_insn(Insn::Integer { value, dest });
+                } else {
+                    program.emit_insn(Insn::Real {
+                        value: val.parse().unwrap(),
+                        dest,
+                    });
+                }
                 dest
             }
             Literal::String(s) => {
@@ -201,9 +234,25 @@ fn translate_expr(
                 });
                 dest
             }
-            Literal::Blob(_) => todo!(),
+            Literal::Blob(s) => {
+                let dest = program.alloc_register();
+                // The lexer strips the X'' delimiters and validates the hex digits.
+                let value = (0..s.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+                    .collect();
+                program.emit_insn(Insn::Blob { value, dest });
+                dest
+            }
             Literal::Keyword(_) => todo!(),
-            Literal::Null => todo!(),
+            Literal::Null => {
+                let dest = program.alloc_register();
+                program.emit_insn(Insn::Null {
+                    dest_start: dest,
+                    dest_end: dest,
+                });
+                dest
+            }
             Literal::CurrentDate => todo!(),
             Literal::CurrentTime => todo!(),
             Literal::CurrentTimestamp => todo!(),
@@ -215,6 +264,90 @@ fn translate_expr(
         Expr::Raise(_, _) => todo!(),
         Expr::Subquery(_) => todo!(),
         Expr::Unary(_, _) => todo!(),
-        Expr::Variable(_) => todo!(),
+        Expr::Variable(name) => {
+            let index = program.alloc_parameter(name)?;
+            let dest = program.alloc_register();
+            program.emit_insn(Insn::Variable { index, dest });
+            dest
+        }
+    };
+    Ok(reg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fallible_iterator::FallibleIterator;
+    use sqlite3_parser::{ast::Cmd, lexer::sql::Parser};
+
+    fn translate_sql(sql: &str) -> Program {
+        let mut parser = Parser::new(sql.as_bytes());
+        match parser.next().unwrap() {
+            Some(Cmd::Stmt(stmt)) => translate(&Schema::new(), stmt).unwrap(),
+            _ => panic!("expected a statement"),
+        }
+    }
+
+    #[test]
+    fn test_query_plan_scan() {
+        let program = translate_sql("SELECT * FROM sqlite_schema");
+        let plan: Vec<String> = program
+            .query_plan()
+            .iter()
+            .map(|step| step.to_string())
+            .collect();
+        assert_eq!(plan, vec!["SCAN TABLE sqlite_schema"]);
+    }
+
+    #[test]
+    fn test_query_plan_without_table() {
+        let program = translate_sql("SELECT 1");
+        assert!(program.query_plan().is_empty());
+    }
+
+    #[test]
+    fn test_translate_result_row_ref() {
+        let program = translate_sql("SELECT type, 1, name FROM sqlite_schema");
+        let sources = program.insns.iter().find_map(|insn| match insn {
+            Insn::ResultRowRef { sources } => Some(sources.clone()),
+            _ => None,
+        });
+        assert_eq!(
+            sources,
+            Some(vec![
+                ResultSource::Column {
+                    cursor_id: 0,
+                    column: 0,
+                },
+                ResultSource::Register(0),
+                ResultSource::Column {
+                    cursor_id: 0,
+                    column: 1,
+                },
+            ])
+        );
+        let program = translate_sql("SELECT 1, 2");
+        assert!(program.insns.iter().any(|insn| matches!(
+            insn,
+            Insn::ResultRow {
+                register_start: 0,
+                register_end: 2
+            }
+        )));
+    }
+
+    #[test]
+    fn test_translate_variables() {
+        let program = translate_sql("SELECT ?, :a, ?5, :a, ?");
+        let indexes: Vec<usize> = program
+            .insns
+            .iter()
+            .filter_map(|insn| match insn {
+                Insn::Variable { index, .. } => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(indexes, vec![1, 2, 5, 2, 6]);
+        assert_eq!(program.parameter_names.get(":a"), Some(&2));
     }
 }
@@ -1,4 +1,4 @@
-use std::{cell::Ref, rc::Rc};
+use std::{cell::Ref, cmp::Ordering, rc::Rc};
 
 use anyhow::Result;
 
@@ -7,7 +7,7 @@ pub enum Value<'a> {
     Null,
     Integer(i64),
     Float(f64),
-    Text(&'a String),
+    Text(&'a str),
     Blob(&'a Vec<u8>),
 }
 
@@ -16,10 +16,461 @@ pub enum OwnedValue {
     Null,
     Integer(i64),
     Float(f64),
-    Text(Rc<String>),
+    Text(TextValue),
     Blob(Rc<Vec<u8>>),
 }
 
+/// The longest text, in bytes, that `TextValue` stores inline.
+const INLINE_TEXT_LEN: usize = 14;
+
+/// The text of a value. Short strings are stored inline so that they don't
+/// need an allocation, and longer ones are shared behind an `Rc`. The text
+/// derefs to `str` either way.
+#[derive(Clone)]
+pub enum TextValue {
+    /// The first `len` bytes of `bytes` are valid UTF-8.
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_TEXT_LEN],
+    },
+    Heap(Rc<String>),
+}
+
+impl TextValue {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TextValue::Inline { len, bytes } => {
+                // SAFETY: inline text is only made by copying a `str`, so
+                // its bytes are valid UTF-8.
+                unsafe { std::str::from_utf8_unchecked(&bytes[..*len as usize]) }
+            }
+            TextValue::Heap(s) => s,
+        }
+    }
+
+    /// Whether the text is stored inline rather than on the heap.
+    pub fn is_inline(&self) -> bool {
+        matches!(self, TextValue::Inline { .. })
+    }
+
+    fn inline(s: &str) -> Option<Self> {
+        if s.len() > INLINE_TEXT_LEN {
+            return None;
+        }
+        let mut bytes = [0; INLINE_TEXT_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Some(TextValue::Inline {
+            len: s.len() as u8,
+            bytes,
+        })
+    }
+}
+
+impl std::ops::Deref for TextValue {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for TextValue {
+    fn from(s: &str) -> Self {
+        TextValue::inline(s).unwrap_or_else(|| TextValue::Heap(Rc::new(s.to_string())))
+    }
+}
+
+impl From<String> for TextValue {
+    fn from(s: String) -> Self {
+        TextValue::inline(&s).unwrap_or_else(|| TextValue::Heap(Rc::new(s)))
+    }
+}
+
+impl PartialEq for TextValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl std::fmt::Debug for TextValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl std::fmt::Display for TextValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The type affinity of a column, which is also the target type of a CAST.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Affinity {
+    Blob,
+    Text,
+    Numeric,
+    Integer,
+    Real,
+}
+
+impl Affinity {
+    /// The name of the type in SQL.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Affinity::Blob => "BLOB",
+            Affinity::Text => "TEXT",
+            Affinity::Numeric => "NUMERIC",
+            Affinity::Integer => "INTEGER",
+            Affinity::Real => "REAL",
+        }
+    }
+
+    /// The affinity of a column declared with the type name `decl_type`,
+    /// by the rules of SQLite, which look for the first matching substring.
+    pub fn from_declared_type(decl_type: &str) -> Affinity {
+        let decl_type = decl_type.to_uppercase();
+        if decl_type.contains("INT") {
+            Affinity::Integer
+        } else if ["CHAR", "CLOB", "TEXT"]
+            .iter()
+            .any(|name| decl_type.contains(name))
+        {
+            Affinity::Text
+        } else if decl_type.contains("BLOB") || decl_type.is_empty() {
+            Affinity::Blob
+        } else if ["REAL", "FLOA", "DOUB"]
+            .iter()
+            .any(|name| decl_type.contains(name))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// The character which represents the affinity in an affinity string.
+    pub fn to_char(self) -> char {
+        match self {
+            Affinity::Blob => 'A',
+            Affinity::Text => 'B',
+            Affinity::Numeric => 'C',
+            Affinity::Integer => 'D',
+            Affinity::Real => 'E',
+        }
+    }
+}
+
+impl TryFrom<char> for Affinity {
+    type Error = anyhow::Error;
+
+    fn try_from(c: char) -> Result<Self> {
+        match c {
+            'A' => Ok(Affinity::Blob),
+            'B' => Ok(Affinity::Text),
+            'C' => Ok(Affinity::Numeric),
+            'D' => Ok(Affinity::Integer),
+            'E' => Ok(Affinity::Real),
+            _ => anyhow::bail!("Invalid affinity '{}'", c),
+        }
+    }
+}
+
+/// A collating sequence, which defines how text values are ordered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Collation {
+    /// Compare text byte by byte.
+    #[default]
+    Binary,
+    /// Compare text ignoring the case of ASCII letters.
+    NoCase,
+    /// Compare text ignoring trailing spaces.
+    Rtrim,
+}
+
+impl Collation {
+    /// The name of the collation in SQL.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collation::Binary => "BINARY",
+            Collation::NoCase => "NOCASE",
+            Collation::Rtrim => "RTRIM",
+        }
+    }
+}
+
+impl OwnedValue {
+    /// Compare two values using the SQLite sort order: NULL values sort
+    /// first, followed by numeric values, text, and finally blobs. Integers
+    /// and floats are compared numerically.
+    pub fn compare(&self, other: &OwnedValue) -> Ordering {
+        match (self, other) {
+            (OwnedValue::Integer(l), OwnedValue::Integer(r)) => l.cmp(r),
+            (OwnedValue::Integer(l), OwnedValue::Float(r)) => compare_f64(*l as f64, *r),
+            (OwnedValue::Float(l), OwnedValue::Integer(r)) => compare_f64(*l, *r as f64),
+            (OwnedValue::Float(l), OwnedValue::Float(r)) => compare_f64(*l, *r),
+            (OwnedValue::Text(l), OwnedValue::Text(r)) => l.cmp(r),
+            (OwnedValue::Blob(l), OwnedValue::Blob(r)) => l.cmp(r),
+            _ => self.type_order().cmp(&other.type_order()),
+        }
+    }
+
+    /// Compare two values like `compare`, ordering text with the given
+    /// collation.
+    pub fn compare_with(&self, other: &OwnedValue, collation: Collation) -> Ordering {
+        match (collation, self, other) {
+            (Collation::NoCase, OwnedValue::Text(l), OwnedValue::Text(r)) => l
+                .bytes()
+                .map(|b| b.to_ascii_lowercase())
+                .cmp(r.bytes().map(|b| b.to_ascii_lowercase())),
+            (Collation::Rtrim, OwnedValue::Text(l), OwnedValue::Text(r)) => {
+                l.trim_end_matches(' ').cmp(r.trim_end_matches(' '))
+            }
+            _ => self.compare(other),
+        }
+    }
+
+    /// Convert the value to text the way SQLite does, or return `None` for
+    /// NULL. Blobs are interpreted as UTF-8 text.
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            OwnedValue::Null => None,
+            OwnedValue::Integer(i) => Some(i.to_string()),
+            OwnedValue::Float(f) => Some(float_to_text(*f)),
+            OwnedValue::Text(s) => Some(s.to_string()),
+            OwnedValue::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        }
+    }
+
+    /// Convert the value with `CAST(value AS type)`. Text is converted to a
+    /// number using its longest numeric prefix, so "12ab" casts to 12 and
+    /// text without one casts to 0. NULL stays NULL.
+    pub fn cast(&self, to_type: Affinity) -> OwnedValue {
+        if *self == OwnedValue::Null {
+            return OwnedValue::Null;
+        }
+        match to_type {
+            Affinity::Blob => match self {
+                OwnedValue::Blob(_) => self.clone(),
+                _ => OwnedValue::Blob(Rc::new(self.to_text().unwrap().into_bytes())),
+            },
+            Affinity::Text => match self {
+                OwnedValue::Text(_) => self.clone(),
+                _ => OwnedValue::Text(self.to_text().unwrap().into()),
+            },
+            Affinity::Numeric => match self {
+                OwnedValue::Integer(_) | OwnedValue::Float(_) => self.clone(),
+                _ => text_to_numeric(&self.to_text().unwrap()),
+            },
+            Affinity::Integer => match self {
+                OwnedValue::Integer(_) => self.clone(),
+                OwnedValue::Float(f) => OwnedValue::Integer(*f as i64),
+                _ => OwnedValue::Integer(text_to_integer(&self.to_text().unwrap())),
+            },
+            Affinity::Real => match self {
+                OwnedValue::Float(_) => self.clone(),
+                OwnedValue::Integer(i) => OwnedValue::Float(*i as f64),
+                _ => OwnedValue::Float(text_to_real(&self.to_text().unwrap())),
+            },
+        }
+    }
+
+    /// Apply a column affinity to the value. Unlike a CAST, only numbers are
+    /// converted to text, and only text which is entirely a well-formed
+    /// number is converted to a number.
+    pub fn apply_affinity(&self, affinity: Affinity) -> OwnedValue {
+        match (affinity, self) {
+            (Affinity::Text, OwnedValue::Integer(_) | OwnedValue::Float(_)) => {
+                OwnedValue::Text(self.to_text().unwrap().into())
+            }
+            (Affinity::Numeric | Affinity::Integer, OwnedValue::Float(f)) => real_to_numeric(*f),
+            (Affinity::Numeric | Affinity::Integer, OwnedValue::Text(s)) => {
+                if is_number(s) {
+                    text_to_numeric(s)
+                } else {
+                    self.clone()
+                }
+            }
+            (Affinity::Real, OwnedValue::Integer(i)) => OwnedValue::Float(*i as f64),
+            (Affinity::Real, OwnedValue::Text(s)) => {
+                if is_number(s) {
+                    OwnedValue::Float(text_to_real(s))
+                } else {
+                    self.clone()
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    fn type_order(&self) -> u8 {
+        match self {
+            OwnedValue::Null => 0,
+            OwnedValue::Integer(_) | OwnedValue::Float(_) => 1,
+            OwnedValue::Text(_) => 2,
+            OwnedValue::Blob(_) => 3,
+        }
+    }
+}
+
+fn compare_f64(lhs: f64, rhs: f64) -> Ordering {
+    lhs.partial_cmp(&rhs).unwrap_or(Ordering::Equal)
+}
+
+/// Format a float with 15 significant digits like SQLite's `%!.15g`, which
+/// always includes a decimal point so that the text reads back as a float.
+fn float_to_text(f: f64) -> String {
+    if f == 0.0 {
+        return "0.0".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    let scientific = format!("{:.14e}", f);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if !(-4..15).contains(&exponent) {
+        let mantissa = trim_fraction(mantissa);
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", mantissa, sign, exponent.abs());
+    }
+    trim_fraction(&format!("{:.*}", (14 - exponent) as usize, f))
+}
+
+/// Remove trailing zeros of the fractional part, keeping at least one digit.
+fn trim_fraction(s: &str) -> String {
+    match s.split_once('.') {
+        Some((int, frac)) => {
+            let frac = frac.trim_end_matches('0');
+            format!("{}.{}", int, if frac.is_empty() { "0" } else { frac })
+        }
+        None => format!("{}.0", s),
+    }
+}
+
+/// Split off the longest prefix of the text that is a number, after leading
+/// whitespace. Also returns whether the prefix is an integer, which has no
+/// decimal point or exponent.
+fn numeric_prefix(s: &str) -> (&str, bool) {
+    let s = s.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let bytes = s.as_bytes();
+    let digits = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+    let start = if matches!(bytes.first(), Some(b'+' | b'-')) {
+        1
+    } else {
+        0
+    };
+    let mut end = digits(start);
+    let mut mantissa_digits = end - start;
+    let mut is_integer = true;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_end = digits(end + 1);
+        mantissa_digits += fraction_end - end - 1;
+        if mantissa_digits > 0 {
+            end = fraction_end;
+            is_integer = false;
+        }
+    }
+    if mantissa_digits == 0 {
+        return ("", true);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let sign = if matches!(bytes.get(end + 1), Some(b'+' | b'-')) {
+            1
+        } else {
+            0
+        };
+        let exponent_end = digits(end + 1 + sign);
+        if exponent_end > end + 1 + sign {
+            end = exponent_end;
+            is_integer = false;
+        }
+    }
+    (&s[..end], is_integer)
+}
+
+/// Whether the text is a number with optional surrounding whitespace.
+pub(crate) fn is_number(s: &str) -> bool {
+    let trimmed = s.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let (prefix, _) = numeric_prefix(trimmed);
+    !prefix.is_empty()
+        && trimmed[prefix.len()..]
+            .chars()
+            .all(|c| c.is_ascii_whitespace())
+}
+
+/// Convert text to an integer from its leading digits, saturating if the
+/// value does not fit.
+fn text_to_integer(s: &str) -> i64 {
+    let s = s.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let mut value: i64 = 0;
+    for c in digits.bytes().take_while(u8::is_ascii_digit) {
+        let digit = (c - b'0') as i64;
+        value = match value.checked_mul(10).and_then(|v| {
+            if negative {
+                v.checked_sub(digit)
+            } else {
+                v.checked_add(digit)
+            }
+        }) {
+            Some(v) => v,
+            None => return if negative { i64::MIN } else { i64::MAX },
+        };
+    }
+    value
+}
+
+fn text_to_real(s: &str) -> f64 {
+    let (prefix, _) = numeric_prefix(s);
+    prefix.parse().unwrap_or(0.0)
+}
+
+/// Convert text to a number the way the arithmetic operators do: an integer
+/// if its numeric prefix is an integer that fits, and a real otherwise. Text
+/// without a numeric prefix is the integer zero.
+pub(crate) fn text_to_number(s: &str) -> OwnedValue {
+    let (prefix, is_integer) = numeric_prefix(s);
+    if is_integer {
+        if let Ok(i) = prefix.parse::<i64>() {
+            return OwnedValue::Integer(i);
+        }
+        if prefix.is_empty() {
+            return OwnedValue::Integer(0);
+        }
+    }
+    OwnedValue::Float(prefix.parse().unwrap_or(0.0))
+}
+
+/// Like [`text_to_number`], but reals which are exactly an integer become
+/// integers too.
+fn text_to_numeric(s: &str) -> OwnedValue {
+    match text_to_number(s) {
+        OwnedValue::Float(f) => real_to_numeric(f),
+        value => value,
+    }
+}
+
+/// Reals that are exactly an integer and small enough to be represented
+/// precisely become integers.
+fn real_to_numeric(f: f64) -> OwnedValue {
+    const MAX_EXACT: f64 = 2251799813685248.0;
+    if f.fract() == 0.0 && (-MAX_EXACT..MAX_EXACT).contains(&f) {
+        OwnedValue::Integer(f as i64)
+    } else {
+        OwnedValue::Float(f)
+    }
+}
+
 pub fn to_value(value: &OwnedValue) -> Value<'_> {
     match value {
         OwnedValue::Null => Value::Null,
@@ -30,6 +481,16 @@ pub fn to_value(value: &OwnedValue) -> Value<'_> {
     }
 }
 
+pub fn to_owned_value(value: &Value) -> OwnedValue {
+    match value {
+        Value::Null => OwnedValue::Null,
+        Value::Integer(i) => OwnedValue::Integer(*i),
+        Value::Float(f) => OwnedValue::Float(*f),
+        Value::Text(s) => OwnedValue::Text((*s).into()),
+        Value::Blob(b) => OwnedValue::Blob(Rc::new(b.to_vec())),
+    }
+}
+
 pub trait FromValue {
     fn from_value(value: &Value) -> Result<Self>
     where
@@ -54,6 +515,16 @@ impl FromValue for String {
     }
 }
 
+/// A NULL value is `None`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Record<'a> {
     pub values: Vec<Value<'a>>,
@@ -65,6 +536,7 @@ impl<'a> Record<'a> {
     }
 }
 
+#[derive(Clone)]
 pub struct OwnedRecord {
     pub values: Vec<OwnedValue>,
 }
@@ -73,6 +545,36 @@ impl OwnedRecord {
     pub fn new(values: Vec<OwnedValue>) -> Self {
         Self { values }
     }
+
+    /// Decode a record in the SQLite record format.
+    pub fn parse(
+        payload: &[u8],
+        encoding: crate::sqlite3_ondisk::TextEncoding,
+    ) -> Result<OwnedRecord> {
+        crate::sqlite3_ondisk::read_record(payload, encoding)
+    }
+
+    /// Encode the record in the SQLite record format, with text in the given
+    /// encoding.
+    pub fn serialize(&self, encoding: crate::sqlite3_ondisk::TextEncoding) -> Vec<u8> {
+        let mut buf = Vec::new();
+        crate::sqlite3_ondisk::write_record(self, encoding, &mut buf);
+        buf
+    }
+}
+
+/// Compare an index key against a key built from registers, field by field.
+/// The index key is equal to the other key if its leading fields are, so
+/// trailing fields such as the rowid of every index entry only break the tie
+/// when the other key includes them.
+pub(crate) fn compare_index_key(index_key: &OwnedRecord, key: &OwnedRecord) -> Ordering {
+    index_key
+        .values
+        .iter()
+        .zip(key.values.iter())
+        .map(|(l, r)| l.compare(r))
+        .find(|ord| *ord != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
 }
 
 pub enum CursorResult<T> {
@@ -84,7 +586,213 @@ pub trait Cursor {
     fn is_empty(&self) -> bool;
     fn rewind(&mut self) -> Result<CursorResult<()>>;
     fn next(&mut self) -> Result<CursorResult<()>>;
+    fn last(&mut self) -> Result<CursorResult<()>>;
+    fn prev(&mut self) -> Result<CursorResult<()>>;
+    fn seek_rowid(&mut self, rowid: u64) -> Result<CursorResult<bool>>;
+    fn seek_ge(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>>;
+    fn seek_gt(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>>;
     fn wait_for_completion(&mut self) -> Result<()>;
-    fn rowid(&self) -> Result<Ref<Option<u64>>>;
-    fn record(&self) -> Result<Ref<Option<OwnedRecord>>>;
+    fn rowid(&self) -> Result<Ref<'_, Option<u64>>>;
+    fn record(&self) -> Result<Ref<'_, Option<OwnedRecord>>>;
+    /// Borrow the current record for as long as the cursor is borrowed,
+    /// which a `record` guard cannot outlive.
+    fn current_record(&mut self) -> Option<&OwnedRecord>;
+    fn insert(&mut self, key: u64, payload: &[u8]) -> Result<CursorResult<()>>;
+    fn delete(&mut self) -> Result<CursorResult<()>>;
+    fn is_writable(&self) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(1.0, "1.0")]
+    #[case(-0.0, "0.0")]
+    #[case(0.1, "0.1")]
+    #[case(-1.5, "-1.5")]
+    #[case(1.0 / 3.0, "0.333333333333333")]
+    #[case(123456789012345.0, "123456789012345.0")]
+    #[case(1e15, "1.0e+15")]
+    #[case(1e20, "1.0e+20")]
+    #[case(123456789012345678.0, "1.23456789012346e+17")]
+    #[case(0.0001, "0.0001")]
+    #[case(1.5e-7, "1.5e-07")]
+    #[case(f64::INFINITY, "Inf")]
+    fn test_float_to_text(#[case] value: f64, #[case] expected: &str) {
+        assert_eq!(OwnedValue::Float(value).to_text().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, None)]
+    #[case(OwnedValue::Integer(-12), Some("-12"))]
+    #[case(OwnedValue::Text("abc".into()), Some("abc"))]
+    #[case(OwnedValue::Blob(Rc::new(b"abc".to_vec())), Some("abc"))]
+    fn test_to_text(#[case] value: OwnedValue, #[case] expected: Option<&str>) {
+        assert_eq!(value.to_text().as_deref(), expected);
+    }
+
+    fn text(s: &str) -> OwnedValue {
+        OwnedValue::Text(s.into())
+    }
+
+    fn blob(b: &[u8]) -> OwnedValue {
+        OwnedValue::Blob(Rc::new(b.to_vec()))
+    }
+
+    #[rstest]
+    #[case(text("42"), OwnedValue::Integer(42))]
+    #[case(text("  -12ab"), OwnedValue::Integer(-12))]
+    #[case(text("+7"), OwnedValue::Integer(7))]
+    #[case(text("1.9"), OwnedValue::Integer(1))]
+    #[case(text("1e3"), OwnedValue::Integer(1))]
+    #[case(text("abc"), OwnedValue::Integer(0))]
+    #[case(text(""), OwnedValue::Integer(0))]
+    #[case(text("99999999999999999999"), OwnedValue::Integer(i64::MAX))]
+    #[case(text("-99999999999999999999"), OwnedValue::Integer(i64::MIN))]
+    #[case(blob(b"5"), OwnedValue::Integer(5))]
+    #[case(OwnedValue::Float(-2.7), OwnedValue::Integer(-2))]
+    #[case(OwnedValue::Float(1e30), OwnedValue::Integer(i64::MAX))]
+    #[case(OwnedValue::Integer(3), OwnedValue::Integer(3))]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    fn test_cast_integer(#[case] value: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(value.cast(Affinity::Integer), expected);
+    }
+
+    #[rstest]
+    #[case(text("1.5"), OwnedValue::Float(1.5))]
+    #[case(text(" 12ab"), OwnedValue::Float(12.0))]
+    #[case(text(".5"), OwnedValue::Float(0.5))]
+    #[case(text("5."), OwnedValue::Float(5.0))]
+    #[case(text("-1e3x"), OwnedValue::Float(-1000.0))]
+    #[case(text("2e"), OwnedValue::Float(2.0))]
+    #[case(text("."), OwnedValue::Float(0.0))]
+    #[case(text("abc"), OwnedValue::Float(0.0))]
+    #[case(OwnedValue::Integer(3), OwnedValue::Float(3.0))]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    fn test_cast_real(#[case] value: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(value.cast(Affinity::Real), expected);
+    }
+
+    #[rstest]
+    #[case(text("42"), OwnedValue::Integer(42))]
+    #[case(text("12ab"), OwnedValue::Integer(12))]
+    #[case(text("1.5x"), OwnedValue::Float(1.5))]
+    #[case(text("3.0"), OwnedValue::Integer(3))]
+    #[case(text("1e2"), OwnedValue::Integer(100))]
+    #[case(text("99999999999999999999"), OwnedValue::Float(1e20))]
+    #[case(text("abc"), OwnedValue::Integer(0))]
+    #[case(OwnedValue::Float(3.0), OwnedValue::Float(3.0))]
+    #[case(OwnedValue::Null, OwnedValue::Null)]
+    fn test_cast_numeric(#[case] value: OwnedValue, #[case] expected: OwnedValue) {
+        assert_eq!(value.cast(Affinity::Numeric), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Integer(-3), Affinity::Text, text("-3"))]
+    #[case(OwnedValue::Float(0.5), Affinity::Text, text("0.5"))]
+    #[case(blob(b"ab"), Affinity::Text, text("ab"))]
+    #[case(OwnedValue::Null, Affinity::Text, OwnedValue::Null)]
+    #[case(text("ab"), Affinity::Blob, blob(b"ab"))]
+    #[case(OwnedValue::Integer(12), Affinity::Blob, blob(b"12"))]
+    #[case(OwnedValue::Null, Affinity::Blob, OwnedValue::Null)]
+    fn test_cast_text_and_blob(
+        #[case] value: OwnedValue,
+        #[case] to_type: Affinity,
+        #[case] expected: OwnedValue,
+    ) {
+        assert_eq!(value.cast(to_type), expected);
+    }
+
+    #[rstest]
+    #[case(text("42"), Affinity::Integer, OwnedValue::Integer(42))]
+    #[case(text(" 42 "), Affinity::Numeric, OwnedValue::Integer(42))]
+    #[case(text("4.5"), Affinity::Numeric, OwnedValue::Float(4.5))]
+    #[case(text("4.0"), Affinity::Numeric, OwnedValue::Integer(4))]
+    #[case(text("1e3"), Affinity::Integer, OwnedValue::Integer(1000))]
+    #[case(text("42abc"), Affinity::Numeric, text("42abc"))]
+    #[case(text("abc"), Affinity::Integer, text("abc"))]
+    #[case(text(""), Affinity::Numeric, text(""))]
+    #[case(OwnedValue::Float(2.0), Affinity::Integer, OwnedValue::Integer(2))]
+    #[case(OwnedValue::Float(2.5), Affinity::Numeric, OwnedValue::Float(2.5))]
+    #[case(blob(b"42"), Affinity::Numeric, blob(b"42"))]
+    #[case(text("42"), Affinity::Real, OwnedValue::Float(42.0))]
+    #[case(OwnedValue::Integer(42), Affinity::Real, OwnedValue::Float(42.0))]
+    #[case(text("x"), Affinity::Real, text("x"))]
+    #[case(OwnedValue::Integer(42), Affinity::Text, text("42"))]
+    #[case(OwnedValue::Float(0.5), Affinity::Text, text("0.5"))]
+    #[case(blob(b"ab"), Affinity::Text, blob(b"ab"))]
+    #[case(text("42"), Affinity::Blob, text("42"))]
+    #[case(OwnedValue::Null, Affinity::Numeric, OwnedValue::Null)]
+    #[case(OwnedValue::Null, Affinity::Text, OwnedValue::Null)]
+    fn test_apply_affinity(
+        #[case] value: OwnedValue,
+        #[case] affinity: Affinity,
+        #[case] expected: OwnedValue,
+    ) {
+        assert_eq!(value.apply_affinity(affinity), expected);
+    }
+
+    #[test]
+    fn test_affinity_chars() {
+        for affinity in [
+            Affinity::Blob,
+            Affinity::Text,
+            Affinity::Numeric,
+            Affinity::Integer,
+            Affinity::Real,
+        ] {
+            assert_eq!(Affinity::try_from(affinity.to_char()).unwrap(), affinity);
+        }
+        assert!(Affinity::try_from('F').is_err());
+    }
+
+    #[rstest]
+    #[case(text("ABC"), text("abc"), Collation::NoCase, Ordering::Equal)]
+    #[case(text("ABC"), text("abc"), Collation::Binary, Ordering::Less)]
+    #[case(text("abc"), text("ABD"), Collation::NoCase, Ordering::Less)]
+    #[case(text("_"), text("a"), Collation::NoCase, Ordering::Less)]
+    #[case(text("\u{c9}"), text("\u{e9}"), Collation::NoCase, Ordering::Less)]
+    #[case(text("x "), text("x"), Collation::Rtrim, Ordering::Equal)]
+    #[case(text("x "), text("x"), Collation::Binary, Ordering::Greater)]
+    #[case(text(" x"), text("x"), Collation::Rtrim, Ordering::Less)]
+    #[case(text("x\t"), text("x"), Collation::Rtrim, Ordering::Greater)]
+    #[case(OwnedValue::Integer(1), text("a"), Collation::NoCase, Ordering::Less)]
+    #[case(blob(b"A"), blob(b"a"), Collation::NoCase, Ordering::Less)]
+    fn test_compare_with_collation(
+        #[case] lhs: OwnedValue,
+        #[case] rhs: OwnedValue,
+        #[case] collation: Collation,
+        #[case] expected: Ordering,
+    ) {
+        assert_eq!(lhs.compare_with(&rhs, collation), expected);
+    }
+
+    #[rstest]
+    #[case::empty("", true)]
+    #[case::short("a", true)]
+    #[case::longest_inline("fourteen bytes", true)]
+    #[case::multibyte("\u{e9}t\u{e9}", true)]
+    #[case::heap("fifteen bytes!!", false)]
+    #[case::long("a string which is much longer than the inline buffer", false)]
+    fn test_text_value(#[case] s: &str, #[case] inline: bool) {
+        let from_str = TextValue::from(s);
+        let from_string = TextValue::from(s.to_string());
+        assert_eq!(from_str.is_inline(), inline);
+        assert_eq!(from_string.is_inline(), inline);
+        assert_eq!(from_str.as_str(), s);
+        assert_eq!(from_str.len(), s.len());
+        assert_eq!(from_str, from_string);
+        assert_eq!(format!("{:?}", from_str), format!("{:?}", s));
+    }
+
+    #[test]
+    fn test_text_value_equality_across_storage() {
+        let inline = TextValue::from("abc");
+        let heap = TextValue::Heap(Rc::new("abc".to_string()));
+        assert!(inline.is_inline() && !heap.is_inline());
+        assert_eq!(inline, heap);
+        assert_eq!(OwnedValue::Text(inline), OwnedValue::Text(heap));
+    }
 }
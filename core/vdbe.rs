@@ -1,11 +1,22 @@
 use crate::btree::BTreeCursor;
-use crate::pager::Pager;
-use crate::types::{Cursor, CursorResult, OwnedValue, Record};
+use crate::ephemeral::EphemeralCursor;
+use crate::error::LimboError;
+use crate::function::{AggFunc, Func, FunctionRegistry};
+use crate::pager::{Pager, Transaction, TransactionMode};
+use crate::sorter::{SortKey, SortOrder, Sorter};
+use crate::sqlite3_ondisk::TextEncoding;
+use crate::types::{
+    compare_index_key, text_to_number, Affinity, Collation, Cursor, CursorResult, OwnedRecord,
+    OwnedValue, Record, Value,
+};
 
 use anyhow::Result;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
 
 pub type BranchOffset = usize;
 
@@ -28,6 +39,72 @@ pub enum Insn {
     // Await for the competion of open cursor.
     OpenReadAwait,
 
+    // Open a cursor for writing.
+    OpenWriteAsync {
+        cursor_id: CursorID,
+        root_page: PageIdx,
+    },
+
+    // Await for the completion of open cursor for writing.
+    OpenWriteAwait,
+
+    // Open a cursor on a new, empty table which lives in memory until the
+    // end of the program. Rows have up to num_columns columns and are
+    // accessed like the rows of a table B-Tree, or like the entries of an
+    // index B-Tree if is_index is set.
+    OpenEphemeral {
+        cursor_id: CursorID,
+        num_columns: usize,
+        is_index: bool,
+    },
+
+    // Insert the key record in record_reg into an index cursor.
+    IdxInsert {
+        cursor_id: CursorID,
+        record_reg: usize,
+    },
+
+    // Open a sorter cursor, which orders the records inserted into it by the
+    // given sort keys.
+    SorterOpen {
+        cursor_id: CursorID,
+        keys: Vec<SortKey>,
+    },
+
+    // Insert the record in record_reg into a sorter.
+    SorterInsert {
+        cursor_id: CursorID,
+        record_reg: usize,
+    },
+
+    // Sort the records of a sorter and move to the first one, or jump to
+    // pc_if_empty if there are none. The columns of the current record can
+    // be read with Column.
+    SorterSort {
+        cursor_id: CursorID,
+        pc_if_empty: BranchOffset,
+    },
+
+    // Store the current record of a sorter in dest_reg.
+    SorterData {
+        cursor_id: CursorID,
+        dest_reg: usize,
+    },
+
+    // Move a sorter to its next record, and jump to pc_if_next if there is
+    // one.
+    SorterNext {
+        cursor_id: CursorID,
+        pc_if_next: BranchOffset,
+    },
+
+    // Open a cursor for reading the keys of an index. Await for its
+    // completion with OpenReadAwait.
+    OpenIndexReadAsync {
+        cursor_id: CursorID,
+        root_page: PageIdx,
+    },
+
     // Rewind the cursor to the beginning of the B-Tree.
     RewindAsync {
         cursor_id: CursorID,
@@ -52,6 +129,17 @@ pub enum Insn {
         register_end: usize,
     },
 
+    // Emit a row of results whose columns are the given registers, in order.
+    ResultRowList {
+        regs: Vec<usize>,
+    },
+
+    // Emit a row of results whose columns are registers or columns borrowed
+    // from the current row of a cursor without copying them.
+    ResultRowRef {
+        sources: Vec<ResultSource>,
+    },
+
     // Advance the cursor to the next row.
     NextAsync {
         cursor_id: CursorID,
@@ -63,11 +151,48 @@ pub enum Insn {
         pc_if_next: BranchOffset,
     },
 
+    // Do nothing. Instructions removed from a program are replaced with Noop
+    // so that the offsets of the other instructions stay the same.
+    Noop,
+
     // Halt the program.
     Halt,
 
-    // Start a transaction.
-    Transaction,
+    // Abort the program with an SQLite error code and message, such as for a
+    // constraint violation.
+    HaltError {
+        err_code: i32,
+        message: String,
+    },
+
+    // Start a read or write transaction, or upgrade the read transaction of
+    // the program to a write transaction.
+    Transaction {
+        write: bool,
+    },
+
+    // Commit the transaction of the program.
+    Commit,
+
+    // Roll back the transaction of the program.
+    Rollback,
+
+    // Create a savepoint named by the text in the register.
+    Savepoint {
+        name_reg: usize,
+    },
+
+    // Remove the named savepoint and every savepoint created after it,
+    // keeping their changes.
+    ReleaseSavepoint {
+        name_reg: usize,
+    },
+
+    // Discard the changes made since the named savepoint was created. The
+    // savepoint itself is kept.
+    RollbackTo {
+        name_reg: usize,
+    },
 
     // Branch to the given PC.
     Goto {
@@ -80,12 +205,37 @@ pub enum Insn {
         dest: usize,
     },
 
+    // Write a floating point value into a register.
+    Real {
+        value: f64,
+        dest: usize,
+    },
+
+    // Write the value bound to the parameter with the given 1-based index into
+    // a register, or NULL if the parameter is not bound.
+    Variable {
+        index: usize,
+        dest: usize,
+    },
+
+    // Write NULL into the registers in the range [dest_start, dest_end].
+    Null {
+        dest_start: usize,
+        dest_end: usize,
+    },
+
     // Write a string value into a register.
     String8 {
         value: String,
         dest: usize,
     },
 
+    // Write a blob value into a register.
+    Blob {
+        value: Vec<u8>,
+        dest: usize,
+    },
+
     // Read the rowid of the current row.
     RowId {
         cursor_id: CursorID,
@@ -99,363 +249,7329 @@ pub enum Insn {
         reg: usize,
         target_pc: BranchOffset,
     },
-}
 
-pub struct ProgramBuilder {
-    next_free_register: usize,
-    next_free_cursor_id: usize,
-    insns: Vec<Insn>,
-}
+    // Add two registers and store the result in a third register.
+    Add {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-impl ProgramBuilder {
-    pub fn new() -> Self {
-        Self {
-            next_free_register: 0,
-            next_free_cursor_id: 0,
-            insns: Vec::new(),
-        }
-    }
+    // Subtract rhs from lhs and store the result in a third register.
+    Subtract {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn alloc_register(&mut self) -> usize {
-        let reg = self.next_free_register;
-        self.next_free_register += 1;
-        reg
-    }
+    // Multiply two registers and store the result in a third register.
+    Multiply {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn next_free_register(&self) -> usize {
-        self.next_free_register
-    }
+    // Divide lhs by rhs and store the result in a third register.
+    //
+    // Division by zero yields NULL, like in SQLite.
+    Divide {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn alloc_cursor_id(&mut self) -> usize {
-        let cursor = self.next_free_cursor_id;
-        self.next_free_cursor_id += 1;
-        cursor
-    }
+    // Store the remainder of dividing lhs by rhs in a third register.
+    //
+    // Real operands are truncated to integers first, and the result is a
+    // real if either operand was. A zero divisor yields NULL.
+    Remainder {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn emit_placeholder(&mut self) -> usize {
-        let offset = self.insns.len();
-        self.insns.push(Insn::Halt);
-        offset
-    }
+    // Compute the bitwise AND of two registers and store the result in a
+    // third register. Both operands are converted to integers first.
+    BitAnd {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn emit_insn(&mut self, insn: Insn) {
-        self.insns.push(insn);
-    }
+    // Compute the bitwise OR of two registers and store the result in a
+    // third register. Both operands are converted to integers first.
+    BitOr {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn fixup_insn(&mut self, offset: usize, insn: Insn) {
-        self.insns[offset] = insn;
-    }
+    // Shift lhs left by rhs bits and store the result in a third register.
+    //
+    // A negative shift amount shifts in the other direction, and shifting
+    // by 64 bits or more yields zero.
+    ShiftLeft {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn offset(&self) -> usize {
-        self.insns.len()
-    }
+    // Shift lhs right by rhs bits and store the result in a third register.
+    //
+    // The shift is arithmetic, so shifting a negative value by 64 bits or
+    // more yields -1. A negative shift amount shifts in the other direction.
+    ShiftRight {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn build(self) -> Program {
-        Program {
-            max_registers: self.next_free_register,
-            insns: self.insns,
-        }
-    }
-}
+    // Store the bitwise complement of a register in another register.
+    BitNot {
+        reg: usize,
+        dest: usize,
+    },
 
-pub enum StepResult<'a> {
-    Done,
-    IO,
-    Row(Record<'a>),
-}
+    // Store the negation of a register in another register.
+    Negative {
+        reg: usize,
+        dest: usize,
+    },
 
-/// The program state describes the environment in which the program executes.
-pub struct ProgramState {
-    pub pc: usize,
-    cursors: RefCell<BTreeMap<usize, Box<dyn Cursor>>>,
-    registers: Vec<OwnedValue>,
-}
+    // Store the logical negation of a register in another register: 0 if
+    // the value is true, 1 if it is false, and NULL if it is NULL.
+    Not {
+        reg: usize,
+        dest: usize,
+    },
 
-impl ProgramState {
-    pub fn new(max_registers: usize) -> Self {
-        let cursors = RefCell::new(BTreeMap::new());
-        let mut registers = Vec::with_capacity(max_registers);
-        registers.resize(max_registers, OwnedValue::Null);
-        Self {
-            pc: 0,
-            cursors,
-            registers,
-        }
-    }
+    // Concatenate the text of two registers and store the result in a third
+    // register. Numbers are converted to text, and if either register is
+    // NULL, the result is NULL.
+    Concat {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
 
-    pub fn column_count(&self) -> usize {
-        self.registers.len()
-    }
+    // Call a scalar function with the arguments in the registers
+    // [start_reg, start_reg + arg_count) and store the result in dest_reg.
+    Function {
+        func: Func,
+        start_reg: usize,
+        arg_count: usize,
+        dest_reg: usize,
+    },
 
-    pub fn column(&self, i: usize) -> Option<String> {
-        Some(format!("{:?}", self.registers[i]))
-    }
-}
+    // Convert the value in a register to the given type, like
+    // CAST(r[reg] AS to_type).
+    Cast {
+        reg: usize,
+        to_type: Affinity,
+    },
 
-pub struct Program {
-    pub max_registers: usize,
-    pub insns: Vec<Insn>,
-}
+    // Apply affinities to the registers [start_reg, start_reg + count), one
+    // for each character of the affinity string: 'A' blob, 'B' text,
+    // 'C' numeric, 'D' integer and 'E' real.
+    Affinity {
+        start_reg: usize,
+        count: usize,
+        affinities: String,
+    },
 
-impl Program {
-    pub fn explain(&self) {
-        println!("addr  opcode         p1    p2    p3    p4             p5  comment");
-        println!("----  -------------  ----  ----  ----  -------------  --  -------");
-        for (addr, insn) in self.insns.iter().enumerate() {
-            print_insn(addr, insn);
-        }
-    }
+    // Add the value in arg_reg to the accumulator of an aggregate function,
+    // which starts at acc_reg. AVG uses two accumulator registers.
+    AggStep {
+        func: AggFunc,
+        arg_reg: usize,
+        acc_reg: usize,
+    },
 
-    pub fn step<'a>(
-        &self,
-        state: &'a mut ProgramState,
-        pager: Rc<Pager>,
-    ) -> Result<StepResult<'a>> {
-        loop {
-            let insn = &self.insns[state.pc];
-            trace_insn(state.pc, insn);
-            let mut cursors = state.cursors.borrow_mut();
-            match insn {
-                Insn::Init { target_pc } => {
-                    state.pc = *target_pc;
-                }
-                Insn::OpenReadAsync {
-                    cursor_id,
-                    root_page,
-                } => {
-                    let cursor = Box::new(BTreeCursor::new(pager.clone(), *root_page));
-                    cursors.insert(*cursor_id, cursor);
-                    state.pc += 1;
-                }
-                Insn::OpenReadAwait => {
-                    state.pc += 1;
-                }
-                Insn::RewindAsync { cursor_id } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    match cursor.rewind()? {
-                        CursorResult::Ok(()) => {}
-                        CursorResult::IO => {
-                            // If there is I/O, the instruction is restarted.
-                            return Ok(StepResult::IO);
-                        }
-                    }
-                    state.pc += 1;
-                }
-                Insn::RewindAwait {
-                    cursor_id,
-                    pc_if_empty,
-                } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    cursor.wait_for_completion()?;
-                    if cursor.is_empty() {
-                        state.pc = *pc_if_empty;
-                    } else {
-                        state.pc += 1;
-                    }
-                }
-                Insn::Column {
-                    cursor_id,
-                    column,
-                    dest,
-                } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    if let Some(ref record) = *cursor.record()? {
-                        state.registers[*dest] = record.values[*column].clone();
-                    } else {
-                        todo!();
-                    }
-                    state.pc += 1;
-                }
-                Insn::ResultRow {
-                    register_start,
-                    register_end,
-                } => {
-                    let record = make_record(&state.registers, register_end, register_start);
-                    state.pc += 1;
-                    return Ok(StepResult::Row(record));
-                }
-                Insn::NextAsync { cursor_id } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    match cursor.next()? {
-                        CursorResult::Ok(_) => {}
-                        CursorResult::IO => {
-                            // If there is I/O, the instruction is restarted.
-                            return Ok(StepResult::IO);
-                        }
-                    }
-                    state.pc += 1;
-                }
-                Insn::NextAwait {
-                    cursor_id,
-                    pc_if_next,
-                } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    cursor.wait_for_completion()?;
-                    if !cursor.is_empty() {
-                        state.pc = *pc_if_next;
-                    } else {
-                        state.pc += 1;
-                    }
+    // Replace the accumulator in acc_reg with the result of the aggregate.
+    AggFinal {
+        acc_reg: usize,
+        func: AggFunc,
+    },
+
+    // Jump to the given PC if lhs is equal to rhs.
+    //
+    // Comparisons involving NULL never take the jump. Like the other
+    // comparison instructions, text is compared with the collation, or
+    // BINARY if there is none.
+    Eq {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        collation: Option<Collation>,
+    },
+
+    // Jump to the given PC if lhs is not equal to rhs.
+    Ne {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        collation: Option<Collation>,
+    },
+
+    // Jump to the given PC if lhs is less than rhs.
+    Lt {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        collation: Option<Collation>,
+    },
+
+    // Jump to the given PC if lhs is less than or equal to rhs.
+    Le {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        collation: Option<Collation>,
+    },
+
+    // Jump to the given PC if lhs is greater than rhs.
+    Gt {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        collation: Option<Collation>,
+    },
+
+    // Jump to the given PC if lhs is greater than or equal to rhs.
+    Ge {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        collation: Option<Collation>,
+    },
+
+    // Copy the registers [src_reg, src_reg + amount] to the registers
+    // starting at dst_reg.
+    Copy {
+        src_reg: usize,
+        dst_reg: usize,
+        amount: usize,
+    },
+
+    // Move the registers [src_reg, src_reg + count) to the registers
+    // starting at dst_reg, leaving NULL in the source registers that are
+    // not part of the destination range.
+    Move {
+        src_reg: usize,
+        dst_reg: usize,
+        count: usize,
+    },
+
+    // If the given register is a positive integer, decrement it by the given
+    // amount and jump to the given PC. Otherwise, fall through.
+    IfPos {
+        reg: usize,
+        target_pc: BranchOffset,
+        decrement: usize,
+    },
+
+    // Jump to the given PC if the register holds NULL.
+    IsNull {
+        reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Jump to the given PC if the register does not hold NULL.
+    NotNull {
+        reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Compare the registers [lhs_start, lhs_start + count) with the
+    // registers [rhs_start, rhs_start + count) one by one, and save whether
+    // the first run sorts before, equal to, or after the second for a
+    // following Jump. Each column is compared with its collation in
+    // collations, or BINARY if there is none. NULL values are equal.
+    Compare {
+        lhs_start: usize,
+        rhs_start: usize,
+        count: usize,
+        collations: Vec<Collation>,
+    },
+
+    // Jump to one of three PCs depending on the result of the most recent
+    // comparison instruction (Eq, Ne, Lt, Le, Gt, Ge, or Compare): target_lt
+    // if the left-hand side was smaller, target_eq if the values were equal,
+    // and target_gt if it was greater. NULL sorts before all other values.
+    Jump {
+        target_lt: BranchOffset,
+        target_eq: BranchOffset,
+        target_gt: BranchOffset,
+    },
+
+    // Seek the cursor to the row with the rowid stored in the given register.
+    // If there is no such row, jump to the given PC.
+    SeekRowid {
+        cursor_id: CursorID,
+        src_reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Seek the cursor to the first entry that is greater than or equal to the
+    // key built from the registers [key_reg, key_reg + num_regs). If there is
+    // no such entry, jump to the given PC.
+    SeekGE {
+        cursor_id: CursorID,
+        key_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Seek the cursor to the first entry that is greater than the key built
+    // from the registers [key_reg, key_reg + num_regs). If there is no such
+    // entry, jump to the given PC.
+    SeekGT {
+        cursor_id: CursorID,
+        key_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Jump to the given PC if the index has an entry whose leading fields
+    // equal the key built from the registers [key_reg, key_reg + num_regs).
+    // The cursor is left at the first entry at or after the key.
+    Found {
+        cursor_id: CursorID,
+        key_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Like Found, but jump if the index has no entry equal to the key.
+    NotFound {
+        cursor_id: CursorID,
+        key_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Compare the key of the current index entry with the key built from the
+    // registers [key_reg, key_reg + num_regs), and jump to the given PC if
+    // the index key is greater than or equal to it. Only the first num_regs
+    // fields of the index key are compared, so an entry whose leading fields
+    // equal the registers counts as equal whatever its rowid.
+    IdxGE {
+        cursor_id: CursorID,
+        key_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Like IdxGE, but jump if the index key is greater than the key built
+    // from the registers.
+    IdxGT {
+        cursor_id: CursorID,
+        key_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Read the rowid of the table row that the current index entry points
+    // to, which is the last field of the index key.
+    IdxRowid {
+        cursor_id: CursorID,
+        dest_reg: usize,
+    },
+
+    // Store the record of the current row of the cursor, in the record
+    // format, as a blob in the destination register.
+    RowData {
+        cursor_id: CursorID,
+        dest_reg: usize,
+    },
+
+    // Move the cursor to the last entry of the B-Tree.
+    LastAsync {
+        cursor_id: CursorID,
+    },
+
+    // Await for the completion of moving the cursor to the last entry.
+    LastAwait {
+        cursor_id: CursorID,
+        pc_if_empty: BranchOffset,
+    },
+
+    // Move the cursor to the previous row.
+    PrevAsync {
+        cursor_id: CursorID,
+    },
+
+    // Await for the completion of moving the cursor to the previous row.
+    PrevAwait {
+        cursor_id: CursorID,
+        pc_if_next: BranchOffset,
+    },
+
+    // Jump to the given PC if the values of the registers
+    // [start_reg, start_reg + count) were already seen by a Distinct
+    // instruction with the same set_id during this run, and otherwise
+    // remember them and fall through. NULL values are equal to each other,
+    // and so are integers and reals with the same value.
+    Distinct {
+        set_id: usize,
+        start_reg: usize,
+        count: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Fall through to the next instruction the first time this instruction
+    // is executed during a program run, and jump to the given PC on every
+    // subsequent execution.
+    Once {
+        target_pc: BranchOffset,
+    },
+
+    // Write the address of the next instruction into the return register and
+    // jump to the subroutine at the given PC.
+    Gosub {
+        return_reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Jump to the address stored in the return register by a Gosub.
+    Return {
+        return_reg: usize,
+    },
+
+    // Swap the current PC with the address stored in the yield register, which
+    // lets two instruction streams, such as a producer and a consumer of rows,
+    // take turns executing. The yield register is initialized with the start
+    // address of the co-routine, for example using Integer, and the first
+    // Yield then transfers control to it. Each subsequent Yield resumes the
+    // other stream right after its own last Yield.
+    //
+    // If the yield register is NULL, the co-routine has ended and control
+    // jumps to end_offset instead.
+    Yield {
+        yield_reg: usize,
+        end_offset: BranchOffset,
+    },
+
+    // End the co-routine whose resume address is stored in the yield register.
+    // This clears the yield register and jumps back to the Yield that last
+    // transferred control to the co-routine, which then jumps to its
+    // end_offset.
+    EndCoroutine {
+        yield_reg: usize,
+    },
+
+    // Insert the record in the record register into the table B-Tree with
+    // the rowid in the key register.
+    Insert {
+        cursor_id: CursorID,
+        key_reg: usize,
+        record_reg: usize,
+    },
+
+    // Make a record out of a range of registers and store it as a blob in the
+    // destination register.
+    MakeRecord {
+        start_reg: usize,
+        count: usize,
+        dest_reg: usize,
+    },
+
+    // Store a rowid one greater than the largest rowid in the table, or 1 if
+    // the table is empty, in the destination register.
+    NewRowid {
+        cursor_id: CursorID,
+        dest_reg: usize,
+    },
+
+    // Delete the row the cursor points at.
+    Delete {
+        cursor_id: CursorID,
+    },
+}
+
+/// Where a column of a `ResultRowRef` row is read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResultSource {
+    Register(usize),
+    Column { cursor_id: CursorID, column: usize },
+}
+
+impl std::fmt::Display for ResultSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResultSource::Register(reg) => write!(f, "r[{}]", reg),
+            ResultSource::Column { cursor_id, column } => {
+                write!(f, "cursor[{}].column[{}]", cursor_id, column)
+            }
+        }
+    }
+}
+
+impl Insn {
+    /// Returns the highest register index the instruction reads or writes, if
+    /// it accesses any registers.
+    fn max_register(&self) -> Option<usize> {
+        match self {
+            Insn::Init { .. }
+            | Insn::OpenIndexReadAsync { .. }
+            | Insn::Delete { .. }
+            | Insn::OpenWriteAsync { .. }
+            | Insn::OpenWriteAwait
+            | Insn::OpenReadAsync { .. }
+            | Insn::OpenReadAwait
+            | Insn::RewindAsync { .. }
+            | Insn::RewindAwait { .. }
+            | Insn::NextAsync { .. }
+            | Insn::NextAwait { .. }
+            | Insn::Halt
+            | Insn::HaltError { .. }
+            | Insn::Noop
+            | Insn::Transaction { .. }
+            | Insn::Commit
+            | Insn::Rollback
+            | Insn::Goto { .. }
+            | Insn::Jump { .. }
+            | Insn::LastAsync { .. }
+            | Insn::LastAwait { .. }
+            | Insn::PrevAsync { .. }
+            | Insn::PrevAwait { .. }
+            | Insn::Once { .. } => None,
+            Insn::IdxRowid { dest_reg, .. } | Insn::RowData { dest_reg, .. } => Some(*dest_reg),
+            Insn::Savepoint { name_reg }
+            | Insn::ReleaseSavepoint { name_reg }
+            | Insn::RollbackTo { name_reg } => Some(*name_reg),
+            Insn::SorterInsert { record_reg, .. } | Insn::IdxInsert { record_reg, .. } => {
+                Some(*record_reg)
+            }
+            Insn::Distinct {
+                start_reg, count, ..
+            } => register_range_max(*start_reg, *count),
+            Insn::SorterData { dest_reg, .. } => Some(*dest_reg),
+            Insn::OpenEphemeral { .. }
+            | Insn::SorterOpen { .. }
+            | Insn::SorterSort { .. }
+            | Insn::SorterNext { .. } => None,
+            Insn::NewRowid { dest_reg, .. } => Some(*dest_reg),
+            Insn::MakeRecord {
+                start_reg,
+                count,
+                dest_reg,
+            } => register_range_max(*start_reg, *count).max(Some(*dest_reg)),
+            Insn::Concat { lhs, rhs, dest } => Some(*lhs.max(rhs).max(dest)),
+            Insn::Cast { reg, .. } => Some(*reg),
+            Insn::Compare {
+                lhs_start,
+                rhs_start,
+                count,
+                ..
+            } => register_range_max(*lhs_start, *count).max(register_range_max(*rhs_start, *count)),
+            Insn::Affinity {
+                start_reg, count, ..
+            } => register_range_max(*start_reg, *count),
+            Insn::Function {
+                start_reg,
+                arg_count,
+                dest_reg,
+                ..
+            } => register_range_max(*start_reg, *arg_count).max(Some(*dest_reg)),
+            Insn::AggStep {
+                func,
+                arg_reg,
+                acc_reg,
+            } => register_range_max(*acc_reg, func.accumulator_size()).max(Some(*arg_reg)),
+            Insn::AggFinal { acc_reg, func } => {
+                register_range_max(*acc_reg, func.accumulator_size())
+            }
+            Insn::Insert {
+                key_reg,
+                record_reg,
+                ..
+            } => Some(*key_reg.max(record_reg)),
+            Insn::Column { dest, .. }
+            | Insn::Integer { dest, .. }
+            | Insn::Real { dest, .. }
+            | Insn::String8 { dest, .. }
+            | Insn::Blob { dest, .. }
+            | Insn::Variable { dest, .. }
+            | Insn::RowId { dest, .. } => Some(*dest),
+            Insn::ResultRow {
+                register_start,
+                register_end,
+            } => register_end
+                .checked_sub(*register_start)
+                .and_then(|count| register_range_max(*register_start, count)),
+            Insn::ResultRowList { regs } => regs.iter().max().copied(),
+            Insn::ResultRowRef { sources } => sources
+                .iter()
+                .filter_map(|source| match source {
+                    ResultSource::Register(reg) => Some(*reg),
+                    ResultSource::Column { .. } => None,
+                })
+                .max(),
+            Insn::Null { dest_end, .. } => Some(*dest_end),
+            Insn::DecrJumpZero { reg, .. }
+            | Insn::IfPos { reg, .. }
+            | Insn::IsNull { reg, .. }
+            | Insn::NotNull { reg, .. } => Some(*reg),
+            Insn::Add { lhs, rhs, dest }
+            | Insn::Subtract { lhs, rhs, dest }
+            | Insn::Multiply { lhs, rhs, dest }
+            | Insn::Divide { lhs, rhs, dest }
+            | Insn::Remainder { lhs, rhs, dest }
+            | Insn::BitAnd { lhs, rhs, dest }
+            | Insn::BitOr { lhs, rhs, dest }
+            | Insn::ShiftLeft { lhs, rhs, dest }
+            | Insn::ShiftRight { lhs, rhs, dest } => Some(*lhs.max(rhs).max(dest)),
+            Insn::BitNot { reg, dest } | Insn::Negative { reg, dest } | Insn::Not { reg, dest } => {
+                Some(*reg.max(dest))
+            }
+            Insn::Eq { lhs, rhs, .. }
+            | Insn::Ne { lhs, rhs, .. }
+            | Insn::Lt { lhs, rhs, .. }
+            | Insn::Le { lhs, rhs, .. }
+            | Insn::Gt { lhs, rhs, .. }
+            | Insn::Ge { lhs, rhs, .. } => Some(*lhs.max(rhs)),
+            Insn::Copy {
+                src_reg,
+                dst_reg,
+                amount,
+            } => Some(*src_reg.max(dst_reg) + *amount),
+            Insn::Move {
+                src_reg,
+                dst_reg,
+                count,
+            } => register_range_max(*src_reg.max(dst_reg), *count),
+            Insn::SeekRowid { src_reg, .. } => Some(*src_reg),
+            Insn::SeekGE {
+                key_reg, num_regs, ..
+            }
+            | Insn::SeekGT {
+                key_reg, num_regs, ..
+            }
+            | Insn::Found {
+                key_reg, num_regs, ..
+            }
+            | Insn::NotFound {
+                key_reg, num_regs, ..
+            }
+            | Insn::IdxGE {
+                key_reg, num_regs, ..
+            }
+            | Insn::IdxGT {
+                key_reg, num_regs, ..
+            } => register_range_max(*key_reg, *num_regs),
+            Insn::Gosub { return_reg, .. } | Insn::Return { return_reg } => Some(*return_reg),
+            Insn::Yield { yield_reg, .. } | Insn::EndCoroutine { yield_reg } => Some(*yield_reg),
+        }
+    }
+}
+
+impl Insn {
+    /// Returns the static branch targets of the instruction. Jumps to
+    /// addresses stored in registers, like Return and Yield, are not included.
+    fn branch_targets(&self) -> Vec<BranchOffset> {
+        match self {
+            Insn::Init { target_pc }
+            | Insn::Goto { target_pc }
+            | Insn::DecrJumpZero { target_pc, .. }
+            | Insn::Eq { target_pc, .. }
+            | Insn::Ne { target_pc, .. }
+            | Insn::Lt { target_pc, .. }
+            | Insn::Le { target_pc, .. }
+            | Insn::Gt { target_pc, .. }
+            | Insn::Ge { target_pc, .. }
+            | Insn::IfPos { target_pc, .. }
+            | Insn::IsNull { target_pc, .. }
+            | Insn::NotNull { target_pc, .. }
+            | Insn::SeekRowid { target_pc, .. }
+            | Insn::SeekGE { target_pc, .. }
+            | Insn::SeekGT { target_pc, .. }
+            | Insn::Found { target_pc, .. }
+            | Insn::NotFound { target_pc, .. }
+            | Insn::IdxGE { target_pc, .. }
+            | Insn::IdxGT { target_pc, .. }
+            | Insn::Distinct { target_pc, .. }
+            | Insn::Once { target_pc }
+            | Insn::Gosub { target_pc, .. } => vec![*target_pc],
+            Insn::RewindAwait { pc_if_empty, .. } | Insn::LastAwait { pc_if_empty, .. } => {
+                vec![*pc_if_empty]
+            }
+            Insn::NextAwait { pc_if_next, .. }
+            | Insn::PrevAwait { pc_if_next, .. }
+            | Insn::SorterNext { pc_if_next, .. } => vec![*pc_if_next],
+            Insn::SorterSort { pc_if_empty, .. } => vec![*pc_if_empty],
+            Insn::Jump {
+                target_lt,
+                target_eq,
+                target_gt,
+            } => vec![*target_lt, *target_eq, *target_gt],
+            Insn::Yield { end_offset, .. } => vec![*end_offset],
+            Insn::OpenReadAsync { .. }
+            | Insn::OpenReadAwait
+            | Insn::RowData { .. }
+            | Insn::ResultRowRef { .. }
+            | Insn::Variable { .. }
+            | Insn::ResultRowList { .. }
+            | Insn::Noop
+            | Insn::HaltError { .. }
+            | Insn::IdxInsert { .. }
+            | Insn::OpenEphemeral { .. }
+            | Insn::SorterOpen { .. }
+            | Insn::SorterInsert { .. }
+            | Insn::SorterData { .. }
+            | Insn::Compare { .. }
+            | Insn::Negative { .. }
+            | Insn::Not { .. }
+            | Insn::Remainder { .. }
+            | Insn::BitAnd { .. }
+            | Insn::BitOr { .. }
+            | Insn::ShiftLeft { .. }
+            | Insn::ShiftRight { .. }
+            | Insn::BitNot { .. }
+            | Insn::Affinity { .. }
+            | Insn::Cast { .. }
+            | Insn::AggStep { .. }
+            | Insn::AggFinal { .. }
+            | Insn::Concat { .. }
+            | Insn::Function { .. }
+            | Insn::IdxRowid { .. }
+            | Insn::OpenIndexReadAsync { .. }
+            | Insn::Delete { .. }
+            | Insn::NewRowid { .. }
+            | Insn::MakeRecord { .. }
+            | Insn::Insert { .. }
+            | Insn::OpenWriteAsync { .. }
+            | Insn::OpenWriteAwait
+            | Insn::RewindAsync { .. }
+            | Insn::Column { .. }
+            | Insn::ResultRow { .. }
+            | Insn::NextAsync { .. }
+            | Insn::Halt
+            | Insn::Transaction { .. }
+            | Insn::Commit
+            | Insn::Rollback
+            | Insn::Savepoint { .. }
+            | Insn::ReleaseSavepoint { .. }
+            | Insn::RollbackTo { .. }
+            | Insn::Integer { .. }
+            | Insn::Real { .. }
+            | Insn::Null { .. }
+            | Insn::String8 { .. }
+            | Insn::Blob { .. }
+            | Insn::RowId { .. }
+            | Insn::Add { .. }
+            | Insn::Subtract { .. }
+            | Insn::Multiply { .. }
+            | Insn::Divide { .. }
+            | Insn::Copy { .. }
+            | Insn::Move { .. }
+            | Insn::LastAsync { .. }
+            | Insn::PrevAsync { .. }
+            | Insn::Return { .. }
+            | Insn::EndCoroutine { .. } => vec![],
+        }
+    }
+
+    /// Returns the target of an instruction with a single static branch
+    /// target, such as a conditional jump.
+    fn jump_target_mut(&mut self) -> Option<&mut BranchOffset> {
+        match self {
+            Insn::Init { target_pc }
+            | Insn::Goto { target_pc }
+            | Insn::DecrJumpZero { target_pc, .. }
+            | Insn::Eq { target_pc, .. }
+            | Insn::Ne { target_pc, .. }
+            | Insn::Lt { target_pc, .. }
+            | Insn::Le { target_pc, .. }
+            | Insn::Gt { target_pc, .. }
+            | Insn::Ge { target_pc, .. }
+            | Insn::IfPos { target_pc, .. }
+            | Insn::IsNull { target_pc, .. }
+            | Insn::NotNull { target_pc, .. }
+            | Insn::SeekRowid { target_pc, .. }
+            | Insn::SeekGE { target_pc, .. }
+            | Insn::SeekGT { target_pc, .. }
+            | Insn::Found { target_pc, .. }
+            | Insn::NotFound { target_pc, .. }
+            | Insn::IdxGE { target_pc, .. }
+            | Insn::IdxGT { target_pc, .. }
+            | Insn::Distinct { target_pc, .. }
+            | Insn::Once { target_pc }
+            | Insn::Gosub { target_pc, .. } => Some(target_pc),
+            Insn::RewindAwait { pc_if_empty, .. }
+            | Insn::LastAwait { pc_if_empty, .. }
+            | Insn::SorterSort { pc_if_empty, .. } => Some(pc_if_empty),
+            Insn::NextAwait { pc_if_next, .. }
+            | Insn::PrevAwait { pc_if_next, .. }
+            | Insn::SorterNext { pc_if_next, .. } => Some(pc_if_next),
+            Insn::Yield { end_offset, .. } => Some(end_offset),
+            _ => None,
+        }
+    }
+
+    /// Whether a register range of the instruction ends before it starts.
+    fn has_inverted_register_range(&self) -> bool {
+        match self {
+            Insn::Null {
+                dest_start,
+                dest_end,
+            } => dest_end < dest_start,
+            Insn::ResultRow {
+                register_start,
+                register_end,
+            } => register_end < register_start,
+            _ => false,
+        }
+    }
+}
+
+/// Returns the highest register in a range of `count` registers starting at
+/// `start`, or `None` if the range is empty.
+fn register_range_max(start: usize, count: usize) -> Option<usize> {
+    (count > 0).then(|| start + count - 1)
+}
+
+/// Errors raised when building a malformed program.
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    /// The instruction at `offset` branches past the end of the program.
+    BranchOutOfRange { offset: usize, target: BranchOffset },
+    /// The instruction at `offset` branches to a placeholder that was never
+    /// fixed up.
+    BranchToPlaceholder { offset: usize, target: BranchOffset },
+    /// The jump at `offset` was emitted with `emit_jump_placeholder` and
+    /// never resolved.
+    UnresolvedJump { offset: usize },
+    /// The jump at `offset` branches to a label that was never resolved.
+    UnresolvedLabel { offset: usize, label: Label },
+    /// A register range of the instruction at `offset` ends before it
+    /// starts.
+    InvertedRegisterRange { offset: usize },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::BranchOutOfRange { offset, target } => write!(
+                f,
+                "instruction {} branches to {}, which is out of range",
+                offset, target
+            ),
+            BuildError::BranchToPlaceholder { offset, target } => write!(
+                f,
+                "instruction {} branches to {}, which is an unfilled placeholder",
+                offset, target
+            ),
+            BuildError::UnresolvedJump { offset } => {
+                write!(f, "jump at {} was never resolved", offset)
+            }
+            BuildError::UnresolvedLabel { offset, label } => write!(
+                f,
+                "instruction {} branches to label {}, which was never resolved",
+                offset, label.0
+            ),
+            BuildError::InvertedRegisterRange { offset } => write!(
+                f,
+                "instruction {} has a register range that ends before it starts",
+                offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A jump emitted with `ProgramBuilder::emit_jump_placeholder` whose target
+/// is filled in later with `ProgramBuilder::resolve_jump`.
+#[derive(Debug)]
+#[must_use]
+pub struct JumpRef {
+    offset: usize,
+}
+
+impl JumpRef {
+    /// The offset of the jump instruction.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// A branch target allocated with `ProgramBuilder::alloc_label`, which can be
+/// jumped to before or after it is bound to an offset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Label(usize);
+
+pub struct ProgramBuilder {
+    next_free_register: usize,
+    next_free_cursor_id: usize,
+    insns: Vec<Insn>,
+    // Offsets of placeholders that have not been fixed up yet.
+    placeholders: HashSet<usize>,
+    // Offsets of jumps whose target has not been resolved yet.
+    unresolved_jumps: HashSet<usize>,
+    // The offsets labels are bound to, indexed by label.
+    labels: Vec<Option<BranchOffset>>,
+    // Jumps to labels, which are patched when the program is built.
+    label_jumps: Vec<(usize, Label)>,
+    plan: Vec<PlanStep>,
+    comments: HashMap<usize, String>,
+    // The indexes of named parameters, and the highest parameter index.
+    parameter_names: HashMap<String, usize>,
+    max_parameter: usize,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self {
+            next_free_register: 0,
+            next_free_cursor_id: 0,
+            insns: Vec::new(),
+            placeholders: HashSet::new(),
+            unresolved_jumps: HashSet::new(),
+            labels: Vec::new(),
+            label_jumps: Vec::new(),
+            plan: Vec::new(),
+            comments: HashMap::new(),
+            parameter_names: HashMap::new(),
+            max_parameter: 0,
+        }
+    }
+
+    pub fn alloc_register(&mut self) -> usize {
+        let reg = self.next_free_register;
+        self.next_free_register += 1;
+        reg
+    }
+
+    /// Allocate `count` contiguous registers and return the first one.
+    #[allow(dead_code)] // used in tests
+    pub fn alloc_registers(&mut self, count: usize) -> usize {
+        let reg = self.next_free_register;
+        self.next_free_register += count;
+        reg
+    }
+
+    #[allow(dead_code)] // used in tests
+    pub fn next_free_register(&self) -> usize {
+        self.next_free_register
+    }
+
+    pub fn alloc_cursor_id(&mut self) -> usize {
+        let cursor = self.next_free_cursor_id;
+        self.next_free_cursor_id += 1;
+        cursor
+    }
+
+    pub fn emit_placeholder(&mut self) -> usize {
+        let offset = self.insns.len();
+        self.insns.push(Insn::Halt);
+        self.placeholders.insert(offset);
+        offset
+    }
+
+    /// Emit a jump whose target is not known yet. The branch target of `insn`
+    /// is ignored until it is set with `resolve_jump`.
+    ///
+    /// Panics if `insn` does not have a single branch target.
+    pub fn emit_jump_placeholder(&mut self, mut insn: Insn) -> JumpRef {
+        assert!(
+            insn.jump_target_mut().is_some(),
+            "instruction has no single branch target"
+        );
+        let offset = self.insns.len();
+        self.insns.push(insn);
+        self.unresolved_jumps.insert(offset);
+        JumpRef { offset }
+    }
+
+    /// Set the branch target of a jump emitted with `emit_jump_placeholder`.
+    pub fn resolve_jump(&mut self, jump: JumpRef, target: BranchOffset) {
+        let target_pc = self.insns[jump.offset]
+            .jump_target_mut()
+            .expect("jump placeholder has a branch target");
+        *target_pc = target;
+        self.unresolved_jumps.remove(&jump.offset);
+    }
+
+    pub fn alloc_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind a label to the offset of the next instruction.
+    ///
+    /// Panics if the label is already bound.
+    pub fn resolve_label(&mut self, label: Label) {
+        let offset = self.insns.len();
+        let bound = &mut self.labels[label.0];
+        assert!(bound.is_none(), "label {} is already resolved", label.0);
+        *bound = Some(offset);
+    }
+
+    /// Emit a jump to a label. The branch target of `insn` is ignored and
+    /// replaced with the offset of the label when the program is built.
+    ///
+    /// Panics if `insn` does not have a single branch target.
+    pub fn emit_jump(&mut self, mut insn: Insn, label: Label) {
+        assert!(
+            insn.jump_target_mut().is_some(),
+            "instruction has no single branch target"
+        );
+        self.label_jumps.push((self.insns.len(), label));
+        self.insns.push(insn);
+    }
+
+    /// Returns the 1-based index of a statement parameter, given its name as
+    /// written after the `?` of a numbered or anonymous parameter, or with its
+    /// prefix for a named one like `:name`. An anonymous parameter and a new
+    /// name get the index after the highest one so far, and a name that was
+    /// seen before gets the same index again.
+    pub fn alloc_parameter(&mut self, name: &str) -> Result<usize> {
+        if name.is_empty() {
+            self.max_parameter += 1;
+            return Ok(self.max_parameter);
+        }
+        if name.bytes().all(|b| b.is_ascii_digit()) {
+            let index: usize = name.parse()?;
+            if index == 0 {
+                anyhow::bail!("variable number must be at least 1");
+            }
+            self.max_parameter = self.max_parameter.max(index);
+            return Ok(index);
+        }
+        if let Some(index) = self.parameter_names.get(name) {
+            return Ok(*index);
+        }
+        self.max_parameter += 1;
+        self.parameter_names
+            .insert(name.to_string(), self.max_parameter);
+        Ok(self.max_parameter)
+    }
+
+    /// Record a step of the query plan reported by `Program::query_plan`.
+    pub fn add_plan_step(&mut self, step: PlanStep) {
+        self.plan.push(step);
+    }
+
+    pub fn emit_insn(&mut self, insn: Insn) {
+        self.insns.push(insn);
+    }
+
+    /// Emit an instruction with a comment that replaces the generated one in
+    /// the EXPLAIN listing.
+    pub fn emit_insn_with_comment(&mut self, insn: Insn, comment: String) {
+        self.comments.insert(self.insns.len(), comment);
+        self.insns.push(insn);
+    }
+
+    /// Replace the instruction at `offset`. An instruction is removed by
+    /// replacing it with `Insn::Noop`, which keeps branch targets valid.
+    pub fn fixup_insn(&mut self, offset: usize, insn: Insn) {
+        self.insns[offset] = insn;
+        self.placeholders.remove(&offset);
+    }
+
+    pub fn offset(&self) -> usize {
+        self.insns.len()
+    }
+
+    /// Build the program, checking that every branch targets an instruction
+    /// that has been emitted and is not a placeholder waiting for a fixup,
+    /// and that every jump placeholder and label has been resolved.
+    pub fn build(mut self) -> Result<Program, BuildError> {
+        if let Some(&offset) = self.unresolved_jumps.iter().min() {
+            return Err(BuildError::UnresolvedJump { offset });
+        }
+        for &(offset, label) in &self.label_jumps {
+            let target = match self.labels[label.0] {
+                Some(target) => target,
+                None => return Err(BuildError::UnresolvedLabel { offset, label }),
+            };
+            *self.insns[offset].jump_target_mut().unwrap() = target;
+        }
+        for (offset, insn) in self.insns.iter().enumerate() {
+            if insn.has_inverted_register_range() {
+                return Err(BuildError::InvertedRegisterRange { offset });
+            }
+            for target in insn.branch_targets() {
+                if target >= self.insns.len() {
+                    return Err(BuildError::BranchOutOfRange { offset, target });
                 }
-                Insn::Halt => {
-                    return Ok(StepResult::Done);
+                if self.placeholders.contains(&target) {
+                    return Err(BuildError::BranchToPlaceholder { offset, target });
+                }
+            }
+        }
+        Ok(Program {
+            max_registers: self.next_free_register,
+            insns: self.insns,
+            plan: self.plan,
+            comments: self.comments,
+            parameter_names: Rc::new(self.parameter_names),
+        })
+    }
+}
+
+pub enum StepResult<'a> {
+    Done,
+    IO,
+    Row(Record<'a>),
+    /// The program stopped before completing the current instruction, and
+    /// can be resumed by stepping it again.
+    Interrupted,
+}
+
+/// What happened when executing a single instruction with
+/// `Program::step_single`.
+pub enum StepOutcome<'a> {
+    /// The instruction was executed and the program continues at `pc`.
+    Next { pc: usize },
+    /// The instruction produced a result row.
+    Row(Record<'a>),
+    /// The instruction needs I/O to complete and is restarted on the next
+    /// step.
+    IO,
+    /// The program halted.
+    Done,
+}
+
+/// Where the program stopped when stepped with `Program::step_to_row`.
+#[derive(Debug, PartialEq)]
+pub enum RowStep {
+    /// The next instruction emits a row, which the next step returns.
+    Row,
+    IO,
+    Done,
+    Interrupted,
+}
+
+/// The result of the most recent comparison instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Comparison {
+    /// The sort order of the compared values, where NULL sorts first.
+    pub ordering: Ordering,
+    /// Whether either of the compared values was NULL.
+    pub has_null: bool,
+}
+
+/// Where an open cursor is positioned, as reported by
+/// `ProgramState::cursor_positions`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CursorPosition {
+    pub cursor_id: CursorID,
+    /// The rowid of the current row, if the cursor is on a row that has one.
+    pub rowid: Option<u64>,
+    /// Whether the cursor is past the last row, or has not been positioned.
+    pub at_eof: bool,
+}
+
+/// The program state describes the environment in which the program executes.
+pub struct ProgramState {
+    pub pc: usize,
+    cursors: RefCell<BTreeMap<usize, Box<dyn Cursor>>>,
+    registers: Vec<OwnedValue>,
+    /// The result of the last comparison, which is what the `Jump`
+    /// instruction branches on.
+    last_compare: Option<Comparison>,
+    /// The addresses of the `Once` instructions executed during this run.
+    once: HashSet<BranchOffset>,
+    /// The keys seen by `Distinct` instructions, by set.
+    distinct: HashMap<usize, HashSet<Vec<u8>>>,
+    /// Set to request the program to stop at the next instruction boundary.
+    interrupt: Arc<AtomicBool>,
+    /// How many times each instruction was executed, by PC, if profiling is
+    /// enabled.
+    insn_counts: Option<Vec<u64>>,
+    /// The values bound to the statement parameters, by index minus one.
+    parameters: Vec<OwnedValue>,
+    parameter_names: Rc<HashMap<String, usize>>,
+    /// The transaction started by a `Transaction` instruction. It is rolled
+    /// back if the state is reset before it is committed.
+    transaction: Option<Transaction>,
+    /// The functions the application registered, which `Function`
+    /// instructions call if the function is not built in.
+    functions: Rc<RefCell<FunctionRegistry>>,
+}
+
+impl ProgramState {
+    pub fn new(max_registers: usize) -> Self {
+        let cursors = RefCell::new(BTreeMap::new());
+        let mut registers = Vec::with_capacity(max_registers);
+        registers.resize(max_registers, OwnedValue::Null);
+        Self {
+            pc: 0,
+            cursors,
+            registers,
+            last_compare: None,
+            once: HashSet::new(),
+            distinct: HashMap::new(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            insn_counts: None,
+            parameters: Vec::new(),
+            parameter_names: Rc::new(HashMap::new()),
+            transaction: None,
+            functions: Rc::new(RefCell::new(FunctionRegistry::new())),
+        }
+    }
+
+    /// Rewind the state to run the program again from the start. Cursors are
+    /// closed, an open transaction is rolled back and registers are set to
+    /// NULL, reusing their allocation, while parameter bindings and profiling
+    /// counts are kept.
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.cursors.get_mut().clear();
+        self.registers.fill(OwnedValue::Null);
+        self.last_compare = None;
+        self.once.clear();
+        self.distinct.clear();
+        self.interrupt.store(false, AtomicOrdering::SeqCst);
+        self.transaction = None;
+    }
+
+    /// Create the state for running a program, which knows the names of the
+    /// program's parameters.
+    pub fn for_program(program: &Program) -> Self {
+        let mut state = Self::new(program.max_registers);
+        state.parameter_names = program.parameter_names.clone();
+        state
+    }
+
+    pub fn set_functions(&mut self, functions: Rc<RefCell<FunctionRegistry>>) {
+        self.functions = functions;
+    }
+
+    /// Bind a value to the parameter with the given 1-based index.
+    pub fn bind(&mut self, index: usize, value: OwnedValue) -> Result<(), LimboError> {
+        if index == 0 {
+            return Err(LimboError::Internal(
+                "parameter index must be at least 1".to_string(),
+            ));
+        }
+        if self.parameters.len() < index {
+            self.parameters.resize(index, OwnedValue::Null);
+        }
+        self.parameters[index - 1] = value;
+        Ok(())
+    }
+
+    /// Bind a value to a named parameter, whose name includes its prefix
+    /// like `:name` or `@name`.
+    pub fn bind_named(&mut self, name: &str, value: OwnedValue) -> Result<(), LimboError> {
+        match self.parameter_index(name) {
+            Some(index) => self.bind(index, value),
+            None => Err(LimboError::Internal(format!("no such parameter: {}", name))),
+        }
+    }
+
+    /// Returns the index of a named parameter, like
+    /// `sqlite3_bind_parameter_index`.
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameter_names.get(name).copied()
+    }
+
+    /// Count how many times each instruction is executed from now on. An
+    /// instruction that is restarted after I/O is counted again.
+    pub fn enable_profiling(&mut self) {
+        self.insn_counts.get_or_insert_with(Vec::new);
+    }
+
+    /// Returns how many times each instruction was executed, by PC. The
+    /// counts are empty if profiling is not enabled, and end at the last
+    /// instruction that was executed.
+    pub fn insn_counts(&self) -> &[u64] {
+        self.insn_counts.as_deref().unwrap_or(&[])
+    }
+
+    /// Request the program to stop before executing its next instruction.
+    /// The step in progress returns `StepResult::Interrupted`.
+    pub fn interrupt(&self) {
+        self.interrupt.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Returns a handle that can be used to interrupt the program from
+    /// another thread or a signal handler.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn column(&self, i: usize) -> Option<String> {
+        match &self.registers[i] {
+            OwnedValue::Blob(b) => Some(format!("x'{}'", to_hex(b))),
+            value => Some(format!("{:?}", value)),
+        }
+    }
+
+    /// Returns the position of each open cursor, in cursor id order.
+    pub fn cursor_positions(&self) -> Result<Vec<CursorPosition>, LimboError> {
+        let cursors = self.cursors.borrow();
+        let mut positions = Vec::with_capacity(cursors.len());
+        for (cursor_id, cursor) in cursors.iter() {
+            positions.push(CursorPosition {
+                cursor_id: *cursor_id,
+                rowid: *cursor.rowid()?,
+                at_eof: cursor.is_empty(),
+            });
+        }
+        Ok(positions)
+    }
+
+    /// Returns the value of a register, or `None` if it is out of range.
+    pub fn register(&self, i: usize) -> Option<&OwnedValue> {
+        self.registers.get(i)
+    }
+
+    /// Returns a copy of all registers, such as for a debugger to show
+    /// between instructions.
+    pub fn registers_snapshot(&self) -> Vec<OwnedValue> {
+        self.registers.clone()
+    }
+}
+
+/// A pool of program states, which lets a connection reuse the register
+/// allocations of finished statements for new ones.
+#[derive(Default)]
+pub struct ProgramStatePool {
+    free: Vec<ProgramState>,
+}
+
+impl ProgramStatePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a state for running `program`, reusing a released state if
+    /// there is one.
+    pub fn acquire(&mut self, program: &Program) -> ProgramState {
+        let mut state = match self.free.pop() {
+            Some(state) => state,
+            None => return ProgramState::for_program(program),
+        };
+        state.reset();
+        state
+            .registers
+            .resize(program.max_registers, OwnedValue::Null);
+        // A handle to the old interrupt flag must not interrupt the new
+        // statement.
+        state.interrupt = Arc::new(AtomicBool::new(false));
+        state.insn_counts = None;
+        state.parameters.clear();
+        state.parameter_names = program.parameter_names.clone();
+        state
+    }
+
+    /// Return a state to the pool once its statement is finished.
+    pub fn release(&mut self, mut state: ProgramState) {
+        // The lock of an unfinished transaction must not outlive the
+        // statement.
+        state.transaction = None;
+        self.free.push(state);
+    }
+}
+
+pub struct Program {
+    pub max_registers: usize,
+    pub insns: Vec<Insn>,
+    pub plan: Vec<PlanStep>,
+    /// Comments attached by the code generator, indexed by instruction
+    /// offset, which replace the generated EXPLAIN comments.
+    pub comments: HashMap<usize, String>,
+    /// The indexes of the named parameters of the statement.
+    pub parameter_names: Rc<HashMap<String, usize>>,
+}
+
+/// A step of the high-level plan of a query, as listed by EXPLAIN QUERY
+/// PLAN.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlanStep {
+    /// Visit every row of a table through a cursor.
+    ScanTable { table: String, cursor_id: CursorID },
+}
+
+impl std::fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanStep::ScanTable { table, .. } => write!(f, "SCAN TABLE {}", table),
+        }
+    }
+}
+
+/// An instruction of a program decomposed into the columns of an EXPLAIN
+/// listing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainRow {
+    pub addr: usize,
+    pub opcode: &'static str,
+    pub p1: usize,
+    pub p2: usize,
+    pub p3: usize,
+    pub p4: String,
+    pub p5: u16,
+    pub comment: String,
+}
+
+impl Program {
+    pub fn explain(&self) {
+        print!("{}", self.explain_to_string());
+    }
+
+    /// Returns the program listing printed by `explain`, one line per
+    /// instruction after a header.
+    pub fn explain_to_string(&self) -> String {
+        let mut s = String::new();
+        s.push_str("addr  opcode         p1    p2    p3    p4             p5  comment\n");
+        s.push_str("----  -------------  ----  ----  ----  -------------  --  -------\n");
+        for (addr, insn) in self.insns.iter().enumerate() {
+            s.push_str(&insn_to_str(addr, insn, self.comment(addr)));
+            s.push('\n');
+        }
+        s
+    }
+
+    /// Returns the high-level plan of the query, in the order the steps
+    /// were added by the code generator.
+    pub fn query_plan(&self) -> &[PlanStep] {
+        &self.plan
+    }
+
+    /// Returns the rows of the program listing, one per instruction.
+    pub fn explain_rows(&self) -> Vec<ExplainRow> {
+        self.insns
+            .iter()
+            .enumerate()
+            .map(|(addr, insn)| explain_insn(addr, insn, self.comment(addr)))
+            .collect()
+    }
+
+    fn comment(&self, addr: usize) -> Option<&str> {
+        self.comments.get(&addr).map(String::as_str)
+    }
+
+    pub fn step<'a>(
+        &self,
+        state: &'a mut ProgramState,
+        pager: Rc<Pager>,
+    ) -> Result<StepResult<'a>, LimboError> {
+        self.step_bounded(state, pager, None)
+    }
+
+    /// Execute the instruction at the current PC and report what happened,
+    /// which lets a debugger inspect the state between instructions.
+    pub fn step_single<'a>(
+        &self,
+        state: &'a mut ProgramState,
+        pager: Rc<Pager>,
+    ) -> Result<StepOutcome<'a>, LimboError> {
+        let pc = state.pc;
+        if is_result_row(&self.insns[pc]) {
+            // The row borrows the state until it is returned, so the PC is
+            // not read back from the state here. The step is only interrupted
+            // without emitting the row if an interrupt was pending.
+            return Ok(match self.step_bounded(state, pager, Some(1))? {
+                StepResult::Row(record) => StepOutcome::Row(record),
+                StepResult::IO => StepOutcome::IO,
+                StepResult::Done => StepOutcome::Done,
+                StepResult::Interrupted => StepOutcome::Next { pc },
+            });
+        }
+        match self.step_bounded(state, pager, Some(1))? {
+            StepResult::Row(_) => unreachable!("only result row instructions produce rows"),
+            StepResult::IO => Ok(StepOutcome::IO),
+            StepResult::Done => Ok(StepOutcome::Done),
+            StepResult::Interrupted => Ok(StepOutcome::Next { pc: state.pc }),
+        }
+    }
+
+    /// Step the program until the next instruction emits a row, without
+    /// executing it. Unlike the result of `step`, the result does not borrow
+    /// the state, so the caller can run I/O and step again in a loop before
+    /// stepping once more for the row.
+    pub fn step_to_row(
+        &self,
+        state: &mut ProgramState,
+        pager: Rc<Pager>,
+    ) -> Result<RowStep, LimboError> {
+        let result = match self.execute(state, pager, None, true)? {
+            StepResult::Row(_) => unreachable!("rows are not emitted when stepping to a row"),
+            StepResult::IO => RowStep::IO,
+            StepResult::Done => RowStep::Done,
+            StepResult::Interrupted => RowStep::Interrupted,
+        };
+        if result == RowStep::Interrupted && is_result_row(&self.insns[state.pc]) {
+            return Ok(RowStep::Row);
+        }
+        Ok(result)
+    }
+
+    /// Step the program, executing at most `max_insns` instructions before
+    /// returning `StepResult::Interrupted`. If `max_insns` is `None`, the
+    /// program runs until it emits a row, needs I/O, or halts.
+    pub fn step_bounded<'a>(
+        &self,
+        state: &'a mut ProgramState,
+        pager: Rc<Pager>,
+        max_insns: Option<usize>,
+    ) -> Result<StepResult<'a>, LimboError> {
+        self.execute(state, pager, max_insns, false)
+    }
+
+    /// Like `step_bounded`, but if `stop_before_row` is set, the program is
+    /// interrupted before an instruction that emits a row.
+    fn execute<'a>(
+        &self,
+        state: &'a mut ProgramState,
+        pager: Rc<Pager>,
+        max_insns: Option<usize>,
+        stop_before_row: bool,
+    ) -> Result<StepResult<'a>, LimboError> {
+        let mut executed = 0;
+        loop {
+            if max_insns.is_some_and(|max_insns| executed >= max_insns) {
+                return Ok(StepResult::Interrupted);
+            }
+            if stop_before_row && is_result_row(&self.insns[state.pc]) {
+                return Ok(StepResult::Interrupted);
+            }
+            if state.interrupt.swap(false, AtomicOrdering::SeqCst) {
+                return Ok(StepResult::Interrupted);
+            }
+            executed += 1;
+            let insn = &self.insns[state.pc];
+            trace_insn(state.pc, insn, self.comment(state.pc));
+            if let Some(counts) = &mut state.insn_counts {
+                if counts.len() <= state.pc {
+                    counts.resize(state.pc + 1, 0);
+                }
+                counts[state.pc] += 1;
+            }
+            if let Some(index) = insn.max_register() {
+                if index >= state.registers.len() {
+                    return Err(LimboError::RegisterOutOfRange {
+                        index,
+                        max: state.registers.len(),
+                    });
+                }
+            }
+            let mut cursors = state.cursors.borrow_mut();
+            match insn {
+                Insn::Init { target_pc } => {
+                    state.once.clear();
+                    state.pc = *target_pc;
+                }
+                Insn::OpenReadAsync {
+                    cursor_id,
+                    root_page,
+                } => {
+                    let cursor = Box::new(BTreeCursor::new(pager.clone(), *root_page));
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
+                }
+                Insn::OpenReadAwait => {
+                    state.pc += 1;
+                }
+                Insn::OpenEphemeral {
+                    cursor_id,
+                    num_columns,
+                    is_index,
+                } => {
+                    let cursor = if *is_index {
+                        EphemeralCursor::new_index(*num_columns)
+                    } else {
+                        EphemeralCursor::new(*num_columns)
+                    };
+                    cursors.insert(*cursor_id, Box::new(cursor));
+                    state.pc += 1;
+                }
+                Insn::IdxInsert {
+                    cursor_id,
+                    record_reg,
+                } => {
+                    let payload = match &state.registers[*record_reg] {
+                        OwnedValue::Blob(payload) => payload.clone(),
+                        _ => {
+                            return Err(LimboError::TypeMismatch(
+                                "IdxInsert record must be a blob".to_string(),
+                            ))
+                        }
+                    };
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    if !cursor.is_writable() {
+                        return Err(LimboError::Internal(format!(
+                            "cursor {} is not writable",
+                            cursor_id
+                        )));
+                    }
+                    match cursor.insert(0, &payload)? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::SorterOpen { cursor_id, keys } => {
+                    let cursor = Box::new(Sorter::new(keys.clone()));
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
+                }
+                Insn::SorterInsert {
+                    cursor_id,
+                    record_reg,
+                } => {
+                    let payload = match &state.registers[*record_reg] {
+                        OwnedValue::Blob(payload) => payload.clone(),
+                        _ => {
+                            return Err(LimboError::TypeMismatch(
+                                "SorterInsert record must be a blob".to_string(),
+                            ))
+                        }
+                    };
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.insert(0, &payload)?;
+                    state.pc += 1;
+                }
+                Insn::SorterSort {
+                    cursor_id,
+                    pc_if_empty,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.rewind()?;
+                    if cursor.is_empty() {
+                        state.pc = *pc_if_empty;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::SorterData {
+                    cursor_id,
+                    dest_reg,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    state.registers[*dest_reg] = match *cursor.record()? {
+                        Some(ref record) => {
+                            OwnedValue::Blob(Rc::new(record.serialize(TextEncoding::Utf8)))
+                        }
+                        None => OwnedValue::Null,
+                    };
+                    state.pc += 1;
+                }
+                Insn::SorterNext {
+                    cursor_id,
+                    pc_if_next,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.next()?;
+                    if cursor.is_empty() {
+                        state.pc += 1;
+                    } else {
+                        state.pc = *pc_if_next;
+                    }
+                }
+                Insn::OpenIndexReadAsync {
+                    cursor_id,
+                    root_page,
+                } => {
+                    let cursor = Box::new(BTreeCursor::new_index(pager.clone(), *root_page));
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
+                }
+                Insn::OpenWriteAsync {
+                    cursor_id,
+                    root_page,
+                } => {
+                    let cursor = Box::new(BTreeCursor::new_writable(pager.clone(), *root_page));
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
+                }
+                Insn::OpenWriteAwait => {
+                    state.pc += 1;
+                }
+                Insn::RewindAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.rewind()? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::RewindAwait {
+                    cursor_id,
+                    pc_if_empty,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    if cursor.is_empty() {
+                        state.pc = *pc_if_empty;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Column {
+                    cursor_id,
+                    column,
+                    dest,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    // Reading a column without a current row, or past the end of a
+                    // record that predates the column, yields NULL.
+                    state.registers[*dest] = match *cursor.record()? {
+                        Some(ref record) => record
+                            .values
+                            .get(*column)
+                            .cloned()
+                            .unwrap_or(OwnedValue::Null),
+                        None => OwnedValue::Null,
+                    };
+                    state.pc += 1;
+                }
+                Insn::ResultRow {
+                    register_start,
+                    register_end,
+                } => {
+                    let record = make_record(&state.registers, register_end, register_start);
+                    state.pc += 1;
+                    return Ok(StepResult::Row(record));
+                }
+                Insn::ResultRowList { regs } => {
+                    let values = regs
+                        .iter()
+                        .map(|reg| crate::types::to_value(&state.registers[*reg]))
+                        .collect();
+                    state.pc += 1;
+                    return Ok(StepResult::Row(Record::new(values)));
+                }
+                Insn::ResultRowRef { sources } => {
+                    // The row borrows the records of the cursors for as long
+                    // as it borrows the state, which the guard cannot.
+                    drop(cursors);
+                    let records: Vec<(CursorID, Option<&OwnedRecord>)> = state
+                        .cursors
+                        .get_mut()
+                        .iter_mut()
+                        .map(|(cursor_id, cursor)| (*cursor_id, cursor.current_record()))
+                        .collect();
+                    let mut values = Vec::with_capacity(sources.len());
+                    for source in sources {
+                        let value = match source {
+                            ResultSource::Register(reg) => {
+                                crate::types::to_value(&state.registers[*reg])
+                            }
+                            ResultSource::Column { cursor_id, column } => {
+                                match records.iter().find(|(id, _)| id == cursor_id) {
+                                    Some((_, Some(record))) => record
+                                        .values
+                                        .get(*column)
+                                        .map(crate::types::to_value)
+                                        .unwrap_or(Value::Null),
+                                    Some((_, None)) => Value::Null,
+                                    None => {
+                                        return Err(LimboError::Internal(format!(
+                                            "cursor {} is not open",
+                                            cursor_id
+                                        )))
+                                    }
+                                }
+                            }
+                        };
+                        values.push(value);
+                    }
+                    state.pc += 1;
+                    return Ok(StepResult::Row(Record::new(values)));
+                }
+                Insn::NextAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.next()? {
+                        CursorResult::Ok(_) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::NextAwait {
+                    cursor_id,
+                    pc_if_next,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    if !cursor.is_empty() {
+                        state.pc = *pc_if_next;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Noop => {
+                    state.pc += 1;
+                }
+                Insn::Variable { index, dest } => {
+                    state.registers[*dest] = state
+                        .parameters
+                        .get(index.wrapping_sub(1))
+                        .cloned()
+                        .unwrap_or(OwnedValue::Null);
+                    state.pc += 1;
+                }
+                Insn::Halt => {
+                    // A transaction that is still open when the program
+                    // halts is committed.
+                    if let Some(transaction) = &mut state.transaction {
+                        if let CursorResult::IO = transaction.commit()? {
+                            return Ok(StepResult::IO);
+                        }
+                        state.transaction = None;
+                    }
+                    return Ok(StepResult::Done);
+                }
+                Insn::HaltError { err_code, message } => {
+                    state.transaction = None;
+                    return Err(LimboError::Constraint {
+                        err_code: *err_code,
+                        message: message.clone(),
+                    });
+                }
+                Insn::Transaction { write } => {
+                    let mode = if *write {
+                        TransactionMode::Write
+                    } else {
+                        TransactionMode::Read
+                    };
+                    let held = state.transaction.as_ref().map(|tx| tx.mode());
+                    if held.is_none() || (held == Some(TransactionMode::Read) && *write) {
+                        // An upgraded read transaction is ended once the write
+                        // transaction has its lock.
+                        state.transaction = Some(pager.begin_transaction(mode)?);
+                    }
+                    state.pc += 1;
+                }
+                Insn::Commit => {
+                    let transaction = match &mut state.transaction {
+                        Some(transaction) => transaction,
+                        None => {
+                            return Err(LimboError::Internal(
+                                "cannot commit - no transaction is active".to_string(),
+                            ))
+                        }
+                    };
+                    if let CursorResult::IO = transaction.commit()? {
+                        // If there is I/O, the instruction is restarted.
+                        return Ok(StepResult::IO);
+                    }
+                    state.transaction = None;
+                    state.pc += 1;
+                }
+                Insn::Rollback => {
+                    if state.transaction.take().is_none() {
+                        return Err(LimboError::Internal(
+                            "cannot rollback - no transaction is active".to_string(),
+                        ));
+                    }
+                    state.pc += 1;
+                }
+                Insn::Savepoint { name_reg } => {
+                    match state.transaction.as_ref().map(|tx| tx.mode()) {
+                        Some(TransactionMode::Write) => {}
+                        _ => {
+                            return Err(LimboError::Internal(
+                                "cannot create a savepoint - no write transaction is active"
+                                    .to_string(),
+                            ))
+                        }
+                    }
+                    pager.savepoint(savepoint_name(&state.registers[*name_reg])?);
+                    state.pc += 1;
+                }
+                Insn::ReleaseSavepoint { name_reg } => {
+                    pager.release_savepoint(savepoint_name(&state.registers[*name_reg])?)?;
+                    state.pc += 1;
+                }
+                Insn::RollbackTo { name_reg } => {
+                    pager.rollback_to_savepoint(savepoint_name(&state.registers[*name_reg])?)?;
+                    state.pc += 1;
+                }
+                Insn::Goto { target_pc } => {
+                    state.pc = *target_pc;
+                }
+                Insn::Integer { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Integer(*value);
+                    state.pc += 1;
+                }
+                Insn::Real { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Float(*value);
+                    state.pc += 1;
+                }
+                Insn::Null {
+                    dest_start,
+                    dest_end,
+                } => {
+                    for reg in &mut state.registers[*dest_start..=*dest_end] {
+                        *reg = OwnedValue::Null;
+                    }
+                    state.pc += 1;
+                }
+                Insn::String8 { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Text(value.as_str().into());
+                    state.pc += 1;
+                }
+                Insn::Blob { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Blob(Rc::new(value.clone()));
+                    state.pc += 1;
+                }
+                Insn::RowId { cursor_id, dest } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    if let Some(ref rowid) = *cursor.rowid()? {
+                        state.registers[*dest] = OwnedValue::Integer(*rowid as i64);
+                    } else {
+                        state.registers[*dest] = OwnedValue::Null;
+                    }
+                    state.pc += 1;
+                }
+                Insn::IdxRowid {
+                    cursor_id,
+                    dest_reg,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let rowid = match cursor.record()?.as_ref().map(|key| key.values.last()) {
+                        Some(Some(OwnedValue::Integer(rowid))) => OwnedValue::Integer(*rowid),
+                        Some(_) => {
+                            return Err(LimboError::Corrupt(
+                                "index entry does not end with a rowid".to_string(),
+                            ))
+                        }
+                        None => OwnedValue::Null,
+                    };
+                    state.registers[*dest_reg] = rowid;
+                    state.pc += 1;
+                }
+                Insn::RowData {
+                    cursor_id,
+                    dest_reg,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    state.registers[*dest_reg] = match *cursor.record()? {
+                        Some(ref record) => {
+                            OwnedValue::Blob(Rc::new(record.serialize(TextEncoding::Utf8)))
+                        }
+                        None => OwnedValue::Null,
+                    };
+                    state.pc += 1;
+                }
+                Insn::DecrJumpZero { reg, target_pc } => match state.registers[*reg] {
+                    OwnedValue::Integer(n) => {
+                        if n > 0 {
+                            state.registers[*reg] = OwnedValue::Integer(n - 1);
+                            state.pc += 1;
+                        } else {
+                            state.pc = *target_pc;
+                        }
+                    }
+                    _ => unreachable!("DecrJumpZero on non-integer register"),
+                },
+                Insn::Add { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_add(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Subtract { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_subtract(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Multiply { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_multiply(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Divide { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_divide(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Remainder { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_remainder(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::BitAnd { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_bitwise(&state.registers[*lhs], &state.registers[*rhs], |l, r| l & r);
+                    state.pc += 1;
+                }
+                Insn::BitOr { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_bitwise(&state.registers[*lhs], &state.registers[*rhs], |l, r| l | r);
+                    state.pc += 1;
+                }
+                Insn::ShiftLeft { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_bitwise(&state.registers[*lhs], &state.registers[*rhs], shift_left);
+                    state.pc += 1;
+                }
+                Insn::ShiftRight { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_bitwise(&state.registers[*lhs], &state.registers[*rhs], shift_right);
+                    state.pc += 1;
+                }
+                Insn::BitNot { reg, dest } => {
+                    state.registers[*dest] = match &state.registers[*reg] {
+                        OwnedValue::Null => OwnedValue::Null,
+                        value => OwnedValue::Integer(!to_integer(value)),
+                    };
+                    state.pc += 1;
+                }
+                Insn::Negative { reg, dest } => {
+                    state.registers[*dest] = exec_negative(&state.registers[*reg]);
+                    state.pc += 1;
+                }
+                Insn::Not { reg, dest } => {
+                    state.registers[*dest] = match &state.registers[*reg] {
+                        OwnedValue::Null => OwnedValue::Null,
+                        value => OwnedValue::Integer(!is_true(value) as i64),
+                    };
+                    state.pc += 1;
+                }
+                Insn::Concat { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_concat(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Function {
+                    func,
+                    start_reg,
+                    arg_count,
+                    dest_reg,
+                } => {
+                    let args = &state.registers[*start_reg..*start_reg + *arg_count];
+                    state.registers[*dest_reg] = match func {
+                        Func::Scalar(func) => func.call(args)?,
+                        Func::External(name) => state.functions.borrow().call(name, args)?,
+                    };
+                    state.pc += 1;
+                }
+                Insn::Cast { reg, to_type } => {
+                    state.registers[*reg] = state.registers[*reg].cast(*to_type);
+                    state.pc += 1;
+                }
+                Insn::Affinity {
+                    start_reg,
+                    count,
+                    affinities,
+                } => {
+                    if affinities.chars().count() != *count {
+                        return Err(LimboError::Internal(format!(
+                            "Affinity string '{}' does not cover {} registers",
+                            affinities, count
+                        )));
+                    }
+                    for (i, c) in affinities.chars().enumerate() {
+                        let reg = &mut state.registers[*start_reg + i];
+                        *reg = reg.apply_affinity(Affinity::try_from(c)?);
+                    }
+                    state.pc += 1;
+                }
+                Insn::AggStep {
+                    func,
+                    arg_reg,
+                    acc_reg,
+                } => {
+                    let arg = state.registers[*arg_reg].clone();
+                    let acc = &mut state.registers[*acc_reg..*acc_reg + func.accumulator_size()];
+                    func.step(acc, &arg)?;
+                    state.pc += 1;
+                }
+                Insn::AggFinal { acc_reg, func } => {
+                    let acc = &state.registers[*acc_reg..*acc_reg + func.accumulator_size()];
+                    state.registers[*acc_reg] = func.finalize(acc);
+                    state.pc += 1;
+                }
+                Insn::Eq {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    collation,
+                } => {
+                    let ord = exec_compare(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        collation.unwrap_or_default(),
+                    );
+                    state.last_compare = Some(ord);
+                    if !ord.has_null && ord.ordering.is_eq() {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Ne {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    collation,
+                } => {
+                    let ord = exec_compare(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        collation.unwrap_or_default(),
+                    );
+                    state.last_compare = Some(ord);
+                    if !ord.has_null && ord.ordering.is_ne() {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Lt {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    collation,
+                } => {
+                    let ord = exec_compare(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        collation.unwrap_or_default(),
+                    );
+                    state.last_compare = Some(ord);
+                    if !ord.has_null && ord.ordering.is_lt() {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Le {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    collation,
+                } => {
+                    let ord = exec_compare(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        collation.unwrap_or_default(),
+                    );
+                    state.last_compare = Some(ord);
+                    if !ord.has_null && ord.ordering.is_le() {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Gt {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    collation,
+                } => {
+                    let ord = exec_compare(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        collation.unwrap_or_default(),
+                    );
+                    state.last_compare = Some(ord);
+                    if !ord.has_null && ord.ordering.is_gt() {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Ge {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    collation,
+                } => {
+                    let ord = exec_compare(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        collation.unwrap_or_default(),
+                    );
+                    state.last_compare = Some(ord);
+                    if !ord.has_null && ord.ordering.is_ge() {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Copy {
+                    src_reg,
+                    dst_reg,
+                    amount,
+                } => {
+                    // Copy via a temporary so that overlapping ranges read the
+                    // source values before they are overwritten.
+                    let values = state.registers[*src_reg..=*src_reg + *amount].to_vec();
+                    state.registers[*dst_reg..=*dst_reg + *amount].clone_from_slice(&values);
+                    state.pc += 1;
+                }
+                Insn::Move {
+                    src_reg,
+                    dst_reg,
+                    count,
+                } => {
+                    // Take all the source values first so that overlapping
+                    // ranges don't clobber values that are yet to be moved.
+                    let values: Vec<_> = state.registers[*src_reg..*src_reg + *count]
+                        .iter_mut()
+                        .map(|reg| std::mem::replace(reg, OwnedValue::Null))
+                        .collect();
+                    for (reg, value) in state.registers[*dst_reg..*dst_reg + *count]
+                        .iter_mut()
+                        .zip(values)
+                    {
+                        *reg = value;
+                    }
+                    state.pc += 1;
+                }
+                Insn::IfPos {
+                    reg,
+                    target_pc,
+                    decrement,
+                } => match state.registers[*reg] {
+                    OwnedValue::Integer(n) if n > 0 => {
+                        state.registers[*reg] = OwnedValue::Integer(n - *decrement as i64);
+                        state.pc = *target_pc;
+                    }
+                    _ => {
+                        state.pc += 1;
+                    }
+                },
+                Insn::IsNull { reg, target_pc } => {
+                    if matches!(state.registers[*reg], OwnedValue::Null) {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::NotNull { reg, target_pc } => {
+                    if !matches!(state.registers[*reg], OwnedValue::Null) {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Compare {
+                    lhs_start,
+                    rhs_start,
+                    count,
+                    collations,
+                } => {
+                    let lhs = &state.registers[*lhs_start..*lhs_start + *count];
+                    let rhs = &state.registers[*rhs_start..*rhs_start + *count];
+                    state.last_compare = Some(exec_compare_run(lhs, rhs, collations));
+                    state.pc += 1;
+                }
+                Insn::Jump {
+                    target_lt,
+                    target_eq,
+                    target_gt,
+                } => {
+                    let cmp = state.last_compare.ok_or_else(|| {
+                        LimboError::Internal("Jump without a preceding comparison".to_string())
+                    })?;
+                    state.pc = match cmp.ordering {
+                        Ordering::Less => *target_lt,
+                        Ordering::Equal => *target_eq,
+                        Ordering::Greater => *target_gt,
+                    };
+                }
+                Insn::SeekRowid {
+                    cursor_id,
+                    src_reg,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let found = match state.registers[*src_reg] {
+                        OwnedValue::Integer(rowid) if rowid >= 0 => {
+                            match cursor.seek_rowid(rowid as u64)? {
+                                CursorResult::Ok(found) => found,
+                                CursorResult::IO => {
+                                    // If there is I/O, the instruction is restarted.
+                                    return Ok(StepResult::IO);
+                                }
+                            }
+                        }
+                        _ => false,
+                    };
+                    if found {
+                        state.pc += 1;
+                    } else {
+                        state.pc = *target_pc;
+                    }
+                }
+                Insn::SeekGE {
+                    cursor_id,
+                    key_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let key = make_owned_record(&state.registers, key_reg, num_regs);
+                    match cursor.seek_ge(&key)? {
+                        CursorResult::Ok(found) => {
+                            if found {
+                                state.pc += 1;
+                            } else {
+                                state.pc = *target_pc;
+                            }
+                        }
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                }
+                Insn::SeekGT {
+                    cursor_id,
+                    key_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let key = make_owned_record(&state.registers, key_reg, num_regs);
+                    match cursor.seek_gt(&key)? {
+                        CursorResult::Ok(found) => {
+                            if found {
+                                state.pc += 1;
+                            } else {
+                                state.pc = *target_pc;
+                            }
+                        }
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                }
+                Insn::Found {
+                    cursor_id,
+                    key_reg,
+                    num_regs,
+                    target_pc,
+                }
+                | Insn::NotFound {
+                    cursor_id,
+                    key_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let key = make_owned_record(&state.registers, key_reg, num_regs);
+                    let found = match cursor.seek_ge(&key)? {
+                        CursorResult::Ok(false) => false,
+                        CursorResult::Ok(true) => match cursor.record()?.as_ref() {
+                            Some(index_key) => compare_index_key(index_key, &key).is_eq(),
+                            None => false,
+                        },
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    };
+                    if found == matches!(insn, Insn::Found { .. }) {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::IdxGE {
+                    cursor_id,
+                    key_reg,
+                    num_regs,
+                    target_pc,
+                }
+                | Insn::IdxGT {
+                    cursor_id,
+                    key_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let record = cursor.record()?;
+                    let index_key = match record.as_ref() {
+                        Some(index_key) => index_key,
+                        None => {
+                            return Err(LimboError::Internal(format!(
+                                "cursor {} does not point at an entry",
+                                cursor_id
+                            )))
+                        }
+                    };
+                    let key = make_owned_record(&state.registers, key_reg, num_regs);
+                    let ord = compare_index_key(index_key, &key);
+                    let jump = match insn {
+                        Insn::IdxGE { .. } => ord != Ordering::Less,
+                        _ => ord == Ordering::Greater,
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::LastAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.last()? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::LastAwait {
+                    cursor_id,
+                    pc_if_empty,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    if cursor.is_empty() {
+                        state.pc = *pc_if_empty;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::PrevAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.prev()? {
+                        CursorResult::Ok(_) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::PrevAwait {
+                    cursor_id,
+                    pc_if_next,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    if !cursor.is_empty() {
+                        state.pc = *pc_if_next;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Distinct {
+                    set_id,
+                    start_reg,
+                    count,
+                    target_pc,
+                } => {
+                    let key = distinct_key(&state.registers[*start_reg..*start_reg + *count]);
+                    if state.distinct.entry(*set_id).or_default().insert(key) {
+                        state.pc += 1;
+                    } else {
+                        state.pc = *target_pc;
+                    }
+                }
+                Insn::Once { target_pc } => {
+                    if state.once.insert(state.pc) {
+                        state.pc += 1;
+                    } else {
+                        state.pc = *target_pc;
+                    }
+                }
+                Insn::Gosub {
+                    return_reg,
+                    target_pc,
+                } => {
+                    state.registers[*return_reg] = OwnedValue::Integer((state.pc + 1) as i64);
+                    state.pc = *target_pc;
+                }
+                Insn::Return { return_reg } => match state.registers[*return_reg] {
+                    OwnedValue::Integer(pc) if pc >= 0 => {
+                        state.pc = pc as usize;
+                    }
+                    _ => {
+                        return Err(LimboError::Internal(
+                            "Return on a register without a return address".to_string(),
+                        ))
+                    }
+                },
+                Insn::Yield {
+                    yield_reg,
+                    end_offset,
+                } => match state.registers[*yield_reg] {
+                    OwnedValue::Integer(pc) if pc >= 0 => {
+                        state.registers[*yield_reg] = OwnedValue::Integer((state.pc + 1) as i64);
+                        state.pc = pc as usize;
+                    }
+                    OwnedValue::Null => {
+                        state.pc = *end_offset;
+                    }
+                    _ => {
+                        return Err(LimboError::Internal(
+                            "Yield on a register without an address".to_string(),
+                        ))
+                    }
+                },
+                Insn::EndCoroutine { yield_reg } => match state.registers[*yield_reg] {
+                    OwnedValue::Integer(pc) if pc >= 1 => {
+                        // The resume address is the instruction right after the Yield.
+                        state.registers[*yield_reg] = OwnedValue::Null;
+                        state.pc = pc as usize - 1;
+                    }
+                    _ => {
+                        return Err(LimboError::Internal(
+                            "EndCoroutine on a register without a resume address".to_string(),
+                        ))
+                    }
+                },
+                Insn::Insert {
+                    cursor_id,
+                    key_reg,
+                    record_reg,
+                } => {
+                    let key = match state.registers[*key_reg] {
+                        OwnedValue::Integer(rowid) if rowid >= 0 => rowid as u64,
+                        OwnedValue::Integer(rowid) => {
+                            return Err(LimboError::Internal(format!(
+                                "negative rowid {} is not supported",
+                                rowid
+                            )))
+                        }
+                        _ => {
+                            return Err(LimboError::TypeMismatch(
+                                "Insert key must be an integer rowid".to_string(),
+                            ))
+                        }
+                    };
+                    let payload = match &state.registers[*record_reg] {
+                        OwnedValue::Blob(payload) => payload.clone(),
+                        _ => {
+                            return Err(LimboError::TypeMismatch(
+                                "Insert record must be a blob".to_string(),
+                            ))
+                        }
+                    };
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    if !cursor.is_writable() {
+                        return Err(LimboError::Internal(format!(
+                            "cursor {} is not writable",
+                            cursor_id
+                        )));
+                    }
+                    match cursor.insert(key, &payload)? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::MakeRecord {
+                    start_reg,
+                    count,
+                    dest_reg,
+                } => {
+                    // Records made by the program hold UTF-8 text. A B-Tree
+                    // cursor encodes them in the database encoding on insert.
+                    let record = make_owned_record(&state.registers, start_reg, count);
+                    state.registers[*dest_reg] =
+                        OwnedValue::Blob(Rc::new(record.serialize(TextEncoding::Utf8)));
+                    state.pc += 1;
+                }
+                Insn::NewRowid {
+                    cursor_id,
+                    dest_reg,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.last()? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    let rowid = match *cursor.rowid()? {
+                        Some(rowid) if rowid >= i64::MAX as u64 => {
+                            return Err(LimboError::Internal(
+                                "database or disk is full".to_string(),
+                            ))
+                        }
+                        Some(rowid) => rowid + 1,
+                        None => 1,
+                    };
+                    state.registers[*dest_reg] = OwnedValue::Integer(rowid as i64);
+                    state.pc += 1;
+                }
+                Insn::Delete { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    if !cursor.is_writable() {
+                        return Err(LimboError::Internal(format!(
+                            "cursor {} is not writable",
+                            cursor_id
+                        )));
+                    }
+                    match cursor.delete()? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Whether the instruction emits a result row.
+fn is_result_row(insn: &Insn) -> bool {
+    matches!(
+        insn,
+        Insn::ResultRow { .. } | Insn::ResultRowList { .. } | Insn::ResultRowRef { .. }
+    )
+}
+
+fn make_record<'a>(
+    registers: &'a [OwnedValue],
+    register_end: &usize,
+    register_start: &usize,
+) -> Record<'a> {
+    let mut values = Vec::with_capacity(*register_end - *register_start);
+    for value in registers.iter().take(*register_end).skip(*register_start) {
+        values.push(crate::types::to_value(value));
+    }
+    Record::new(values)
+}
+
+/// The name of a savepoint, which must be text.
+fn savepoint_name(value: &OwnedValue) -> Result<&str, LimboError> {
+    match value {
+        OwnedValue::Text(name) => Ok(name.as_str()),
+        _ => Err(LimboError::TypeMismatch(
+            "savepoint name must be text".to_string(),
+        )),
+    }
+}
+
+/// A numeric operand of an arithmetic instruction.
+enum Numeric {
+    Null,
+    Integer(i64),
+    Float(f64),
+}
+
+/// Convert a register value to a numeric operand. Text and blobs use their
+/// longest numeric prefix, so ones which don't look like numbers are zero.
+fn to_numeric(value: &OwnedValue) -> Numeric {
+    let number = match value {
+        OwnedValue::Text(_) | OwnedValue::Blob(_) => text_to_number(&value.to_text().unwrap()),
+        _ => value.clone(),
+    };
+    match number {
+        OwnedValue::Integer(i) => Numeric::Integer(i),
+        OwnedValue::Float(f) => Numeric::Float(f),
+        _ => Numeric::Null,
+    }
+}
+
+/// Apply an arithmetic operator to two values. Integer overflow falls back
+/// to floating point, like in SQLite.
+fn exec_arith(
+    lhs: &OwnedValue,
+    rhs: &OwnedValue,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> OwnedValue {
+    match (to_numeric(lhs), to_numeric(rhs)) {
+        (Numeric::Null, _) | (_, Numeric::Null) => OwnedValue::Null,
+        (Numeric::Integer(l), Numeric::Integer(r)) => match int_op(l, r) {
+            Some(v) => OwnedValue::Integer(v),
+            None => OwnedValue::Float(float_op(l as f64, r as f64)),
+        },
+        (Numeric::Integer(l), Numeric::Float(r)) => OwnedValue::Float(float_op(l as f64, r)),
+        (Numeric::Float(l), Numeric::Integer(r)) => OwnedValue::Float(float_op(l, r as f64)),
+        (Numeric::Float(l), Numeric::Float(r)) => OwnedValue::Float(float_op(l, r)),
+    }
+}
+
+/// Negate a value. Negating the smallest integer overflows to a real.
+fn exec_negative(value: &OwnedValue) -> OwnedValue {
+    match to_numeric(value) {
+        Numeric::Null => OwnedValue::Null,
+        Numeric::Integer(i) => match i.checked_neg() {
+            Some(i) => OwnedValue::Integer(i),
+            None => OwnedValue::Float(-(i as f64)),
+        },
+        Numeric::Float(f) => OwnedValue::Float(-f),
+    }
+}
+
+/// Whether a non-NULL value is true, which is when its numeric value is
+/// not zero.
+fn is_true(value: &OwnedValue) -> bool {
+    match to_numeric(value) {
+        Numeric::Null => false,
+        Numeric::Integer(i) => i != 0,
+        Numeric::Float(f) => f != 0.0,
+    }
+}
+
+fn exec_remainder(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    let (lhs, rhs, is_real) = match (to_numeric(lhs), to_numeric(rhs)) {
+        (Numeric::Null, _) | (_, Numeric::Null) => return OwnedValue::Null,
+        (Numeric::Integer(l), Numeric::Integer(r)) => (l, r, false),
+        (Numeric::Integer(l), Numeric::Float(r)) => (l, r as i64, true),
+        (Numeric::Float(l), Numeric::Integer(r)) => (l as i64, r, true),
+        (Numeric::Float(l), Numeric::Float(r)) => (l as i64, r as i64, true),
+    };
+    if rhs == 0 {
+        return OwnedValue::Null;
+    }
+    // i64::MIN % -1 overflows, but the remainder of dividing by -1 is
+    // always zero.
+    let remainder = if rhs == -1 { 0 } else { lhs % rhs };
+    if is_real {
+        OwnedValue::Float(remainder as f64)
+    } else {
+        OwnedValue::Integer(remainder)
+    }
+}
+
+/// Convert a non-NULL value to an integer like CAST(value AS INTEGER).
+fn to_integer(value: &OwnedValue) -> i64 {
+    match value.cast(Affinity::Integer) {
+        OwnedValue::Integer(i) => i,
+        _ => 0,
+    }
+}
+
+/// Apply a bitwise operator to the integer values of two registers.
+fn exec_bitwise(lhs: &OwnedValue, rhs: &OwnedValue, op: fn(i64, i64) -> i64) -> OwnedValue {
+    match (lhs, rhs) {
+        (OwnedValue::Null, _) | (_, OwnedValue::Null) => OwnedValue::Null,
+        _ => OwnedValue::Integer(op(to_integer(lhs), to_integer(rhs))),
+    }
+}
+
+fn shift_left(value: i64, amount: i64) -> i64 {
+    match amount {
+        0..=63 => value << amount,
+        64.. => 0,
+        _ => shift_right(value, amount.checked_neg().unwrap_or(i64::MAX)),
+    }
+}
+
+fn shift_right(value: i64, amount: i64) -> i64 {
+    match amount {
+        0..=63 => value >> amount,
+        64.. => {
+            if value < 0 {
+                -1
+            } else {
+                0
+            }
+        }
+        _ => shift_left(value, amount.checked_neg().unwrap_or(i64::MAX)),
+    }
+}
+
+fn exec_concat(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (lhs.to_text(), rhs.to_text()) {
+        (Some(lhs), Some(rhs)) => OwnedValue::Text((lhs + &rhs).into()),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_add(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    exec_arith(lhs, rhs, i64::checked_add, |l, r| l + r)
+}
+
+fn exec_subtract(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    exec_arith(lhs, rhs, i64::checked_sub, |l, r| l - r)
+}
+
+fn exec_multiply(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    exec_arith(lhs, rhs, i64::checked_mul, |l, r| l * r)
+}
+
+fn exec_divide(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match to_numeric(rhs) {
+        Numeric::Integer(0) => return OwnedValue::Null,
+        Numeric::Float(0.0) => return OwnedValue::Null,
+        _ => {}
+    }
+    exec_arith(lhs, rhs, i64::checked_div, |l, r| l / r)
+}
+
+/// Compare two values for a conditional jump. Comparisons with NULL are
+/// never true, so the result records whether either value was NULL in
+/// addition to the sort order of the values.
+fn exec_compare(lhs: &OwnedValue, rhs: &OwnedValue, collation: Collation) -> Comparison {
+    Comparison {
+        ordering: lhs.compare_with(rhs, collation),
+        has_null: matches!(lhs, OwnedValue::Null) || matches!(rhs, OwnedValue::Null),
+    }
+}
+
+/// Compare two runs of registers column by column. The ordering is that of
+/// the first pair of values which are not equal.
+fn exec_compare_run(
+    lhs: &[OwnedValue],
+    rhs: &[OwnedValue],
+    collations: &[Collation],
+) -> Comparison {
+    let ordering = lhs
+        .iter()
+        .zip(rhs)
+        .enumerate()
+        .map(|(i, (l, r))| l.compare_with(r, collations.get(i).copied().unwrap_or_default()))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal);
+    Comparison {
+        ordering,
+        has_null: false,
+    }
+}
+
+/// Serialize the values of a Distinct key. Reals that are exactly an
+/// integer are serialized as integers so that 1 and 1.0 are the same key.
+fn distinct_key(values: &[OwnedValue]) -> Vec<u8> {
+    let values = values
+        .iter()
+        .map(|value| match value {
+            OwnedValue::Float(f)
+                if f.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(f) =>
+            {
+                OwnedValue::Integer(*f as i64)
+            }
+            _ => value.clone(),
+        })
+        .collect();
+    OwnedRecord::new(values).serialize(TextEncoding::Utf8)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn make_owned_record(registers: &[OwnedValue], start: &usize, count: &usize) -> OwnedRecord {
+    OwnedRecord::new(registers[*start..*start + *count].to_vec())
+}
+
+fn trace_insn(addr: usize, insn: &Insn, comment: Option<&str>) {
+    if !log::log_enabled!(log::Level::Trace) {
+        return;
+    }
+    log::trace!("{}", insn_to_str(addr, insn, comment));
+}
+
+fn insn_to_str(addr: usize, insn: &Insn, comment: Option<&str>) -> String {
+    let row = explain_insn(addr, insn, comment);
+    format!(
+        "{:<4}  {:<13}  {:<4}  {:<4}  {:<4}  {:<13}  {:<2}  {}",
+        row.addr, row.opcode, row.p1, row.p2, row.p3, row.p4, row.p5, row.comment
+    )
+}
+
+/// Decompose an instruction into the columns of an EXPLAIN listing, using
+/// `custom_comment` in place of the generated comment if it is set.
+fn explain_insn(addr: usize, insn: &Insn, custom_comment: Option<&str>) -> ExplainRow {
+    let (opcode, p1, p2, p3, p4, p5, comment) = match insn {
+        Insn::Init { target_pc } => (
+            "Init",
+            0,
+            *target_pc,
+            0,
+            "",
+            0,
+            format!("Start at {}", target_pc),
+        ),
+        Insn::OpenReadAsync {
+            cursor_id,
+            root_page,
+        } => (
+            "OpenReadAsync",
+            *cursor_id,
+            *root_page,
+            0,
+            "",
+            0,
+            format!("root={}", root_page),
+        ),
+        Insn::OpenReadAwait => ("OpenReadAwait", 0, 0, 0, "", 0, "".to_string()),
+        Insn::OpenWriteAsync {
+            cursor_id,
+            root_page,
+        } => (
+            "OpenWriteAsync",
+            *cursor_id,
+            *root_page,
+            0,
+            "",
+            0,
+            format!("root={}", root_page),
+        ),
+        Insn::OpenWriteAwait => ("OpenWriteAwait", 0, 0, 0, "", 0, "".to_string()),
+        Insn::OpenIndexReadAsync {
+            cursor_id,
+            root_page,
+        } => (
+            "OpenIndexReadAsync",
+            *cursor_id,
+            *root_page,
+            0,
+            "",
+            0,
+            format!("root={}", root_page),
+        ),
+        Insn::OpenEphemeral {
+            cursor_id,
+            num_columns,
+            is_index,
+        } => (
+            "OpenEphemeral",
+            *cursor_id,
+            *num_columns,
+            0,
+            if *is_index { "index" } else { "" },
+            0,
+            format!("nColumn={}", num_columns),
+        ),
+        Insn::IdxInsert {
+            cursor_id,
+            record_reg,
+        } => (
+            "IdxInsert",
+            *cursor_id,
+            *record_reg,
+            0,
+            "",
+            0,
+            format!("key=r[{}]", record_reg),
+        ),
+        Insn::SorterOpen { cursor_id, keys } => (
+            "SorterOpen",
+            *cursor_id,
+            keys.len(),
+            0,
+            "",
+            0,
+            format!(
+                "k({})",
+                keys.iter()
+                    .map(|key| format!(
+                        "{}{},{}",
+                        if key.order == SortOrder::Desc {
+                            "-"
+                        } else {
+                            ""
+                        },
+                        key.column,
+                        key.collation.name()
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(";")
+            ),
+        ),
+        Insn::SorterInsert {
+            cursor_id,
+            record_reg,
+        } => (
+            "SorterInsert",
+            *cursor_id,
+            *record_reg,
+            0,
+            "",
+            0,
+            format!("key=r[{}]", record_reg),
+        ),
+        Insn::SorterSort {
+            cursor_id,
+            pc_if_empty,
+        } => (
+            "SorterSort",
+            *cursor_id,
+            *pc_if_empty,
+            0,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::SorterData {
+            cursor_id,
+            dest_reg,
+        } => (
+            "SorterData",
+            *cursor_id,
+            *dest_reg,
+            0,
+            "",
+            0,
+            format!("r[{}]=data", dest_reg),
+        ),
+        Insn::SorterNext {
+            cursor_id,
+            pc_if_next,
+        } => (
+            "SorterNext",
+            *cursor_id,
+            *pc_if_next,
+            0,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::RewindAsync { cursor_id } => ("RewindAsync", *cursor_id, 0, 0, "", 0, "".to_string()),
+        Insn::RewindAwait {
+            cursor_id,
+            pc_if_empty,
+        } => (
+            "RewindAwait",
+            *cursor_id,
+            *pc_if_empty,
+            0,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::Column {
+            cursor_id,
+            column,
+            dest,
+        } => (
+            "Column",
+            *cursor_id,
+            *column,
+            *dest,
+            "",
+            0,
+            format!("r[{}]= cursor {} column {}", dest, cursor_id, column),
+        ),
+        Insn::ResultRow {
+            register_start,
+            register_end,
+        } => (
+            "ResultRow",
+            *register_start,
+            *register_end,
+            0,
+            "",
+            0,
+            format!("output=r[{}..{}]", register_start, register_end),
+        ),
+        Insn::ResultRowList { regs } => (
+            "ResultRowList",
+            regs.len(),
+            0,
+            0,
+            "",
+            0,
+            format!(
+                "output={}",
+                regs.iter()
+                    .map(|reg| format!("r[{}]", reg))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        ),
+        Insn::ResultRowRef { sources } => (
+            "ResultRowRef",
+            sources.len(),
+            0,
+            0,
+            "",
+            0,
+            format!(
+                "output={}",
+                sources
+                    .iter()
+                    .map(|source| source.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        ),
+        Insn::NextAsync { cursor_id } => ("NextAsync", *cursor_id, 0, 0, "", 0, "".to_string()),
+        Insn::NextAwait {
+            cursor_id,
+            pc_if_next,
+        } => (
+            "NextAwait",
+            *cursor_id,
+            *pc_if_next,
+            0,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::Noop => ("Noop", 0, 0, 0, "", 0, "".to_string()),
+        Insn::Variable { index, dest } => (
+            "Variable",
+            *index,
+            *dest,
+            0,
+            "",
+            0,
+            format!("r[{}]=parameter({})", dest, index),
+        ),
+        Insn::Halt => ("Halt", 0, 0, 0, "", 0, "".to_string()),
+        Insn::HaltError { err_code, message } => (
+            "Halt",
+            *err_code as usize,
+            0,
+            0,
+            message.as_str(),
+            0,
+            "".to_string(),
+        ),
+        Insn::Transaction { write } => (
+            "Transaction",
+            0,
+            *write as usize,
+            0,
+            "",
+            0,
+            if *write { "write" } else { "read" }.to_string(),
+        ),
+        Insn::Commit => ("Commit", 0, 0, 0, "", 0, "".to_string()),
+        Insn::Rollback => ("Rollback", 0, 0, 0, "", 0, "".to_string()),
+        Insn::Savepoint { name_reg } => (
+            "Savepoint",
+            0,
+            *name_reg,
+            0,
+            "",
+            0,
+            format!("begin r[{}]", name_reg),
+        ),
+        Insn::ReleaseSavepoint { name_reg } => (
+            "Savepoint",
+            1,
+            *name_reg,
+            0,
+            "",
+            0,
+            format!("release r[{}]", name_reg),
+        ),
+        Insn::RollbackTo { name_reg } => (
+            "Savepoint",
+            2,
+            *name_reg,
+            0,
+            "",
+            0,
+            format!("rollback r[{}]", name_reg),
+        ),
+        Insn::Goto { target_pc } => ("Goto", 0, *target_pc, 0, "", 0, "".to_string()),
+        Insn::Integer { value, dest } => {
+            ("Integer", *dest, *value as usize, 0, "", 0, "".to_string())
+        }
+        Insn::Real { value, dest } => {
+            ("Real", 0, *dest, 0, "", 0, format!("r[{}]={}", dest, value))
+        }
+        Insn::Null {
+            dest_start,
+            dest_end,
+        } => (
+            "Null",
+            0,
+            *dest_start,
+            *dest_end,
+            "",
+            0,
+            if dest_start == dest_end {
+                format!("r[{}]=NULL", dest_start)
+            } else {
+                format!("r[{}..{}]=NULL", dest_start, dest_end)
+            },
+        ),
+        Insn::String8 { value, dest } => (
+            "String8",
+            *dest,
+            0,
+            0,
+            value.as_str(),
+            0,
+            format!("r[{}]= '{}'", dest, value),
+        ),
+        Insn::Blob { value, dest } => (
+            "Blob",
+            value.len(),
+            *dest,
+            0,
+            "",
+            0,
+            format!("r[{}]= x'{}'", dest, to_hex(value)),
+        ),
+        Insn::RowId { cursor_id, dest } => ("RowId", *cursor_id, *dest, 0, "", 0, "".to_string()),
+        Insn::IdxRowid {
+            cursor_id,
+            dest_reg,
+        } => ("IdxRowid", *cursor_id, *dest_reg, 0, "", 0, "".to_string()),
+        Insn::RowData {
+            cursor_id,
+            dest_reg,
+        } => (
+            "RowData",
+            *cursor_id,
+            *dest_reg,
+            0,
+            "",
+            0,
+            format!("r[{}]=data", dest_reg),
+        ),
+        Insn::DecrJumpZero { reg, target_pc } => {
+            ("DecrJumpZero", *reg, *target_pc, 0, "", 0, "".to_string())
+        }
+        Insn::Add { lhs, rhs, dest } => (
+            "Add",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]+r[{}]", dest, lhs, rhs),
+        ),
+        Insn::Subtract { lhs, rhs, dest } => (
+            "Subtract",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]-r[{}]", dest, lhs, rhs),
+        ),
+        Insn::Multiply { lhs, rhs, dest } => (
+            "Multiply",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]*r[{}]", dest, lhs, rhs),
+        ),
+        Insn::Negative { reg, dest } => (
+            "Negative",
+            *reg,
+            *dest,
+            0,
+            "",
+            0,
+            format!("r[{}]=-r[{}]", dest, reg),
+        ),
+        Insn::Not { reg, dest } => (
+            "Not",
+            *reg,
+            *dest,
+            0,
+            "",
+            0,
+            format!("r[{}]=!r[{}]", dest, reg),
+        ),
+        Insn::Concat { lhs, rhs, dest } => (
+            "Concat",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]||r[{}]", dest, lhs, rhs),
+        ),
+        Insn::Function {
+            func,
+            start_reg,
+            arg_count,
+            dest_reg,
+        } => (
+            "Function",
+            0,
+            *start_reg,
+            *dest_reg,
+            func.name(),
+            0,
+            format!(
+                "r[{}]={}(r[{}..{}])",
+                dest_reg,
+                func.name(),
+                start_reg,
+                start_reg + arg_count
+            ),
+        ),
+        Insn::Cast { reg, to_type } => (
+            "Cast",
+            *reg,
+            0,
+            0,
+            to_type.name(),
+            0,
+            format!("r[{}]=CAST(r[{}] AS {})", reg, reg, to_type.name()),
+        ),
+        Insn::Affinity {
+            start_reg,
+            count,
+            affinities,
+        } => (
+            "Affinity",
+            *start_reg,
+            *count,
+            0,
+            affinities.as_str(),
+            0,
+            format!("affinity(r[{}..{}])", start_reg, start_reg + count),
+        ),
+        Insn::AggStep {
+            func,
+            arg_reg,
+            acc_reg,
+        } => (
+            "AggStep",
+            0,
+            *arg_reg,
+            *acc_reg,
+            func.name(),
+            1,
+            format!("accum=r[{}] step(r[{}])", acc_reg, arg_reg),
+        ),
+        Insn::AggFinal { acc_reg, func } => (
+            "AggFinal",
+            *acc_reg,
+            1,
+            0,
+            func.name(),
+            0,
+            format!("accum=r[{}] N=1", acc_reg),
+        ),
+        Insn::Divide { lhs, rhs, dest } => (
+            "Divide",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]/r[{}]", dest, lhs, rhs),
+        ),
+        Insn::Remainder { lhs, rhs, dest } => (
+            "Remainder",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]%r[{}]", dest, lhs, rhs),
+        ),
+        Insn::BitAnd { lhs, rhs, dest } => (
+            "BitAnd",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]&r[{}]", dest, lhs, rhs),
+        ),
+        Insn::BitOr { lhs, rhs, dest } => (
+            "BitOr",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]|r[{}]", dest, lhs, rhs),
+        ),
+        Insn::ShiftLeft { lhs, rhs, dest } => (
+            "ShiftLeft",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]<<r[{}]", dest, lhs, rhs),
+        ),
+        Insn::ShiftRight { lhs, rhs, dest } => (
+            "ShiftRight",
+            *rhs,
+            *lhs,
+            *dest,
+            "",
+            0,
+            format!("r[{}]=r[{}]>>r[{}]", dest, lhs, rhs),
+        ),
+        Insn::BitNot { reg, dest } => (
+            "BitNot",
+            *reg,
+            *dest,
+            0,
+            "",
+            0,
+            format!("r[{}]=~r[{}]", dest, reg),
+        ),
+        Insn::Eq {
+            lhs,
+            rhs,
+            target_pc,
+            collation,
+        } => (
+            "Eq",
+            *lhs,
+            *rhs,
+            *target_pc,
+            collation.map_or("", |c| c.name()),
+            0,
+            format!("if r[{}]==r[{}] goto {}", lhs, rhs, target_pc),
+        ),
+        Insn::Ne {
+            lhs,
+            rhs,
+            target_pc,
+            collation,
+        } => (
+            "Ne",
+            *lhs,
+            *rhs,
+            *target_pc,
+            collation.map_or("", |c| c.name()),
+            0,
+            format!("if r[{}]!=r[{}] goto {}", lhs, rhs, target_pc),
+        ),
+        Insn::Lt {
+            lhs,
+            rhs,
+            target_pc,
+            collation,
+        } => (
+            "Lt",
+            *lhs,
+            *rhs,
+            *target_pc,
+            collation.map_or("", |c| c.name()),
+            0,
+            format!("if r[{}]<r[{}] goto {}", lhs, rhs, target_pc),
+        ),
+        Insn::Le {
+            lhs,
+            rhs,
+            target_pc,
+            collation,
+        } => (
+            "Le",
+            *lhs,
+            *rhs,
+            *target_pc,
+            collation.map_or("", |c| c.name()),
+            0,
+            format!("if r[{}]<=r[{}] goto {}", lhs, rhs, target_pc),
+        ),
+        Insn::Gt {
+            lhs,
+            rhs,
+            target_pc,
+            collation,
+        } => (
+            "Gt",
+            *lhs,
+            *rhs,
+            *target_pc,
+            collation.map_or("", |c| c.name()),
+            0,
+            format!("if r[{}]>r[{}] goto {}", lhs, rhs, target_pc),
+        ),
+        Insn::Ge {
+            lhs,
+            rhs,
+            target_pc,
+            collation,
+        } => (
+            "Ge",
+            *lhs,
+            *rhs,
+            *target_pc,
+            collation.map_or("", |c| c.name()),
+            0,
+            format!("if r[{}]>=r[{}] goto {}", lhs, rhs, target_pc),
+        ),
+        Insn::Copy {
+            src_reg,
+            dst_reg,
+            amount,
+        } => (
+            "Copy",
+            *src_reg,
+            *dst_reg,
+            *amount,
+            "",
+            0,
+            if *amount == 0 {
+                format!("r[{}]=r[{}]", dst_reg, src_reg)
+            } else {
+                format!(
+                    "r[{}..{}]=r[{}..{}]",
+                    dst_reg,
+                    dst_reg + amount,
+                    src_reg,
+                    src_reg + amount
+                )
+            },
+        ),
+        Insn::Move {
+            src_reg,
+            dst_reg,
+            count,
+        } => (
+            "Move",
+            *src_reg,
+            *dst_reg,
+            *count,
+            "",
+            0,
+            format!(
+                "r[{}..{}]=r[{}..{}]",
+                dst_reg,
+                dst_reg + count,
+                src_reg,
+                src_reg + count
+            ),
+        ),
+        Insn::IfPos {
+            reg,
+            target_pc,
+            decrement,
+        } => (
+            "IfPos",
+            *reg,
+            *target_pc,
+            *decrement,
+            "",
+            0,
+            format!(
+                "r[{}]>0 -> r[{}]-={}, goto {}",
+                reg, reg, decrement, target_pc
+            ),
+        ),
+        Insn::IsNull { reg, target_pc } => (
+            "IsNull",
+            *reg,
+            *target_pc,
+            0,
+            "",
+            0,
+            format!("if (r[{}]==NULL) goto {}", reg, target_pc),
+        ),
+        Insn::NotNull { reg, target_pc } => (
+            "NotNull",
+            *reg,
+            *target_pc,
+            0,
+            "",
+            0,
+            format!("if (r[{}]!=NULL) goto {}", reg, target_pc),
+        ),
+        Insn::Compare {
+            lhs_start,
+            rhs_start,
+            count,
+            collations,
+        } => (
+            "Compare",
+            *lhs_start,
+            *rhs_start,
+            *count,
+            "",
+            0,
+            format!(
+                "r[{}..{}] <-> r[{}..{}] k({})",
+                lhs_start,
+                lhs_start + count,
+                rhs_start,
+                rhs_start + count,
+                (0..*count)
+                    .map(|i| collations.get(i).copied().unwrap_or_default().name())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        ),
+        Insn::Jump {
+            target_lt,
+            target_eq,
+            target_gt,
+        } => (
+            "Jump",
+            *target_lt,
+            *target_eq,
+            *target_gt,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::SeekRowid {
+            cursor_id,
+            src_reg,
+            target_pc,
+        } => (
+            "SeekRowid",
+            *cursor_id,
+            *target_pc,
+            *src_reg,
+            "",
+            0,
+            format!(
+                "if (r[{}]!=cursor {} rowid) goto {}",
+                src_reg, cursor_id, target_pc
+            ),
+        ),
+        Insn::SeekGE {
+            cursor_id,
+            key_reg,
+            num_regs,
+            target_pc,
+        } => (
+            "SeekGE",
+            *cursor_id,
+            *target_pc,
+            *key_reg,
+            "",
+            0,
+            format!("key=r[{}..{}]", key_reg, key_reg + num_regs),
+        ),
+        Insn::SeekGT {
+            cursor_id,
+            key_reg,
+            num_regs,
+            target_pc,
+        } => (
+            "SeekGT",
+            *cursor_id,
+            *target_pc,
+            *key_reg,
+            "",
+            0,
+            format!("key=r[{}..{}]", key_reg, key_reg + num_regs),
+        ),
+        Insn::Found {
+            cursor_id,
+            key_reg,
+            num_regs,
+            target_pc,
+        } => (
+            "Found",
+            *cursor_id,
+            *target_pc,
+            *key_reg,
+            "",
+            0,
+            format!("key=r[{}..{}]", key_reg, key_reg + num_regs),
+        ),
+        Insn::NotFound {
+            cursor_id,
+            key_reg,
+            num_regs,
+            target_pc,
+        } => (
+            "NotFound",
+            *cursor_id,
+            *target_pc,
+            *key_reg,
+            "",
+            0,
+            format!("key=r[{}..{}]", key_reg, key_reg + num_regs),
+        ),
+        Insn::IdxGE {
+            cursor_id,
+            key_reg,
+            num_regs,
+            target_pc,
+        } => (
+            "IdxGE",
+            *cursor_id,
+            *target_pc,
+            *key_reg,
+            "",
+            0,
+            format!("key=r[{}..{}]", key_reg, key_reg + num_regs),
+        ),
+        Insn::IdxGT {
+            cursor_id,
+            key_reg,
+            num_regs,
+            target_pc,
+        } => (
+            "IdxGT",
+            *cursor_id,
+            *target_pc,
+            *key_reg,
+            "",
+            0,
+            format!("key=r[{}..{}]", key_reg, key_reg + num_regs),
+        ),
+        Insn::LastAsync { cursor_id } => ("LastAsync", *cursor_id, 0, 0, "", 0, "".to_string()),
+        Insn::LastAwait {
+            cursor_id,
+            pc_if_empty,
+        } => (
+            "LastAwait",
+            *cursor_id,
+            *pc_if_empty,
+            0,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::PrevAsync { cursor_id } => ("PrevAsync", *cursor_id, 0, 0, "", 0, "".to_string()),
+        Insn::PrevAwait {
+            cursor_id,
+            pc_if_next,
+        } => (
+            "PrevAwait",
+            *cursor_id,
+            *pc_if_next,
+            0,
+            "",
+            0,
+            "".to_string(),
+        ),
+        Insn::Distinct {
+            set_id,
+            start_reg,
+            count,
+            target_pc,
+        } => (
+            "Distinct",
+            *set_id,
+            *target_pc,
+            *start_reg,
+            "",
+            *count as u16,
+            format!(
+                "if r[{}..{}] in set {} goto {}",
+                start_reg,
+                start_reg + count,
+                set_id,
+                target_pc
+            ),
+        ),
+        Insn::Once { target_pc } => ("Once", 0, *target_pc, 0, "", 0, "".to_string()),
+        Insn::Gosub {
+            return_reg,
+            target_pc,
+        } => ("Gosub", *return_reg, *target_pc, 0, "", 0, "".to_string()),
+        Insn::Return { return_reg } => ("Return", *return_reg, 0, 0, "", 0, "".to_string()),
+        Insn::Yield {
+            yield_reg,
+            end_offset,
+        } => ("Yield", *yield_reg, *end_offset, 0, "", 0, "".to_string()),
+        Insn::EndCoroutine { yield_reg } => {
+            ("EndCoroutine", *yield_reg, 0, 0, "", 0, "".to_string())
+        }
+        Insn::Insert {
+            cursor_id,
+            key_reg,
+            record_reg,
+        } => (
+            "Insert",
+            *cursor_id,
+            *record_reg,
+            *key_reg,
+            "",
+            0,
+            format!("intkey=r[{}] data=r[{}]", key_reg, record_reg),
+        ),
+        Insn::MakeRecord {
+            start_reg,
+            count,
+            dest_reg,
+        } => (
+            "MakeRecord",
+            *start_reg,
+            *count,
+            *dest_reg,
+            "",
+            0,
+            format!(
+                "r[{}]=mkrec(r[{}..{}])",
+                dest_reg,
+                start_reg,
+                start_reg + count
+            ),
+        ),
+        Insn::NewRowid {
+            cursor_id,
+            dest_reg,
+        } => (
+            "NewRowid",
+            *cursor_id,
+            *dest_reg,
+            0,
+            "",
+            0,
+            format!("r[{}]=rowid", dest_reg),
+        ),
+        Insn::Delete { cursor_id } => ("Delete", *cursor_id, 0, 0, "", 0, "".to_string()),
+    };
+    ExplainRow {
+        addr,
+        opcode,
+        p1,
+        p2,
+        p3,
+        p4: p4.to_string(),
+        p5,
+        comment: custom_comment.map_or(comment, str::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::ScalarFunc;
+    use crate::sqlite3_ondisk::{BTreeCell, DatabaseHeader};
+    use crate::storage::{PageIO, PageSource};
+    use crate::test_utils::{create_fixture, open_fixture};
+    use crate::types::{to_owned_value, Value};
+    use crate::{Completion, Database, PlatformIO, IO};
+    use rstest::rstest;
+
+    struct NoPageIO;
+
+    impl PageIO for NoPageIO {
+        fn get(&self, _page_idx: usize, _c: Rc<Completion>) -> Result<()> {
+            unreachable!("test programs do not read pages")
+        }
+    }
+
+    fn test_pager() -> Rc<Pager> {
+        let mut header = DatabaseHeader::default();
+        header.page_size = 4096;
+        let page_source = PageSource::from_io(Rc::new(NoPageIO));
+        Rc::new(Pager::finish_open(Rc::new(RefCell::new(header)), page_source).unwrap())
+    }
+
+    #[rstest]
+    #[case(Insn::Add { lhs: 0, rhs: 1, dest: 2 }, 7, 3, Value::Integer(10))]
+    #[case(Insn::Subtract { lhs: 0, rhs: 1, dest: 2 }, 7, 3, Value::Integer(4))]
+    #[case(Insn::Multiply { lhs: 0, rhs: 1, dest: 2 }, 7, 3, Value::Integer(21))]
+    #[case(Insn::Divide { lhs: 0, rhs: 1, dest: 2 }, 7, 3, Value::Integer(2))]
+    #[case(Insn::Divide { lhs: 0, rhs: 1, dest: 2 }, 7, 0, Value::Null)]
+    #[case(Insn::Add { lhs: 0, rhs: 1, dest: 2 }, i64::MAX, 1, Value::Float(i64::MAX as f64 + 1.0))]
+    #[case(Insn::Remainder { lhs: 0, rhs: 1, dest: 2 }, 7, 3, Value::Integer(1))]
+    #[case(Insn::Remainder { lhs: 0, rhs: 1, dest: 2 }, -7, 3, Value::Integer(-1))]
+    #[case(Insn::Remainder { lhs: 0, rhs: 1, dest: 2 }, 7, -3, Value::Integer(1))]
+    #[case(Insn::Remainder { lhs: 0, rhs: 1, dest: 2 }, 7, 0, Value::Null)]
+    #[case(Insn::Remainder { lhs: 0, rhs: 1, dest: 2 }, i64::MIN, -1, Value::Integer(0))]
+    fn test_arithmetic(
+        #[case] insn: Insn,
+        #[case] lhs: i64,
+        #[case] rhs: i64,
+        #[case] expected: Value,
+    ) {
+        let mut program = ProgramBuilder::new();
+        let lhs_reg = program.alloc_register();
+        let rhs_reg = program.alloc_register();
+        let dest = program.alloc_register();
+        program.emit_insn(Insn::Integer {
+            value: lhs,
+            dest: lhs_reg,
+        });
+        program.emit_insn(Insn::Integer {
+            value: rhs,
+            dest: rhs_reg,
+        });
+        program.emit_insn(insn);
+        program.emit_insn(Insn::ResultRow {
+            register_start: dest,
+            register_end: dest + 1,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[rstest]
+    #[case::real("1.0", Value::Float(2.0))]
+    #[case::integer_prefix("12abc", Value::Integer(13))]
+    #[case::whitespace(" 7 ", Value::Integer(8))]
+    #[case::hex("0x10", Value::Integer(1))]
+    #[case::nan("nan", Value::Integer(1))]
+    #[case::inf("inf", Value::Integer(1))]
+    #[case::too_large("1e400", Value::Float(f64::INFINITY))]
+    fn test_arithmetic_on_text(#[case] text: &str, #[case] expected: Value) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::String8 {
+            value: text.to_string(),
+            dest: 0,
+        });
+        program.emit_insn(Insn::Integer { value: 1, dest: 1 });
+        program.emit_insn(Insn::Add {
+            lhs: 0,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn test_real() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Real {
+            value: 3.14,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Integer { value: 2, dest: 1 });
+        program.emit_insn(Insn::Add {
+            lhs: 0,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(
+            program,
+            &[
+                Value::Float(3.14),
+                Value::Integer(2),
+                Value::Float(3.14 + 2.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_blob() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Blob {
+            value: vec![0xde, 0xad, 0x00, 0xbe, 0xef],
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Blob(&vec![0xde, 0xad, 0x00, 0xbe, 0xef])]);
+    }
+
+    #[test]
+    fn test_column_renders_blob_as_hex() {
+        let mut state = ProgramState::new(1);
+        state.registers[0] = OwnedValue::Blob(Rc::new(vec![0x01, 0xab]));
+        assert_eq!(state.column(0), Some("x'01AB'".to_string()));
+    }
+
+    #[rstest]
+    #[case(1, 1, &[Value::Integer(0), Value::Null, Value::Integer(2), Value::Integer(3)])]
+    #[case(1, 2, &[Value::Integer(0), Value::Null, Value::Null, Value::Integer(3)])]
+    #[case(0, 3, &[Value::Null, Value::Null, Value::Null, Value::Null])]
+    fn test_null(#[case] dest_start: usize, #[case] dest_end: usize, #[case] expected: &[Value]) {
+        let mut program = program_with_registers(4);
+        for reg in 0..4 {
+            program.emit_insn(Insn::Integer {
+                value: reg as i64,
+                dest: reg,
+            });
+        }
+        program.emit_insn(Insn::Null {
+            dest_start,
+            dest_end,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, expected);
+    }
+
+    #[rstest]
+    #[case(Insn::Eq { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 1, 1, true)]
+    #[case(Insn::Eq { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 1, 2, false)]
+    #[case(Insn::Ne { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 1, 2, true)]
+    #[case(Insn::Ne { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 1, 1, false)]
+    #[case(Insn::Lt { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 1, 2, true)]
+    #[case(Insn::Lt { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 2, 2, false)]
+    #[case(Insn::Le { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 2, 2, true)]
+    #[case(Insn::Le { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 3, 2, false)]
+    #[case(Insn::Gt { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 3, 2, true)]
+    #[case(Insn::Gt { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 2, 2, false)]
+    #[case(Insn::Ge { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 2, 2, true)]
+    #[case(Insn::Ge { lhs: 0, rhs: 1, target_pc: 6, collation: None }, 1, 2, false)]
+    fn test_compare_and_jump(
+        #[case] insn: Insn,
+        #[case] lhs: i64,
+        #[case] rhs: i64,
+        #[case] jump_taken: bool,
+    ) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer {
+            value: lhs,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Integer {
+            value: rhs,
+            dest: 1,
+        });
+        program.emit_insn(insn);
+        emit_jump_probe(&mut program);
+        assert_result_row(program, &[Value::Integer(jump_taken as i64)]);
+    }
+
+    #[test]
+    fn test_compare_with_null_falls_through() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        // Register 1 is never written, so it's NULL.
+        program.emit_insn(Insn::Ne {
+            lhs: 0,
+            rhs: 1,
+            target_pc: 5,
+            collation: None,
+        });
+        emit_jump_probe(&mut program);
+        assert_result_row(program, &[Value::Integer(0)]);
+    }
+
+    #[test]
+    fn test_compare_type_order() {
+        let text = OwnedValue::Text("a".into());
+        let blob = OwnedValue::Blob(Rc::new(vec![0]));
+        assert!(OwnedValue::Null.compare(&OwnedValue::Integer(0)).is_lt());
+        assert!(OwnedValue::Integer(1)
+            .compare(&OwnedValue::Float(1.5))
+            .is_lt());
+        assert!(OwnedValue::Float(2.0)
+            .compare(&OwnedValue::Integer(2))
+            .is_eq());
+        assert!(OwnedValue::Integer(100).compare(&text).is_lt());
+        assert!(text.compare(&blob).is_lt());
+    }
+
+    /// Emit a tail that writes 0 into register 2 and emits it when the
+    /// preceding jump falls through, or 1 when the jump to the instruction
+    /// right after the fall-through block is taken.
+    fn emit_jump_probe(program: &mut ProgramBuilder) {
+        program.emit_insn(Insn::Integer { value: 0, dest: 2 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        program.emit_insn(Insn::Integer { value: 1, dest: 2 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+    }
+
+    #[rstest]
+    #[case(0, 3, 0, &[Value::Integer(0), Value::Integer(1), Value::Integer(2), Value::Integer(0)])]
+    #[case(0, 2, 1, &[Value::Integer(0), Value::Integer(1), Value::Integer(0), Value::Integer(1)])]
+    #[case(0, 1, 2, &[Value::Integer(0), Value::Integer(0), Value::Integer(1), Value::Integer(2)])]
+    #[case(1, 0, 2, &[Value::Integer(1), Value::Integer(2), Value::Integer(3), Value::Integer(3)])]
+    fn test_copy(
+        #[case] src_reg: usize,
+        #[case] dst_reg: usize,
+        #[case] amount: usize,
+        #[case] expected: &[Value],
+    ) {
+        let mut program = program_with_registers(4);
+        for reg in 0..4 {
+            program.emit_insn(Insn::Integer {
+                value: reg as i64,
+                dest: reg,
+            });
+        }
+        program.emit_insn(Insn::Copy {
+            src_reg,
+            dst_reg,
+            amount,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, expected);
+    }
+
+    #[rstest]
+    #[case(0, 2, 2, &[Value::Null, Value::Null, Value::Integer(0), Value::Integer(1)])]
+    #[case(0, 1, 2, &[Value::Null, Value::Integer(0), Value::Integer(1), Value::Integer(3)])]
+    #[case(1, 0, 3, &[Value::Integer(1), Value::Integer(2), Value::Integer(3), Value::Null])]
+    fn test_move(
+        #[case] src_reg: usize,
+        #[case] dst_reg: usize,
+        #[case] count: usize,
+        #[case] expected: &[Value],
+    ) {
+        let mut program = program_with_registers(4);
+        for reg in 0..4 {
+            program.emit_insn(Insn::Integer {
+                value: reg as i64,
+                dest: reg,
+            });
+        }
+        program.emit_insn(Insn::Move {
+            src_reg,
+            dst_reg,
+            count,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Integer(5), 0, true, Value::Integer(5))]
+    #[case(OwnedValue::Integer(5), 2, true, Value::Integer(3))]
+    #[case(OwnedValue::Integer(0), 1, false, Value::Integer(0))]
+    #[case(OwnedValue::Integer(-1), 1, false, Value::Integer(-1))]
+    #[case(OwnedValue::Null, 1, false, Value::Null)]
+    #[case(OwnedValue::Float(1.0), 1, false, Value::Float(1.0))]
+    fn test_if_pos(
+        #[case] value: OwnedValue,
+        #[case] decrement: usize,
+        #[case] jump_taken: bool,
+        #[case] expected: Value,
+    ) {
+        let program = {
+            let mut program = program_with_registers(3);
+            program.emit_insn(Insn::IfPos {
+                reg: 0,
+                target_pc: 4,
+                decrement,
+            });
+            emit_jump_probe(&mut program);
+            program.build().unwrap()
+        };
+        let mut state = ProgramState::new(program.max_registers);
+        state.registers[0] = value;
+        let pager = test_pager();
+        match program.step(&mut state, pager).unwrap() {
+            StepResult::Row(record) => {
+                assert_eq!(record.values, vec![Value::Integer(jump_taken as i64)])
+            }
+            _ => panic!("expected a result row"),
+        }
+        assert_eq!(crate::types::to_value(&state.registers[0]), expected);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, true)]
+    #[case(OwnedValue::Integer(0), false)]
+    #[case(OwnedValue::Text("".into()), false)]
+    fn test_is_null_and_not_null(#[case] value: OwnedValue, #[case] is_null: bool) {
+        for (insn, jump_taken) in [
+            (
+                Insn::IsNull {
+                    reg: 0,
+                    target_pc: 4,
+                },
+                is_null,
+            ),
+            (
+                Insn::NotNull {
+                    reg: 0,
+                    target_pc: 4,
+                },
+                !is_null,
+            ),
+        ] {
+            let mut program = program_with_registers(3);
+            program.emit_insn(insn);
+            emit_jump_probe(&mut program);
+            let program = program.build().unwrap();
+            let mut state = ProgramState::new(program.max_registers);
+            state.registers[0] = value.clone();
+            match program.step(&mut state, test_pager()).unwrap() {
+                StepResult::Row(record) => {
+                    assert_eq!(record.values, vec![Value::Integer(jump_taken as i64)])
                 }
-                Insn::Transaction => {
-                    state.pc += 1;
+                _ => panic!("expected a result row"),
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(1, 2, 1)]
+    #[case(2, 2, 2)]
+    #[case(3, 2, 3)]
+    fn test_jump(#[case] lhs: i64, #[case] rhs: i64, #[case] expected: i64) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer {
+            value: lhs,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Integer {
+            value: rhs,
+            dest: 1,
+        });
+        // The comparison only sets the flag; its jump target is the next instruction.
+        program.emit_insn(Insn::Eq {
+            lhs: 0,
+            rhs: 1,
+            target_pc: 3,
+            collation: None,
+        });
+        program.emit_insn(Insn::Jump {
+            target_lt: 4,
+            target_eq: 6,
+            target_gt: 8,
+        });
+        for value in 1..=3 {
+            program.emit_insn(Insn::Integer { value, dest: 2 });
+            program.emit_insn(Insn::Goto { target_pc: 10 });
+        }
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Integer(expected)]);
+    }
+
+    #[test]
+    fn test_jump_without_comparison() {
+        let mut program = program_with_registers(0);
+        program.emit_insn(Insn::Jump {
+            target_lt: 1,
+            target_eq: 1,
+            target_gt: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(
+            err,
+            LimboError::Internal(message) if message == "Jump without a preceding comparison"
+        ));
+    }
+
+    #[rstest]
+    #[case(1, true)]
+    #[case(500, true)]
+    #[case(1000, true)]
+    #[case(0, false)]
+    #[case(1001, false)]
+    fn test_seek_rowid(#[case] rowid: i64, #[case] found: bool) {
+        let (io, db) = open_fixture(
+            "seek_rowid",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+             INSERT INTO t SELECT i, printf('%050d', i) FROM n;",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::Integer {
+            value: rowid,
+            dest: 0,
+        });
+        program.emit_insn(Insn::SeekRowid {
+            cursor_id: 0,
+            src_reg: 0,
+            target_pc: 9,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 1,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        // A successful seek continues the scan from the row it landed on.
+        let expected: Vec<_> = (rowid..=1000)
+            .filter(|_| found)
+            .map(|i| {
+                vec![
+                    OwnedValue::Integer(i),
+                    OwnedValue::Text(format!("{:050}", i).into()),
+                ]
+            })
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    /// Run a query to completion and return its rows.
+    fn query_rows(io: &Rc<dyn IO>, db: &Database, sql: &str) -> Vec<Vec<OwnedValue>> {
+        let conn = db.connect();
+        let mut rows = conn.query(sql).unwrap().unwrap();
+        let mut result = Vec::new();
+        loop {
+            match rows.try_next().unwrap() {
+                crate::RowResult::Row(row) => {
+                    result.push(row.values.iter().map(to_owned_value).collect())
                 }
-                Insn::Goto { target_pc } => {
-                    state.pc = *target_pc;
+                crate::RowResult::IO => io.run_once().unwrap(),
+                crate::RowResult::Done => break,
+            }
+        }
+        result
+    }
+
+    /// Run the program against the database to completion and return the
+    /// rows it emitted.
+    fn run_program(
+        io: &Rc<dyn IO>,
+        db: &Database,
+        program: ProgramBuilder,
+    ) -> Vec<Vec<OwnedValue>> {
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let mut rows = Vec::new();
+        loop {
+            match program.step(&mut state, db.pager.clone()).unwrap() {
+                StepResult::Row(record) => {
+                    rows.push(record.values.iter().map(to_owned_value).collect());
                 }
-                Insn::Integer { value, dest } => {
-                    state.registers[*dest] = OwnedValue::Integer(*value);
-                    state.pc += 1;
+                StepResult::IO => io.run_once().unwrap(),
+                StepResult::Done => break,
+                StepResult::Interrupted => unreachable!(),
+            }
+        }
+        rows
+    }
+
+    #[rstest]
+    #[case(true, 501, Some(502))]
+    #[case(true, 502, Some(502))]
+    #[case(false, 502, Some(504))]
+    #[case(true, -5, Some(2))]
+    #[case(false, -5, Some(2))]
+    #[case(true, 2000, Some(2000))]
+    #[case(false, 2000, None)]
+    #[case(true, 2001, None)]
+    fn test_seek_ge_and_gt(#[case] ge: bool, #[case] key: i64, #[case] expected: Option<i64>) {
+        let (io, db) = open_fixture(
+            "seek_ge_gt",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000)
+             INSERT INTO t SELECT i * 2, printf('%050d', i) FROM n;",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::Integer {
+            value: key,
+            dest: 0,
+        });
+        let seek = if ge {
+            Insn::SeekGE {
+                cursor_id: 0,
+                key_reg: 0,
+                num_regs: 1,
+                target_pc: 6,
+            }
+        } else {
+            Insn::SeekGT {
+                cursor_id: 0,
+                key_reg: 0,
+                num_regs: 1,
+                target_pc: 6,
+            }
+        };
+        program.emit_insn(seek);
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|rowid| vec![OwnedValue::Integer(rowid)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[rstest]
+    #[case(1000)]
+    #[case(0)]
+    fn test_last_and_prev(#[case] num_rows: i64) {
+        let (io, db) = open_fixture(
+            "last_prev",
+            &format!(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+                 INSERT INTO t SELECT i, printf('%050d', i) FROM n WHERE i <= {};",
+                num_rows, num_rows
+            ),
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::LastAsync { cursor_id: 0 });
+        program.emit_insn(Insn::LastAwait {
+            cursor_id: 0,
+            pc_if_empty: 8,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::PrevAsync { cursor_id: 0 });
+        program.emit_insn(Insn::PrevAwait {
+            cursor_id: 0,
+            pc_if_next: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = (1..=num_rows)
+            .rev()
+            .map(|rowid| vec![OwnedValue::Integer(rowid)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_once() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Init { target_pc: 1 });
+        program.emit_insn(Insn::Integer { value: 3, dest: 0 });
+        program.emit_insn(Insn::Integer { value: 0, dest: 1 });
+        program.emit_insn(Insn::Integer { value: 1, dest: 2 });
+        // Loop three times, but only increment r[1] on the first iteration.
+        program.emit_insn(Insn::Once { target_pc: 6 });
+        program.emit_insn(Insn::Add {
+            lhs: 1,
+            rhs: 2,
+            dest: 1,
+        });
+        program.emit_insn(Insn::DecrJumpZero {
+            reg: 0,
+            target_pc: 8,
+        });
+        program.emit_insn(Insn::Goto { target_pc: 4 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        // Running the program again re-arms the Once instruction.
+        for _ in 0..2 {
+            state.pc = 0;
+            match program.step(&mut state, test_pager()).unwrap() {
+                StepResult::Row(record) => assert_eq!(record.values, vec![Value::Integer(1)]),
+                _ => panic!("expected a result row"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_gosub_and_return() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 0, dest: 1 });
+        program.emit_insn(Insn::Integer { value: 10, dest: 2 });
+        program.emit_insn(Insn::Gosub {
+            return_reg: 0,
+            target_pc: 7,
+        });
+        program.emit_insn(Insn::Integer { value: 5, dest: 2 });
+        program.emit_insn(Insn::Gosub {
+            return_reg: 0,
+            target_pc: 7,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        // Subroutine: r[1] += r[2]
+        program.emit_insn(Insn::Add {
+            lhs: 1,
+            rhs: 2,
+            dest: 1,
+        });
+        program.emit_insn(Insn::Return { return_reg: 0 });
+        assert_result_row(program, &[Value::Integer(15)]);
+    }
+
+    #[rstest]
+    #[case::null(Insn::Null { dest_start: 0, dest_end: 0 })]
+    #[case::negative_address(Insn::Integer { value: -1, dest: 0 })]
+    fn test_return_without_address(#[case] value: Insn) {
+        let mut program = program_with_registers(1);
+        program.emit_insn(value);
+        program.emit_insn(Insn::Return { return_reg: 0 });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(
+            err,
+            LimboError::Internal(message) if message == "Return on a register without a return address"
+        ));
+    }
+
+    #[test]
+    fn test_yield() {
+        let mut program = program_with_registers(3);
+        // Consumer: sum the values produced into r[1] until the producer ends.
+        program.emit_insn(Insn::Integer { value: 7, dest: 0 });
+        program.emit_insn(Insn::Integer { value: 0, dest: 2 });
+        program.emit_insn(Insn::Yield {
+            yield_reg: 0,
+            end_offset: 5,
+        });
+        program.emit_insn(Insn::Add {
+            lhs: 2,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::Goto { target_pc: 2 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        // Producer: yield the values 1, 2, and 3 in r[1].
+        for value in 1..=3 {
+            program.emit_insn(Insn::Integer { value, dest: 1 });
+            program.emit_insn(Insn::Yield {
+                yield_reg: 0,
+                end_offset: 0,
+            });
+        }
+        program.emit_insn(Insn::EndCoroutine { yield_reg: 0 });
+        assert_result_row(program, &[Value::Integer(6)]);
+    }
+
+    #[rstest]
+    #[case::yield_on_text(
+        Insn::String8 { value: "x".to_string(), dest: 0 },
+        Insn::Yield { yield_reg: 0, end_offset: 2 },
+        "Yield on a register without an address"
+    )]
+    #[case::yield_to_negative_address(
+        Insn::Integer { value: -1, dest: 0 },
+        Insn::Yield { yield_reg: 0, end_offset: 2 },
+        "Yield on a register without an address"
+    )]
+    #[case::end_coroutine_on_text(
+        Insn::String8 { value: "x".to_string(), dest: 0 },
+        Insn::EndCoroutine { yield_reg: 0 },
+        "EndCoroutine on a register without a resume address"
+    )]
+    #[case::end_coroutine_at_address_zero(
+        Insn::Integer { value: 0, dest: 0 },
+        Insn::EndCoroutine { yield_reg: 0 },
+        "EndCoroutine on a register without a resume address"
+    )]
+    fn test_coroutine_errors(#[case] value: Insn, #[case] insn: Insn, #[case] message: &str) {
+        let mut program = program_with_registers(1);
+        program.emit_insn(value);
+        program.emit_insn(insn);
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(err, LimboError::Internal(m) if m == message));
+    }
+
+    #[test]
+    fn test_step_bounded() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Integer { value: 10, dest: 0 });
+        program.emit_insn(Insn::DecrJumpZero {
+            reg: 0,
+            target_pc: 3,
+        });
+        program.emit_insn(Insn::Goto { target_pc: 1 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let pager = test_pager();
+        // The program executes 1 + 10 * 2 + 1 + 1 = 23 instructions before
+        // emitting the row.
+        let mut interruptions = 0;
+        loop {
+            match program
+                .step_bounded(&mut state, pager.clone(), Some(3))
+                .unwrap()
+            {
+                StepResult::Interrupted => interruptions += 1,
+                StepResult::Row(record) => {
+                    assert_eq!(record.values, vec![Value::Integer(0)]);
+                    break;
                 }
-                Insn::String8 { value, dest } => {
-                    state.registers[*dest] = OwnedValue::Text(Rc::new(value.into()));
-                    state.pc += 1;
+                _ => panic!("expected a result row"),
+            }
+        }
+        assert_eq!(interruptions, 7);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut program = program_with_registers(1);
+        // Emit rows forever.
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Goto { target_pc: 1 });
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let pager = test_pager();
+        assert!(matches!(
+            program.step(&mut state, pager.clone()).unwrap(),
+            StepResult::Row(_)
+        ));
+        state.interrupt();
+        assert!(matches!(
+            program.step(&mut state, pager.clone()).unwrap(),
+            StepResult::Interrupted
+        ));
+        assert_eq!(state.pc, 2);
+    }
+
+    #[test]
+    fn test_column_after_cursor_is_exhausted() {
+        let (io, db) = open_fixture(
+            "column_exhausted",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'a');",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 9,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 9,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        assert_eq!(rows, vec![vec![OwnedValue::Null]]);
+    }
+
+    #[test]
+    fn test_rowid_on_empty_table() {
+        let (io, db) = open_fixture(
+            "rowid_empty",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        // Fall through even though the table is empty.
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 4,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        assert_eq!(rows, vec![vec![OwnedValue::Null]]);
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::Copy {
+            src_reg: 0,
+            dst_reg: 1,
+            amount: 0,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(
+            err,
+            LimboError::RegisterOutOfRange { index: 1, max: 1 }
+        ));
+    }
+
+    #[test]
+    fn test_halt_error() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Null {
+            dest_start: 0,
+            dest_end: 0,
+        });
+        program.emit_insn(Insn::NotNull {
+            reg: 0,
+            target_pc: 3,
+        });
+        program.emit_insn(Insn::HaltError {
+            err_code: 1299,
+            message: "NOT NULL constraint failed: t.x".to_string(),
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(
+            err,
+            LimboError::Constraint { err_code: 1299, message }
+                if message == "NOT NULL constraint failed: t.x"
+        ));
+    }
+
+    #[test]
+    fn test_sorter_insert_type_mismatch() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::SorterOpen {
+            cursor_id: 0,
+            keys: vec![],
+        });
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::SorterInsert {
+            cursor_id: 0,
+            record_reg: 0,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(err, LimboError::TypeMismatch(_)));
+    }
+
+    #[test]
+    fn test_build_valid_program() {
+        let mut program = ProgramBuilder::new();
+        let init = program.emit_placeholder();
+        program.emit_insn(Insn::Halt);
+        program.fixup_insn(init, Insn::Init { target_pc: 1 });
+        assert!(program.build().is_ok());
+    }
+
+    #[test]
+    fn test_build_dangling_goto() {
+        let mut program = ProgramBuilder::new();
+        program.emit_insn(Insn::Goto { target_pc: 2 });
+        program.emit_insn(Insn::Halt);
+        assert_eq!(
+            program.build().err(),
+            Some(BuildError::BranchOutOfRange {
+                offset: 0,
+                target: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_branch_to_placeholder() {
+        let mut program = ProgramBuilder::new();
+        program.emit_insn(Insn::Goto { target_pc: 1 });
+        program.emit_placeholder();
+        assert_eq!(
+            program.build().err(),
+            Some(BuildError::BranchToPlaceholder {
+                offset: 0,
+                target: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_inverted_null_range() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Null {
+            dest_start: 2,
+            dest_end: 0,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_eq!(
+            program.build().err(),
+            Some(BuildError::InvertedRegisterRange { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_build_inverted_result_row_range() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_eq!(
+            program.build().err(),
+            Some(BuildError::InvertedRegisterRange { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_resolve_forward_jump() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Integer { value: 0, dest: 0 });
+        let jump = program.emit_jump_placeholder(Insn::IfPos {
+            reg: 0,
+            target_pc: 0,
+            decrement: 0,
+        });
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        let target = program.offset();
+        program.resolve_jump(jump, target);
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        assert_eq!(program.insns[1].branch_targets(), vec![3]);
+    }
+
+    #[test]
+    fn test_build_unresolved_jump() {
+        let mut program = ProgramBuilder::new();
+        let _jump = program.emit_jump_placeholder(Insn::Goto { target_pc: 0 });
+        program.emit_insn(Insn::Halt);
+        assert_eq!(
+            program.build().err(),
+            Some(BuildError::UnresolvedJump { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_alloc_registers() {
+        let mut program = ProgramBuilder::new();
+        assert_eq!(program.alloc_register(), 0);
+        assert_eq!(program.alloc_registers(3), 1);
+        assert_eq!(program.next_free_register(), 4);
+        assert_eq!(program.alloc_registers(0), 4);
+        assert_eq!(program.alloc_register(), 4);
+    }
+
+    #[test]
+    fn test_label_forward_reference() {
+        let mut program = program_with_registers(1);
+        let end = program.alloc_label();
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_jump(Insn::Goto { target_pc: 0 }, end);
+        program.emit_insn(Insn::Integer { value: 2, dest: 0 });
+        program.resolve_label(end);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let built = program.build().unwrap();
+        assert_eq!(built.insns[1].branch_targets(), vec![3]);
+    }
+
+    #[test]
+    fn test_label_backward_reference() {
+        // Count r[0] down from 3 to 0, adding one to r[1] on each iteration.
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 3, dest: 0 });
+        program.emit_insn(Insn::Integer { value: 0, dest: 1 });
+        program.emit_insn(Insn::Integer { value: 1, dest: 2 });
+        let body = program.alloc_label();
+        program.resolve_label(body);
+        program.emit_insn(Insn::Add {
+            lhs: 1,
+            rhs: 2,
+            dest: 1,
+        });
+        program.emit_jump(
+            Insn::IfPos {
+                reg: 0,
+                target_pc: 0,
+                decrement: 1,
+            },
+            body,
+        );
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Integer(4)]);
+    }
+
+    #[test]
+    fn test_build_unresolved_label() {
+        let mut program = ProgramBuilder::new();
+        let label = program.alloc_label();
+        program.emit_jump(Insn::Goto { target_pc: 0 }, label);
+        program.emit_insn(Insn::Halt);
+        assert_eq!(
+            program.build().err(),
+            Some(BuildError::UnresolvedLabel { offset: 0, label })
+        );
+    }
+
+    #[test]
+    fn test_open_write_cursor() {
+        let mut program = program_with_registers(0);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page: 2,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 1,
+            root_page: 2,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        assert!(matches!(
+            program.step(&mut state, test_pager()).unwrap(),
+            StepResult::Done
+        ));
+        let cursors = state.cursors.borrow();
+        assert!(!cursors.get(&0).unwrap().is_writable());
+        assert!(cursors.get(&1).unwrap().is_writable());
+    }
+
+    #[rstest]
+    #[case::middle(1000, 501)]
+    #[case::first(1000, 1)]
+    #[case::last(1000, 3001)]
+    #[case::replace(1000, 500)]
+    #[case::empty_table(0, 7)]
+    fn test_insert(#[case] num_rows: i64, #[case] key: i64) {
+        let (io, db) = open_fixture(
+            "insert",
+            &format!(
+                // Every other row is deleted, so that the leaves have room for
+                // the new row, since full pages cannot be split yet.
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+                 INSERT INTO t SELECT i, printf('%d', i) FROM n WHERE i <= {};
+                 DELETE FROM t WHERE id % 2 = 1;",
+                num_rows * 2,
+                num_rows * 2
+            ),
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(4);
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        program.emit_insn(Insn::Integer {
+            value: key,
+            dest: 0,
+        });
+        // The record (NULL, 'new'), where the NULL is the rowid alias.
+        program.emit_insn(Insn::Blob {
+            value: vec![3, 0, 19, b'n', b'e', b'w'],
+            dest: 1,
+        });
+        program.emit_insn(Insn::Insert {
+            cursor_id: 0,
+            key_reg: 0,
+            record_reg: 1,
+        });
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 12,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 2,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 3,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 4,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 7,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let mut expected: Vec<_> = (1..=num_rows)
+            .map(|i| i * 2)
+            .filter(|rowid| *rowid != key)
+            .map(|rowid| {
+                vec![
+                    OwnedValue::Integer(rowid),
+                    OwnedValue::Text(rowid.to_string().into()),
+                ]
+            })
+            .collect();
+        expected.push(vec![
+            OwnedValue::Integer(key),
+            OwnedValue::Text("new".into()),
+        ]);
+        expected.sort_by_key(|row| match row[0] {
+            OwnedValue::Integer(rowid) => rowid,
+            _ => unreachable!(),
+        });
+        assert_eq!(rows, expected);
+    }
+
+    #[rstest]
+    #[case::utf16le("UTF-16le")]
+    #[case::utf16be("UTF-16be")]
+    fn test_insert_utf16_database(#[case] encoding: &str) {
+        let (io, db) = open_fixture(
+            &format!("insert_{}", encoding),
+            &format!(
+                "PRAGMA encoding = '{}';
+                 CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+                 INSERT INTO t VALUES (1, 'old');",
+                encoding
+            ),
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(5);
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        program.emit_insn(Insn::Integer { value: 2, dest: 0 });
+        program.emit_insn(Insn::Null {
+            dest_start: 1,
+            dest_end: 1,
+        });
+        program.emit_insn(Insn::String8 {
+            value: "n\u{e9}w \u{1f600}".to_string(),
+            dest: 2,
+        });
+        program.emit_insn(Insn::MakeRecord {
+            start_reg: 1,
+            count: 2,
+            dest_reg: 3,
+        });
+        program.emit_insn(Insn::Insert {
+            cursor_id: 0,
+            key_reg: 0,
+            record_reg: 3,
+        });
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 13,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 4,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 4,
+            register_end: 5,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 9,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_eq!(
+            run_program(&io, &db, program),
+            vec![
+                vec![OwnedValue::Text("old".into())],
+                vec![OwnedValue::Text("n\u{e9}w \u{1f600}".into())],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_insert_requires_write_cursor() {
+        let (io, db) = open_fixture("insert_read_cursor", "CREATE TABLE t (x);");
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::Blob {
+            value: vec![2, 1, 42],
+            dest: 1,
+        });
+        program.emit_insn(Insn::Insert {
+            cursor_id: 0,
+            key_reg: 0,
+            record_reg: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = loop {
+            match program.step(&mut state, db.pager.clone()) {
+                Ok(StepResult::IO) => io.run_once().unwrap(),
+                Ok(_) => panic!("insert through a read cursor succeeded"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.to_string(), "cursor 0 is not writable");
+    }
+
+    #[test]
+    fn test_make_record_matches_sqlite() {
+        let (io, db) = open_fixture(
+            "make_record",
+            "CREATE TABLE t (a INTEGER, b TEXT);
+             INSERT INTO t VALUES (42, 'hello');",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 42, dest: 0 });
+        program.emit_insn(Insn::String8 {
+            value: "hello".to_string(),
+            dest: 1,
+        });
+        program.emit_insn(Insn::MakeRecord {
+            start_reg: 0,
+            count: 2,
+            dest_reg: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let record = vec![3, 1, 23, 42, b'h', b'e', b'l', b'l', b'o'];
+        assert_eq!(rows, vec![vec![OwnedValue::Blob(Rc::new(record.clone()))]]);
+
+        // SQLite stored the same row with the same encoding.
+        let page = loop {
+            match db.pager.read_page(root_page).unwrap() {
+                CursorResult::Ok(page) => break page,
+                CursorResult::IO => io.run_once().unwrap(),
+            }
+        };
+        let contents = page.contents.read().unwrap();
+        match &contents.as_ref().unwrap().as_btree().unwrap().cells[0] {
+            BTreeCell::TableLeafCell(cell) => assert_eq!(cell._payload, record),
+            _ => unreachable!(),
+        }
+    }
+
+    #[rstest]
+    #[case::empty_table(0, 1)]
+    #[case::populated_table(1000, 2001)]
+    fn test_new_rowid(#[case] num_rows: i64, #[case] expected: i64) {
+        let (io, db) = open_fixture(
+            "new_rowid",
+            &format!(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+                 INSERT INTO t SELECT i * 2, printf('%050d', i) FROM n WHERE i <= {};",
+                num_rows, num_rows
+            ),
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        program.emit_insn(Insn::NewRowid {
+            cursor_id: 0,
+            dest_reg: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        assert_eq!(rows, vec![vec![OwnedValue::Integer(expected)]]);
+    }
+
+    #[rstest]
+    #[case(1000)]
+    #[case(3)]
+    #[case(0)]
+    fn test_delete_every_other_row(#[case] num_rows: i64) {
+        let (io, db) = open_fixture(
+            "delete",
+            &format!(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {})
+                 INSERT INTO t SELECT i, printf('%050d', i) FROM n WHERE i <= {};",
+                num_rows, num_rows
+            ),
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        program.emit_insn(Insn::Integer { value: 0, dest: 1 });
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 11,
+        });
+        // Delete the row if r1 is set, otherwise keep it and set r1.
+        program.emit_insn(Insn::IfPos {
+            reg: 1,
+            target_pc: 8,
+            decrement: 1,
+        });
+        program.emit_insn(Insn::Integer { value: 1, dest: 1 });
+        program.emit_insn(Insn::Goto { target_pc: 9 });
+        program.emit_insn(Insn::Delete { cursor_id: 0 });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 5,
+        });
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 17,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 13,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = (1..=num_rows)
+            .step_by(2)
+            .map(|rowid| vec![OwnedValue::Integer(rowid)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_scan_index() {
+        let (io, db) = open_fixture(
+            "scan_index",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, 'c'), (2, 'a'), (3, 'b'), (4, 'a');",
+        );
+        let mut program = program_with_registers(2);
+        // The index is created right after the table, so it gets the next page.
+        program.emit_insn(Insn::OpenIndexReadAsync {
+            cursor_id: 0,
+            root_page: db.schema.get_table("t").unwrap().root_page + 1,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 9,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = [("a", 2), ("a", 4), ("b", 3), ("c", 1)]
+            .into_iter()
+            .map(|(v, id)| vec![OwnedValue::Text(v.into()), OwnedValue::Integer(id)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[rstest]
+    #[case::ge_prefix(true, vec![OwnedValue::Text("b".into())], 2)]
+    #[case::gt_prefix(false, vec![OwnedValue::Text("b".into())], 4)]
+    #[case::ge_rowid(true, vec![OwnedValue::Text("a".into()), OwnedValue::Integer(4)], 1)]
+    #[case::gt_rowid(false, vec![OwnedValue::Text("a".into()), OwnedValue::Integer(4)], 2)]
+    #[case::ge_null(true, vec![OwnedValue::Null], 0)]
+    #[case::ge_past_end(true, vec![OwnedValue::Text("d".into())], 5)]
+    fn test_index_range_scan(
+        #[case] ge: bool,
+        #[case] bound: Vec<OwnedValue>,
+        #[case] expected_rows: usize,
+    ) {
+        let (io, db) = open_fixture(
+            "index_range",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, 'c'), (2, 'a'), (3, 'b'), (4, 'a'), (5, 'b');",
+        );
+        let num_regs = bound.len();
+        let mut program = program_with_registers(2 + num_regs);
+        // The index is created right after the table, so it gets the next page.
+        program.emit_insn(Insn::OpenIndexReadAsync {
+            cursor_id: 0,
+            root_page: db.schema.get_table("t").unwrap().root_page + 1,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        for (i, value) in bound.into_iter().enumerate() {
+            let dest = 2 + i;
+            program.emit_insn(match value {
+                OwnedValue::Null => Insn::Null {
+                    dest_start: dest,
+                    dest_end: dest,
+                },
+                OwnedValue::Integer(value) => Insn::Integer { value, dest },
+                OwnedValue::Text(value) => Insn::String8 {
+                    value: value.to_string(),
+                    dest,
+                },
+                _ => unreachable!(),
+            });
+        }
+        let start = program.offset();
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: start + 8,
+        });
+        let idx = if ge {
+            Insn::IdxGE {
+                cursor_id: 0,
+                key_reg: 2,
+                num_regs,
+                target_pc: start + 8,
+            }
+        } else {
+            Insn::IdxGT {
+                cursor_id: 0,
+                key_reg: 2,
+                num_regs,
+                target_pc: start + 8,
+            }
+        };
+        program.emit_insn(idx);
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: start + 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = [("a", 2), ("a", 4), ("b", 3), ("b", 5), ("c", 1)]
+            .into_iter()
+            .take(expected_rows)
+            .map(|(v, id)| vec![OwnedValue::Text(v.into()), OwnedValue::Integer(id)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[rstest]
+    #[case::ge_prefix(true, vec![OwnedValue::Text("b".into())], 2)]
+    #[case::gt_prefix(false, vec![OwnedValue::Text("b".into())], 4)]
+    #[case::ge_rowid(true, vec![OwnedValue::Text("a".into()), OwnedValue::Integer(4)], 1)]
+    #[case::gt_rowid(false, vec![OwnedValue::Text("a".into()), OwnedValue::Integer(4)], 2)]
+    #[case::ge_null(true, vec![OwnedValue::Null], 0)]
+    #[case::ge_past_end(true, vec![OwnedValue::Text("d".into())], 5)]
+    fn test_index_seek(
+        #[case] ge: bool,
+        #[case] key: Vec<OwnedValue>,
+        #[case] skipped_rows: usize,
+    ) {
+        let (io, db) = open_fixture(
+            "index_seek",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, 'c'), (2, 'a'), (3, 'b'), (4, 'a'), (5, 'b');",
+        );
+        let num_regs = key.len();
+        let mut program = program_with_registers(2 + num_regs);
+        // The index is created right after the table, so it gets the next page.
+        program.emit_insn(Insn::OpenIndexReadAsync {
+            cursor_id: 0,
+            root_page: db.schema.get_table("t").unwrap().root_page + 1,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        for (i, value) in key.into_iter().enumerate() {
+            let dest = 2 + i;
+            program.emit_insn(match value {
+                OwnedValue::Null => Insn::Null {
+                    dest_start: dest,
+                    dest_end: dest,
+                },
+                OwnedValue::Integer(value) => Insn::Integer { value, dest },
+                OwnedValue::Text(value) => Insn::String8 {
+                    value: value.to_string(),
+                    dest,
+                },
+                _ => unreachable!(),
+            });
+        }
+        let start = program.offset();
+        program.emit_insn(if ge {
+            Insn::SeekGE {
+                cursor_id: 0,
+                key_reg: 2,
+                num_regs,
+                target_pc: start + 6,
+            }
+        } else {
+            Insn::SeekGT {
+                cursor_id: 0,
+                key_reg: 2,
+                num_regs,
+                target_pc: start + 6,
+            }
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: start + 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = [("a", 2), ("a", 4), ("b", 3), ("b", 5), ("c", 1)]
+            .into_iter()
+            .skip(skipped_rows)
+            .map(|(v, id)| vec![OwnedValue::Text(v.into()), OwnedValue::Integer(id)])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_idx_rowid_resolves_table_rows() {
+        let (io, db) = open_fixture(
+            "idx_rowid",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX t_v ON t (v);
+             INSERT INTO t VALUES (1, 'c'), (2, 'a'), (3, 'b'), (4, 'a'), (5, 'b');",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(2);
+        // The index is created right after the table, so it gets the next page.
+        program.emit_insn(Insn::OpenIndexReadAsync {
+            cursor_id: 0,
+            root_page: root_page + 1,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 1,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 12,
+        });
+        program.emit_insn(Insn::IdxRowid {
+            cursor_id: 0,
+            dest_reg: 0,
+        });
+        program.emit_insn(Insn::SeekRowid {
+            cursor_id: 1,
+            src_reg: 0,
+            target_pc: 10,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 1,
+            column: 1,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 6,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = [(2, "a"), (4, "a"), (3, "b"), (5, "b"), (1, "c")]
+            .into_iter()
+            .map(|(id, v)| vec![OwnedValue::Integer(id), OwnedValue::Text(v.into())])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[rstest]
+    #[case(ScalarFunc::Abs, Insn::Integer { value: -3, dest: 0 }, Value::Integer(3))]
+    #[case(ScalarFunc::Length, Insn::String8 { value: "h\u{e9}llo".to_string(), dest: 0 }, Value::Integer(5))]
+    #[case(ScalarFunc::Lower, Insn::String8 { value: "ABC".to_string(), dest: 0 }, Value::Text("abc"))]
+    #[case(ScalarFunc::Upper, Insn::String8 { value: "abc".to_string(), dest: 0 }, Value::Text("ABC"))]
+    #[case(ScalarFunc::Typeof, Insn::Real { value: 1.0, dest: 0 }, Value::Text("real"))]
+    fn test_function(#[case] func: ScalarFunc, #[case] arg: Insn, #[case] expected: Value) {
+        let mut program = program_with_registers(2);
+        program.emit_insn(arg);
+        program.emit_insn(Insn::Function {
+            func: Func::Scalar(func),
+            start_reg: 0,
+            arg_count: 1,
+            dest_reg: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[rstest]
+    #[case::text_text(
+        Insn::String8 { value: "foo".to_string(), dest: 0 },
+        Insn::String8 { value: "bar".to_string(), dest: 1 },
+        Value::Text("foobar")
+    )]
+    #[case::int_text(
+        Insn::Integer { value: -12, dest: 0 },
+        Insn::String8 { value: "ab".to_string(), dest: 1 },
+        Value::Text("-12ab")
+    )]
+    #[case::text_real(
+        Insn::String8 { value: "x".to_string(), dest: 0 },
+        Insn::Real { value: 2.0, dest: 1 },
+        Value::Text("x2.0")
+    )]
+    #[case::null_lhs(
+        Insn::Null { dest_start: 0, dest_end: 0 },
+        Insn::String8 { value: "bar".to_string(), dest: 1 },
+        Value::Null
+    )]
+    #[case::null_rhs(
+        Insn::String8 { value: "foo".to_string(), dest: 0 },
+        Insn::Null { dest_start: 1, dest_end: 1 },
+        Value::Null
+    )]
+    fn test_concat(#[case] lhs: Insn, #[case] rhs: Insn, #[case] expected: Value) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(lhs);
+        program.emit_insn(rhs);
+        program.emit_insn(Insn::Concat {
+            lhs: 0,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[rstest]
+    #[case(AggFunc::Count, Value::Integer(3))]
+    #[case(AggFunc::Sum, Value::Float(7.5))]
+    #[case(AggFunc::Avg, Value::Float(2.5))]
+    #[case(AggFunc::Min, Value::Integer(1))]
+    #[case(AggFunc::Max, Value::Float(4.5))]
+    fn test_aggregate(#[case] func: AggFunc, #[case] expected: Value) {
+        let values = [
+            Insn::Integer { value: 2, dest: 0 },
+            Insn::Null {
+                dest_start: 0,
+                dest_end: 0,
+            },
+            Insn::Real {
+                value: 4.5,
+                dest: 0,
+            },
+            Insn::Integer { value: 1, dest: 0 },
+        ];
+        let mut program = program_with_registers(3);
+        for value in values {
+            program.emit_insn(value);
+            program.emit_insn(Insn::AggStep {
+                func,
+                arg_reg: 0,
+                acc_reg: 1,
+            });
+        }
+        program.emit_insn(Insn::AggFinal { acc_reg: 1, func });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[test]
+    fn test_aggregate_over_no_rows() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::AggFinal {
+            acc_reg: 0,
+            func: AggFunc::Count,
+        });
+        program.emit_insn(Insn::AggFinal {
+            acc_reg: 1,
+            func: AggFunc::Avg,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Integer(0), Value::Null]);
+    }
+
+    #[rstest]
+    #[case::integer(Insn::String8 { value: "12ab".to_string(), dest: 0 }, Affinity::Integer, Value::Integer(12))]
+    #[case::real(Insn::String8 { value: "1.5e1x".to_string(), dest: 0 }, Affinity::Real, Value::Float(15.0))]
+    #[case::text(Insn::Real { value: 2.5, dest: 0 }, Affinity::Text, Value::Text("2.5"))]
+    #[case::blob(Insn::Integer { value: 7, dest: 0 }, Affinity::Blob, Value::Blob(&vec![b'7']))]
+    #[case::numeric(Insn::String8 { value: "3.0".to_string(), dest: 0 }, Affinity::Numeric, Value::Integer(3))]
+    #[case::null(Insn::Null { dest_start: 0, dest_end: 0 }, Affinity::Integer, Value::Null)]
+    fn test_cast(#[case] value: Insn, #[case] to_type: Affinity, #[case] expected: Value) {
+        let mut program = program_with_registers(1);
+        program.emit_insn(value);
+        program.emit_insn(Insn::Cast { reg: 0, to_type });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[test]
+    fn test_affinity() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::String8 {
+            value: "42".to_string(),
+            dest: 0,
+        });
+        program.emit_insn(Insn::String8 {
+            value: "forty-two".to_string(),
+            dest: 1,
+        });
+        program.emit_insn(Insn::Integer { value: 42, dest: 2 });
+        program.emit_insn(Insn::Affinity {
+            start_reg: 0,
+            count: 3,
+            affinities: "DCB".to_string(),
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(
+            program,
+            &[
+                Value::Integer(42),
+                Value::Text("forty-two"),
+                Value::Text("42"),
+            ],
+        );
+    }
+
+    #[rstest]
+    #[case::too_short("D")]
+    #[case::invalid("DX")]
+    fn test_affinity_rejects_bad_string(#[case] affinities: &str) {
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::Affinity {
+            start_reg: 0,
+            count: 2,
+            affinities: affinities.to_string(),
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        assert!(program.step(&mut state, test_pager()).is_err());
+    }
+
+    #[rstest]
+    #[case::and(Insn::BitAnd { lhs: 0, rhs: 1, dest: 2 }, 12, 10, Value::Integer(8))]
+    #[case::or(Insn::BitOr { lhs: 0, rhs: 1, dest: 2 }, 12, 10, Value::Integer(14))]
+    #[case::shl(Insn::ShiftLeft { lhs: 0, rhs: 1, dest: 2 }, 3, 2, Value::Integer(12))]
+    #[case::shl_overflow(Insn::ShiftLeft { lhs: 0, rhs: 1, dest: 2 }, 3, 63, Value::Integer(i64::MIN))]
+    #[case::shl_large(Insn::ShiftLeft { lhs: 0, rhs: 1, dest: 2 }, 3, 64, Value::Integer(0))]
+    #[case::shl_negative(Insn::ShiftLeft { lhs: 0, rhs: 1, dest: 2 }, 12, -2, Value::Integer(3))]
+    #[case::shr(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 }, 12, 2, Value::Integer(3))]
+    #[case::shr_sign(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 }, -12, 2, Value::Integer(-3))]
+    #[case::shr_large(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 }, 12, 100, Value::Integer(0))]
+    #[case::shr_large_negative(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 }, -12, 64, Value::Integer(-1))]
+    #[case::shr_negative(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 }, 3, -2, Value::Integer(12))]
+    #[case::shr_min(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 }, 3, i64::MIN, Value::Integer(0))]
+    fn test_bitwise(
+        #[case] insn: Insn,
+        #[case] lhs: i64,
+        #[case] rhs: i64,
+        #[case] expected: Value,
+    ) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer {
+            value: lhs,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Integer {
+            value: rhs,
+            dest: 1,
+        });
+        program.emit_insn(insn);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[rstest]
+    #[case(Insn::BitAnd { lhs: 0, rhs: 1, dest: 2 })]
+    #[case(Insn::BitOr { lhs: 0, rhs: 1, dest: 2 })]
+    #[case(Insn::ShiftLeft { lhs: 0, rhs: 1, dest: 2 })]
+    #[case(Insn::ShiftRight { lhs: 0, rhs: 1, dest: 2 })]
+    #[case(Insn::BitNot { reg: 1, dest: 2 })]
+    fn test_bitwise_null(#[case] insn: Insn) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(insn);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Null]);
+    }
+
+    #[rstest]
+    #[case(Insn::Integer { value: 5, dest: 0 }, Value::Integer(-6))]
+    #[case(Insn::Real { value: 5.7, dest: 0 }, Value::Integer(-6))]
+    #[case(Insn::String8 { value: "5x".to_string(), dest: 0 }, Value::Integer(-6))]
+    #[case(Insn::Integer { value: -1, dest: 0 }, Value::Integer(0))]
+    fn test_bit_not(#[case] value: Insn, #[case] expected: Value) {
+        let mut program = program_with_registers(2);
+        program.emit_insn(value);
+        program.emit_insn(Insn::BitNot { reg: 0, dest: 1 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[test]
+    fn test_bitwise_converts_operands() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Real {
+            value: 6.9,
+            dest: 0,
+        });
+        program.emit_insn(Insn::String8 {
+            value: "3abc".to_string(),
+            dest: 1,
+        });
+        program.emit_insn(Insn::BitAnd {
+            lhs: 0,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Integer(2)]);
+    }
+
+    #[rstest]
+    #[case(Insn::Real { value: 7.9, dest: 0 }, Insn::Integer { value: 3, dest: 1 }, Value::Float(1.0))]
+    #[case(Insn::Integer { value: -7, dest: 0 }, Insn::Real { value: 2.5, dest: 1 }, Value::Float(-1.0))]
+    #[case(Insn::Integer { value: 7, dest: 0 }, Insn::Real { value: 0.5, dest: 1 }, Value::Null)]
+    #[case(Insn::String8 { value: "7".to_string(), dest: 0 }, Insn::Integer { value: 4, dest: 1 }, Value::Integer(3))]
+    #[case(Insn::Null { dest_start: 0, dest_end: 0 }, Insn::Integer { value: 4, dest: 1 }, Value::Null)]
+    fn test_remainder(#[case] lhs: Insn, #[case] rhs: Insn, #[case] expected: Value) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(lhs);
+        program.emit_insn(rhs);
+        program.emit_insn(Insn::Remainder {
+            lhs: 0,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[rstest]
+    #[case::integer(Insn::Integer { value: 5, dest: 0 }, Value::Integer(-5))]
+    #[case::negative(Insn::Integer { value: -5, dest: 0 }, Value::Integer(5))]
+    #[case::min(Insn::Integer { value: i64::MIN, dest: 0 }, Value::Float(9.223372036854776e18))]
+    #[case::real(Insn::Real { value: 2.5, dest: 0 }, Value::Float(-2.5))]
+    #[case::text(Insn::String8 { value: "3".to_string(), dest: 0 }, Value::Integer(-3))]
+    #[case::null(Insn::Null { dest_start: 0, dest_end: 0 }, Value::Null)]
+    fn test_negative(#[case] value: Insn, #[case] expected: Value) {
+        assert_unary_result(value, Insn::Negative { reg: 0, dest: 1 }, expected);
+    }
+
+    #[rstest]
+    #[case::zero(Insn::Integer { value: 0, dest: 0 }, Value::Integer(1))]
+    #[case::integer(Insn::Integer { value: -2, dest: 0 }, Value::Integer(0))]
+    #[case::real(Insn::Real { value: 0.5, dest: 0 }, Value::Integer(0))]
+    #[case::real_zero(Insn::Real { value: 0.0, dest: 0 }, Value::Integer(1))]
+    #[case::text(Insn::String8 { value: "abc".to_string(), dest: 0 }, Value::Integer(1))]
+    #[case::null(Insn::Null { dest_start: 0, dest_end: 0 }, Value::Null)]
+    fn test_not(#[case] value: Insn, #[case] expected: Value) {
+        assert_unary_result(value, Insn::Not { reg: 0, dest: 1 }, expected);
+    }
+
+    #[rstest]
+    #[case::real(Insn::Real { value: -0.5, dest: 0 }, Value::Integer(-1))]
+    #[case::null(Insn::Null { dest_start: 0, dest_end: 0 }, Value::Null)]
+    fn test_unary_bit_not(#[case] value: Insn, #[case] expected: Value) {
+        assert_unary_result(value, Insn::BitNot { reg: 0, dest: 1 }, expected);
+    }
+
+    /// Apply a unary instruction from register 0 to register 1.
+    fn assert_unary_result(value: Insn, insn: Insn, expected: Value) {
+        let mut program = program_with_registers(2);
+        program.emit_insn(value);
+        program.emit_insn(insn);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[expected]);
+    }
+
+    #[rstest]
+    #[case::less((1, "b"), (1, "c"), Ordering::Less)]
+    #[case::equal((1, "b"), (1, "b"), Ordering::Equal)]
+    #[case::greater((2, "a"), (1, "z"), Ordering::Greater)]
+    #[case::first_column_wins((0, "z"), (1, "a"), Ordering::Less)]
+    fn test_compare(
+        #[case] lhs: (i64, &str),
+        #[case] rhs: (i64, &str),
+        #[case] expected: Ordering,
+    ) {
+        let mut program = program_with_registers(4);
+        for (start, (num, text)) in [(0, lhs), (2, rhs)] {
+            program.emit_insn(Insn::Integer {
+                value: num,
+                dest: start,
+            });
+            program.emit_insn(Insn::String8 {
+                value: text.to_string(),
+                dest: start + 1,
+            });
+        }
+        program.emit_insn(Insn::Compare {
+            lhs_start: 0,
+            rhs_start: 2,
+            count: 2,
+            collations: vec![Collation::Binary, Collation::Binary],
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        assert!(matches!(
+            program.step(&mut state, test_pager()).unwrap(),
+            StepResult::Done
+        ));
+        assert_eq!(state.last_compare.unwrap().ordering, expected);
+    }
+
+    #[rstest]
+    #[case::null_sorts_first(Insn::Null { dest_start: 0, dest_end: 0 }, Insn::Integer { value: 1, dest: 1 }, 4)]
+    #[case::nulls_are_equal(Insn::Null { dest_start: 0, dest_end: 0 }, Insn::Null { dest_start: 1, dest_end: 1 }, 6)]
+    #[case::greater(Insn::String8 { value: "a".to_string(), dest: 0 }, Insn::Integer { value: 1, dest: 1 }, 8)]
+    fn test_compare_then_jump(#[case] lhs: Insn, #[case] rhs: Insn, #[case] expected: i64) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(lhs);
+        program.emit_insn(rhs);
+        program.emit_insn(Insn::Compare {
+            lhs_start: 0,
+            rhs_start: 1,
+            count: 1,
+            collations: vec![],
+        });
+        program.emit_insn(Insn::Jump {
+            target_lt: 4,
+            target_eq: 6,
+            target_gt: 8,
+        });
+        for value in [4, 6, 8] {
+            program.emit_insn(Insn::Integer { value, dest: 2 });
+            program.emit_insn(Insn::Goto { target_pc: 10 });
+        }
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Integer(expected)]);
+    }
+
+    #[rstest]
+    #[case::binary("ABC", "abc", None, false)]
+    #[case::nocase("ABC", "abc", Some(Collation::NoCase), true)]
+    #[case::rtrim("x  ", "x", Some(Collation::Rtrim), true)]
+    #[case::rtrim_differs("x  ", "y", Some(Collation::Rtrim), false)]
+    fn test_eq_with_collation(
+        #[case] lhs: &str,
+        #[case] rhs: &str,
+        #[case] collation: Option<Collation>,
+        #[case] jumps: bool,
+    ) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::String8 {
+            value: lhs.to_string(),
+            dest: 0,
+        });
+        program.emit_insn(Insn::String8 {
+            value: rhs.to_string(),
+            dest: 1,
+        });
+        program.emit_insn(Insn::Eq {
+            lhs: 0,
+            rhs: 1,
+            target_pc: 5,
+            collation,
+        });
+        program.emit_insn(Insn::Integer { value: 0, dest: 2 });
+        program.emit_insn(Insn::Goto { target_pc: 6 });
+        program.emit_insn(Insn::Integer { value: 1, dest: 2 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Integer(jumps as i64)]);
+    }
+
+    #[test]
+    fn test_compare_with_collations() {
+        let mut program = program_with_registers(4);
+        for (dest, value) in ["b ", "ABC", "b", "abc"].into_iter().enumerate() {
+            program.emit_insn(Insn::String8 {
+                value: value.to_string(),
+                dest,
+            });
+        }
+        program.emit_insn(Insn::Compare {
+            lhs_start: 0,
+            rhs_start: 2,
+            count: 2,
+            collations: vec![Collation::Rtrim, Collation::NoCase],
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        program.step(&mut state, test_pager()).unwrap();
+        assert_eq!(state.last_compare.unwrap().ordering, Ordering::Equal);
+    }
+
+    #[rstest]
+    #[case::asc(SortOrder::Asc, &[(1, "c"), (2, "a"), (3, "b")])]
+    #[case::desc(SortOrder::Desc, &[(3, "b"), (2, "a"), (1, "c")])]
+    fn test_sorter(#[case] order: SortOrder, #[case] expected: &[(i64, &str)]) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::SorterOpen {
+            cursor_id: 0,
+            keys: vec![SortKey {
+                column: 0,
+                order,
+                collation: Collation::Binary,
+            }],
+        });
+        for (num, text) in [(2, "a"), (3, "b"), (1, "c")] {
+            program.emit_insn(Insn::Integer {
+                value: num,
+                dest: 0,
+            });
+            program.emit_insn(Insn::String8 {
+                value: text.to_string(),
+                dest: 1,
+            });
+            program.emit_insn(Insn::MakeRecord {
+                start_reg: 0,
+                count: 2,
+                dest_reg: 2,
+            });
+            program.emit_insn(Insn::SorterInsert {
+                cursor_id: 0,
+                record_reg: 2,
+            });
+        }
+        program.emit_insn(Insn::SorterSort {
+            cursor_id: 0,
+            pc_if_empty: 18,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 1,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::SorterNext {
+            cursor_id: 0,
+            pc_if_next: 14,
+        });
+        program.emit_insn(Insn::Halt);
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(num, text)| vec![OwnedValue::Integer(*num), OwnedValue::Text((*text).into())])
+            .collect();
+        assert_eq!(collect_rows(program), expected);
+    }
+
+    #[test]
+    fn test_sorter_utf16_database() {
+        // Records made by the program hold UTF-8 text, even in a database
+        // whose text is UTF-16.
+        let (io, db) = open_fixture(
+            "sorter_utf16",
+            "PRAGMA encoding = 'UTF-16le'; CREATE TABLE t (x TEXT);",
+        );
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::SorterOpen {
+            cursor_id: 0,
+            keys: vec![SortKey {
+                column: 0,
+                order: SortOrder::Asc,
+                collation: Collation::Binary,
+            }],
+        });
+        for text in ["b", "c", "a"] {
+            program.emit_insn(Insn::String8 {
+                value: text.to_string(),
+                dest: 0,
+            });
+            program.emit_insn(Insn::MakeRecord {
+                start_reg: 0,
+                count: 1,
+                dest_reg: 1,
+            });
+            program.emit_insn(Insn::SorterInsert {
+                cursor_id: 0,
+                record_reg: 1,
+            });
+        }
+        program.emit_insn(Insn::SorterSort {
+            cursor_id: 0,
+            pc_if_empty: 14,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::SorterNext {
+            cursor_id: 0,
+            pc_if_next: 11,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows: Vec<_> = run_program(&io, &db, program)
+            .iter()
+            .map(|row| row[0].to_text().unwrap())
+            .collect();
+        assert_eq!(rows, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_sorter_data() {
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::SorterOpen {
+            cursor_id: 0,
+            keys: vec![],
+        });
+        // Sorting the empty sorter jumps over the Halt.
+        program.emit_insn(Insn::SorterSort {
+            cursor_id: 0,
+            pc_if_empty: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::MakeRecord {
+            start_reg: 0,
+            count: 1,
+            dest_reg: 1,
+        });
+        program.emit_insn(Insn::SorterInsert {
+            cursor_id: 0,
+            record_reg: 1,
+        });
+        program.emit_insn(Insn::SorterSort {
+            cursor_id: 0,
+            pc_if_empty: 2,
+        });
+        program.emit_insn(Insn::SorterData {
+            cursor_id: 0,
+            dest_reg: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let record = OwnedRecord::new(vec![OwnedValue::Integer(1)]).serialize(TextEncoding::Utf8);
+        let record = OwnedValue::Blob(Rc::new(record));
+        assert_eq!(collect_rows(program), vec![vec![record.clone(), record]]);
+    }
+
+    #[test]
+    fn test_distinct() {
+        let rows = [
+            (Insn::Integer { value: 1, dest: 0 }, "a"),
+            (Insn::Integer { value: 2, dest: 0 }, "a"),
+            (Insn::Integer { value: 1, dest: 0 }, "a"),
+            (
+                Insn::Real {
+                    value: 1.0,
+                    dest: 0,
+                },
+                "a",
+            ),
+            (Insn::Integer { value: 1, dest: 0 }, "b"),
+            (
+                Insn::Null {
+                    dest_start: 0,
+                    dest_end: 0,
+                },
+                "a",
+            ),
+            (
+                Insn::Null {
+                    dest_start: 0,
+                    dest_end: 0,
+                },
+                "a",
+            ),
+        ];
+        let mut program = program_with_registers(2);
+        for (value, text) in rows {
+            let next_row = program.offset() + 4;
+            program.emit_insn(value);
+            program.emit_insn(Insn::String8 {
+                value: text.to_string(),
+                dest: 1,
+            });
+            program.emit_insn(Insn::Distinct {
+                set_id: 0,
+                start_reg: 0,
+                count: 2,
+                target_pc: next_row,
+            });
+            program.emit_insn(Insn::ResultRow {
+                register_start: 0,
+                register_end: 2,
+            });
+        }
+        program.emit_insn(Insn::Halt);
+        let text = |s: &str| OwnedValue::Text(s.into());
+        assert_eq!(
+            collect_rows(program),
+            vec![
+                vec![OwnedValue::Integer(1), text("a")],
+                vec![OwnedValue::Integer(2), text("a")],
+                vec![OwnedValue::Integer(1), text("b")],
+                vec![OwnedValue::Null, text("a")],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ephemeral_table() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: 0,
+            num_columns: 1,
+            is_index: false,
+        });
+        for (rowid, text) in [(3, "c"), (1, "a"), (2, "b")] {
+            program.emit_insn(Insn::Integer {
+                value: rowid,
+                dest: 0,
+            });
+            program.emit_insn(Insn::String8 {
+                value: text.to_string(),
+                dest: 1,
+            });
+            program.emit_insn(Insn::MakeRecord {
+                start_reg: 1,
+                count: 1,
+                dest_reg: 2,
+            });
+            program.emit_insn(Insn::Insert {
+                cursor_id: 0,
+                key_reg: 0,
+                record_reg: 2,
+            });
+        }
+        // Read the rows back in rowid order, then seek to one of them.
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 24,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 15,
+        });
+        program.emit_insn(Insn::Integer { value: 2, dest: 0 });
+        program.emit_insn(Insn::SeekRowid {
+            cursor_id: 0,
+            src_reg: 0,
+            target_pc: 24,
+        });
+        program.emit_insn(Insn::Column {
+            cursor_id: 0,
+            column: 0,
+            dest: 1,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let row =
+            |rowid, text: &str| vec![OwnedValue::Integer(rowid), OwnedValue::Text(text.into())];
+        assert_eq!(
+            collect_rows(program),
+            vec![row(1, "a"), row(2, "b"), row(3, "c"), row(2, "b")]
+        );
+    }
+
+    #[rstest]
+    #[case::member(2, true)]
+    #[case::first(1, true)]
+    #[case::last(5, true)]
+    #[case::between(3, false)]
+    #[case::past_end(9, false)]
+    fn test_found_in_ephemeral_set(#[case] probe: i64, #[case] member: bool) {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: 0,
+            num_columns: 1,
+            is_index: true,
+        });
+        for value in [5, 1, 2] {
+            program.emit_insn(Insn::Integer { value, dest: 0 });
+            program.emit_insn(Insn::MakeRecord {
+                start_reg: 0,
+                count: 1,
+                dest_reg: 1,
+            });
+            program.emit_insn(Insn::IdxInsert {
+                cursor_id: 0,
+                record_reg: 1,
+            });
+        }
+        // r[1] = Found, r[2] = NotFound: each is 1 if the jump was taken.
+        program.emit_insn(Insn::Integer {
+            value: probe,
+            dest: 0,
+        });
+        for (offset, dest) in [(11, 1), (15, 2)] {
+            program.emit_insn(Insn::Integer { value: 0, dest });
+            let insn = if dest == 1 {
+                Insn::Found {
+                    cursor_id: 0,
+                    key_reg: 0,
+                    num_regs: 1,
+                    target_pc: offset + 3,
                 }
-                Insn::RowId { cursor_id, dest } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    if let Some(ref rowid) = *cursor.rowid()? {
-                        state.registers[*dest] = OwnedValue::Integer(*rowid as i64);
-                    } else {
-                        todo!();
-                    }
-                    state.pc += 1;
+            } else {
+                Insn::NotFound {
+                    cursor_id: 0,
+                    key_reg: 0,
+                    num_regs: 1,
+                    target_pc: offset + 3,
                 }
-                Insn::DecrJumpZero { reg, target_pc } => match state.registers[*reg] {
-                    OwnedValue::Integer(n) => {
-                        if n > 0 {
-                            state.registers[*reg] = OwnedValue::Integer(n - 1);
-                            state.pc += 1;
-                        } else {
-                            state.pc = *target_pc;
-                        }
-                    }
-                    _ => unreachable!("DecrJumpZero on non-integer register"),
+            };
+            program.emit_insn(insn);
+            program.emit_insn(Insn::Goto {
+                target_pc: offset + 4,
+            });
+            program.emit_insn(Insn::Integer { value: 1, dest });
+        }
+        program.emit_insn(Insn::ResultRow {
+            register_start: 1,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(
+            program,
+            &[
+                Value::Integer(member as i64),
+                Value::Integer(!member as i64),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_explain_to_string() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Integer { value: 42, dest: 0 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let listing = program.build().unwrap().explain_to_string();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("addr  opcode"));
+        assert!(lines[2].starts_with("0     Integer        0     42"));
+        assert!(lines[3].starts_with("1     ResultRow"));
+        assert!(lines[4].starts_with("2     Halt"));
+    }
+
+    #[test]
+    fn test_explain_custom_comment() {
+        let mut program = program_with_registers(1);
+        program.emit_insn_with_comment(
+            Insn::Integer { value: 7, dest: 0 },
+            "r[0]=users.id".to_string(),
+        );
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let lines: Vec<String> = program
+            .explain_to_string()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert!(lines[2].ends_with("r[0]=users.id"));
+        assert_eq!(program.explain_rows()[0].comment, "r[0]=users.id");
+        assert_eq!(program.explain_rows()[1].comment, "");
+    }
+
+    #[test]
+    fn test_explain_rows() {
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::Integer { value: 42, dest: 0 });
+        program.emit_insn(Insn::AggStep {
+            func: AggFunc::Sum,
+            arg_reg: 0,
+            acc_reg: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = program.build().unwrap().explain_rows();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[0],
+            ExplainRow {
+                addr: 0,
+                opcode: "Integer",
+                p1: 0,
+                p2: 42,
+                p3: 0,
+                p4: "".to_string(),
+                p5: 0,
+                comment: "".to_string(),
+            }
+        );
+        assert_eq!(
+            rows[1],
+            ExplainRow {
+                addr: 1,
+                opcode: "AggStep",
+                p1: 0,
+                p2: 0,
+                p3: 1,
+                p4: "sum".to_string(),
+                p5: 1,
+                comment: "accum=r[1] step(r[0])".to_string(),
+            }
+        );
+        assert_eq!(rows[2].opcode, "Halt");
+    }
+
+    #[test]
+    fn test_explain_concat() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Concat {
+            lhs: 0,
+            rhs: 1,
+            dest: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = program.build().unwrap().explain_rows();
+        assert_eq!(rows[0].opcode, "Concat");
+        assert_eq!(rows[0].comment, "r[2]=r[0]||r[1]");
+    }
+
+    #[test]
+    fn test_insn_counts() {
+        // Add one to r[1] three times, counting r[0] down from 2.
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 2, dest: 0 });
+        program.emit_insn(Insn::Integer { value: 0, dest: 1 });
+        program.emit_insn(Insn::Integer { value: 1, dest: 2 });
+        program.emit_insn(Insn::Add {
+            lhs: 1,
+            rhs: 2,
+            dest: 1,
+        });
+        program.emit_insn(Insn::IfPos {
+            reg: 0,
+            target_pc: 3,
+            decrement: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+
+        let mut state = ProgramState::new(program.max_registers);
+        state.enable_profiling();
+        assert!(matches!(
+            program.step(&mut state, test_pager()).unwrap(),
+            StepResult::Done
+        ));
+        assert_eq!(state.insn_counts(), &[1, 1, 1, 3, 3, 1]);
+
+        let mut state = ProgramState::new(program.max_registers);
+        program.step(&mut state, test_pager()).unwrap();
+        assert!(state.insn_counts().is_empty());
+    }
+
+    #[test]
+    fn test_step_single() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::Goto { target_pc: 3 });
+        program.emit_insn(Insn::Halt);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let pager = test_pager();
+
+        let mut pcs = Vec::new();
+        for _ in 0..2 {
+            match program.step_single(&mut state, pager.clone()).unwrap() {
+                StepOutcome::Next { pc } => pcs.push(pc),
+                _ => panic!("expected the program to continue"),
+            }
+        }
+        assert_eq!(pcs, vec![1, 3]);
+        match program.step_single(&mut state, pager.clone()).unwrap() {
+            StepOutcome::Row(record) => assert_eq!(record.values, vec![Value::Integer(1)]),
+            _ => panic!("expected a result row"),
+        }
+        assert_eq!(state.pc, 4);
+        assert!(matches!(
+            program.step_single(&mut state, pager).unwrap(),
+            StepOutcome::Done
+        ));
+        assert_eq!(state.pc, 4);
+    }
+
+    #[test]
+    fn test_register_accessors() {
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::Integer { value: 42, dest: 0 });
+        program.emit_insn(Insn::String8 {
+            value: "hello".to_string(),
+            dest: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        program.step(&mut state, test_pager()).unwrap();
+        let hello = OwnedValue::Text("hello".into());
+        assert_eq!(state.register(0), Some(&OwnedValue::Integer(42)));
+        assert_eq!(state.register(1), Some(&OwnedValue::Null));
+        assert_eq!(state.register(2), Some(&hello));
+        assert_eq!(state.register(3), None);
+        assert_eq!(
+            state.registers_snapshot(),
+            vec![OwnedValue::Integer(42), OwnedValue::Null, hello]
+        );
+    }
+
+    #[test]
+    fn test_cursor_positions() {
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: 0,
+            num_columns: 1,
+            is_index: false,
+        });
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: 1,
+            num_columns: 1,
+            is_index: false,
+        });
+        program.emit_insn(Insn::MakeRecord {
+            start_reg: 1,
+            count: 1,
+            dest_reg: 1,
+        });
+        for rowid in [1, 2] {
+            program.emit_insn(Insn::Integer {
+                value: rowid,
+                dest: 0,
+            });
+            program.emit_insn(Insn::Insert {
+                cursor_id: 0,
+                key_reg: 0,
+                record_reg: 1,
+            });
+        }
+        // Move cursor 0 to its second row, and cursor 1 past the end of its
+        // empty table.
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 14,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 11,
+        });
+        program.emit_insn(Insn::RewindAsync { cursor_id: 1 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 1,
+            pc_if_empty: 13,
+        });
+        program.emit_insn(Insn::Halt);
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        assert!(matches!(
+            program.step(&mut state, test_pager()).unwrap(),
+            StepResult::Done
+        ));
+        assert_eq!(state.pc, 13);
+        assert_eq!(
+            state.cursor_positions().unwrap(),
+            vec![
+                CursorPosition {
+                    cursor_id: 0,
+                    rowid: Some(2),
+                    at_eof: false,
+                },
+                CursorPosition {
+                    cursor_id: 1,
+                    rowid: None,
+                    at_eof: true,
                 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_noop() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        let removed = program.offset();
+        program.emit_insn(Insn::Integer { value: 2, dest: 0 });
+        program.emit_insn(Insn::Goto { target_pc: 4 });
+        program.emit_insn(Insn::Halt);
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        program.fixup_insn(removed, Insn::Noop);
+        assert_result_row(program, &[Value::Integer(1)]);
+    }
+
+    #[test]
+    fn test_result_row_list() {
+        let mut program = program_with_registers(6);
+        program.emit_insn(Insn::Integer { value: 1, dest: 5 });
+        program.emit_insn(Insn::Integer { value: 2, dest: 0 });
+        program.emit_insn(Insn::Integer { value: 3, dest: 3 });
+        program.emit_insn(Insn::ResultRowList {
+            regs: vec![5, 0, 3, 5],
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(
+            program,
+            &[
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+                Value::Integer(1),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_alloc_parameter() {
+        let mut program = ProgramBuilder::new();
+        assert_eq!(program.alloc_parameter("").unwrap(), 1);
+        assert_eq!(program.alloc_parameter(":a").unwrap(), 2);
+        assert_eq!(program.alloc_parameter("5").unwrap(), 5);
+        assert_eq!(program.alloc_parameter("").unwrap(), 6);
+        assert_eq!(program.alloc_parameter(":a").unwrap(), 2);
+        assert_eq!(program.alloc_parameter("@b").unwrap(), 7);
+        assert!(program.alloc_parameter("0").is_err());
+    }
+
+    #[test]
+    fn test_bind_named() {
+        let mut program = program_with_registers(3);
+        let id = program.alloc_parameter(":id").unwrap();
+        let name = program.alloc_parameter("@name").unwrap();
+        program.emit_insn(Insn::Variable {
+            index: name,
+            dest: 0,
+        });
+        program.emit_insn(Insn::Variable { index: id, dest: 1 });
+        program.emit_insn(Insn::Variable { index: 3, dest: 2 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::for_program(&program);
+        assert_eq!(state.parameter_index("@name"), Some(2));
+        state.bind_named(":id", OwnedValue::Integer(7)).unwrap();
+        state
+            .bind_named("@name", OwnedValue::Text("alice".into()))
+            .unwrap();
+        assert!(matches!(
+            state.bind_named(":missing", OwnedValue::Null),
+            Err(LimboError::Internal(_))
+        ));
+        let alice = "alice".to_string();
+        match program.step(&mut state, test_pager()).unwrap() {
+            StepResult::Row(record) => assert_eq!(
+                record.values,
+                vec![Value::Text(&alice), Value::Integer(7), Value::Null]
+            ),
+            _ => panic!("expected a result row"),
+        }
+    }
+
+    #[test]
+    fn test_reset_state() {
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::Init { target_pc: 1 });
+        program.emit_insn(Insn::Once { target_pc: 3 });
+        program.emit_insn(Insn::Integer { value: 1, dest: 1 });
+        program.emit_insn(Insn::Variable { index: 1, dest: 0 });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::for_program(&program);
+        state.bind(1, OwnedValue::Integer(42)).unwrap();
+        let registers = state.registers.as_ptr();
+        let expected = [Value::Integer(42), Value::Integer(1)];
+        for _ in 0..3 {
+            match program.step(&mut state, test_pager()).unwrap() {
+                StepResult::Row(record) => assert_eq!(record.values, expected),
+                _ => panic!("expected a result row"),
             }
+            assert!(matches!(
+                program.step(&mut state, test_pager()).unwrap(),
+                StepResult::Done
+            ));
+            state.reset();
+            assert_eq!(state.pc, 0);
+            assert!(state
+                .registers
+                .iter()
+                .all(|value| *value == OwnedValue::Null));
         }
+        assert_eq!(state.registers.as_ptr(), registers);
     }
-}
 
-fn make_record<'a>(registers: &'a [OwnedValue], register_end: &usize, register_start: &usize) -> Record<'a> {
-    let mut values = Vec::with_capacity(*register_end - *register_start);
-    for i in *register_start..*register_end {
-        values.push(crate::types::to_value(&registers[i]));
+    #[test]
+    fn test_state_pool_reuses_registers() {
+        let mut big = program_with_registers(8);
+        big.emit_insn(Insn::Integer { value: 1, dest: 7 });
+        big.emit_insn(Insn::Halt);
+        let big = big.build().unwrap();
+        let mut small = program_with_registers(2);
+        small.emit_insn(Insn::Variable { index: 1, dest: 0 });
+        small.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 2,
+        });
+        small.emit_insn(Insn::Halt);
+        let small = small.build().unwrap();
+
+        let mut pool = ProgramStatePool::new();
+        let mut state = pool.acquire(&big);
+        state.bind(1, OwnedValue::Integer(5)).unwrap();
+        big.step(&mut state, test_pager()).unwrap();
+        let registers = state.registers.as_ptr();
+        pool.release(state);
+
+        let mut state = pool.acquire(&small);
+        assert_eq!(state.registers.as_ptr(), registers);
+        assert_eq!(state.column_count(), 2);
+        match small.step(&mut state, test_pager()).unwrap() {
+            StepResult::Row(record) => {
+                assert_eq!(record.values, vec![Value::Null, Value::Null])
+            }
+            _ => panic!("expected a result row"),
+        }
+        pool.release(state);
+
+        let state = pool.acquire(&big);
+        assert_eq!(state.registers.as_ptr(), registers);
+        assert_eq!(state.column_count(), 8);
+        assert_eq!(state.register(7), Some(&OwnedValue::Null));
     }
-    Record::new(values)
-}
 
-fn trace_insn(addr: usize, insn: &Insn) {
-    if !log::log_enabled!(log::Level::Trace) {
-        return;
+    #[test]
+    fn test_result_row_ref() {
+        let (io, db) = open_fixture(
+            "result_row_ref",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, a TEXT, b TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+             INSERT INTO t SELECT i, 'short' || i, printf('%05000d', i) FROM n;",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 8,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 0,
+        });
+        program.emit_insn(Insn::ResultRowRef {
+            sources: vec![
+                ResultSource::Column {
+                    cursor_id: 0,
+                    column: 1,
+                },
+                ResultSource::Register(0),
+                ResultSource::Column {
+                    cursor_id: 0,
+                    column: 2,
+                },
+                ResultSource::Column {
+                    cursor_id: 0,
+                    column: 3,
+                },
+            ],
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 4,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected: Vec<_> = (1..=200)
+            .map(|i| {
+                vec![
+                    OwnedValue::Text(format!("short{}", i).into()),
+                    OwnedValue::Integer(i),
+                    OwnedValue::Text(format!("{:05000}", i).into()),
+                    OwnedValue::Null,
+                ]
+            })
+            .collect();
+        assert_eq!(rows, expected);
     }
-    log::trace!("{}", insn_to_str(addr, insn));
-}
 
-fn print_insn(addr: usize, insn: &Insn) {
-    let s = insn_to_str(addr, insn);
-    println!("{}", s);
-}
+    #[test]
+    fn test_result_row_ref_without_cursor() {
+        let mut program = program_with_registers(0);
+        program.emit_insn(Insn::ResultRowRef {
+            sources: vec![ResultSource::Column {
+                cursor_id: 0,
+                column: 0,
+            }],
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(
+            err,
+            LimboError::Internal(message) if message == "cursor 0 is not open"
+        ));
+    }
 
-fn insn_to_str(addr: usize, insn: &Insn) -> String {
-    let (opcode, p1, p2, p3, p4, p5, comment) = match insn {
-        Insn::Init { target_pc } => (
-            "Init",
-            0,
-            *target_pc,
-            0,
-            "",
-            0,
-            format!("Start at {}", target_pc),
-        ),
-        Insn::OpenReadAsync {
-            cursor_id,
+    #[test]
+    fn test_row_data_copy() {
+        let (io, db) = open_fixture(
+            "row_data",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT, n REAL);
+             INSERT INTO t VALUES (1, 'one', 1.5), (2, NULL, -2.25), (3, 'three', NULL);",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(3);
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: 0,
             root_page,
-        } => (
-            "OpenReadAsync",
-            *cursor_id,
-            *root_page,
-            0,
-            "",
-            0,
-            format!("root={}", root_page),
-        ),
-        Insn::OpenReadAwait => ("OpenReadAwait", 0, 0, 0, "", 0, "".to_string()),
-        Insn::RewindAsync { cursor_id } => ("RewindAsync", *cursor_id, 0, 0, "", 0, "".to_string()),
-        Insn::RewindAwait {
-            cursor_id,
-            pc_if_empty,
-        } => (
-            "RewindAwait",
-            *cursor_id,
-            *pc_if_empty,
-            0,
-            "",
-            0,
-            "".to_string(),
-        ),
-        Insn::Column {
-            cursor_id,
-            column,
-            dest,
-        } => (
-            "Column",
-            *cursor_id,
-            *column,
-            *dest,
-            "",
-            0,
-            format!("r[{}]= cursor {} column {}", dest, cursor_id, column),
-        ),
-        Insn::ResultRow {
-            register_start,
-            register_end,
-        } => (
-            "ResultRow",
-            *register_start,
-            *register_end,
-            0,
-            "",
-            0,
-            format!("output=r[{}..{}]", register_start, register_end),
-        ),
-        Insn::NextAsync { cursor_id } => ("NextAsync", *cursor_id, 0, 0, "", 0, "".to_string()),
-        Insn::NextAwait {
-            cursor_id,
-            pc_if_next,
-        } => (
-            "NextAwait",
-            *cursor_id,
-            *pc_if_next,
-            0,
-            "",
-            0,
-            "".to_string(),
-        ),
-        Insn::Halt => ("Halt", 0, 0, 0, "", 0, "".to_string()),
-        Insn::Transaction => ("Transaction", 0, 0, 0, "", 0, "".to_string()),
-        Insn::Goto { target_pc } => ("Goto", 0, *target_pc, 0, "", 0, "".to_string()),
-        Insn::Integer { value, dest } => {
-            ("Integer", *dest, *value as usize, 0, "", 0, "".to_string())
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: 1,
+            num_columns: 3,
+            is_index: false,
+        });
+        program.emit_insn(Insn::RewindAsync { cursor_id: 0 });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id: 0,
+            pc_if_empty: 12,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: 0,
+            dest: 1,
+        });
+        program.emit_insn(Insn::RowData {
+            cursor_id: 0,
+            dest_reg: 0,
+        });
+        program.emit_insn(Insn::Insert {
+            cursor_id: 1,
+            key_reg: 1,
+            record_reg: 0,
+        });
+        program.emit_insn(Insn::RowData {
+            cursor_id: 1,
+            dest_reg: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::NextAsync { cursor_id: 0 });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id: 0,
+            pc_if_next: 5,
+        });
+        program.emit_insn(Insn::Halt);
+        let rows = run_program(&io, &db, program);
+        let expected = [
+            vec![OwnedValue::Text("one".into()), OwnedValue::Float(1.5)],
+            vec![OwnedValue::Null, OwnedValue::Float(-2.25)],
+            vec![OwnedValue::Text("three".into()), OwnedValue::Null],
+        ];
+        assert_eq!(rows.len(), expected.len());
+        for (rowid, (row, values)) in rows.iter().zip(expected).enumerate() {
+            let mut record = vec![OwnedValue::Null];
+            record.extend(values);
+            let bytes = OwnedValue::Blob(Rc::new(
+                OwnedRecord::new(record).serialize(TextEncoding::Utf8),
+            ));
+            assert_eq!(row[0], bytes);
+            assert_eq!(row[1], OwnedValue::Integer(rowid as i64 + 1));
+            assert_eq!(row[2], bytes);
         }
-        Insn::String8 { value, dest } => (
-            "String8",
-            *dest,
-            0,
-            0,
-            value.as_str(),
-            0,
-            format!("r[{}]= '{}'", dest, value),
-        ),
-        Insn::RowId { cursor_id, dest } => ("RowId", *cursor_id, *dest, 0, "", 0, "".to_string()),
-        Insn::DecrJumpZero { reg, target_pc } => {
-            ("DecrJumpZero", *reg, *target_pc, 0, "", 0, "".to_string())
+    }
+
+    #[test]
+    fn test_row_data_without_row() {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: 0,
+            num_columns: 1,
+            is_index: false,
+        });
+        program.emit_insn(Insn::Integer { value: 1, dest: 0 });
+        program.emit_insn(Insn::RowData {
+            cursor_id: 0,
+            dest_reg: 0,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 0,
+            register_end: 1,
+        });
+        program.emit_insn(Insn::Halt);
+        assert_result_row(program, &[Value::Null]);
+    }
+
+    /// A program that inserts the row (`key`, 'new') in a write transaction
+    /// and ends the transaction with `end`.
+    fn insert_in_transaction(root_page: usize, key: i64, end: Option<Insn>) -> ProgramBuilder {
+        let mut program = program_with_registers(2);
+        program.emit_insn(Insn::Transaction { write: true });
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        program.emit_insn(Insn::Integer {
+            value: key,
+            dest: 0,
+        });
+        // The record (NULL, 'new'), where the NULL is the rowid alias.
+        program.emit_insn(Insn::Blob {
+            value: vec![3, 0, 19, b'n', b'e', b'w'],
+            dest: 1,
+        });
+        program.emit_insn(Insn::Insert {
+            cursor_id: 0,
+            key_reg: 0,
+            record_reg: 1,
+        });
+        if let Some(end) = end {
+            program.emit_insn(end);
         }
-    };
-    format!(
-        "{:<4}  {:<13}  {:<4}  {:<4}  {:<4}  {:<13}  {:<2}  {}",
-        addr, opcode, p1, p2, p3, p4, p5, comment
-    )
+        program.emit_insn(Insn::Halt);
+        program
+    }
+
+    #[rstest]
+    #[case::commit(Some(Insn::Commit), true)]
+    #[case::commit_on_halt(None, true)]
+    #[case::rollback(Some(Insn::Rollback), false)]
+    fn test_write_transaction(#[case] end: Option<Insn>, #[case] persisted: bool) {
+        let path = create_fixture(
+            "transaction",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'one'), (2, 'two');",
+        );
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        run_program(&io, &db, insert_in_transaction(root_page, 5, end));
+        assert!(!db.pager.in_write_transaction());
+        let mut expected = vec![
+            vec![OwnedValue::Integer(1), OwnedValue::Text("one".into())],
+            vec![OwnedValue::Integer(2), OwnedValue::Text("two".into())],
+        ];
+        if persisted {
+            expected.push(vec![OwnedValue::Integer(5), OwnedValue::Text("new".into())]);
+        }
+        assert_eq!(query_rows(&io, &db, "SELECT * FROM t"), expected);
+        drop(db);
+        let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+        assert_eq!(query_rows(&io, &db, "SELECT * FROM t"), expected);
+        drop(db);
+        // SQLite reads the committed file too.
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT count(*) FROM t", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, expected.len() as i64);
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}-journal", path.display()));
+    }
+
+    #[test]
+    fn test_commit_without_transaction() {
+        let mut program = program_with_registers(0);
+        program.emit_insn(Insn::Commit);
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(matches!(
+            err,
+            LimboError::Internal(message) if message == "cannot commit - no transaction is active"
+        ));
+    }
+
+    #[test]
+    fn test_unfinished_transaction_is_rolled_back() {
+        let (io, db) = open_fixture(
+            "unfinished_transaction",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'one');",
+        );
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let program = insert_in_transaction(root_page, 5, None).build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        // Stop before the Halt that would commit the insert.
+        while state.pc < program.insns.len() - 1 {
+            match program.step_single(&mut state, db.pager.clone()).unwrap() {
+                StepOutcome::IO => io.run_once().unwrap(),
+                StepOutcome::Next { .. } => {}
+                _ => panic!("unexpected outcome"),
+            }
+        }
+        assert!(db.pager.in_write_transaction());
+        state.reset();
+        assert!(!db.pager.in_write_transaction());
+        assert_eq!(
+            query_rows(&io, &db, "SELECT * FROM t"),
+            vec![vec![OwnedValue::Integer(1), OwnedValue::Text("one".into())]]
+        );
+    }
+
+    #[test]
+    fn test_query_wal_database() {
+        // The first commit of a new WAL database puts every page, including
+        // the header on page 1, in the WAL only.
+        let source = create_fixture("wal-source", "PRAGMA journal_mode = WAL;");
+        let conn = rusqlite::Connection::open(&source).unwrap();
+        conn.execute_batch(
+            "PRAGMA wal_autocheckpoint = 0;
+             CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'one'), (2, 'two');",
+        )
+        .unwrap();
+        // Copy the files before closing the connection checkpoints the WAL.
+        let path = create_fixture("wal", "");
+        std::fs::copy(&source, &path).unwrap();
+        let wal_path = |path: &std::path::Path| format!("{}-wal", path.display());
+        std::fs::copy(wal_path(&source), wal_path(&path)).unwrap();
+        drop(conn);
+        let _ = std::fs::remove_file(&source);
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            query_rows(&io, &db, "SELECT * FROM t"),
+            vec![
+                vec![OwnedValue::Integer(1), OwnedValue::Text("one".into())],
+                vec![OwnedValue::Integer(2), OwnedValue::Text("two".into())],
+            ]
+        );
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal_path(&path));
+    }
+
+    #[test]
+    fn test_rollback_to_inner_savepoint() {
+        let path = create_fixture(
+            "savepoint",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT);
+             INSERT INTO t VALUES (1, 'one');",
+        );
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+        let root_page = db.schema.get_table("t").unwrap().root_page;
+        let mut program = program_with_registers(4);
+        program.emit_insn(Insn::Transaction { write: true });
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: 0,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+        // The record (NULL, 'new'), where the NULL is the rowid alias.
+        program.emit_insn(Insn::Blob {
+            value: vec![3, 0, 19, b'n', b'e', b'w'],
+            dest: 1,
+        });
+        for (key, name) in [(5, "outer"), (6, "inner")] {
+            program.emit_insn(Insn::String8 {
+                value: name.to_string(),
+                dest: 2,
+            });
+            program.emit_insn(Insn::Savepoint { name_reg: 2 });
+            program.emit_insn(Insn::Integer {
+                value: key,
+                dest: 0,
+            });
+            program.emit_insn(Insn::Insert {
+                cursor_id: 0,
+                key_reg: 0,
+                record_reg: 1,
+            });
+        }
+        // Roll back the insert of 6, then the outer savepoint is released
+        // with the insert of 5.
+        program.emit_insn(Insn::RollbackTo { name_reg: 2 });
+        program.emit_insn(Insn::String8 {
+            value: "OUTER".to_string(),
+            dest: 3,
+        });
+        program.emit_insn(Insn::ReleaseSavepoint { name_reg: 3 });
+        program.emit_insn(Insn::Commit);
+        program.emit_insn(Insn::Halt);
+        run_program(&io, &db, program);
+        let expected = vec![
+            vec![OwnedValue::Integer(1), OwnedValue::Text("one".into())],
+            vec![OwnedValue::Integer(5), OwnedValue::Text("new".into())],
+        ];
+        assert_eq!(query_rows(&io, &db, "SELECT * FROM t"), expected);
+        drop(db);
+        let db = Database::open_file(io.clone(), path.to_str().unwrap()).unwrap();
+        assert_eq!(query_rows(&io, &db, "SELECT * FROM t"), expected);
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}-journal", path.display()));
+    }
+
+    #[rstest]
+    #[case::no_transaction(
+        Insn::Savepoint { name_reg: 0 },
+        "cannot create a savepoint - no write transaction is active"
+    )]
+    #[case::release(Insn::ReleaseSavepoint { name_reg: 0 }, "no such savepoint: sp")]
+    #[case::rollback_to(Insn::RollbackTo { name_reg: 0 }, "no such savepoint: sp")]
+    fn test_savepoint_errors(#[case] insn: Insn, #[case] message: &str) {
+        let mut program = program_with_registers(1);
+        program.emit_insn(Insn::String8 {
+            value: "sp".to_string(),
+            dest: 0,
+        });
+        program.emit_insn(insn);
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let err = match program.step(&mut state, test_pager()) {
+            Err(err) => err,
+            Ok(_) => panic!("expected an error"),
+        };
+        assert_eq!(err.to_string(), message);
+    }
+
+    fn call_double(args: Vec<Insn>) -> Result<OwnedValue, LimboError> {
+        let mut functions = FunctionRegistry::new();
+        functions.register(
+            "double",
+            1,
+            Box::new(|args: &[OwnedValue]| match &args[0] {
+                OwnedValue::Integer(i) => Ok(OwnedValue::Integer(i * 2)),
+                _ => anyhow::bail!("double() expects an integer"),
+            }),
+        );
+        let mut program = program_with_registers(3);
+        let arg_count = args.len();
+        for arg in args {
+            program.emit_insn(arg);
+        }
+        program.emit_insn(Insn::Function {
+            func: Func::External("DOUBLE".to_string()),
+            start_reg: 0,
+            arg_count,
+            dest_reg: 2,
+        });
+        program.emit_insn(Insn::ResultRow {
+            register_start: 2,
+            register_end: 3,
+        });
+        program.emit_insn(Insn::Halt);
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        state.set_functions(Rc::new(RefCell::new(functions)));
+        match program.step(&mut state, test_pager())? {
+            StepResult::Row(record) => Ok(to_owned_value(&record.values[0])),
+            _ => panic!("expected a result row"),
+        }
+    }
+
+    #[test]
+    fn test_external_function() {
+        let arg = Insn::Integer { value: 21, dest: 0 };
+        assert_eq!(call_double(vec![arg]).unwrap(), OwnedValue::Integer(42));
+    }
+
+    #[rstest]
+    #[case::error(vec![Insn::String8 { value: "x".to_string(), dest: 0 }], "double() expects an integer")]
+    #[case::wrong_arg_count(vec![], "wrong number of arguments to function double()")]
+    fn test_external_function_errors(#[case] args: Vec<Insn>, #[case] message: &str) {
+        let err = call_double(args).unwrap_err();
+        assert_eq!(err.to_string(), message);
+    }
+
+    fn program_with_registers(count: usize) -> ProgramBuilder {
+        let mut program = ProgramBuilder::new();
+        program.alloc_registers(count);
+        program
+    }
+
+    /// Run a program which doesn't read pages and collect its result rows.
+    fn collect_rows(program: ProgramBuilder) -> Vec<Vec<OwnedValue>> {
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        let mut rows = Vec::new();
+        loop {
+            match program.step(&mut state, test_pager()).unwrap() {
+                StepResult::Row(record) => {
+                    rows.push(record.values.iter().map(to_owned_value).collect());
+                }
+                StepResult::Done => break,
+                _ => panic!("unexpected step result"),
+            }
+        }
+        rows
+    }
+
+    fn assert_result_row(program: ProgramBuilder, expected: &[Value]) {
+        let program = program.build().unwrap();
+        let mut state = ProgramState::new(program.max_registers);
+        match program.step(&mut state, test_pager()).unwrap() {
+            StepResult::Row(record) => assert_eq!(record.values, expected),
+            _ => panic!("expected a result row"),
+        }
+    }
 }
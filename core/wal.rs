@@ -0,0 +1,252 @@
+//! Write-ahead log reader.
+//!
+//! A database in WAL mode appends changed pages to a `-wal` file instead of
+//! writing them to the database file, until a checkpoint copies them back.
+//! The latest committed frame of a page in the WAL replaces the page in the
+//! database file.
+//!
+//! The WAL has the layout of an SQLite WAL:
+//!
+//! ```text
+//! +-----------+----------------------+------+-----+
+//! |  Header   |     Frame header     | Page | ... |
+//! | (32 bytes)|      (24 bytes)      |      |     |
+//! +-----------+----------------------+------+-----+
+//! ```
+//!
+//! Frames are only valid up to the last commit frame whose salts match the
+//! header and whose checksums, which run over the header and every frame
+//! before it, are correct. SQLite keeps an index of the frames in the
+//! `-shm` file; this reader builds its own index when it opens the WAL.
+use crate::io::{is_not_found, read_at, Completion, File, IO};
+use crate::{PageIO, PageSource};
+use anyhow::{anyhow, Result};
+use log::trace;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The magic number of a WAL whose checksums use little-endian words. The
+/// magic number plus one means big-endian words.
+const WAL_MAGIC: u32 = 0x377f0682;
+
+const WAL_HEADER_SIZE: usize = 32;
+
+const WAL_FRAME_HEADER_SIZE: usize = 24;
+
+pub struct Wal {
+    /// The latest committed frame of each page, numbered from 1.
+    frames: Rc<HashMap<usize, usize>>,
+    page_source: PageSource,
+}
+
+impl Wal {
+    /// Open the WAL at `path` and index its committed frames. Returns `None`
+    /// if there is no WAL or it holds no committed frames.
+    pub fn open(io: &dyn IO, path: &str) -> Result<Option<Wal>> {
+        let file = match io.open_file(path) {
+            Ok(file) => file,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let size = file.size()?;
+        if size < WAL_HEADER_SIZE {
+            return Ok(None);
+        }
+        let data = read_at(&*file, io, 0, size)?;
+        let magic = read_u32(&data, 0);
+        if magic & !1 != WAL_MAGIC {
+            return Err(anyhow!("invalid WAL magic: {:#x}", magic));
+        }
+        let big_endian = magic & 1 == 1;
+        let page_size = match read_u32(&data, 8) {
+            1 => 65536,
+            page_size => page_size as usize,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            return Err(anyhow!("invalid WAL page size: {}", page_size));
+        }
+        let mut sum = checksum((0, 0), &data[0..24], big_endian);
+        if sum != (read_u32(&data, 24), read_u32(&data, 28)) {
+            // The header was not completely written, so no frame is valid.
+            return Ok(None);
+        }
+        let salts = &data[16..24];
+        let mut frames = HashMap::new();
+        let mut uncommitted = HashMap::new();
+        let frame_size = WAL_FRAME_HEADER_SIZE + page_size;
+        let mut pos = WAL_HEADER_SIZE;
+        while pos + frame_size <= size {
+            let frame = &data[pos..pos + frame_size];
+            let frame_no = (pos - WAL_HEADER_SIZE) / frame_size + 1;
+            pos += frame_size;
+            if &frame[8..16] != salts {
+                break;
+            }
+            sum = checksum(sum, &frame[0..8], big_endian);
+            sum = checksum(sum, &frame[WAL_FRAME_HEADER_SIZE..], big_endian);
+            if sum != (read_u32(frame, 16), read_u32(frame, 20)) {
+                break;
+            }
+            let page_idx = read_u32(frame, 0) as usize;
+            uncommitted.insert(page_idx, frame_no);
+            // A frame with the database size after the transaction ends it.
+            if read_u32(frame, 4) != 0 {
+                frames.extend(uncommitted.drain());
+            }
+        }
+        trace!("WAL has {} committed pages", frames.len());
+        if frames.is_empty() {
+            return Ok(None);
+        }
+        let frames = Rc::new(frames);
+        let page_source = PageSource::from_io(Rc::new(WalFile {
+            file,
+            frames: frames.clone(),
+            page_size,
+        }));
+        Ok(Some(Self {
+            frames,
+            page_source,
+        }))
+    }
+
+    /// Whether the WAL has a committed version of the page.
+    pub fn contains(&self, page_idx: usize) -> bool {
+        self.frames.contains_key(&page_idx)
+    }
+
+    /// The pages in the WAL. Only pages the WAL contains can be read.
+    pub fn page_source(&self) -> &PageSource {
+        &self.page_source
+    }
+}
+
+/// Reads pages from the latest committed frame that holds them.
+struct WalFile {
+    file: Box<dyn File>,
+    frames: Rc<HashMap<usize, usize>>,
+    page_size: usize,
+}
+
+impl PageIO for WalFile {
+    fn get(&self, page_idx: usize, c: Rc<Completion>) -> Result<()> {
+        let frame_no = match self.frames.get(&page_idx) {
+            Some(frame_no) => *frame_no,
+            None => anyhow::bail!("page {} is not in the WAL", page_idx),
+        };
+        trace!("read page {} from WAL frame {}", page_idx, frame_no);
+        let frame_size = WAL_FRAME_HEADER_SIZE + self.page_size;
+        let pos = WAL_HEADER_SIZE + (frame_no - 1) * frame_size + WAL_FRAME_HEADER_SIZE;
+        self.file.pread(pos, c)
+    }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+/// Continue the checksum `sum` over `data`, whose length is a multiple of 8,
+/// as pairs of 32-bit words in the byte order of the WAL.
+fn checksum(sum: (u32, u32), data: &[u8], big_endian: bool) -> (u32, u32) {
+    let word = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let (mut s1, mut s2) = sum;
+    for chunk in data.chunks_exact(8) {
+        s1 = s1.wrapping_add(word(&chunk[0..4])).wrapping_add(s2);
+        s2 = s2.wrapping_add(word(&chunk[4..8])).wrapping_add(s1);
+    }
+    (s1, s2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::empty_completion;
+    use crate::MemoryIO;
+    use std::cell::RefCell;
+
+    const PAGE_SIZE: usize = 512;
+
+    /// The bytes of a WAL with a frame for each `(page_idx, commit, fill)`,
+    /// whose page is filled with `fill`.
+    fn wal_bytes(frames: &[(u32, bool, u8)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(WAL_MAGIC + 1).to_be_bytes());
+        buf.extend_from_slice(&3007000u32.to_be_bytes());
+        buf.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut sum = checksum((0, 0), &buf, true);
+        buf.extend_from_slice(&sum.0.to_be_bytes());
+        buf.extend_from_slice(&sum.1.to_be_bytes());
+        for (page_idx, commit, fill) in frames {
+            let mut header = Vec::new();
+            header.extend_from_slice(&page_idx.to_be_bytes());
+            header.extend_from_slice(&(*commit as u32 * 4).to_be_bytes());
+            let page = vec![*fill; PAGE_SIZE];
+            sum = checksum(sum, &header, true);
+            sum = checksum(sum, &page, true);
+            header.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            header.extend_from_slice(&sum.0.to_be_bytes());
+            header.extend_from_slice(&sum.1.to_be_bytes());
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(&page);
+        }
+        buf
+    }
+
+    fn open_wal(io: &Rc<dyn IO>, data: Vec<u8>) -> Option<Wal> {
+        let file = io.open_file("test.db-wal").unwrap();
+        file.pwrite(0, Rc::new(RefCell::new(data)), empty_completion())
+            .unwrap();
+        Wal::open(&**io, "test.db-wal").unwrap()
+    }
+
+    fn read_page(wal: &Wal, page_idx: usize) -> Vec<u8> {
+        let data = Rc::new(RefCell::new(Vec::new()));
+        let complete = Box::new({
+            let data = data.clone();
+            move |buf: &crate::Buffer| {
+                data.replace(buf.as_slice().to_vec());
+            }
+        });
+        let buf = crate::Buffer::allocate(PAGE_SIZE, Rc::new(|_buf| {}));
+        let c = Rc::new(Completion::new(buf, complete));
+        wal.page_source().get(page_idx, c).unwrap();
+        data.take()
+    }
+
+    #[test]
+    fn test_latest_committed_frame() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        let frames = [(2, false, 1), (3, true, 2), (2, true, 3), (3, false, 4)];
+        let wal = open_wal(&io, wal_bytes(&frames)).unwrap();
+        assert!(!wal.contains(1));
+        assert_eq!(read_page(&wal, 2), vec![3; PAGE_SIZE]);
+        // The last frame of page 3 is not committed.
+        assert_eq!(read_page(&wal, 3), vec![2; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_invalid_checksum_ends_wal() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        let mut data = wal_bytes(&[(2, true, 1), (2, true, 2)]);
+        let last = data.len() - 1;
+        data[last] = 0;
+        let wal = open_wal(&io, data).unwrap();
+        assert_eq!(read_page(&wal, 2), vec![1; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_no_committed_frames() {
+        let io: Rc<dyn IO> = Rc::new(MemoryIO::new().unwrap());
+        assert!(open_wal(&io, Vec::new()).is_none());
+        assert!(open_wal(&io, wal_bytes(&[(2, false, 1)])).is_none());
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(non_camel_case_types)]
+#![allow(clippy::missing_safety_doc)]
 
 use std::cell::RefCell;
 use std::ffi;
@@ -13,6 +14,7 @@ pub const SQLITE_ROW: ffi::c_int = 100;
 pub const SQLITE_DONE: ffi::c_int = 101;
 
 pub struct sqlite3 {
+    #[allow(dead_code)]
     pub(crate) db: limbo_core::Database,
     pub(crate) conn: limbo_core::Connection,
 }
@@ -111,7 +113,7 @@ pub unsafe extern "C" fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> ffi::c_int
 #[no_mangle]
 pub unsafe extern "C" fn sqlite3_step(stmt: *mut sqlite3_stmt) -> std::ffi::c_int {
     let stmt = &mut *stmt;
-    if let Ok(result) = stmt.stmt.step() {
+    if let Ok(result) = stmt.stmt.try_step() {
         match result {
             limbo_core::RowResult::IO => SQLITE_BUSY,
             limbo_core::RowResult::Done => SQLITE_DONE,
@@ -137,10 +139,7 @@ pub unsafe extern "C" fn sqlite3_column_text(
         None => return std::ptr::null(),
     };
     match row.values.get(idx as usize) {
-        Some(value) => match value {
-            limbo_core::Value::Text(text) => text.as_bytes().as_ptr(),
-            _ => std::ptr::null(),
-        },
-        None => std::ptr::null(),
+        Some(limbo_core::Value::Text(text)) => text.as_bytes().as_ptr(),
+        _ => std::ptr::null(),
     }
 }